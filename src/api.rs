@@ -0,0 +1,367 @@
+//! JSON in, JSON out: the routes for scripts and other apps, mounted at /api/v1. Errors come as
+//! {"error": "..."} with a matching status, never as HTML or plain text.
+
+use std::sync::Arc;
+
+use rocket::http::Status;
+use rocket::serde::json::{Json, Value};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Route, State};
+
+use crate::area::compute_area;
+use crate::auth::Caller;
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::gc::{ApiUsage, Cache};
+use crate::gcgeo::{self, Coordinate, Geocache, GeocacheFilter, TrackFormat};
+use crate::job::{CodeSelection, Job, JobQueue, JobStatus, SortOrder};
+use crate::polygon::compute_polygon;
+use crate::track::compute_track;
+use crate::{JobResult, JobSummary, WithHeaders};
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        create_area,
+        create_track,
+        create_polygon,
+        list,
+        status,
+        geocaches,
+        geocache
+    ]
+}
+
+/// What all job requests have in common.
+#[derive(Debug, Default, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+struct JobOptions {
+    /// always fetched
+    include_codes: Vec<String>,
+    /// dropped even if they match
+    exclude_codes: Vec<String>,
+    /// the complete filter, instead of the profile or the defaults of the job kind
+    filter: Option<GeocacheFilter>,
+    /// named filter, e.g. kid-friendly
+    profile: Option<String>,
+    /// webhook URL, ntfy:topic or telegram:chat_id
+    callback: Option<String>,
+    /// a new job even if an identical one ran recently
+    force: bool,
+    /// high, normal or low
+    priority: Option<String>,
+}
+
+impl JobOptions {
+    fn selection(&self) -> CodeSelection {
+        CodeSelection::parse(
+            Some(&self.include_codes.join(",")),
+            Some(&self.exclude_codes.join(",")),
+        )
+    }
+
+    fn filter(
+        &self,
+        defaults: GeocacheFilter,
+        config: &Config,
+    ) -> Result<GeocacheFilter, ApiError> {
+        match &self.filter {
+            Some(filter) => Ok(filter.clone()),
+            None => crate::profile(self.profile.as_deref(), defaults, config)
+                .map_err(ApiError::bad_request),
+        }
+    }
+
+    fn callback(&self) -> Result<Option<crate::notify::Callback>, ApiError> {
+        self.callback
+            .as_deref()
+            .map(|callback| callback.parse().map_err(ApiError::bad_request))
+            .transpose()
+    }
+
+    fn priority(&self) -> Result<Option<crate::job::Priority>, ApiError> {
+        self.priority
+            .as_deref()
+            .map(|priority| priority.parse().map_err(ApiError::bad_request))
+            .transpose()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct AreaJob {
+    lat: f64,
+    lon: f64,
+    /// meters
+    radius: f64,
+    #[serde(flatten)]
+    options: JobOptions,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct TrackJob {
+    /// GPX, KML or GeoJSON document
+    track: String,
+    corridor_m: Option<u16>,
+    #[serde(default)]
+    waypoints: bool,
+    #[serde(default)]
+    reverse: bool,
+    split_km: Option<f64>,
+    #[serde(default)]
+    split_days: bool,
+    #[serde(flatten)]
+    options: JobOptions,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct PolygonJob {
+    /// GeoJSON Polygon or MultiPolygon, also as a Feature or FeatureCollection
+    polygon: Value,
+    #[serde(flatten)]
+    options: JobOptions,
+}
+
+/// The summary of the new job, 200 if it was already done, 202 while it's running.
+fn summary(job: &Job, jobs: &JobQueue) -> JobResult {
+    let snapshot = job.get_snapshot();
+    JobResult::Summary(JobSummary::new(jobs.status(job), snapshot.as_ref()))
+}
+
+#[post("/jobs/area", format = "json", data = "<area>")]
+async fn create_area(
+    area: Json<AreaJob>,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+    config: &State<Config>,
+) -> Result<JobResult, ApiError> {
+    caller.check_quota(jobs)?;
+    let center = Coordinate {
+        lat: area.lat,
+        lon: area.lon,
+    };
+    crate::check_area(&center, area.radius)?;
+    let job = compute_area(
+        &center,
+        area.radius,
+        area.options.selection(),
+        area.options.filter(GeocacheFilter::default(), config)?,
+        area.options.callback()?,
+        area.options.force,
+        area.options.priority()?,
+        caller.name(),
+        jobs.inner(),
+        cache.inner(),
+    )
+    .await;
+    Ok(summary(&job, jobs))
+}
+
+#[post("/jobs/track", format = "json", data = "<request>")]
+async fn create_track(
+    request: Json<TrackJob>,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+    config: &State<Config>,
+) -> Result<JobResult, ApiError> {
+    caller.check_quota(jobs)?;
+    let data = request.track.as_bytes();
+    let track = crate::parse_track(
+        data,
+        TrackFormat::sniff(data),
+        request.waypoints,
+        request.reverse,
+    )?;
+    let job = compute_track(
+        track,
+        crate::corridor_m(request.corridor_m)?,
+        crate::split(request.split_km, Some(request.split_days))?,
+        request.options.selection(),
+        request
+            .options
+            .filter(GeocacheFilter::quick_stop(), config)?,
+        request.options.callback()?,
+        request.options.force,
+        request.options.priority()?,
+        caller.name(),
+        jobs.inner(),
+        cache.inner(),
+        config.inner(),
+    )
+    .await;
+    Ok(summary(&job, jobs))
+}
+
+#[post("/jobs/polygon", format = "json", data = "<request>")]
+async fn create_polygon(
+    request: Json<PolygonJob>,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+    config: &State<Config>,
+) -> Result<JobResult, ApiError> {
+    caller.check_quota(jobs)?;
+    let polygon: gcgeo::Polygon = request
+        .polygon
+        .to_string()
+        .parse()
+        .map_err(|e: String| ApiError::bad_request(e))?;
+    let job = compute_polygon(
+        polygon,
+        request.options.selection(),
+        request.options.filter(GeocacheFilter::default(), config)?,
+        request.options.callback()?,
+        request.options.force,
+        request.options.priority()?,
+        caller.name(),
+        jobs.inner(),
+        cache.inner(),
+    )
+    .await;
+    Ok(summary(&job, jobs))
+}
+
+#[get("/jobs")]
+fn list(caller: Caller, jobs: &State<JobQueue>) -> Json<Vec<JobStatus>> {
+    let mut statuses: Vec<JobStatus> = jobs
+        .list()
+        .iter()
+        .filter(|job| caller.sees(job.owner.as_deref()))
+        .map(|job| jobs.status(job))
+        .collect();
+    statuses.sort_by_key(|status| std::cmp::Reverse(status.started_at));
+    Json(statuses)
+}
+
+#[get("/jobs/<job_id>")]
+async fn status(
+    job_id: &str,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+) -> Result<JobResult, ApiError> {
+    let job = crate::find_job(job_id, &caller, jobs, cache).await?;
+    Ok(summary(&job, jobs))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct JobGeocaches {
+    snapshot: chrono::DateTime<chrono::Utc>,
+    geocaches: Vec<Geocache>,
+}
+
+/// The geocaches of a finished job, 409 while it's still running.
+#[get("/jobs/<job_id>/geocaches?<leg>&<sort>&<limit>")]
+async fn geocaches(
+    job_id: &str,
+    leg: Option<usize>,
+    sort: Option<&str>,
+    limit: Option<usize>,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+) -> Result<Json<JobGeocaches>, ApiError> {
+    let job = crate::find_job(job_id, &caller, jobs, cache).await?;
+    let Some(snapshot) = job.get_snapshot() else {
+        return Err(ApiError::new(
+            Status::Conflict,
+            format!("job {} isn't finished yet", job.id),
+        ));
+    };
+    let mut snapshot = crate::with_leg(snapshot, leg)?;
+    if let Some(sort) = sort {
+        let order: SortOrder = sort.parse().map_err(ApiError::bad_request)?;
+        order.sort(
+            &mut snapshot.geocaches,
+            &snapshot.distances,
+            &snapshot.offsets,
+        );
+    }
+    if let Some(limit) = limit {
+        snapshot.geocaches.truncate(limit);
+    }
+    Ok(Json(JobGeocaches {
+        snapshot: snapshot.ts,
+        geocaches: snapshot.geocaches,
+    }))
+}
+
+/// One geocache from the cache, fetched if it isn't there or too old. X-Provenance tells where
+/// it came from: cache, live or premium.
+#[get("/geocaches/<code>")]
+async fn geocache(
+    code: &str,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+) -> Result<WithHeaders<Json<Geocache>>, ApiError> {
+    caller.check_quota(jobs)?;
+    let code = Geocache::parse_code(code).map_err(ApiError::bad_request)?;
+    let lookup = cache
+        .lookup(vec![code.clone()], &ApiUsage::default())
+        .await
+        .map_err(|e| {
+            error!("Unable to get geocache {}: {}", code, e);
+            ApiError::new(Status::BadGateway, e.to_string())
+        })?
+        .into_iter()
+        .next()
+        .ok_or_else(|| ApiError::new(Status::NotFound, format!("no geocache {}", code)))?;
+    let header = crate::provenance(&lookup);
+    lookup
+        .geocache
+        .map(|geocache| WithHeaders(Json(geocache), vec![header]))
+        .ok_or_else(|| ApiError::new(Status::NotFound, format!("no geocache {}", code)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn area_request() {
+        let area: AreaJob = rocket::serde::json::from_str(
+            r#"{"lat": 47.9, "lon": 8.5, "radius": 2000, "include_codes": ["gc1bxn4"],
+                "filter": {"types": ["Traditional"], "max_d": 2.5}, "force": true}"#,
+        )
+        .unwrap();
+        assert!(area.options.force);
+        assert_eq!(area.options.selection().include, vec!["GC1BXN4"]);
+        let filter = area
+            .options
+            .filter(GeocacheFilter::quick_stop(), &Config::default())
+            .unwrap();
+        assert_eq!(filter.max_d, Some(2.5));
+        assert!(!filter.exclude_premium);
+
+        let area: AreaJob = rocket::serde::json::from_str(
+            r#"{"lat": 47.9, "lon": 8.5, "radius": 2000, "profile": "no-such-profile"}"#,
+        )
+        .unwrap();
+        assert!(area.options.selection().include.is_empty());
+        assert!(area
+            .options
+            .filter(GeocacheFilter::default(), &Config::default())
+            .is_err());
+    }
+
+    #[test]
+    fn area_bounds() {
+        let center = Coordinate {
+            lat: 47.9,
+            lon: 8.5,
+        };
+        assert!(crate::check_area(&center, 2000.0).is_ok());
+        for radius in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e9] {
+            let e = crate::check_area(&center, radius).unwrap_err();
+            assert_eq!(e.status, Status::BadRequest);
+        }
+        for (lat, lon) in [(91.0, 8.5), (47.9, -181.0), (f64::NAN, 8.5)] {
+            assert!(crate::check_area(&Coordinate { lat, lon }, 2000.0).is_err());
+        }
+    }
+}
@@ -1,22 +1,68 @@
 use crate::gc::Cache;
-use crate::gcgeo::{Coordinate, Tile};
-use crate::job::{Job, JobQueue};
+use crate::gcgeo::{Circle, Coordinate, Geocache, GeocacheFilter, Tile};
+use crate::job::{approx_within, CodeSelection, InputKey, Job, JobQueue, Priority};
+use crate::notify::Callback;
 use std::sync::Arc;
 
-pub async fn compute_area(coordinate: &Coordinate, radius: f64, jobs: &JobQueue) -> Arc<Job> {
-    let job = Arc::new(Job::new());
+/// Largest radius in meters an area job covers, about 3000 tiles at zoom 14
+pub const MAX_RADIUS_M: f64 = 50_000.0;
+
+#[allow(clippy::too_many_arguments)]
+pub async fn compute_area(
+    coordinate: &Coordinate,
+    radius: f64,
+    selection: CodeSelection,
+    filter: GeocacheFilter,
+    callback: Option<Callback>,
+    force: bool,
+    priority: Option<Priority>,
+    owner: Option<String>,
+    jobs: &JobQueue,
+    cache: &Arc<Cache>,
+) -> Arc<Job> {
+    let key = InputKey::new(
+        "area",
+        &[vec![[coordinate.lon, coordinate.lat]]],
+        &radius.to_string(),
+        &selection,
+        &filter,
+    );
+    if let Some(job) = jobs
+        .find_identical(key, owner.as_deref())
+        .filter(|_| !force)
+    {
+        info!("Reusing job {} for the same area", job.id);
+        return job;
+    }
+    let tiles = Tile::near(coordinate, radius, Tile::DEFAULT_ZOOM);
+    let job = Arc::new(
+        Job::with_selection(selection)
+            .with_kind("area")
+            .with_callback(callback)
+            .with_filter(filter)
+            .with_priority(priority.unwrap_or_else(|| Priority::for_tiles(tiles.len())))
+            .with_owner(owner)
+            .with_input_key(key),
+    );
     let job_for_result = job.clone();
     jobs.add(job.clone());
 
-    let tiles = Tile::near(coordinate, radius);
-    let handle = tokio::task::spawn(async move {
-        let cache = Cache::new_lite().await.unwrap();
-        job.process(tiles, &cache).await;
+    // codes in the corners of the covering tiles aren't worth a fetch
+    let pre_filter = approx_within(Circle {
+        center: coordinate.clone(),
+        radius,
+    });
+    let center = coordinate.clone();
+    let job_for_filter = job.clone();
+    let post_filter = move |gc: &Geocache| {
+        job_for_filter.set_offset(&gc.code, center.distance(&gc.coord));
+        true
+    };
+    let cache = cache.clone();
+    jobs.spawn(job.clone(), async move {
+        job.process_filtered(tiles, &cache, pre_filter, post_filter)
+            .await;
     });
-
-    // If everything is already cached, the job will finish very quickly, and we can immediately return the result
-    let timeout = tokio::time::Duration::from_secs(2);
-    let _ = tokio::time::timeout(timeout, handle).await;
 
     job_for_result
 }
@@ -0,0 +1,251 @@
+//! API keys for the web service. With require_api_key set, requests without a valid key of a user
+//! are turned away before they reach a route, except for the login and what the login needs.
+
+use std::sync::Arc;
+
+use chrono::{Duration, Utc};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Data, Request};
+
+use crate::config::Config;
+use crate::gc::{Cache, User};
+use crate::job::JobQueue;
+
+/// Where requests without a valid key end up.
+pub const REQUIRED_PATH: &str = "/auth/required";
+
+/// Name of the user behind admin_api_key. Reserved, no user in the database can have it.
+pub const ADMIN_USER: &str = "admin";
+
+/// Paths that work without a key.
+const PUBLIC_PATHS: [&str; 4] = [REQUIRED_PATH, "/login", "/auth/callback", "/static/"];
+
+/// The user of a request as found by the fairing, once per request.
+#[derive(Debug, Clone, Default)]
+struct Resolved {
+    user: Option<User>,
+    /// a key was given, but it belongs to nobody
+    invalid: bool,
+}
+
+/// The key from the X-Api-Key header, a bearer token, the api_key query parameter or the api_key
+/// cookie set by /login, in that order.
+pub fn api_key(req: &Request<'_>) -> Option<String> {
+    req.headers()
+        .get_one("X-Api-Key")
+        .or_else(|| {
+            req.headers()
+                .get_one("Authorization")
+                .and_then(|auth| auth.strip_prefix("Bearer "))
+        })
+        .map(String::from)
+        .or_else(|| req.query_value::<String>("api_key").and_then(Result::ok))
+        .or_else(|| {
+            req.cookies()
+                .get("api_key")
+                .map(|cookie| cookie.value().to_string())
+        })
+        .filter(|key| !key.is_empty())
+}
+
+/// Compares keys in time that depends on their length only, not on how much of them matches.
+fn same_key(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+async fn resolve(req: &Request<'_>) -> Resolved {
+    let Some(key) = api_key(req) else {
+        return Resolved::default();
+    };
+    let config = req.rocket().state::<Config>();
+    let admin_key = config.and_then(|config| config.admin_api_key.as_deref());
+    if admin_key.is_some_and(|admin_key| same_key(admin_key, &key)) {
+        return Resolved {
+            user: Some(User {
+                name: ADMIN_USER.to_string(),
+                admin: true,
+                jobs_per_hour: None,
+            }),
+            invalid: false,
+        };
+    }
+    let Some(cache) = req.rocket().state::<Arc<Cache>>() else {
+        return Resolved::default();
+    };
+    match cache.user_for_key(&key).await {
+        Ok(user) => Resolved {
+            invalid: user.is_none(),
+            user,
+        },
+        Err(e) => {
+            error!("Unable to look up API key: {}", e);
+            Resolved {
+                user: None,
+                invalid: true,
+            }
+        }
+    }
+}
+
+/// Finds the user of each request and sends requests that need a key and have none, or a wrong
+/// one, to REQUIRED_PATH.
+pub struct ApiKeyAuth;
+
+#[rocket::async_trait]
+impl Fairing for ApiKeyAuth {
+    fn info(&self) -> Info {
+        Info {
+            name: "API key authentication",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let resolved = req.local_cache_async(resolve(req)).await.clone();
+        let required = req
+            .rocket()
+            .state::<Config>()
+            .is_some_and(|config| config.require_api_key);
+        let path = req.uri().path().to_string();
+        let public = PUBLIC_PATHS.iter().any(|public| path.starts_with(public));
+        if !public && (resolved.invalid || (required && resolved.user.is_none())) {
+            info!(
+                "Rejecting {} {} without a valid API key",
+                req.method(),
+                path
+            );
+            req.set_method(Method::Get);
+            req.set_uri(Origin::parse(REQUIRED_PATH).unwrap());
+        }
+    }
+}
+
+/// The user making the request, None without a key when keys aren't required.
+pub struct Caller(pub Option<User>);
+
+impl Caller {
+    pub fn name(&self) -> Option<String> {
+        self.0.as_ref().map(|user| user.name.clone())
+    }
+
+    /// Whose jobs to list: all for admins, the user's own, or those nobody owns.
+    pub fn sees(&self, owner: Option<&str>) -> bool {
+        match &self.0 {
+            Some(user) if user.admin => true,
+            Some(user) => owner == Some(user.name.as_str()),
+            None => owner.is_none(),
+        }
+    }
+
+    /// 429 once the user started jobs_per_hour jobs within the last hour.
+    pub fn check_quota(&self, jobs: &JobQueue) -> Result<(), Status> {
+        let Some(user) = &self.0 else {
+            return Ok(());
+        };
+        let Some(limit) = user.jobs_per_hour else {
+            return Ok(());
+        };
+        let started = jobs.started_since(&user.name, Utc::now() - Duration::hours(1));
+        if started >= limit as usize {
+            info!(
+                "{} started {} jobs within the last hour, limit is {}",
+                user.name, started, limit
+            );
+            return Err(Status::TooManyRequests);
+        }
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Caller {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let resolved = req.local_cache_async(resolve(req)).await;
+        Outcome::Success(Caller(resolved.user.clone()))
+    }
+}
+
+/// Guard for the admin routes: an admin key, or anyone as long as no keys are configured.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let open = req
+            .rocket()
+            .state::<Config>()
+            .is_some_and(|config| !config.require_api_key && config.admin_api_key.is_none());
+        let resolved = req.local_cache_async(resolve(req)).await;
+        if open || resolved.user.as_ref().is_some_and(|user| user.admin) {
+            Outcome::Success(Admin)
+        } else {
+            Outcome::Error((Status::Forbidden, ()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_compare() {
+        assert!(same_key("secret", "secret"));
+        assert!(!same_key("secret", "secreT"));
+        assert!(!same_key("secret", "secret2"));
+        assert!(!same_key("", "secret"));
+    }
+
+    #[test]
+    fn visible_jobs() {
+        let user = |name: &str, admin| User {
+            name: name.to_string(),
+            admin,
+            jobs_per_hour: None,
+        };
+        assert!(Caller(None).sees(None));
+        assert!(!Caller(None).sees(Some("alice")));
+        assert!(Caller(Some(user("alice", false))).sees(Some("alice")));
+        assert!(!Caller(Some(user("alice", false))).sees(Some("bob")));
+        assert!(!Caller(Some(user("alice", false))).sees(None));
+        assert!(Caller(Some(user("admin", true))).sees(Some("bob")));
+    }
+
+    #[post("/logs")]
+    fn admin_only(_admin: Admin) {}
+
+    #[rocket::async_test]
+    async fn admin_routes_need_the_admin_key() {
+        use rocket::local::asynchronous::Client;
+
+        let config = Config {
+            admin_api_key: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let rocket = rocket::build()
+            .manage(config)
+            .mount("/", routes![admin_only]);
+        let client = Client::untracked(rocket).await.unwrap();
+        let status = |key: Option<&'static str>| {
+            let mut request = client.post("/logs");
+            if let Some(key) = key {
+                request = request.header(rocket::http::Header::new("X-Api-Key", key));
+            }
+            async move { request.dispatch().await.status() }
+        };
+        assert_eq!(status(None).await, Status::Forbidden);
+        assert_eq!(status(Some("wrong")).await, Status::Forbidden);
+        assert_eq!(status(Some("secret")).await, Status::Ok);
+    }
+}
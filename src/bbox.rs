@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use crate::gc::Cache;
+use crate::gcgeo::{BBox, Geocache, GeocacheFilter, Region, Tile};
+use crate::job::{approx_within, CodeSelection, InputKey, Job, JobQueue, Priority};
+use crate::notify::Callback;
+
+#[allow(clippy::too_many_arguments)]
+pub async fn compute_bbox(
+    bbox: BBox,
+    selection: CodeSelection,
+    filter: GeocacheFilter,
+    callback: Option<Callback>,
+    force: bool,
+    priority: Option<Priority>,
+    owner: Option<String>,
+    jobs: &JobQueue,
+    cache: &Arc<Cache>,
+) -> Arc<Job> {
+    let key = InputKey::new(
+        "bbox",
+        &[vec![
+            [bbox.min_lon, bbox.min_lat],
+            [bbox.max_lon, bbox.max_lat],
+        ]],
+        "",
+        &selection,
+        &filter,
+    );
+    if let Some(job) = jobs
+        .find_identical(key, owner.as_deref())
+        .filter(|_| !force)
+    {
+        info!("Reusing job {} for the same bounding box", job.id);
+        return job;
+    }
+    let tiles = bbox.tiles(Tile::DEFAULT_ZOOM);
+    let pre_filter = approx_within(bbox.clone());
+    let post_filter = move |gc: &Geocache| bbox.contains(&gc.coord);
+
+    let job = Arc::new(
+        Job::with_selection(selection)
+            .with_kind("bbox")
+            .with_callback(callback)
+            .with_filter(filter)
+            .with_priority(priority.unwrap_or_else(|| Priority::for_tiles(tiles.len())))
+            .with_owner(owner)
+            .with_input_key(key),
+    );
+    let job_for_result = job.clone();
+    jobs.add(job.clone());
+    let cache = cache.clone();
+    jobs.spawn(job.clone(), async move {
+        job.process_filtered(tiles, &cache, pre_filter, post_filter)
+            .await;
+    });
+
+    job_for_result
+}
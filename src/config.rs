@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use rocket::serde::Deserialize;
+
+use crate::gc::tokenstore::TokenStoreKind;
+use crate::gcgeo::GeocacheFilter;
+
+/// Service configuration, read from Rocket.toml or ROCKET_* environment variables.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct Config {
+    /// HTTP(S) or SOCKS proxy for all outgoing requests, e.g. socks5h://localhost:1080
+    pub proxy: Option<String>,
+    /// Comma separated hosts/domains/CIDRs that bypass the proxy, e.g. localhost,.internal
+    pub no_proxy: Option<String>,
+    /// Additional PEM encoded root certificates (file paths) to trust
+    pub root_certificates: Vec<String>,
+    /// Maximum number of Groundspeak calls per day, unlimited if not set
+    pub daily_budget: Option<u32>,
+    /// Hour of the day (UTC) at which the daily budget resets
+    pub budget_reset_hour: u32,
+    /// Write all Groundspeak responses as fixture files into this directory
+    pub record_fixtures: Option<String>,
+    /// Answer Groundspeak requests from fixture files in this directory instead of the network
+    pub replay_fixtures: Option<String>,
+    /// User agent for the map tile servers
+    pub tile_user_agent: String,
+    /// User agent for the geocache API
+    pub fetch_user_agent: String,
+    /// User agent for the OAuth token endpoint
+    pub auth_user_agent: String,
+    pub auth_redirect_url: String,
+    pub auth_username: String,
+    pub auth_password: String,
+    /// Where OAuth tokens are kept: settings (Postgres), file or keyring
+    pub token_store: TokenStoreKind,
+    /// JSON file for token_store = "file"
+    pub token_file: String,
+    /// Additional Groundspeak accounts, geocache fetches rotate over all accounts
+    pub auth_accounts: Vec<AuthAccount>,
+    /// Maximum number of Groundspeak calls per account and day, unlimited if not set
+    pub account_daily_limit: Option<u32>,
+    /// Directory for downloaded geocache images
+    pub image_dir: String,
+    /// LibreTranslate compatible endpoint for lang= exports, e.g. https://libretranslate.com/translate
+    pub translate_url: Option<String>,
+    pub translate_api_key: Option<String>,
+    /// Number of geocache chunks fetched from Groundspeak in parallel
+    pub fetch_concurrency: usize,
+    /// Number of jobs processed at the same time, further jobs wait in a queue
+    pub max_parallel_jobs: usize,
+    /// Base URL of this service for links in job notifications, e.g. https://gc.example.com
+    pub public_url: Option<String>,
+    /// Bot token for telegram:chat_id job callbacks
+    pub telegram_bot_token: Option<String>,
+    /// Simplify uploaded tracks, dropping points closer than this many meters to the simplified line (0 disables)
+    pub track_simplify_m: f64,
+    /// Directory with BMP icons for GPI exports: traditional.bmp, mystery.bmp, ..., found.bmp and default.bmp
+    pub gpi_icon_dir: Option<String>,
+    /// GPI waypoint title, e.g. "{code_short} {size_letter}{type_letter} {d}/{t}" (the default)
+    pub gpi_title: Option<String>,
+    /// GPI waypoint description, "{name}\n{hint}" by default
+    pub gpi_description: Option<String>,
+    /// Regions that get a periodic digest of new, archived and disabled geocaches
+    pub digests: Vec<DigestRegion>,
+    /// Hours between two digests
+    pub digest_interval_hours: u64,
+    /// Also write each digest as Markdown into this directory
+    pub digest_dir: Option<String>,
+    /// Turn away requests without the API key of a user, see /admin/users
+    pub require_api_key: bool,
+    /// Key of the built-in admin user, who may create other users
+    pub admin_api_key: Option<String>,
+    /// Job creating requests per API key (or client address without one) in a burst
+    pub rate_limit_burst: u32,
+    /// Rate at which the burst refills, 0 disables the limit
+    pub rate_limit_per_minute: f64,
+    /// Geocaching usernames of the people using this service, exclude_own=true skips their hides
+    pub owner_names: Vec<String>,
+    /// Filters selectable by name with profile=, replacing built-in presets of the same name
+    pub filter_profiles: HashMap<String, GeocacheFilter>,
+}
+
+impl Config {
+    /// The filter profile by name, from the configuration or the built-in presets.
+    pub fn filter_profile(&self, name: &str) -> Option<GeocacheFilter> {
+        self.filter_profiles
+            .get(name)
+            .cloned()
+            .or_else(|| GeocacheFilter::preset(name))
+    }
+}
+
+/// An additional Groundspeak account, log in through /auth/login?account=name.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AuthAccount {
+    pub name: String,
+    /// OAuth client id, defaults to auth_username
+    pub username: Option<String>,
+    /// OAuth client secret, defaults to auth_password
+    pub password: Option<String>,
+}
+
+/// A watched region, the latest digest is shown at /digest/name.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DigestRegion {
+    pub name: String,
+    /// minLat,minLon,maxLat,maxLon
+    pub bbox: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let user_agent = concat!("gc5/", env!("CARGO_PKG_VERSION")).to_string();
+        Self {
+            proxy: None,
+            no_proxy: None,
+            root_certificates: vec![],
+            daily_budget: None,
+            budget_reset_hour: 0,
+            record_fixtures: None,
+            replay_fixtures: None,
+            tile_user_agent: "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:109.0) Gecko/20100101 Firefox/112.0".to_string(),
+            fetch_user_agent: user_agent.clone(),
+            auth_user_agent: user_agent,
+            auth_redirect_url: String::new(),
+            auth_username: String::new(),
+            auth_password: String::new(),
+            token_store: TokenStoreKind::Settings,
+            token_file: "tokens.json".to_string(),
+            auth_accounts: vec![],
+            account_daily_limit: None,
+            image_dir: "images".to_string(),
+            translate_url: None,
+            translate_api_key: None,
+            fetch_concurrency: 4,
+            max_parallel_jobs: 2,
+            public_url: None,
+            telegram_bot_token: None,
+            track_simplify_m: 5.0,
+            gpi_icon_dir: None,
+            gpi_title: None,
+            gpi_description: None,
+            digests: vec![],
+            digest_interval_hours: 24 * 7,
+            digest_dir: None,
+            require_api_key: false,
+            admin_api_key: None,
+            rate_limit_burst: 10,
+            rate_limit_per_minute: 2.0,
+            owner_names: vec![],
+            filter_profiles: HashMap::new(),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use chrono::{Duration, Utc};
+use log::{error, info};
+
+use crate::config::{Config, DigestRegion};
+use crate::gc::{Cache, Digest};
+use crate::gcgeo::BBox;
+
+/// Periodically create a digest for every configured region.
+pub fn schedule_digests(config: &Config, cache: Arc<Cache>) {
+    if config.digests.is_empty() {
+        return;
+    }
+    let config = config.clone();
+    tokio::task::spawn(async move {
+        let every = Duration::hours(config.digest_interval_hours as i64);
+        // check hourly, so restarts don't postpone or repeat digests
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            for region in &config.digests {
+                if let Err(e) = run_digest(&cache, region, every, &config).await {
+                    error!("Unable to create digest for {}: {}", region.name, e);
+                }
+            }
+        }
+    });
+}
+
+async fn run_digest(
+    cache: &Cache,
+    region: &DigestRegion,
+    every: Duration,
+    config: &Config,
+) -> Result<(), crate::Error> {
+    let bbox: BBox = region.bbox.parse().map_err(|e| {
+        error!("Invalid digest region {}: {}", region.name, e);
+        crate::Error::Unknown
+    })?;
+    if let Some(latest) = cache.latest_digest(&region.name).await? {
+        if Utc::now() - latest.ts < every {
+            return Ok(());
+        }
+    }
+
+    let digest = cache.digest(&region.name, &bbox).await?;
+    if let Some(dir) = &config.digest_dir {
+        write_markdown(Path::new(dir), &digest)?;
+    }
+    Ok(())
+}
+
+fn write_markdown(dir: &Path, digest: &Digest) -> Result<(), std::io::Error> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!(
+        "{}-{}.md",
+        digest.region,
+        digest.ts.format("%Y-%m-%d")
+    ));
+    info!("Writing digest to {}", path.display());
+    std::fs::write(path, digest.to_markdown())
+}
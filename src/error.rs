@@ -0,0 +1,93 @@
+//! The error of all routes: a status and a message, sent as {"error": "..."}. Bad input is a
+//! 400, unknown jobs and geocaches 404, unfinished jobs 409, Groundspeak failures 502
+//! and an exhausted daily budget or no usable account 503.
+
+use rocket::http::Status;
+use rocket::response::Responder;
+use rocket::serde::json::{json, Json};
+
+use crate::gc;
+
+/// An error status with a message for the client.
+#[derive(Debug, PartialEq)]
+pub struct ApiError {
+    pub status: Status,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: Status, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(Status::BadRequest, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(Status::NotFound, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(Status::Conflict, message)
+    }
+}
+
+/// Helpers that only tell the status get its reason as the message.
+impl From<Status> for ApiError {
+    fn from(status: Status) -> Self {
+        Self::new(status, status.reason_lossy())
+    }
+}
+
+/// Failures of Groundspeak are a bad gateway, everything else is ours.
+impl From<gc::Error> for ApiError {
+    fn from(e: gc::Error) -> Self {
+        let status = match e {
+            gc::Error::Geocaching | gc::Error::GroundSpeak(_) | gc::Error::Reqwest(_) => {
+                Status::BadGateway
+            }
+            gc::Error::BudgetExhausted | gc::Error::NoAccount => Status::ServiceUnavailable,
+            _ => Status::InternalServerError,
+        };
+        Self::new(status, e.to_string())
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, req: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        (self.status, Json(json!({ "error": self.message }))).respond_to(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses() {
+        assert_eq!(
+            ApiError::from(gc::Error::Geocaching).status,
+            Status::BadGateway
+        );
+        assert_eq!(
+            ApiError::from(gc::Error::BudgetExhausted),
+            ApiError::new(
+                Status::ServiceUnavailable,
+                "daily groundspeak budget exhausted"
+            )
+        );
+        assert_eq!(
+            ApiError::from(gc::Error::NoAccount).status,
+            Status::ServiceUnavailable
+        );
+        assert_eq!(
+            ApiError::from(gc::Error::Unknown).status,
+            Status::InternalServerError
+        );
+        assert_eq!(ApiError::from(Status::NotFound).message, "Not Found");
+    }
+}
@@ -0,0 +1,710 @@
+use std::collections::HashSet;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+use futures::Stream;
+use geojson::GeoJson;
+use log::error;
+use rocket::http::{ContentType, MediaType};
+use tokio::sync::mpsc;
+
+use crate::config::Config;
+use crate::gc::garmin::{ExportMode, Garmin, GpxFlavor, Icons, PoiTemplates};
+use crate::gc::kml::Kml;
+use crate::gc::Error;
+use crate::gcgeo::{CacheType, Geocache, Health};
+use crate::job::Snapshot;
+
+/// What the request asked for besides the format.
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    pub mode: ExportMode,
+    pub flavor: GpxFlavor,
+    /// codes of found geocaches, formats that set them apart need them
+    pub found: HashSet<String>,
+    /// name of the download without the extension, shown inline if not set
+    pub file_stem: Option<String>,
+}
+
+impl ExportOptions {
+    /// The attachment name for the export, e.g. route-2024-06-01-tradis.gpx
+    pub fn file_name(&self, exporter: &dyn Exporter) -> Option<String> {
+        let stem = self.file_stem.as_ref()?;
+        Some(if exporter.uses_mode() {
+            format!("{}-{}.{}", stem, self.mode.file_suffix(), exporter.name())
+        } else {
+            format!("{}.{}", stem, exporter.name())
+        })
+    }
+}
+
+/// A download format for job results.
+pub trait Exporter: Send + Sync {
+    /// Short name, also accepted as the subtype in Accept headers, e.g. application/gpx
+    fn name(&self) -> &'static str;
+
+    fn content_type(&self) -> ContentType;
+
+    /// Whether ExportOptions::mode narrows the export down, it's part of the file name then
+    fn uses_mode(&self) -> bool {
+        true
+    }
+
+    fn write(
+        &self,
+        snapshot: Snapshot,
+        options: &ExportOptions,
+        writer: &mut dyn Write,
+    ) -> Result<(), Error>;
+}
+
+/// All formats, found by name or by the media type a client accepts.
+pub struct Exporters {
+    exporters: Vec<Arc<dyn Exporter>>,
+}
+
+impl Exporters {
+    /// Answer for clients that accept nothing in particular
+    const FALLBACK: &'static str = "geojson";
+
+    pub fn new(config: &Config, templates: PoiTemplates) -> Self {
+        let icons = Icons::new(config.gpi_icon_dir.as_deref());
+        Self {
+            exporters: vec![
+                Arc::new(GeoJsonExporter {}),
+                Arc::new(GpxExporter {}),
+                Arc::new(GpiExporter {
+                    icons: icons.clone(),
+                    templates: templates.clone(),
+                }),
+                Arc::new(GpiZipExporter {
+                    icons: icons.clone(),
+                    templates,
+                }),
+                Arc::new(KmlExporter {}),
+                Arc::new(KmzExporter { icons }),
+                Arc::new(CsvExporter {}),
+            ],
+        }
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<Arc<dyn Exporter>> {
+        self.exporters
+            .iter()
+            .find(|exporter| exporter.name() == name)
+            .cloned()
+    }
+
+    /// The exporter for a media type, GeoJSON for anything unknown.
+    pub fn for_media_type(&self, media_type: &MediaType) -> Arc<dyn Exporter> {
+        let sub = media_type.sub().as_str();
+        self.exporters
+            .iter()
+            .find(|exporter| exporter.name() == sub || exporter.content_type().sub() == sub)
+            .or_else(|| {
+                self.exporters
+                    .iter()
+                    .find(|exporter| exporter.name() == Self::FALLBACK)
+            })
+            .cloned()
+            .expect("the fallback exporter is always registered")
+    }
+}
+
+/// Bytes per chunk of a streamed export
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+/// Chunks buffered between the exporter and a slow client
+const STREAM_CHUNKS: usize = 16;
+
+/// Bytes written on a blocking thread, sent on as chunks of a response body.
+struct ChannelWriter {
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.sender
+            .blocking_send(buf.to_vec())
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run the exporter on a blocking thread and stream its output, so large results are never
+/// held in memory as a whole. Errors cut the output short, the status has been sent by then.
+pub fn stream(
+    exporter: Arc<dyn Exporter>,
+    snapshot: Snapshot,
+    options: ExportOptions,
+) -> impl Stream<Item = Vec<u8>> {
+    let (sender, receiver) = mpsc::channel(STREAM_CHUNKS);
+    tokio::task::spawn_blocking(move || {
+        let mut writer = BufWriter::with_capacity(STREAM_CHUNK_SIZE, ChannelWriter { sender });
+        let result = exporter
+            .write(snapshot, &options, &mut writer)
+            .and_then(|_| writer.flush().map_err(Error::from));
+        if let Err(e) = result {
+            error!("Unable to write {}: {}", exporter.name(), e);
+        }
+    });
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    })
+}
+
+pub struct GeoJsonExporter {}
+
+impl GeoJsonExporter {
+    /// Markers of archived and disabled geocaches
+    const INACTIVE_COLOR: &'static str = "#808080";
+    const TRACK_COLOR: &'static str = "#e000e0";
+
+    /// A FeatureCollection with the track (if any) and one point per geocache. Colors and the
+    /// name follow the simplestyle spec, so geojson.io and most map viewers pick them up.
+    pub fn bundle(snapshot: &Snapshot) -> GeoJson {
+        let mut features: Vec<geojson::Feature> = vec![];
+        if !snapshot.track.is_empty() {
+            features.push(Self::track(&snapshot.track));
+        }
+        features.extend(
+            snapshot
+                .geocaches
+                .iter()
+                .map(|gc| Self::geocache(gc, snapshot)),
+        );
+        let mut foreign_members = geojson::JsonObject::new();
+        foreign_members.insert(
+            "snapshot".to_string(),
+            geojson::JsonValue::from(snapshot.ts.to_rfc3339()),
+        );
+        if !snapshot.legs.is_empty() {
+            foreign_members.insert("legs".to_string(), serde_json::json!(snapshot.legs));
+        }
+        if let Some(ascent) = snapshot.ascent {
+            foreign_members.insert("ascent".to_string(), geojson::JsonValue::from(ascent));
+        }
+        GeoJson::FeatureCollection(geojson::FeatureCollection {
+            features,
+            bbox: None,
+            foreign_members: Some(foreign_members),
+        })
+    }
+
+    fn geocache(gc: &Geocache, snapshot: &Snapshot) -> geojson::Feature {
+        let health = gc.health(snapshot.ts);
+        let color = if gc.archived || !gc.available {
+            Self::INACTIVE_COLOR
+        } else if health == Health::Bad {
+            // still listed but a streak of DNFs
+            health.color()
+        } else {
+            Garmin::color(&gc.cache_type)
+        };
+        let mut properties = geojson::JsonObject::new();
+        properties.insert("name".to_string(), gc.code.clone().into());
+        properties.insert("title".to_string(), gc.name.clone().into());
+        properties.insert("type".to_string(), Garmin::type_name(&gc.cache_type).into());
+        properties.insert("size".to_string(), gc.size.to_string().into());
+        properties.insert("difficulty".to_string(), gc.difficulty.into());
+        properties.insert("terrain".to_string(), gc.terrain.into());
+        properties.insert("available".to_string(), gc.available.into());
+        properties.insert("archived".to_string(), gc.archived.into());
+        properties.insert("approximate".to_string(), gc.approximate.into());
+        properties.insert("health".to_string(), format!("{:?}", health).into());
+        properties.insert("health-color".to_string(), health.color().into());
+        properties.insert("url".to_string(), gc.page_url().into());
+        properties.insert(
+            "solution-checker".to_string(),
+            gc.has_solution_checker.into(),
+        );
+        if let Some(cartridge) = &gc.cartridge {
+            properties.insert("cartridge".to_string(), cartridge.clone().into());
+        }
+        properties.insert("marker-color".to_string(), color.into());
+        properties.insert("marker-symbol".to_string(), Self::symbol(gc, health).into());
+        properties.insert(
+            "description".to_string(),
+            format!("{}, D{:.1}/T{:.1}", gc.name, gc.difficulty, gc.terrain).into(),
+        );
+        if let Some(elevation) = snapshot.elevations.get(&gc.code) {
+            properties.insert("track_elevation".to_string(), (*elevation).into());
+        }
+        geojson::Feature {
+            properties: Some(properties),
+            geometry: Some(geojson::Geometry::new(geojson::Value::Point(vec![
+                gc.coord.lon,
+                gc.coord.lat,
+            ]))),
+            bbox: None,
+            id: None,
+            foreign_members: None,
+        }
+    }
+
+    /// Maki icon or letter: what's wrong with the geocache if anything, its type otherwise
+    fn symbol(gc: &Geocache, health: Health) -> &'static str {
+        if gc.approximate {
+            return "cross";
+        }
+        if gc.is_premium {
+            return "p";
+        }
+        if health == Health::Bad {
+            return "danger";
+        }
+        match gc.cache_type {
+            CacheType::Traditional => "t",
+            CacheType::Multi => "m",
+            CacheType::Mystery => "u",
+            CacheType::Earth => "e",
+            CacheType::Virtual => "v",
+            CacheType::Webcam => "w",
+            CacheType::Letterbox => "l",
+            CacheType::Wherigo => "g",
+            CacheType::Event | CacheType::MegaEvent | CacheType::GigaEvent | CacheType::Cito => {
+                "star"
+            }
+            _ => "marker",
+        }
+    }
+
+    fn track(lines: &[Vec<[f64; 2]>]) -> geojson::Feature {
+        let lines: Vec<Vec<Vec<f64>>> = lines
+            .iter()
+            .map(|line| line.iter().map(|position| position.to_vec()).collect())
+            .collect();
+        let mut properties = geojson::JsonObject::new();
+        properties.insert("name".to_string(), "track".into());
+        properties.insert("stroke".to_string(), Self::TRACK_COLOR.into());
+        properties.insert("stroke-width".to_string(), 3.into());
+        geojson::Feature {
+            properties: Some(properties),
+            geometry: Some(geojson::Geometry::new(geojson::Value::MultiLineString(
+                lines,
+            ))),
+            bbox: None,
+            id: None,
+            foreign_members: None,
+        }
+    }
+}
+
+impl Exporter for GeoJsonExporter {
+    fn name(&self) -> &'static str {
+        "geojson"
+    }
+
+    fn content_type(&self) -> ContentType {
+        ContentType::Plain
+    }
+
+    fn uses_mode(&self) -> bool {
+        false
+    }
+
+    fn write(
+        &self,
+        snapshot: Snapshot,
+        _options: &ExportOptions,
+        writer: &mut dyn Write,
+    ) -> Result<(), Error> {
+        serde_json::to_writer(writer, &Self::bundle(&snapshot))?;
+        Ok(())
+    }
+}
+
+pub struct GpxExporter {}
+
+impl Exporter for GpxExporter {
+    fn name(&self) -> &'static str {
+        "gpx"
+    }
+
+    fn content_type(&self) -> ContentType {
+        ContentType::XML
+    }
+
+    fn write(
+        &self,
+        snapshot: Snapshot,
+        options: &ExportOptions,
+        mut writer: &mut dyn Write,
+    ) -> Result<(), Error> {
+        Garmin::gpx(
+            snapshot.geocaches,
+            &options.mode,
+            &options.flavor,
+            &snapshot.ts,
+            &mut writer,
+        )
+    }
+}
+
+pub struct GpiExporter {
+    icons: Icons,
+    templates: PoiTemplates,
+}
+
+impl Exporter for GpiExporter {
+    fn name(&self) -> &'static str {
+        "gpi"
+    }
+
+    fn content_type(&self) -> ContentType {
+        ContentType::new("application", "gpi")
+    }
+
+    fn write(
+        &self,
+        snapshot: Snapshot,
+        options: &ExportOptions,
+        writer: &mut dyn Write,
+    ) -> Result<(), Error> {
+        Garmin::gpi(
+            snapshot.geocaches,
+            &options.mode,
+            &self.icons,
+            &self.templates,
+            &snapshot.ts,
+            writer,
+        )
+    }
+}
+
+pub struct GpiZipExporter {
+    icons: Icons,
+    templates: PoiTemplates,
+}
+
+impl Exporter for GpiZipExporter {
+    fn name(&self) -> &'static str {
+        "gpi.zip"
+    }
+
+    fn content_type(&self) -> ContentType {
+        ContentType::ZIP
+    }
+
+    /// one GPI per type
+    fn uses_mode(&self) -> bool {
+        false
+    }
+
+    fn write(
+        &self,
+        snapshot: Snapshot,
+        options: &ExportOptions,
+        writer: &mut dyn Write,
+    ) -> Result<(), Error> {
+        Garmin::gpi_zip(
+            snapshot.geocaches,
+            &options.found,
+            &self.icons,
+            &self.templates,
+            &snapshot.ts,
+            writer,
+        )
+    }
+}
+
+pub struct KmlExporter {}
+
+impl Exporter for KmlExporter {
+    fn name(&self) -> &'static str {
+        "kml"
+    }
+
+    fn content_type(&self) -> ContentType {
+        ContentType::new("application", "vnd.google-earth.kml+xml")
+    }
+
+    fn write(
+        &self,
+        snapshot: Snapshot,
+        options: &ExportOptions,
+        mut writer: &mut dyn Write,
+    ) -> Result<(), Error> {
+        Kml::kml(snapshot.geocaches, &options.mode, &snapshot.ts, &mut writer)
+    }
+}
+
+pub struct KmzExporter {
+    icons: Icons,
+}
+
+impl Exporter for KmzExporter {
+    fn name(&self) -> &'static str {
+        "kmz"
+    }
+
+    fn content_type(&self) -> ContentType {
+        ContentType::new("application", "vnd.google-earth.kmz")
+    }
+
+    fn write(
+        &self,
+        snapshot: Snapshot,
+        options: &ExportOptions,
+        writer: &mut dyn Write,
+    ) -> Result<(), Error> {
+        Kml::kmz(
+            snapshot.geocaches,
+            &options.mode,
+            &self.icons,
+            &snapshot.ts,
+            writer,
+        )
+    }
+}
+
+/// One line per geocache for spreadsheets, with the distance along the route if there is one.
+pub struct CsvExporter {}
+
+impl CsvExporter {
+    const HEADER: &'static str =
+        "code,name,type,size,difficulty,terrain,lat,lon,favorites,owner,distance_km";
+
+    /// Quoted if needed, with inner quotes doubled
+    fn field(value: &str) -> String {
+        if value.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value.to_string()
+        }
+    }
+}
+
+impl Exporter for CsvExporter {
+    fn name(&self) -> &'static str {
+        "csv"
+    }
+
+    fn content_type(&self) -> ContentType {
+        ContentType::CSV
+    }
+
+    fn write(
+        &self,
+        snapshot: Snapshot,
+        options: &ExportOptions,
+        writer: &mut dyn Write,
+    ) -> Result<(), Error> {
+        writeln!(writer, "{}", Self::HEADER)?;
+        for gc in snapshot
+            .geocaches
+            .iter()
+            .filter(|gc| options.mode.includes(gc))
+        {
+            let distance = snapshot
+                .distances
+                .get(&gc.code)
+                .map(|meters| format!("{:.1}", meters / 1000.0))
+                .unwrap_or_default();
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{:.6},{:.6},{},{},{}",
+                gc.code,
+                Self::field(&gc.name),
+                gc.cache_type,
+                gc.size,
+                gc.difficulty,
+                gc.terrain,
+                gc.coord.lat,
+                gc.coord.lon,
+                gc.favorite_points,
+                Self::field(gc.owner.as_deref().unwrap_or_default()),
+                distance
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use std::collections::HashMap;
+
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::gcgeo::{GeocacheLog, LogType};
+
+    #[test]
+    fn exporters_by_media_type() {
+        let exporters = Exporters::new(&Config::default(), PoiTemplates::default());
+        let name = |media_type: &str| {
+            exporters
+                .for_media_type(&MediaType::parse_flexible(media_type).unwrap())
+                .name()
+        };
+        assert_eq!(name("application/gpx"), "gpx");
+        assert_eq!(name("application/gpi"), "gpi");
+        assert_eq!(name("application/vnd.google-earth.kmz"), "kmz");
+        assert_eq!(name("application/zip"), "gpi.zip");
+        assert_eq!(name("application/json"), "geojson");
+        assert_eq!(name("text/html"), "geojson");
+        assert_eq!(name("text/csv"), "csv");
+        assert!(exporters.by_name("kml").is_some());
+        assert!(exporters.by_name("pdf").is_none());
+    }
+
+    #[test]
+    fn file_names() {
+        let mut options = ExportOptions::default();
+        assert_eq!(options.file_name(&GpxExporter {}), None);
+        options.file_stem = Some("route-2024-06-01".to_string());
+        assert_eq!(
+            options.file_name(&GpxExporter {}).unwrap(),
+            "route-2024-06-01-tradis.gpx"
+        );
+        assert_eq!(
+            options.file_name(&GeoJsonExporter {}).unwrap(),
+            "route-2024-06-01.geojson"
+        );
+        options.mode = ExportMode::All;
+        assert_eq!(
+            options.file_name(&CsvExporter {}).unwrap(),
+            "route-2024-06-01-all.csv"
+        );
+    }
+
+    #[test]
+    fn csv_quotes_names() {
+        let mut gc = Geocache::premium("GC1".to_string());
+        gc.name = "Der \"Schatz\", im See".to_string();
+        gc.difficulty = 1.5;
+        gc.terrain = 2.0;
+        gc.favorite_points = 7;
+        let snapshot = Snapshot {
+            ts: DateTime::from_timestamp(1_717_243_200, 0).unwrap(),
+            geocaches: vec![gc],
+            legs: vec![],
+            ascent: None,
+            elevations: HashMap::new(),
+            distances: HashMap::from([("GC1".to_string(), 12345.0)]),
+            offsets: HashMap::new(),
+            track: vec![],
+        };
+        let mut output = Vec::new();
+        CsvExporter {}
+            .write(
+                snapshot,
+                &ExportOptions {
+                    mode: ExportMode::All,
+                    ..Default::default()
+                },
+                &mut output,
+            )
+            .unwrap();
+        let csv = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], CsvExporter::HEADER);
+        assert!(lines[1].starts_with("GC1,\"Der \"\"Schatz\"\", im See\","));
+        assert!(lines[1].ends_with(",1.5,2,0.000000,0.000000,7,,12.3"));
+    }
+
+    #[test]
+    fn geojson_has_track_and_geocache_details() {
+        let mut gc = Geocache::premium("GC1".to_string());
+        gc.name = "Der \"Schatz\" im See".to_string();
+        gc.cache_type = CacheType::Mystery;
+        gc.available = true;
+        gc.difficulty = 3.5;
+        gc.has_solution_checker = true;
+        gc.is_premium = false;
+        let mut disabled = Geocache::premium("GC2".to_string());
+        disabled.available = false;
+        let mut dnf = Geocache::premium("GC3".to_string());
+        dnf.is_premium = false;
+        dnf.available = true;
+        dnf.logs = vec![
+            GeocacheLog {
+                text: "nix".to_string(),
+                timestamp: "2024-05-30T10:00:00Z".to_string(),
+                log_type: LogType::DidNotFind,
+            },
+            GeocacheLog {
+                text: "nichts".to_string(),
+                timestamp: "2024-05-20T10:00:00Z".to_string(),
+                log_type: LogType::DidNotFind,
+            },
+        ];
+        let snapshot = Snapshot {
+            ts: DateTime::from_timestamp(1_717_243_200, 0).unwrap(),
+            geocaches: vec![gc, disabled, dnf],
+            legs: vec![],
+            ascent: None,
+            elevations: HashMap::new(),
+            distances: HashMap::new(),
+            offsets: HashMap::new(),
+            track: vec![vec![[8.5, 47.9], [8.6, 47.9]]],
+        };
+
+        let mut output = Vec::new();
+        GeoJsonExporter {}
+            .write(snapshot, &ExportOptions::default(), &mut output)
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        let features = json["features"].as_array().unwrap();
+        assert_eq!(features.len(), 4);
+        assert_eq!(features[0]["geometry"]["type"], "MultiLineString");
+        assert_eq!(features[0]["geometry"]["coordinates"][0][1][0], 8.6);
+        let properties = &features[1]["properties"];
+        assert_eq!(properties["name"], "GC1");
+        assert_eq!(properties["title"], "Der \"Schatz\" im See");
+        assert_eq!(properties["type"], "Unknown Cache");
+        assert_eq!(properties["difficulty"], 3.5);
+        assert_eq!(properties["marker-color"], "#0000ff");
+        assert_eq!(properties["marker-symbol"], "u");
+        assert_eq!(properties["url"], "https://coord.info/GC1");
+        assert_eq!(properties["solution-checker"], true);
+        assert_eq!(
+            properties["description"],
+            "Der \"Schatz\" im See, D3.5/T0.0"
+        );
+        assert_eq!(features[2]["properties"]["marker-color"], "#808080");
+        assert_eq!(features[2]["properties"]["marker-symbol"], "p");
+        assert_eq!(features[3]["properties"]["marker-color"], "#c62828");
+        assert_eq!(features[3]["properties"]["marker-symbol"], "danger");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streamed_export_is_complete() {
+        let mut geocaches = vec![];
+        for i in 0..2000 {
+            let mut gc = Geocache::premium(format!("GC{}", i));
+            gc.long_description = "Listing ".repeat(50);
+            geocaches.push(gc);
+        }
+        let snapshot = Snapshot {
+            ts: DateTime::from_timestamp(1_717_243_200, 0).unwrap(),
+            geocaches,
+            legs: vec![],
+            ascent: None,
+            elevations: HashMap::new(),
+            distances: HashMap::new(),
+            offsets: HashMap::new(),
+            track: vec![],
+        };
+        let options = ExportOptions {
+            mode: ExportMode::All,
+            ..Default::default()
+        };
+
+        let mut buffered = Vec::new();
+        GpxExporter {}
+            .write(snapshot.clone(), &options, &mut buffered)
+            .unwrap();
+        let chunks: Vec<Vec<u8>> = stream(Arc::new(GpxExporter {}), snapshot, options)
+            .collect()
+            .await;
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), buffered);
+    }
+}
@@ -1,8 +1,26 @@
+pub use budget::{ApiCalls, ApiUsage};
 pub use cache::*;
+pub use digest::Digest;
+pub use jobstore::JobRecord;
+pub use tokencache::AuthStatus;
+pub use users::{key_hash, User};
 
 // is this idiomatic?
+mod budget;
 mod cache;
+mod clock;
+mod digest;
+mod fixture;
 pub(crate) mod garmin;
 pub mod groundspeak;
+pub(crate) mod html2text;
+pub mod images;
+mod jobstore;
+pub(crate) mod kml;
+pub mod logqueue;
+pub(crate) mod sheet;
 mod tokencache;
+pub mod tokenstore;
+mod translate;
+mod users;
 mod utfgrid;
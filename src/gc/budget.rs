@@ -0,0 +1,211 @@
+use chrono::{DateTime, Duration, Timelike, Utc};
+use log::warn;
+use rocket::serde::{Deserialize, Serialize};
+use sqlx::Row;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use super::cache::Error;
+use super::clock::Clock;
+
+/// Groundspeak calls made on behalf of one job, counted while it runs.
+#[derive(Debug, Default)]
+pub struct ApiUsage {
+    tiles: AtomicUsize,
+    fetches: AtomicUsize,
+}
+
+/// Totals of an ApiUsage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ApiCalls {
+    /// map tiles discovered from the network rather than the database
+    pub tiles: usize,
+    /// geocache batches fetched from the API
+    pub fetches: usize,
+}
+
+impl ApiUsage {
+    pub fn count_tile(&self) {
+        self.tiles.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_fetch(&self) {
+        self.fetches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn calls(&self) -> ApiCalls {
+        ApiCalls {
+            tiles: self.tiles.load(Ordering::Relaxed),
+            fetches: self.fetches.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl From<ApiCalls> for ApiUsage {
+    fn from(calls: ApiCalls) -> Self {
+        Self {
+            tiles: AtomicUsize::new(calls.tiles),
+            fetches: AtomicUsize::new(calls.fetches),
+        }
+    }
+}
+
+/// Daily cap on Groundspeak calls, shared by all instances through the database.
+pub struct Budget {
+    db: sqlx::PgPool,
+    limit: Option<u32>,
+    account_limit: Option<u32>,
+    reset_hour: u32,
+    clock: Arc<dyn Clock>,
+}
+
+impl Budget {
+    pub fn new(
+        pool: sqlx::PgPool,
+        limit: Option<u32>,
+        account_limit: Option<u32>,
+        reset_hour: u32,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            db: pool,
+            limit,
+            account_limit,
+            reset_hour: reset_hour % 24,
+            clock,
+        }
+    }
+
+    pub async fn init(&self) -> Result<(), Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS api_budget (
+            window_start TIMESTAMPTZ PRIMARY KEY,
+            calls INTEGER NOT NULL
+        )",
+        )
+        .execute(&self.db)
+        .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS api_account_usage (
+            account TEXT NOT NULL,
+            window_start TIMESTAMPTZ NOT NULL,
+            calls INTEGER NOT NULL,
+            PRIMARY KEY (account, window_start)
+        )",
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Account for one call of the given account, see account_exhausted for its limit.
+    pub async fn consume_account(&self, account: &str) -> Result<(), Error> {
+        sqlx::query("INSERT INTO api_account_usage (account, window_start, calls) VALUES ($1, $2, 1) ON CONFLICT (account, window_start) DO UPDATE SET calls = api_account_usage.calls + 1")
+            .bind(account)
+            .bind(self.window_start(self.clock.now()))
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Calls made by the given account in the current window.
+    pub async fn account_calls(&self, account: &str) -> Result<u32, Error> {
+        let row = sqlx::query(
+            "SELECT calls FROM api_account_usage WHERE account = $1 AND window_start = $2",
+        )
+        .bind(account)
+        .bind(self.window_start(self.clock.now()))
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(row.map(|row| row.get::<i32, _>(0)).unwrap_or(0).max(0) as u32)
+    }
+
+    /// Whether the given account has used up its daily limit, without accounting for a call.
+    pub async fn account_exhausted(&self, account: &str) -> Result<bool, Error> {
+        let Some(limit) = self.account_limit else {
+            return Ok(false);
+        };
+        let calls = self.account_calls(account).await?;
+        if calls >= limit {
+            warn!(
+                "Daily limit of {} calls for account {} exhausted",
+                limit, account
+            );
+        }
+        Ok(calls >= limit)
+    }
+
+    /// Account for one call, returns false if the daily budget is already used up.
+    pub async fn consume(&self) -> Result<bool, Error> {
+        let Some(limit) = self.limit else {
+            return Ok(true);
+        };
+        let row = sqlx::query("INSERT INTO api_budget (window_start, calls) VALUES ($1, 1) ON CONFLICT (window_start) DO UPDATE SET calls = api_budget.calls + 1 RETURNING calls")
+            .bind(self.window_start(self.clock.now()))
+            .fetch_one(&self.db)
+            .await?;
+        let calls: i32 = row.get(0);
+        if calls as i64 > limit as i64 {
+            warn!("Daily Groundspeak budget of {} calls exhausted", limit);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    pub async fn exhausted(&self) -> Result<bool, Error> {
+        let Some(limit) = self.limit else {
+            return Ok(false);
+        };
+        let row = sqlx::query("SELECT calls FROM api_budget WHERE window_start = $1")
+            .bind(self.window_start(self.clock.now()))
+            .fetch_optional(&self.db)
+            .await?;
+        let calls: i32 = row.map(|row| row.get(0)).unwrap_or(0);
+        Ok(calls as i64 >= limit as i64)
+    }
+
+    /// The most recent reset time at or before `now`.
+    fn window_start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now
+            .date_naive()
+            .and_hms_opt(self.reset_hour, 0, 0)
+            .unwrap()
+            .and_utc();
+        if now.hour() >= self.reset_hour {
+            today
+        } else {
+            today - Duration::days(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::super::clock::SystemClock;
+    use super::*;
+
+    #[tokio::test]
+    async fn window_starts_at_reset_hour() {
+        let pool = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/gc")
+            .unwrap();
+        let uut = Budget::new(pool, Some(100), None, 6, Arc::new(SystemClock));
+
+        let before = Utc.with_ymd_and_hms(2024, 6, 1, 5, 59, 0).unwrap();
+        assert_eq!(
+            uut.window_start(before),
+            Utc.with_ymd_and_hms(2024, 5, 31, 6, 0, 0).unwrap()
+        );
+
+        let after = Utc.with_ymd_and_hms(2024, 6, 1, 6, 0, 0).unwrap();
+        assert_eq!(
+            uut.window_start(after),
+            Utc.with_ymd_and_hms(2024, 6, 1, 6, 0, 0).unwrap()
+        );
+    }
+}
@@ -1,20 +1,46 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use chrono::prelude::*;
-use log::{debug, error, info};
+use futures::future::join_all;
+use log::{debug, error, info, warn};
+use rocket::serde::Serialize;
 use sqlx::postgres::PgPoolOptions;
 use sqlx::{Executor, Row};
 use thiserror::Error;
+use tokio::sync::Semaphore;
 
-use crate::gcgeo::{Coordinate, Geocache, Tile, Track};
+use crate::config::Config;
+use crate::gcgeo::{BBox, Coordinate, Geocache, Tile};
 
-use super::groundspeak::{parse, GcCode, GcCodes, Groundspeak, BATCH_SIZE};
-use super::tokencache::AuthProvider;
+use super::budget::{ApiUsage, Budget};
+use super::clock::{Clock, SystemClock};
+use super::digest::{Digest, DigestStore};
+use super::fixture::FixtureMode;
+use super::groundspeak::{http_client, parse, GcCode, GcCodes, Groundspeak, TileInfo, BATCH_SIZE};
+use super::images::{image_urls, ImageStore};
+use super::jobstore::{JobRecord, JobStore};
+use super::logqueue::{LogDraft, LogQueue, LogQueueStatus};
+use super::tokencache::{AuthProvider, AuthStatus};
+use super::translate::Translator;
+use super::users::{User, UserStore};
 
 pub struct Cache {
     db: sqlx::PgPool,
     groundspeak: Groundspeak,
-    token_cache: AuthProvider,
+    /// the first one is the main account used for logs and images, fetches rotate over all
+    accounts: Vec<AuthProvider>,
+    next_account: AtomicUsize,
+    budget: Budget,
+    images: ImageStore,
+    translator: Translator,
+    log_queue: LogQueue,
+    digests: DigestStore,
+    jobs: JobStore,
+    users: UserStore,
+    clock: Arc<dyn Clock>,
+    fetch_concurrency: usize,
 }
 
 #[derive(Error, Debug)]
@@ -33,50 +59,361 @@ pub enum Error {
     IO(#[from] std::io::Error),
     #[error("gpx")]
     Gpx(#[from] gpx::errors::GpxError),
+    #[error("zip")]
+    Zip(#[from] zip::result::ZipError),
     #[error("utf8")]
     Utf8(#[from] std::str::Utf8Error),
+    #[error("daily groundspeak budget exhausted")]
+    BudgetExhausted,
+    #[error("no groundspeak account logged in with calls left today")]
+    NoAccount,
     #[error("unknown data store error")]
     Unknown,
 }
 
 impl Cache {
-    pub fn new(pool: sqlx::PgPool) -> Self {
-        let groundspeak = Groundspeak::new();
-        let token_cache = AuthProvider::new(pool.clone());
-        Self {
+    /// Geocaches and tiles older than this are refreshed from Groundspeak
+    const TTL_DAYS: i64 = 7;
+    /// Zoom levels tiles are discovered at (areas and tracks)
+    const TILE_ZOOMS: [u8; 2] = [12, 14];
+    const PURGE_BATCH_SIZE: usize = 500;
+
+    pub fn new(pool: sqlx::PgPool, config: &Config, clock: Arc<dyn Clock>) -> Result<Self, Error> {
+        let client = http_client(config)?;
+        let fixtures = match (&config.replay_fixtures, &config.record_fixtures) {
+            (Some(dir), _) => FixtureMode::Replay(dir.into()),
+            (None, Some(dir)) => FixtureMode::Record(dir.into()),
+            (None, None) => FixtureMode::Live,
+        };
+        let groundspeak = Groundspeak::new(client.clone(), fixtures, config);
+        let mut accounts = vec![AuthProvider::new(pool.clone(), client.clone(), config)];
+        accounts.extend(config.auth_accounts.iter().map(|account| {
+            AuthProvider::for_account(pool.clone(), client.clone(), config, account)
+        }));
+        let images = ImageStore::new(
+            pool.clone(),
+            client.clone(),
+            config.image_dir.clone().into(),
+        );
+        let translator = Translator::new(pool.clone(), client, config);
+        let budget = Budget::new(
+            pool.clone(),
+            config.daily_budget,
+            config.account_daily_limit,
+            config.budget_reset_hour,
+            clock.clone(),
+        );
+        let log_queue = LogQueue::new(pool.clone());
+        let digests = DigestStore::new(pool.clone());
+        let jobs = JobStore::new(pool.clone());
+        let users = UserStore::new(pool.clone());
+        Ok(Self {
             db: pool,
             groundspeak,
-            token_cache,
-        }
+            accounts,
+            next_account: AtomicUsize::new(0),
+            budget,
+            images,
+            translator,
+            log_queue,
+            digests,
+            jobs,
+            users,
+            clock,
+            fetch_concurrency: config.fetch_concurrency.max(1),
+        })
     }
 
-    pub async fn new_lite() -> Result<Self, Error> {
+    pub async fn new_lite(config: &Config) -> Result<Self, Error> {
         let pool = PgPoolOptions::new()
             .max_connections(5)
             .connect("postgres://localhost/gc")
             .await?;
-        let s = Self::new(pool);
-        s.token_cache.init().await?;
+        let s = Self::new(pool, config, Arc::new(SystemClock))?;
+        s.init().await?;
+        s.main_account().init().await?;
+        s.budget.init().await?;
+        s.images.init().await?;
+        s.translator.init().await?;
+        s.log_queue.init().await?;
+        s.digests.init().await?;
+        s.jobs.init().await?;
+        s.users.init().await?;
         Ok(s)
     }
 
-    pub async fn find_tile(&mut self, tile: &Tile) -> Result<Timestamped<Vec<Geocache>>, Error> {
-        let result: Vec<Geocache> = vec![];
-        let codes = self.discover(tile).await?;
-        self.get(codes.data.iter().map(|x| x.code.clone()).collect())
+    async fn init(&self) -> Result<(), Error> {
+        sqlx::query("ALTER TABLE tiles2 ADD COLUMN IF NOT EXISTS etag TEXT")
+            .execute(&self.db)
+            .await?;
+        self.migrate_tile_ids().await
+    }
+
+    /// Tile ids used to be 32 bit quadkeys without the zoom level. They get the leading zoom bit of
+    /// the new keys, see migrated_tile_id.
+    async fn migrate_tile_ids(&self) -> Result<(), Error> {
+        let data_type: Option<String> = sqlx::query_scalar("SELECT data_type::TEXT FROM information_schema.columns WHERE table_name = 'tiles2' AND column_name = 'id'")
+            .fetch_optional(&self.db)
+            .await?;
+        if data_type.as_deref() != Some("integer") {
+            return Ok(());
+        }
+        warn!("Migrating tile ids to 64 bit quadkeys");
+        let mut tx = self.db.begin().await?;
+        tx.execute("ALTER TABLE tiles_codes ALTER COLUMN id TYPE BIGINT")
             .await?;
-        Ok(Timestamped::now(result))
+        tx.execute("ALTER TABLE tiles2 ALTER COLUMN id TYPE BIGINT")
+            .await?;
+        let rows = sqlx::query("SELECT t.id, c.lat, c.lon FROM tiles2 t LEFT JOIN LATERAL (SELECT lat, lon FROM tiles_codes WHERE id = t.id AND lat IS NOT NULL LIMIT 1) c ON true")
+            .fetch_all(&mut *tx)
+            .await?;
+        // old ids move out of the way first, so old and new keys never collide midway
+        tx.execute("UPDATE tiles2 SET id = -id - 1").await?;
+        tx.execute("UPDATE tiles_codes SET id = -id - 1").await?;
+        for row in &rows {
+            let old: i64 = row.get(0);
+            let sample = match (row.get(1), row.get(2)) {
+                (Some(lat), Some(lon)) => Some(Coordinate { lat, lon }),
+                _ => None,
+            };
+            let new = migrated_tile_id(old, sample.as_ref());
+            for table in ["tiles2", "tiles_codes"] {
+                tx.execute(
+                    sqlx::query(&format!("UPDATE {} SET id = $2 WHERE id = $1", table))
+                        .bind(-old - 1)
+                        .bind(new),
+                )
+                .await?;
+            }
+        }
+        // codes of tiles that were never stored
+        tx.execute("DELETE FROM tiles_codes WHERE id < 0").await?;
+        tx.commit().await?;
+        info!("Migrated {} tile ids", rows.len());
+        Ok(())
+    }
+
+    /// True if the daily budget is used up and only cached data is served.
+    pub async fn is_cache_only(&self) -> Result<bool, Error> {
+        self.budget.exhausted().await
+    }
+
+    /// Number of chunks fetched from Groundspeak in parallel
+    pub fn fetch_concurrency(&self) -> usize {
+        self.fetch_concurrency
+    }
+
+    /// True if no OAuth token was set up, only the public map tiles can be used then.
+    pub async fn is_discovery_only(&self) -> Result<bool, Error> {
+        Ok(!self.main_account().is_configured().await?)
+    }
+
+    /// Translate hints and descriptions into lang, geocaches that can't be translated are kept as they are.
+    pub async fn translate(&self, mut geocaches: Vec<Geocache>, lang: &str) -> Vec<Geocache> {
+        if !self.translator.is_enabled() {
+            warn!(
+                "Translation to {} requested, but no translate_url configured",
+                lang
+            );
+            return geocaches;
+        }
+        for geocache in geocaches.iter_mut() {
+            if let Err(e) = self.translator.translate(geocache, lang).await {
+                warn!("Unable to translate {} to {}: {}", geocache.code, lang, e);
+            }
+        }
+        geocaches
+    }
+
+    /// Download the description images and the gallery of a geocache, returns url -> local file.
+    pub async fn download_images(
+        &self,
+        geocache: &Geocache,
+    ) -> Result<HashMap<String, String>, Error> {
+        let code = &geocache.code;
+        let mut urls = image_urls(&geocache.short_description);
+        urls.extend(image_urls(&geocache.long_description));
+        let token = self.main_account().token().await?;
+        if self.budget.consume().await? {
+            match self.groundspeak.fetch_images(&token, code).await {
+                Ok(gallery) => urls.extend(gallery),
+                Err(e) => warn!("Unable to fetch gallery of {}: {}", code, e),
+            }
+        }
+        self.images.store(code, urls).await
+    }
+
+    /// Queue logs for posting and try to post everything that is pending.
+    pub async fn submit_logs(&self, drafts: Vec<LogDraft>) -> Result<LogQueueStatus, Error> {
+        for draft in &drafts {
+            self.log_queue.enqueue(draft).await?;
+        }
+        self.flush_logs().await
+    }
+
+    /// Post all pending logs, stops at the first failure to retry later.
+    pub async fn flush_logs(&self) -> Result<LogQueueStatus, Error> {
+        let pending = self.log_queue.pending().await?;
+        let mut posted = 0;
+        for log in &pending {
+            let token = self.main_account().token().await?;
+            if !self.budget.consume().await? {
+                break;
+            }
+            let result = self
+                .groundspeak
+                .post_log(
+                    &token,
+                    &log.draft.code,
+                    &log.draft.log_type,
+                    &log.draft.text,
+                    &log.draft.date,
+                )
+                .await;
+            match result {
+                Ok(log_code) => {
+                    info!("Posted log {} for {}", log_code, log.draft.code);
+                    self.log_queue.mark_posted(log.id, &log_code).await?;
+                    posted += 1;
+                }
+                Err(e) => {
+                    warn!("Unable to post log for {}: {}", log.draft.code, e);
+                    self.log_queue.mark_failed(log.id, &e.to_string()).await?;
+                    self.main_account()
+                        .record_error(&format!("posting log failed: {}", e))
+                        .await;
+                    break;
+                }
+            }
+        }
+        Ok(LogQueueStatus {
+            posted,
+            pending: pending.len() - posted,
+        })
     }
 
-    pub async fn get(&self, codes: Vec<String>) -> Result<Vec<Geocache>, Error> {
+    /// Geocaches found according to the logs submitted through this service
+    pub async fn found_codes(&self) -> Result<HashSet<String>, Error> {
+        self.log_queue.found_codes().await
+    }
+
+    /// Keep a job's progress and result across restarts.
+    pub async fn save_job(&self, record: &JobRecord) -> Result<(), Error> {
+        self.jobs.save(record, self.clock.now()).await
+    }
+
+    pub async fn delete_job(&self, id: &str) -> Result<(), Error> {
+        self.jobs.delete(id).await
+    }
+
+    /// Add a user, or replace the key of an existing one. The new key is only known to the caller.
+    pub async fn create_user(&self, user: &User) -> Result<String, Error> {
+        self.users.create(user, self.clock.now()).await
+    }
+
+    pub async fn user_for_key(&self, key: &str) -> Result<Option<User>, Error> {
+        self.users.by_key(key).await
+    }
+
+    /// A job saved before a restart, with the geocaches of its result as they are cached now,
+    /// however old. Geocaches that were only known from the map tiles are gone.
+    pub async fn load_job(&self, id: &str) -> Result<Option<(JobRecord, Vec<Geocache>)>, Error> {
+        let Some(record) = self.jobs.load(id).await? else {
+            return Ok(None);
+        };
+        let geocaches = self.load_stored(&record.codes).await;
+        Ok(Some((record, geocaches)))
+    }
+
+    /// Geocaches as stored in the database however old, without fetching. Unknown codes are
+    /// skipped.
+    pub async fn load_stored(&self, codes: &[String]) -> Vec<Geocache> {
+        let mut geocaches = Vec::with_capacity(codes.len());
+        for code in codes {
+            if let Some(geocache) = self.load_geocache(code, &DateTime::<Utc>::MIN_UTC).await {
+                geocaches.push(geocache);
+            }
+        }
+        geocaches
+    }
+
+    fn main_account(&self) -> &AuthProvider {
+        &self.accounts[0]
+    }
+
+    /// Next account in round-robin order that is logged in and still has calls left today. The
+    /// call isn't charged to the account yet.
+    async fn next_account(&self) -> Result<Option<&AuthProvider>, Error> {
+        for _ in 0..self.accounts.len() {
+            let index = self.next_account.fetch_add(1, Ordering::Relaxed) % self.accounts.len();
+            let account = &self.accounts[index];
+            if account.is_configured().await?
+                && !self.budget.account_exhausted(account.account()).await?
+            {
+                return Ok(Some(account));
+            }
+        }
+        Ok(None)
+    }
+
+    fn find_account(&self, name: &str) -> Result<&AuthProvider, Error> {
+        self.accounts
+            .iter()
+            .find(|account| account.account() == name)
+            .ok_or(Error::Unknown)
+    }
+
+    /// Token status and calls made today of every account.
+    pub async fn auth_status(&self) -> Result<Vec<(String, AuthStatus, u32)>, Error> {
+        let mut result = Vec::new();
+        for account in &self.accounts {
+            result.push((
+                account.account().to_string(),
+                account.status().await?,
+                self.budget.account_calls(account.account()).await?,
+            ));
+        }
+        Ok(result)
+    }
+
+    pub async fn refresh_token(&self, account: &str) -> Result<(), Error> {
+        self.find_account(account)?.refresh().await?;
+        Ok(())
+    }
+
+    /// Start a login of the given account, the main account if none is given.
+    pub async fn login_url(&self, account: Option<&str>) -> Result<String, Error> {
+        match account {
+            Some(account) => self.find_account(account)?.login_url().await,
+            None => self.main_account().login_url().await,
+        }
+    }
+
+    /// Finish a login, the account is encoded in the state parameter.
+    pub async fn complete_login(&self, code: &str, state: &str) -> Result<(), Error> {
+        let (account, _) = state.split_once(':').ok_or(Error::Geocaching)?;
+        self.find_account(account)?.exchange_code(code, state).await
+    }
+
+    pub async fn get(&self, codes: Vec<String>, usage: &ApiUsage) -> Result<Vec<Geocache>, Error> {
+        Ok(self
+            .lookup(codes, usage)
+            .await?
+            .into_iter()
+            .filter_map(|lookup| lookup.geocache)
+            .collect())
+    }
+
+    /// Like get, but keeps the order of the requested codes and reports where each result came from.
+    pub async fn lookup(&self, codes: Vec<String>, usage: &ApiUsage) -> Result<Vec<Lookup>, Error> {
         let mut cache_hit: Vec<Geocache> = vec![];
         let mut cache_miss: Vec<String> = vec![];
-        let cutoff = Utc::now() - chrono::Duration::days(7);
+        let cutoff = self.cutoff();
         let codes_len = codes.len();
-        for code in codes {
-            match self.load_geocache(&code, &cutoff).await {
+        for code in &codes {
+            match self.load_geocache(code, &cutoff).await {
                 Some(geocache) => cache_hit.push(geocache),
-                None => cache_miss.push(code),
+                None => cache_miss.push(code.clone()),
             }
         }
         info!(
@@ -85,30 +422,41 @@ impl Cache {
             cache_hit.len(),
             cache_miss.len()
         );
-        info!("missing: {:?}", cache_miss);
 
+        let mut fetched = Vec::new();
         if !cache_miss.is_empty() {
             info!("Fetching {} geocaches from Groundspeak", cache_miss.len());
-            let chunk_size = BATCH_SIZE;
-            let mut fetched = Vec::new();
-            for chunk in cache_miss.chunks(chunk_size) {
+            let semaphore = Semaphore::new(self.fetch_concurrency);
+            let results = join_all(cache_miss.chunks(BATCH_SIZE).map(|chunk| async {
+                let _permit = semaphore.acquire().await.map_err(|_| Error::Unknown)?;
                 info!("Fetching next chunk");
-                let chunk: Vec<&String> = chunk.into_iter().collect();
-                fetched.extend(self.fetch_chunk(chunk).await?);
-            }
-
-            /*
-            let mut fetched: Vec<Geocache> = stream::iter(&cache_miss)
-                .chunks(groundspeak::BATCH_SIZE)
-                .then(|x| self.groundspeak.fetch(token, x))
-                .filter_map(|x| ready(x.ok()))
-                .flat_map(stream::iter)
-                .then(|x| self.save_geocache(x))
-                .filter_map(|x| ready(x.ok()))
-                .collect()
-                .await;
+                match self.fetch_chunk(chunk.iter().collect(), usage).await {
+                    Ok(result) => Ok((result, vec![])),
+                    Err(Error::BudgetExhausted | Error::NoAccount) => Ok((vec![], chunk.to_vec())),
+                    Err(e) => Err(e),
+                }
+            }))
+            .await;
 
-             */
+            let mut stale = vec![];
+            for result in results {
+                let (result, exhausted) = result?;
+                fetched.extend(result);
+                stale.extend(exhausted);
+            }
+            if !stale.is_empty() {
+                warn!(
+                    "No budget or account left, serving {} geocaches from stale cache",
+                    stale.len()
+                );
+                for code in &stale {
+                    if let Some(geocache) =
+                        self.load_geocache(code, &DateTime::<Utc>::MIN_UTC).await
+                    {
+                        cache_hit.push(geocache);
+                    }
+                }
+            }
 
             if fetched.len() < cache_miss.len() {
                 error!(
@@ -118,17 +466,29 @@ impl Cache {
                 );
                 // return Err(Error::Geocaching);
             }
-            cache_hit.append(&mut fetched);
         }
 
-        Ok(cache_hit)
+        Ok(order_lookups(codes, cache_hit, fetched))
     }
 
-    async fn fetch_chunk(&self, codes: Vec<&String>) -> Result<Vec<Geocache>, Error> {
+    async fn fetch_chunk(
+        &self,
+        codes: Vec<&String>,
+        usage: &ApiUsage,
+    ) -> Result<Vec<Geocache>, Error> {
         info!("Fetching {} geocaches from Groundspeak", codes.len());
         let mut attempts = 0;
         while attempts < 2 {
-            let token = self.token_cache.token().await?;
+            let Some(account) = self.next_account().await? else {
+                return Err(Error::NoAccount);
+            };
+            let token = account.token().await?;
+            if !self.budget.consume().await? {
+                return Err(Error::BudgetExhausted);
+            }
+            // only charged once the call is sure to be made
+            self.budget.consume_account(account.account()).await?;
+            usage.count_fetch();
             let fetched = self.groundspeak.fetch(&token, codes.clone()).await;
             match fetched {
                 Ok(fetched) => {
@@ -159,7 +519,8 @@ impl Cache {
                         "Unable to fetch geocaches from Groundspeak, refreshing token {:?}",
                         e
                     );
-                    self.token_cache.refresh().await?;
+                    account.record_error(&format!("fetch failed: {}", e)).await;
+                    account.refresh().await?;
                     attempts += 1;
                 }
             }
@@ -173,9 +534,9 @@ impl Cache {
             .ok_or(Error::Geocaching)?;
         info!("Save {}", code);
         sqlx::query("INSERT INTO geocaches (id, raw, ts) VALUES ($1, $2, $3) ON CONFLICT (id) DO UPDATE SET raw = $2::JSON, ts = $3")
-            .bind(&code)
+            .bind(code)
             .bind(&geocache)
-            .bind(Utc::now())
+            .bind(self.clock.now())
             .execute(&self.db).await?;
         Ok(parse(&geocache)?)
     }
@@ -204,40 +565,96 @@ impl Cache {
         match json_result {
             Some(row) => {
                 let gc: serde_json::Value = serde_json::from_str(row.get(0))?;
-                return Ok(Some(parse(&gc)?));
-            }
-            None => {
-                return Ok(None);
+                Ok(Some(parse(&gc)?))
             }
+            None => Ok(None),
         }
     }
 
-    pub async fn discover(&self, tile: &Tile) -> Result<Timestamped<GcCodes>, Error> {
+    /// Codes on a tile, from the database while fresh, from the map tiles otherwise.
+    pub async fn discover(
+        &self,
+        tile: &Tile,
+        usage: &ApiUsage,
+    ) -> Result<Timestamped<GcCodes>, Error> {
         debug!("Discover {}", tile);
-        let cutoff = Utc::now() - chrono::Duration::days(7);
-        let tile_row = sqlx::query("SELECT ts FROM tiles2 where id = $1 and ts >= $2")
-            .bind(tile.quadkey() as i32)
-            .bind(cutoff)
+        let tile_row = sqlx::query("SELECT ts, etag FROM tiles2 where id = $1")
+            .bind(tile.quadkey() as i64)
             .fetch_optional(&self.db)
             .await?;
-        return match tile_row {
-            Some(row) => {
-                let ts: DateTime<Utc> = row.get(0);
+        let (ts, etag): (Option<DateTime<Utc>>, Option<String>) = match tile_row {
+            Some(row) => (Some(row.get(0)), row.get(1)),
+            None => (None, None),
+        };
+        if let Some(ts) = ts {
+            if self.is_fresh(&ts) {
                 debug!("already have a tile from {}", ts);
                 let codes = self.load_gccodes(tile).await?;
-                Ok(Timestamped { ts, data: codes })
+                return Ok(Timestamped { ts, data: codes });
+            }
+        }
+
+        if !self.budget.consume().await? {
+            warn!("Budget exhausted, using cached data for tile {}", tile);
+            let codes = self.load_gccodes(tile).await?;
+            return Ok(Timestamped {
+                ts: ts.unwrap_or_else(|| self.clock.now()),
+                data: codes,
+            });
+        }
+
+        usage.count_tile();
+        let info = self
+            .groundspeak
+            .discover(tile, etag.as_deref(), ts.as_ref())
+            .await?;
+        match info {
+            TileInfo::NotModified => {
+                debug!("tile {} not modified, keeping codes", tile);
+                self.touch_tile(tile).await?;
+                let codes = self.load_gccodes(tile).await?;
+                Ok(self.timestamped(codes))
             }
-            None => {
-                let codes = self.groundspeak.discover(&tile).await?;
-                self.store_gccodes(tile, &codes).await?;
-                Ok(Timestamped::now(codes))
+            TileInfo::Modified { codes, etag } => {
+                self.store_gccodes(tile, &codes, etag.as_deref()).await?;
+                Ok(self.timestamped(codes))
             }
-        };
+        }
+    }
+
+    /// The time by the cache's clock, for decisions that should agree with its freshness.
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    /// Oldest timestamp that is still considered fresh.
+    fn cutoff(&self) -> DateTime<Utc> {
+        self.clock.now() - chrono::Duration::days(Self::TTL_DAYS)
+    }
+
+    fn is_fresh(&self, ts: &DateTime<Utc>) -> bool {
+        *ts >= self.cutoff()
+    }
+
+    fn timestamped<T>(&self, data: T) -> Timestamped<T> {
+        Timestamped {
+            ts: self.clock.now(),
+            data,
+        }
+    }
+
+    async fn touch_tile(&self, tile: &Tile) -> Result<(), Error> {
+        sqlx::query("UPDATE tiles2 SET ts = $2 WHERE id = $1")
+            .bind(tile.quadkey() as i64)
+            .bind(self.clock.now())
+            .execute(&self.db)
+            .await?;
+        Ok(())
     }
 
     async fn load_gccodes(&self, tile: &Tile) -> Result<GcCodes, Error> {
         let rows = sqlx::query("SELECT gccode, lat, lon FROM tiles_codes where id = $1")
-            .bind(tile.quadkey() as i32)
+            .bind(tile.quadkey() as i64)
             .fetch_all(&self.db)
             .await?;
         let gccodes = rows
@@ -259,27 +676,33 @@ impl Cache {
         Ok(gccodes)
     }
 
-    async fn store_gccodes(&self, tile: &Tile, codes: &GcCodes) -> Result<(), Error> {
+    async fn store_gccodes(
+        &self,
+        tile: &Tile,
+        codes: &GcCodes,
+        etag: Option<&str>,
+    ) -> Result<(), Error> {
         let mut tx = self.db.begin().await?;
         tx.execute(
-            sqlx::query("DELETE FROM tiles_codes WHERE id = $1").bind(tile.quadkey() as i32),
+            sqlx::query("DELETE FROM tiles_codes WHERE id = $1").bind(tile.quadkey() as i64),
         )
         .await?;
-        tx.execute(sqlx::query("INSERT INTO tiles2 (id, ts) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET ts = $2")
-            .bind(tile.quadkey() as i32)
-            .bind(Utc::now()))
+        tx.execute(sqlx::query("INSERT INTO tiles2 (id, ts, etag) VALUES ($1, $2, $3) ON CONFLICT (id) DO UPDATE SET ts = $2, etag = $3")
+            .bind(tile.quadkey() as i64)
+            .bind(self.clock.now())
+            .bind(etag))
             .await?;
         for code in codes {
             if let Some(coord) = &code.approx_coord {
                 tx.execute(sqlx::query("INSERT INTO tiles_codes (id, gccode, lat, lon) VALUES ($1, $2, $3, $4) ON CONFLICT (id, gccode) DO UPDATE SET lat = $3, lon = $4")
-                    .bind(tile.quadkey() as i32)
+                    .bind(tile.quadkey() as i64)
                     .bind(&code.code)
                     .bind(coord.lat)
                     .bind(coord.lon))
                     .await?;
             } else {
                 tx.execute(sqlx::query("INSERT INTO tiles_codes (id, gccode) VALUES ($1, $2) ON CONFLICT (id, gccode) DO UPDATE SET lat = NULL, lon = NULL")
-                    .bind(tile.quadkey() as i32)
+                    .bind(tile.quadkey() as i64)
                     .bind(&code.code))
                     .await?;
             }
@@ -288,10 +711,179 @@ impl Cache {
         Ok(())
     }
 
-    pub async fn tracks<R: std::io::Read>(&self, io: R) -> Result<Vec<Tile>, Error> {
-        let track = Track::from_gpx(io)?;
-        Ok(track.tiles)
+    /// Remove all tiles and geocaches within the bounding box, so they get fetched again.
+    pub async fn purge<F>(&self, bbox: &BBox, progress: F) -> Result<(u64, u64), Error>
+    where
+        F: Fn(String),
+    {
+        let tiles: Vec<i64> = Self::TILE_ZOOMS
+            .iter()
+            .flat_map(|z| bbox.tiles(*z))
+            .map(|tile| tile.quadkey() as i64)
+            .collect();
+        let mut tiles_deleted = 0;
+        for (index, chunk) in tiles.chunks(Self::PURGE_BATCH_SIZE).enumerate() {
+            progress(format!(
+                "Purging tiles {}/{}",
+                index * Self::PURGE_BATCH_SIZE + chunk.len(),
+                tiles.len()
+            ));
+            let mut tx = self.db.begin().await?;
+            tx.execute(sqlx::query("DELETE FROM tiles_codes WHERE id = ANY($1)").bind(chunk))
+                .await?;
+            tiles_deleted += tx
+                .execute(sqlx::query("DELETE FROM tiles2 WHERE id = ANY($1)").bind(chunk))
+                .await?
+                .rows_affected();
+            tx.commit().await?;
+        }
+
+        let mut geocaches_deleted = 0;
+        loop {
+            let deleted = sqlx::query("DELETE FROM geocaches WHERE id IN (SELECT id FROM geocaches WHERE (raw->'postedCoordinates'->>'latitude')::float8 BETWEEN $1 AND $2 AND (raw->'postedCoordinates'->>'longitude')::float8 BETWEEN $3 AND $4 LIMIT $5)")
+                .bind(bbox.min_lat)
+                .bind(bbox.max_lat)
+                .bind(bbox.min_lon)
+                .bind(bbox.max_lon)
+                .bind(Self::PURGE_BATCH_SIZE as i64)
+                .execute(&self.db)
+                .await?
+                .rows_affected();
+            if deleted == 0 {
+                break;
+            }
+            geocaches_deleted += deleted;
+            progress(format!("Purged {} geocaches", geocaches_deleted));
+        }
+
+        let area: f64 = bbox
+            .tiles(Self::TILE_ZOOMS[0])
+            .iter()
+            .map(Tile::area_km2)
+            .sum();
+        info!(
+            "Purged {} tiles and {} geocaches in {} (about {:.0} km²)",
+            tiles_deleted, geocaches_deleted, bbox, area
+        );
+        Ok((tiles_deleted, geocaches_deleted))
+    }
+
+    /// Summarize what changed in the cached geocaches of a region since its last digest.
+    pub async fn digest(&self, region: &str, bbox: &BBox) -> Result<Digest, Error> {
+        let geocaches = self.geocaches_in(bbox).await?;
+        let previous = self.digests.previous(region).await?;
+        let digest = Digest::compute(region, previous, &geocaches, self.clock.now());
+        self.digests.save(&digest, &geocaches).await?;
+        info!(
+            "Digest for {}: {} new, {} archived, {} disabled",
+            region,
+            digest.new.len(),
+            digest.archived.len(),
+            digest.disabled.len()
+        );
+        Ok(digest)
+    }
+
+    pub async fn latest_digest(&self, region: &str) -> Result<Option<Digest>, Error> {
+        self.digests.latest(region).await
+    }
+
+    /// All cached geocaches within the bounding box, regardless of their age.
+    async fn geocaches_in(&self, bbox: &BBox) -> Result<Vec<Geocache>, Error> {
+        let rows = sqlx::query("SELECT raw::VARCHAR FROM geocaches WHERE (raw->'postedCoordinates'->>'latitude')::float8 BETWEEN $1 AND $2 AND (raw->'postedCoordinates'->>'longitude')::float8 BETWEEN $3 AND $4")
+            .bind(bbox.min_lat)
+            .bind(bbox.max_lat)
+            .bind(bbox.min_lon)
+            .bind(bbox.max_lon)
+            .fetch_all(&self.db)
+            .await?;
+        let mut geocaches = Vec::with_capacity(rows.len());
+        for row in rows {
+            let gc: serde_json::Value = serde_json::from_str(row.get(0))?;
+            geocaches.push(parse(&gc)?);
+        }
+        Ok(geocaches)
+    }
+}
+
+/// Where a geocache returned by Cache::lookup came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Provenance {
+    /// stored in the database (may be stale if the budget is exhausted)
+    Cache,
+    /// freshly fetched from Groundspeak
+    Live,
+    /// premium only, we only know the code
+    Premium,
+    /// neither in the database nor returned by Groundspeak
+    Missing,
+}
+
+impl Provenance {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provenance::Cache => "cache",
+            Provenance::Live => "live",
+            Provenance::Premium => "premium",
+            Provenance::Missing => "missing",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    pub code: String,
+    pub provenance: Provenance,
+    pub geocache: Option<Geocache>,
+}
+
+fn order_lookups(codes: Vec<String>, cached: Vec<Geocache>, live: Vec<Geocache>) -> Vec<Lookup> {
+    let mut found: HashMap<String, (Provenance, Geocache)> = HashMap::new();
+    for geocache in cached {
+        found.insert(geocache.code.clone(), (Provenance::Cache, geocache));
+    }
+    for geocache in live {
+        found.insert(geocache.code.clone(), (Provenance::Live, geocache));
     }
+    codes
+        .into_iter()
+        .map(|code| match found.get(&code) {
+            Some((_, geocache)) if geocache.is_premium => Lookup {
+                code,
+                provenance: Provenance::Premium,
+                geocache: Some(geocache.clone()),
+            },
+            Some((provenance, geocache)) => Lookup {
+                code,
+                provenance: *provenance,
+                geocache: Some(geocache.clone()),
+            },
+            None => Lookup {
+                code,
+                provenance: Provenance::Missing,
+                geocache: None,
+            },
+        })
+        .collect()
+}
+
+/// The new key of an old tile id. Tiles were discovered at zoom 12 and 14 only, ids from 2^24 on
+/// need 28 bits and can only be zoom 14. Below that a geocache of the tile tells the zoom level
+/// if there is one, otherwise it's taken as zoom 12.
+fn migrated_tile_id(old: i64, sample: Option<&Coordinate>) -> i64 {
+    let with_zoom = |z: u8| old | 1 << (2 * z as i64);
+    let in_tile = |z: u8| {
+        sample.is_some_and(|coord| {
+            Tile::from_coordinates(coord.lat, coord.lon, z).quadkey() as i64 == with_zoom(z)
+        })
+    };
+    let z = if old >= 1 << 24 || (in_tile(14) && !in_tile(12)) {
+        14
+    } else {
+        12
+    };
+    with_zoom(z)
 }
 
 pub struct Timestamped<T> {
@@ -299,11 +891,100 @@ pub struct Timestamped<T> {
     pub data: T,
 }
 
-impl<T> Timestamped<T> {
-    fn now(data: T) -> Self {
-        Self {
-            ts: Utc::now(),
-            data,
-        }
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::super::clock::MockClock;
+    use super::*;
+
+    fn cache_at(clock: Arc<MockClock>) -> Cache {
+        let pool = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/gc")
+            .unwrap();
+        Cache::new(pool, &Config::default(), clock).unwrap()
+    }
+
+    #[test]
+    fn tile_ids_get_their_zoom() {
+        let old = |tile: &Tile| (tile.quadkey() & !(1 << (2 * tile.z as u64))) as i64;
+        let coord = Coordinate {
+            lat: 47.947971,
+            lon: 8.508224,
+        };
+        let z12 = Tile::from_coordinates(coord.lat, coord.lon, 12);
+        let z14 = Tile::from_coordinates(coord.lat, coord.lon, 14);
+        assert_eq!(migrated_tile_id(old(&z12), None), z12.quadkey() as i64);
+        assert_eq!(migrated_tile_id(old(&z14), None), z14.quadkey() as i64);
+
+        // far north-west zoom 14 ids are as short as zoom 12 ones
+        let north = Coordinate {
+            lat: 70.2,
+            lon: -148.4,
+        };
+        let z14 = Tile::from_coordinates(north.lat, north.lon, 14);
+        assert!(old(&z14) < 1 << 24);
+        assert_eq!(
+            migrated_tile_id(old(&z14), Some(&north)),
+            z14.quadkey() as i64
+        );
+        let z12 = Tile::from_coordinates(north.lat, north.lon, 12);
+        assert_eq!(
+            migrated_tile_id(old(&z12), Some(&north)),
+            z12.quadkey() as i64
+        );
+    }
+
+    #[tokio::test]
+    async fn entries_go_stale_after_ttl() {
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let clock = Arc::new(MockClock::new(start));
+        let uut = cache_at(clock.clone());
+
+        assert!(uut.is_fresh(&start));
+        clock.advance(chrono::Duration::days(7));
+        assert!(uut.is_fresh(&start));
+        clock.advance(chrono::Duration::seconds(1));
+        assert!(!uut.is_fresh(&start));
+    }
+
+    #[test]
+    fn lookups_keep_requested_order() {
+        let mut premium = Geocache::premium("GC3".to_string());
+        premium.is_premium = true;
+        let mut live = Geocache::premium("GC1".to_string());
+        live.is_premium = false;
+        let mut cached = Geocache::premium("GC2".to_string());
+        cached.is_premium = false;
+
+        let codes = vec!["GC3", "GC4", "GC2", "GC1"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let result = order_lookups(codes, vec![cached, premium], vec![live]);
+
+        let summary: Vec<(&str, Provenance)> = result
+            .iter()
+            .map(|lookup| (lookup.code.as_str(), lookup.provenance))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("GC3", Provenance::Premium),
+                ("GC4", Provenance::Missing),
+                ("GC2", Provenance::Cache),
+                ("GC1", Provenance::Live),
+            ]
+        );
+        assert!(result[1].geocache.is_none());
+    }
+
+    #[tokio::test]
+    async fn timestamps_come_from_clock() {
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let uut = cache_at(Arc::new(MockClock::new(start)));
+        assert_eq!(uut.timestamped(()).ts, start);
+        assert_eq!(uut.cutoff(), start - chrono::Duration::days(7));
     }
 }
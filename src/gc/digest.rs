@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use rocket::serde::{Deserialize, Serialize};
+use sqlx::{Executor, Row};
+
+use super::cache::Error;
+use crate::gcgeo::Geocache;
+
+/// Status of a geocache as of the previous digest, to tell what changed since.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Seen {
+    pub available: bool,
+    pub archived: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DigestEntry {
+    pub code: String,
+    pub name: String,
+    pub cache_type: String,
+    pub detail: Option<String>,
+}
+
+/// What happened in a watched region since the previous digest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Digest {
+    pub region: String,
+    pub ts: DateTime<Utc>,
+    /// None for the first digest of a region, nothing counts as new then
+    pub since: Option<DateTime<Utc>>,
+    pub total: usize,
+    pub new: Vec<DigestEntry>,
+    pub archived: Vec<DigestEntry>,
+    pub disabled: Vec<DigestEntry>,
+    pub dnf_streaks: Vec<DigestEntry>,
+    pub events: Vec<DigestEntry>,
+}
+
+impl DigestEntry {
+    fn new(geocache: &Geocache, detail: Option<String>) -> Self {
+        Self {
+            code: geocache.code.clone(),
+            name: geocache.name.clone(),
+            cache_type: geocache.cache_type.to_string(),
+            detail,
+        }
+    }
+}
+
+impl Digest {
+    /// DNF streaks at least this long are worth mentioning
+    const DNF_STREAK: usize = 3;
+    /// Events within this many days are listed as upcoming
+    const EVENT_DAYS: i64 = 14;
+
+    pub fn compute(
+        region: &str,
+        previous: Option<(DateTime<Utc>, HashMap<String, Seen>)>,
+        geocaches: &[Geocache],
+        now: DateTime<Utc>,
+    ) -> Self {
+        let (since, seen) = match previous {
+            Some((ts, seen)) => (Some(ts), Some(seen)),
+            None => (None, None),
+        };
+        let mut digest = Self {
+            region: region.to_string(),
+            ts: now,
+            since,
+            total: geocaches.len(),
+            new: vec![],
+            archived: vec![],
+            disabled: vec![],
+            dnf_streaks: vec![],
+            events: vec![],
+        };
+
+        let today = now.date_naive();
+        for geocache in geocaches {
+            let before = seen.as_ref().map(|seen| seen.get(&geocache.code));
+            match before {
+                Some(None) => digest.new.push(DigestEntry::new(geocache, None)),
+                Some(Some(before)) if geocache.archived && !before.archived => {
+                    digest.archived.push(DigestEntry::new(geocache, None))
+                }
+                Some(Some(before))
+                    if !geocache.archived && !geocache.available && before.available =>
+                {
+                    digest.disabled.push(DigestEntry::new(geocache, None))
+                }
+                _ => {}
+            }
+
+            if geocache.archived {
+                continue;
+            }
+            let dnfs = geocache.dnf_streak();
+            if dnfs >= Self::DNF_STREAK {
+                digest
+                    .dnf_streaks
+                    .push(DigestEntry::new(geocache, Some(format!("{} DNFs", dnfs))));
+            }
+            if let Some(date) = geocache
+                .placed
+                .filter(|date| is_upcoming(geocache, *date, today))
+            {
+                digest
+                    .events
+                    .push(DigestEntry::new(geocache, Some(date.to_string())));
+            }
+        }
+        digest.events.sort_by(|a, b| a.detail.cmp(&b.detail));
+        digest
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut md = String::new();
+        writeln!(md, "# Geocaching digest for {}", self.region).unwrap();
+        writeln!(md).unwrap();
+        match self.since {
+            Some(since) => writeln!(
+                md,
+                "{} geocaches, changes since {}.",
+                self.total,
+                since.format("%Y-%m-%d")
+            ),
+            None => writeln!(
+                md,
+                "{} geocaches, this is the first digest for this region.",
+                self.total
+            ),
+        }
+        .unwrap();
+
+        for (title, entries) in [
+            ("New geocaches", &self.new),
+            ("Archived", &self.archived),
+            ("Newly disabled", &self.disabled),
+            ("DNF streaks", &self.dnf_streaks),
+            ("Upcoming events", &self.events),
+        ] {
+            if entries.is_empty() {
+                continue;
+            }
+            writeln!(md).unwrap();
+            writeln!(md, "## {}", title).unwrap();
+            writeln!(md).unwrap();
+            for entry in entries {
+                write!(
+                    md,
+                    "- [{}](https://coord.info/{}) {} ({})",
+                    entry.code, entry.code, entry.name, entry.cache_type
+                )
+                .unwrap();
+                if let Some(detail) = &entry.detail {
+                    write!(md, ", {}", detail).unwrap();
+                }
+                writeln!(md).unwrap();
+            }
+        }
+        md
+    }
+}
+
+fn is_upcoming(geocache: &Geocache, date: NaiveDate, today: NaiveDate) -> bool {
+    geocache.cache_type.is_event()
+        && date >= today
+        && date <= today + Duration::days(Digest::EVENT_DAYS)
+}
+
+/// Remembers what each region looked like at its last digest.
+pub struct DigestStore {
+    db: sqlx::PgPool,
+}
+
+impl DigestStore {
+    pub fn new(pool: sqlx::PgPool) -> Self {
+        Self { db: pool }
+    }
+
+    pub async fn init(&self) -> Result<(), Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS digests (
+            region TEXT PRIMARY KEY,
+            ts TIMESTAMPTZ NOT NULL,
+            report JSON NOT NULL
+        )",
+        )
+        .execute(&self.db)
+        .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS digest_state (
+            region TEXT NOT NULL,
+            gccode TEXT NOT NULL,
+            available BOOLEAN NOT NULL,
+            archived BOOLEAN NOT NULL,
+            PRIMARY KEY (region, gccode)
+        )",
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn previous(
+        &self,
+        region: &str,
+    ) -> Result<Option<(DateTime<Utc>, HashMap<String, Seen>)>, Error> {
+        let Some(row) = sqlx::query("SELECT ts FROM digests WHERE region = $1")
+            .bind(region)
+            .fetch_optional(&self.db)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let seen =
+            sqlx::query("SELECT gccode, available, archived FROM digest_state WHERE region = $1")
+                .bind(region)
+                .fetch_all(&self.db)
+                .await?
+                .iter()
+                .map(|row| {
+                    (
+                        row.get(0),
+                        Seen {
+                            available: row.get(1),
+                            archived: row.get(2),
+                        },
+                    )
+                })
+                .collect();
+        Ok(Some((row.get(0), seen)))
+    }
+
+    /// Store the digest and the current state of all geocaches for the next run.
+    pub async fn save(&self, digest: &Digest, geocaches: &[Geocache]) -> Result<(), Error> {
+        let mut tx = self.db.begin().await?;
+        tx.execute(sqlx::query("DELETE FROM digest_state WHERE region = $1").bind(&digest.region))
+            .await?;
+        for geocache in geocaches {
+            tx.execute(
+                sqlx::query("INSERT INTO digest_state (region, gccode, available, archived) VALUES ($1, $2, $3, $4)")
+                    .bind(&digest.region)
+                    .bind(&geocache.code)
+                    .bind(geocache.available)
+                    .bind(geocache.archived),
+            )
+            .await?;
+        }
+        tx.execute(
+            sqlx::query("INSERT INTO digests (region, ts, report) VALUES ($1, $2, $3) ON CONFLICT (region) DO UPDATE SET ts = $2, report = $3")
+                .bind(&digest.region)
+                .bind(digest.ts)
+                .bind(serde_json::to_value(digest)?),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn latest(&self, region: &str) -> Result<Option<Digest>, Error> {
+        let row = sqlx::query("SELECT report::VARCHAR FROM digests WHERE region = $1")
+            .bind(region)
+            .fetch_optional(&self.db)
+            .await?;
+        match row {
+            Some(row) => Ok(Some(serde_json::from_str(row.get(0))?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::gcgeo::{CacheType, GeocacheLog, LogType};
+
+    fn geocache(code: &str, available: bool, archived: bool) -> Geocache {
+        let mut gc = Geocache::premium(code.to_string());
+        gc.is_premium = false;
+        gc.name = format!("Name of {}", code);
+        gc.cache_type = CacheType::Traditional;
+        gc.available = available;
+        gc.archived = archived;
+        gc
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn first_digest_has_no_changes() {
+        let geocaches = vec![geocache("GC1", true, false)];
+        let digest = Digest::compute("home", None, &geocaches, now());
+        assert_eq!(digest.total, 1);
+        assert!(digest.new.is_empty());
+        assert!(digest.to_markdown().contains("first digest"));
+    }
+
+    #[test]
+    fn reports_changes_since_previous_digest() {
+        let seen = HashMap::from([
+            (
+                "GC1".to_string(),
+                Seen {
+                    available: true,
+                    archived: false,
+                },
+            ),
+            (
+                "GC2".to_string(),
+                Seen {
+                    available: true,
+                    archived: false,
+                },
+            ),
+            (
+                "GC3".to_string(),
+                Seen {
+                    available: true,
+                    archived: false,
+                },
+            ),
+        ]);
+        let mut dnf = geocache("GC3", true, false);
+        dnf.logs = (1..=3)
+            .map(|day| GeocacheLog {
+                text: String::new(),
+                timestamp: format!("2024-05-0{}T10:00:00+02:00", day),
+                log_type: LogType::DidNotFind,
+            })
+            .collect();
+        let mut event = geocache("GC5", true, false);
+        event.cache_type = CacheType::Event;
+        event.placed = NaiveDate::from_ymd_opt(2024, 6, 8);
+        let mut past_event = geocache("GC6", true, false);
+        past_event.cache_type = CacheType::Event;
+        past_event.placed = NaiveDate::from_ymd_opt(2024, 5, 8);
+        let geocaches = vec![
+            geocache("GC1", false, false),
+            geocache("GC2", false, true),
+            dnf,
+            geocache("GC4", true, false),
+            event,
+            past_event,
+        ];
+
+        let digest = Digest::compute("home", Some((now(), seen)), &geocaches, now());
+        let codes = |entries: &Vec<DigestEntry>| -> Vec<String> {
+            entries.iter().map(|e| e.code.clone()).collect()
+        };
+        assert_eq!(codes(&digest.disabled), vec!["GC1"]);
+        assert_eq!(codes(&digest.archived), vec!["GC2"]);
+        assert_eq!(codes(&digest.dnf_streaks), vec!["GC3"]);
+        assert_eq!(codes(&digest.new), vec!["GC4", "GC5", "GC6"]);
+        assert_eq!(codes(&digest.events), vec!["GC5"]);
+
+        let md = digest.to_markdown();
+        assert!(md.contains("## Upcoming events"));
+        assert!(md.contains("- [GC5](https://coord.info/GC5) Name of GC5 (Event), 2024-06-08"));
+    }
+}
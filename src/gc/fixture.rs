@@ -0,0 +1,66 @@
+use std::path::PathBuf;
+
+use log::info;
+use rocket::serde::{Deserialize, Serialize};
+
+use super::groundspeak::Error;
+
+/// Whether HTTP responses from Groundspeak are passed through, recorded to or replayed from fixture files.
+#[derive(Debug, Clone)]
+pub enum FixtureMode {
+    Live,
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+/// The parts of a HTTP response we care about, in a form that can be written to disk.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RawResponse {
+    pub status: u16,
+    pub etag: Option<String>,
+    pub body: String,
+}
+
+impl RawResponse {
+    pub async fn from_response(response: reqwest::Response) -> Result<Self, Error> {
+        let status = response.status().as_u16();
+        let etag = response
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let body = response.text().await?;
+        Ok(Self { status, etag, body })
+    }
+}
+
+impl FixtureMode {
+    pub fn is_replay(&self) -> bool {
+        matches!(self, FixtureMode::Replay(_))
+    }
+
+    /// Run the request (unless replaying) and record the response if requested.
+    pub async fn send(
+        &self,
+        key: &str,
+        request: reqwest::RequestBuilder,
+    ) -> Result<RawResponse, Error> {
+        match self {
+            FixtureMode::Live => RawResponse::from_response(request.send().await?).await,
+            FixtureMode::Record(dir) => {
+                let response = RawResponse::from_response(request.send().await?).await?;
+                let path = dir.join(format!("{}.json", key));
+                info!("Recording {} to {}", key, path.to_string_lossy());
+                std::fs::create_dir_all(dir)?;
+                std::fs::write(path, serde_json::to_vec_pretty(&response)?)?;
+                Ok(response)
+            }
+            FixtureMode::Replay(dir) => {
+                let path = dir.join(format!("{}.json", key));
+                info!("Replaying {} from {}", key, path.to_string_lossy());
+                Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+            }
+        }
+    }
+}
@@ -1,36 +1,645 @@
-use std::{io::Write, path::Path, process::Command};
+use std::{
+    collections::HashSet,
+    io::{Seek, Write},
+    path::PathBuf,
+    process::Command,
+    str::FromStr,
+};
 
+use chrono::{DateTime, SecondsFormat, Utc};
 use geo::Point;
-use gpx::{GpxVersion, Waypoint};
+use gpx::errors::GpxError;
+use gpx::{GpxVersion, Metadata, Time, Waypoint};
 use log::{error, info};
 use regex::Regex;
 use tempfile::NamedTempFile;
+use time::OffsetDateTime;
+use xml::writer::{EmitterConfig, EventWriter, XmlEvent};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
 
-use crate::gcgeo::{CacheType, Geocache};
+use crate::gcgeo::{CacheType, ContainerSize, Geocache, GeocacheLog, LogType};
 
 use super::cache::Error;
+use super::html2text::html2text;
+
+/// Which geocaches go into an export.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExportMode {
+    /// only this type, plus approximate positions whose type is unknown
+    Only(CacheType),
+    /// every type, told apart by symbol (GPX) or category (GPI)
+    All,
+    /// geocaches already found, the caller narrows the list down to those
+    Found,
+}
+
+impl Default for ExportMode {
+    fn default() -> Self {
+        Self::Only(CacheType::Traditional)
+    }
+}
+
+/// "all", "found" or a cache type
+impl FromStr for ExportMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "all" => Ok(Self::All),
+            "found" => Ok(Self::Found),
+            _ => s.parse().map(Self::Only),
+        }
+    }
+}
+
+impl ExportMode {
+    pub(crate) fn includes(&self, gc: &Geocache) -> bool {
+        match self {
+            Self::Only(cache_type) => gc.cache_type == *cache_type || gc.approximate,
+            Self::All | Self::Found => true,
+        }
+    }
+
+    /// Part of download file names, e.g. route-2024-06-01-tradis.gpx
+    pub(crate) fn file_suffix(&self) -> String {
+        match self {
+            Self::Only(CacheType::Traditional) => "tradis".to_string(),
+            Self::Only(cache_type) => cache_type.to_string().to_lowercase(),
+            Self::All => "all".to_string(),
+            Self::Found => "found".to_string(),
+        }
+    }
+
+    /// Name of the POI category on Garmin devices
+    fn category(&self) -> String {
+        match self {
+            Self::Only(cache_type) => Garmin::type_name(cache_type).to_string(),
+            Self::All => "Geocaches".to_string(),
+            Self::Found => "Found".to_string(),
+        }
+    }
+}
+
+/// App specific additions to the Pocket Query GPX, ?flavor=locus or osmand
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum GpxFlavor {
+    /// plain Pocket Query, as Garmin devices, c:geo and GSAK expect it
+    #[default]
+    PocketQuery,
+    /// Locus Map: waypoint link and icon
+    Locus,
+    /// OsmAnd: favourite color and background by cache type
+    OsmAnd,
+}
+
+impl FromStr for GpxFlavor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "pq" | "pocketquery" => Ok(Self::PocketQuery),
+            "locus" => Ok(Self::Locus),
+            "osmand" => Ok(Self::OsmAnd),
+            _ => Err(format!("unknown GPX flavor {}", s)),
+        }
+    }
+}
+
+impl GpxFlavor {
+    const LOCUS_NS: &'static str = "https://www.locusmap.app";
+    const OSMAND_NS: &'static str = "https://osmand.net";
+}
+
+/// What a GPI waypoint shows, placeholders in braces, e.g. "{code_short} {size_letter}{type_letter} {d}/{t}":
+/// code, code_short, name, type, type_letter, size, size_letter, d, t and hint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoiTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TemplatePart {
+    Text(String),
+    Field(TemplateField),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TemplateField {
+    Code,
+    CodeShort,
+    Name,
+    Type,
+    TypeLetter,
+    Size,
+    SizeLetter,
+    Difficulty,
+    Terrain,
+    Hint,
+}
+
+impl FromStr for TemplateField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "code" => Ok(Self::Code),
+            "code_short" => Ok(Self::CodeShort),
+            "name" => Ok(Self::Name),
+            "type" => Ok(Self::Type),
+            "type_letter" => Ok(Self::TypeLetter),
+            "size" => Ok(Self::Size),
+            "size_letter" => Ok(Self::SizeLetter),
+            "d" => Ok(Self::Difficulty),
+            "t" => Ok(Self::Terrain),
+            "hint" => Ok(Self::Hint),
+            _ => Err(format!("unknown placeholder {{{}}}", s)),
+        }
+    }
+}
+
+impl FromStr for PoiTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(TemplatePart::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed placeholder in {}", s))?;
+            parts.push(TemplatePart::Field(rest[start + 1..start + end].parse()?));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Text(rest.to_string()));
+        }
+        let text: usize = parts
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Text(text) => text.chars().count(),
+                TemplatePart::Field(_) => 0,
+            })
+            .sum();
+        if text > PoiTemplate::MAX_LENGTH {
+            return Err(format!(
+                "{} leaves no room for the geocache, GPI texts have at most {} characters",
+                s,
+                PoiTemplate::MAX_LENGTH
+            ));
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl PoiTemplate {
+    /// Garmin devices cut longer POI texts
+    const MAX_LENGTH: usize = 100;
+
+    /// The filled in template, trailing whitespace of empty fields removed and cut to MAX_LENGTH.
+    fn render(&self, gc: &Geocache) -> String {
+        let text: String = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Text(text) => text.clone(),
+                TemplatePart::Field(field) => Self::field(gc, *field),
+            })
+            .collect();
+        text.trim_end().chars().take(Self::MAX_LENGTH).collect()
+    }
+
+    fn field(gc: &Geocache, field: TemplateField) -> String {
+        match field {
+            TemplateField::Code => gc.code.clone(),
+            TemplateField::CodeShort => Garmin::code(gc),
+            TemplateField::Name => Garmin::name(gc),
+            TemplateField::Type => Garmin::type_name(&gc.cache_type).to_string(),
+            TemplateField::TypeLetter => Garmin::gctype(gc),
+            TemplateField::Size => Garmin::container_name(&gc.size).to_string(),
+            TemplateField::SizeLetter => Garmin::size(gc),
+            TemplateField::Difficulty => format!("{:.1}", gc.difficulty),
+            TemplateField::Terrain => format!("{:.1}", gc.terrain),
+            TemplateField::Hint => Garmin::hint(gc),
+        }
+    }
+}
+
+/// Title and description of GPI waypoints, from gpi_title and gpi_description.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoiTemplates {
+    title: PoiTemplate,
+    description: PoiTemplate,
+}
+
+impl Default for PoiTemplates {
+    fn default() -> Self {
+        Self::new(None, None).unwrap()
+    }
+}
+
+impl PoiTemplates {
+    pub const DEFAULT_TITLE: &'static str = "{code_short} {size_letter}{type_letter} {d}/{t}";
+    pub const DEFAULT_DESCRIPTION: &'static str = "{name}\n{hint}";
+
+    pub fn new(title: Option<&str>, description: Option<&str>) -> Result<Self, String> {
+        Ok(Self {
+            title: title.unwrap_or(Self::DEFAULT_TITLE).parse()?,
+            description: description.unwrap_or(Self::DEFAULT_DESCRIPTION).parse()?,
+        })
+    }
+
+    /// Approximate positions have no details, their title says so instead.
+    fn title(&self, gc: &Geocache) -> String {
+        if gc.approximate {
+            return format!("{} ~approx", Garmin::code(gc));
+        }
+        self.title.render(gc)
+    }
+
+    fn description(&self, gc: &Geocache) -> String {
+        self.description.render(gc)
+    }
+}
+
+/// BMP icons for the GPI categories: `<type>.bmp` (e.g. mystery.bmp) and found.bmp from a
+/// directory, falling back to default.bmp there and to the bundled image.bmp.
+#[derive(Debug, Clone, Default)]
+pub struct Icons {
+    dir: Option<PathBuf>,
+}
+
+impl Icons {
+    const BUNDLED: &'static str = "image.bmp";
+
+    pub fn new(dir: Option<&str>) -> Self {
+        Self {
+            dir: dir.map(PathBuf::from),
+        }
+    }
+
+    pub(crate) fn for_mode(&self, mode: &ExportMode) -> PathBuf {
+        let name = match mode {
+            ExportMode::Only(cache_type) => cache_type.to_string().to_lowercase(),
+            ExportMode::All => "default".to_string(),
+            ExportMode::Found => "found".to_string(),
+        };
+        self.dir
+            .iter()
+            .flat_map(|dir| [dir.join(format!("{}.bmp", name)), dir.join("default.bmp")])
+            .find(|path| path.is_file())
+            .unwrap_or_else(|| PathBuf::from(Self::BUNDLED))
+    }
+}
 
 pub struct Garmin {}
 
 impl Garmin {
+    /// Maximum length of the listing text in the waypoint comment
+    const LISTING_LENGTH: usize = 2000;
+    const GROUNDSPEAK_NS: &'static str = "http://www.groundspeak.com/cache/1/0/1";
+
+    /// Pocket Query style GPX 1.0 with the groundspeak:cache extension, so c:geo, GSAK and
+    /// Garmin devices show D/T, size, hint, listing and logs.
     pub fn gpx<W: Write>(
         geocaches: Vec<Geocache>,
-        cache_type: &CacheType,
+        mode: &ExportMode,
+        flavor: &GpxFlavor,
+        snapshot: &DateTime<Utc>,
+        writer: &mut W,
+    ) -> Result<(), Error> {
+        info!("Writing {:?} gpx", flavor);
+        let mut xml = EmitterConfig::new()
+            .perform_indent(true)
+            .create_writer(writer);
+        Self::write_pocket_query(
+            &mut xml,
+            geocaches.iter().filter(|gc| mode.includes(gc)),
+            flavor,
+            snapshot,
+        )
+        .map_err(GpxError::from)?;
+        Ok(())
+    }
+
+    fn write_pocket_query<'a, W: Write>(
+        xml: &mut EventWriter<W>,
+        geocaches: impl Iterator<Item = &'a Geocache>,
+        flavor: &GpxFlavor,
+        snapshot: &DateTime<Utc>,
+    ) -> xml::writer::Result<()> {
+        let gpx = XmlEvent::start_element("gpx")
+            .default_ns("http://www.topografix.com/GPX/1/0")
+            .ns("groundspeak", Self::GROUNDSPEAK_NS);
+        let gpx = match flavor {
+            GpxFlavor::PocketQuery => gpx,
+            GpxFlavor::Locus => gpx.ns("locus", GpxFlavor::LOCUS_NS),
+            GpxFlavor::OsmAnd => gpx.ns("osmand", GpxFlavor::OSMAND_NS),
+        };
+        xml.write(gpx.attr("version", "1.0").attr("creator", "cachecache"))?;
+        Self::element(xml, "name", "cachecache")?;
+        Self::element(
+            xml,
+            "time",
+            &snapshot.to_rfc3339_opts(SecondsFormat::Secs, true),
+        )?;
+        for gc in geocaches {
+            Self::write_waypoint(xml, gc, flavor)?;
+        }
+        xml.write(XmlEvent::end_element())
+    }
+
+    fn write_waypoint<W: Write>(
+        xml: &mut EventWriter<W>,
+        gc: &Geocache,
+        flavor: &GpxFlavor,
+    ) -> xml::writer::Result<()> {
+        let lat = gc.coord.lat.to_string();
+        let lon = gc.coord.lon.to_string();
+        xml.write(
+            XmlEvent::start_element("wpt")
+                .attr("lat", &lat)
+                .attr("lon", &lon),
+        )?;
+        if let Some(placed) = gc.placed {
+            Self::element(xml, "time", &format!("{}T00:00:00Z", placed))?;
+        }
+        Self::element(xml, "name", &gc.code)?;
+        Self::element(
+            xml,
+            "desc",
+            &format!(
+                "{}, {} ({}/{})",
+                gc.name,
+                Self::type_name(&gc.cache_type),
+                gc.difficulty,
+                gc.terrain
+            ),
+        )?;
+        Self::element(xml, "url", &gc.page_url())?;
+        Self::element(xml, "urlname", &gc.name)?;
+        Self::element(xml, "sym", Self::symbol(gc))?;
+        Self::element(
+            xml,
+            "type",
+            &format!("Geocache|{}", Self::type_name(&gc.cache_type)),
+        )?;
+
+        let id = Self::numeric_id(&gc.code).unwrap_or_default().to_string();
+        xml.write(
+            XmlEvent::start_element("groundspeak:cache")
+                .attr("id", &id)
+                .attr("available", Self::bool_attr(gc.available))
+                .attr("archived", Self::bool_attr(gc.archived)),
+        )?;
+        Self::element(xml, "groundspeak:name", &gc.name)?;
+        Self::element(xml, "groundspeak:type", Self::type_name(&gc.cache_type))?;
+        Self::element(xml, "groundspeak:container", Self::container_name(&gc.size))?;
+        Self::element(xml, "groundspeak:difficulty", &gc.difficulty.to_string())?;
+        Self::element(xml, "groundspeak:terrain", &gc.terrain.to_string())?;
+        for (name, html) in [
+            ("groundspeak:short_description", &gc.short_description),
+            ("groundspeak:long_description", &gc.long_description),
+        ] {
+            xml.write(XmlEvent::start_element(name).attr("html", "True"))?;
+            xml.write(XmlEvent::characters(html))?;
+            xml.write(XmlEvent::end_element())?;
+        }
+        // despite the name, Pocket Queries have the hint in plain text
+        Self::element(xml, "groundspeak:encoded_hints", &gc.decoded_hint())?;
+        xml.write(XmlEvent::start_element("groundspeak:logs"))?;
+        for (index, log) in gc.logs.iter().enumerate() {
+            Self::write_log(xml, index + 1, log)?;
+        }
+        xml.write(XmlEvent::end_element())?;
+        xml.write(XmlEvent::end_element())?;
+
+        Self::write_flavor(xml, gc, flavor)?;
+        Self::write_links(xml, gc, flavor)?;
+        xml.write(XmlEvent::end_element())
+    }
+
+    /// Solution checker and cartridge as GPX 1.1 style links, Locus has its own element.
+    fn write_links<W: Write>(
+        xml: &mut EventWriter<W>,
+        gc: &Geocache,
+        flavor: &GpxFlavor,
+    ) -> xml::writer::Result<()> {
+        let (link, text) = match flavor {
+            GpxFlavor::Locus => ("locus:link", "locus:text"),
+            _ => ("link", "text"),
+        };
+        for (href, title) in gc.links() {
+            xml.write(XmlEvent::start_element(link).attr("href", &href))?;
+            Self::element(xml, text, title)?;
+            xml.write(XmlEvent::end_element())?;
+        }
+        Ok(())
+    }
+
+    /// Extension elements after groundspeak:cache, GPX 1.0 allows them anywhere at the end.
+    fn write_flavor<W: Write>(
+        xml: &mut EventWriter<W>,
+        gc: &Geocache,
+        flavor: &GpxFlavor,
+    ) -> xml::writer::Result<()> {
+        match flavor {
+            GpxFlavor::PocketQuery => Ok(()),
+            GpxFlavor::Locus => {
+                xml.write(XmlEvent::start_element("locus:link").attr("href", &gc.page_url()))?;
+                Self::element(xml, "locus:text", &gc.name)?;
+                xml.write(XmlEvent::end_element())?;
+                Self::element(
+                    xml,
+                    "locus:icon",
+                    &format!("file:{}.png", gc.cache_type.to_string().to_lowercase()),
+                )
+            }
+            GpxFlavor::OsmAnd => {
+                Self::element(xml, "osmand:color", Self::color(&gc.cache_type))?;
+                Self::element(xml, "osmand:background", "circle")?;
+                Self::element(xml, "osmand:icon", "special_star")
+            }
+        }
+    }
+
+    fn write_log<W: Write>(
+        xml: &mut EventWriter<W>,
+        id: usize,
+        log: &GeocacheLog,
+    ) -> xml::writer::Result<()> {
+        xml.write(XmlEvent::start_element("groundspeak:log").attr("id", &id.to_string()))?;
+        Self::element(xml, "groundspeak:date", &log.timestamp)?;
+        Self::element(xml, "groundspeak:type", Self::log_type_name(&log.log_type))?;
+        xml.write(XmlEvent::start_element("groundspeak:text").attr("encoded", "False"))?;
+        xml.write(XmlEvent::characters(&log.text))?;
+        xml.write(XmlEvent::end_element())?;
+        xml.write(XmlEvent::end_element())
+    }
+
+    fn element<W: Write>(
+        xml: &mut EventWriter<W>,
+        name: &str,
+        text: &str,
+    ) -> xml::writer::Result<()> {
+        xml.write(XmlEvent::start_element(name))?;
+        xml.write(XmlEvent::characters(text))?;
+        xml.write(XmlEvent::end_element())
+    }
+
+    fn bool_attr(value: bool) -> &'static str {
+        if value {
+            "True"
+        } else {
+            "False"
+        }
+    }
+
+    /// The id behind a GC code: hex up to GCFFFF, base 31 without ambiguous letters above.
+    fn numeric_id(code: &str) -> Option<u64> {
+        const ALPHABET: &str = "0123456789ABCDEFGHJKMNPQRTVWXYZ";
+        let digits = code.strip_prefix("GC")?;
+        if digits.len() <= 4 && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return u64::from_str_radix(digits, 16).ok();
+        }
+        let mut id: u64 = 0;
+        for c in digits.chars() {
+            id = id * 31 + ALPHABET.find(c)? as u64;
+        }
+        id.checked_sub(411_120)
+    }
+
+    /// Waypoint symbol, the names Garmin devices and GSAK use for geocaches
+    fn symbol(gc: &Geocache) -> &'static str {
+        if gc.approximate {
+            return "Geocache";
+        }
+        match gc.cache_type {
+            CacheType::Traditional => "Geocache",
+            CacheType::Multi => "Multi-Cache",
+            CacheType::Mystery => "Unknown Cache",
+            CacheType::Earth => "Earthcache",
+            CacheType::Virtual => "Virtual cache",
+            CacheType::Webcam => "Webcam Cache",
+            CacheType::Letterbox => "Letterbox Cache",
+            CacheType::Wherigo => "Wherigo Cache",
+            CacheType::Event | CacheType::MegaEvent | CacheType::GigaEvent | CacheType::Cito => {
+                "Event Cache"
+            }
+            _ => "Geocache",
+        }
+    }
+
+    /// Map color of a cache type as #rrggbb, close to the geocaching.com icons
+    pub(crate) fn color(cache_type: &CacheType) -> &'static str {
+        match cache_type {
+            CacheType::Traditional => "#00a000",
+            CacheType::Multi => "#ff8000",
+            CacheType::Mystery => "#0000ff",
+            CacheType::Earth => "#a55a2a",
+            CacheType::Virtual | CacheType::Webcam => "#c0c0c0",
+            CacheType::Letterbox => "#000080",
+            CacheType::Wherigo => "#00ffff",
+            CacheType::Event | CacheType::MegaEvent | CacheType::GigaEvent | CacheType::Cito => {
+                "#ff0000"
+            }
+            _ => "#ffff00",
+        }
+    }
+
+    /// Cache type as spelled in Pocket Queries
+    pub(crate) fn type_name(cache_type: &CacheType) -> &'static str {
+        match cache_type {
+            CacheType::Traditional => "Traditional Cache",
+            CacheType::Multi => "Multi-cache",
+            CacheType::Earth => "Earthcache",
+            CacheType::Webcam => "Webcam Cache",
+            CacheType::Mystery => "Unknown Cache",
+            CacheType::Wherigo => "Wherigo Cache",
+            CacheType::Event => "Event Cache",
+            CacheType::Virtual => "Virtual Cache",
+            CacheType::Letterbox => "Letterbox Hybrid",
+            CacheType::Cito => "Cache In Trash Out Event",
+            CacheType::Ape => "Project APE Cache",
+            CacheType::MegaEvent => "Mega-Event Cache",
+            CacheType::GigaEvent => "Giga-Event Cache",
+            CacheType::GpsAdventures => "GPS Adventures Exhibit",
+            CacheType::Headquarter => "Groundspeak HQ",
+            CacheType::Waypoint => "Reference Point",
+            CacheType::Unknown => "Geocache",
+        }
+    }
+
+    fn container_name(size: &ContainerSize) -> &'static str {
+        match size {
+            ContainerSize::Micro => "Micro",
+            ContainerSize::Small => "Small",
+            ContainerSize::Regular => "Regular",
+            ContainerSize::Large => "Large",
+            ContainerSize::Other => "Other",
+            ContainerSize::Virtual => "Virtual",
+            ContainerSize::NotChosen => "Not chosen",
+            ContainerSize::Unknown => "Unknown",
+        }
+    }
+
+    pub(crate) fn log_type_name(log_type: &LogType) -> &'static str {
+        match log_type {
+            LogType::Found => "Found it",
+            LogType::DidNotFind => "Didn't find it",
+            LogType::WriteNote => "Write note",
+            LogType::WillAttend => "Will Attend",
+            LogType::Attended => "Attended",
+            LogType::WebcamPhotoTaken => "Webcam Photo Taken",
+            LogType::NeedsMaintenance => "Needs Maintenance",
+            LogType::NeedsArchived => "Needs Archived",
+            LogType::OwnerMaintenance => "Owner Maintenance",
+            LogType::TemporarilyDisabled => "Temporarily Disable Listing",
+            LogType::Enabled => "Enable Listing",
+            LogType::Archived => "Archive",
+            LogType::Unarchived => "Unarchive",
+            LogType::Published => "Publish Listing",
+            LogType::Retracted => "Retract Listing",
+            LogType::UpdateCoordinates => "Update Coordinates",
+            LogType::ReviewerNote => "Post Reviewer Note",
+            LogType::Announcement => "Announcement",
+            LogType::Unknown => "Write note",
+        }
+    }
+
+    /// Plain waypoints with short titles for gpsbabel, POIs have no room for more.
+    fn poi_gpx<W: Write>(
+        geocaches: Vec<Geocache>,
+        mode: &ExportMode,
+        templates: &PoiTemplates,
+        snapshot: &DateTime<Utc>,
         writer: &mut W,
     ) -> Result<(), Error> {
-        info!("Writing gpx");
-        let mut gpx = gpx::Gpx::default();
-        gpx.creator = Some(String::from("cachecache"));
-        gpx.version = GpxVersion::Gpx11;
+        info!("Writing POI gpx");
+        let mut gpx = gpx::Gpx {
+            creator: Some(String::from("cachecache")),
+            version: GpxVersion::Gpx11,
+            metadata: Some(Metadata {
+                time: OffsetDateTime::from_unix_timestamp(snapshot.timestamp())
+                    .ok()
+                    .map(Time::from),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
         gpx.waypoints.extend(
             geocaches
                 .into_iter()
-                .filter(|gc| gc.cache_type == *cache_type)
+                .filter(|gc| mode.includes(gc))
                 .map(|gc| {
                     let mut waypoint = Waypoint::new(Point::new(gc.coord.lon, gc.coord.lat));
-                    waypoint.name = Some(Self::title(&gc));
-                    waypoint.description = Some(Self::description(&gc));
+                    waypoint.name = Some(templates.title(&gc));
+                    waypoint.description = Some(templates.description(&gc));
+                    waypoint.comment = Some(Self::listing(&gc)).filter(|l| !l.is_empty());
                     waypoint.type_ = Some(String::from("geocache"));
+                    waypoint.symbol = Some(Self::symbol(&gc).to_string());
                     waypoint
                 }),
         );
@@ -38,25 +647,29 @@ impl Garmin {
         Ok(())
     }
 
-    pub fn gpi<W: ?Sized>(
+    pub fn gpi<W>(
         geocaches: Vec<Geocache>,
-        cache_type: &CacheType,
+        mode: &ExportMode,
+        icons: &Icons,
+        templates: &PoiTemplates,
+        snapshot: &DateTime<Utc>,
         writer: &mut W,
     ) -> Result<(), Error>
     where
-        W: Write,
+        W: Write + ?Sized,
     {
         let mut gpx_file = NamedTempFile::new()?;
         let mut gpi_file = NamedTempFile::new()?;
         let image_file = NamedTempFile::new()?;
-        Self::gpx(geocaches, cache_type, &mut gpx_file)?;
+        Self::poi_gpx(geocaches, mode, templates, snapshot, &mut gpx_file)?;
+        info!("Wrote {:?} to {}", mode, gpx_file.path().to_string_lossy());
+        let icon = icons.for_mode(mode);
+        std::fs::copy(&icon, image_file.path())?;
         info!(
-            "Wrote {:?} to {}",
-            cache_type,
-            gpx_file.path().to_string_lossy()
+            "Copied {} to {}",
+            icon.to_string_lossy(),
+            image_file.path().to_string_lossy()
         );
-        std::fs::copy(Path::new("image.bmp"), image_file.path())?;
-        info!("Copied image to {}", image_file.path().to_string_lossy());
         let gpsbabel_output = Command::new("gpsbabel")
             .args([
                 "-i",
@@ -65,8 +678,9 @@ impl Garmin {
                 &gpx_file.path().to_string_lossy(),
                 "-o",
                 &format!(
-                    "garmin_gpi,bitmap={},sleep=1",
-                    image_file.path().to_string_lossy()
+                    "garmin_gpi,bitmap={},category={},sleep=1",
+                    image_file.path().to_string_lossy(),
+                    mode.category()
                 ),
                 "-F",
                 &gpi_file.path().to_string_lossy(),
@@ -85,14 +699,80 @@ impl Garmin {
         Ok(())
     }
 
-    fn title(gc: &Geocache) -> String {
-        format!(
-            "{} {}{} {}",
-            Self::code(gc),
-            Self::size(gc),
-            Self::gctype(gc),
-            Self::skill(gc)
-        )
+    /// A zip to unpack into /Garmin/POI: one GPI per cache type, found geocaches in their own
+    /// found.gpi, and a README listing them all.
+    pub fn gpi_zip<W: Write + ?Sized>(
+        geocaches: Vec<Geocache>,
+        found: &HashSet<String>,
+        icons: &Icons,
+        templates: &PoiTemplates,
+        snapshot: &DateTime<Utc>,
+        writer: &mut W,
+    ) -> Result<(), Error> {
+        let groups = Self::poi_groups(geocaches, found);
+        let mut zip = ZipWriter::new(tempfile::tempfile()?);
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file("README.txt", options)?;
+        zip.write_all(Self::readme(&groups, snapshot).as_bytes())?;
+        for (mode, geocaches) in groups {
+            zip.start_file(Self::gpi_name(&mode), options)?;
+            Self::gpi(geocaches, &mode, icons, templates, snapshot, &mut zip)?;
+        }
+        let mut zip_file = zip.finish()?;
+        zip_file.rewind()?;
+        std::io::copy(&mut zip_file, writer)?;
+        Ok(())
+    }
+
+    /// Geocaches by POI category, in the order of CacheType::ALL with the found ones last.
+    fn poi_groups(
+        geocaches: Vec<Geocache>,
+        found: &HashSet<String>,
+    ) -> Vec<(ExportMode, Vec<Geocache>)> {
+        let (found, geocaches): (Vec<_>, Vec<_>) = geocaches
+            .into_iter()
+            .partition(|gc| found.contains(&gc.code));
+        let mut groups: Vec<(ExportMode, Vec<Geocache>)> = CacheType::ALL
+            .iter()
+            .map(|cache_type| {
+                let of_type = geocaches
+                    .iter()
+                    .filter(|gc| gc.cache_type == *cache_type)
+                    .cloned()
+                    .collect();
+                (ExportMode::Only(cache_type.clone()), of_type)
+            })
+            .collect();
+        groups.push((ExportMode::Found, found));
+        groups.retain(|(_, geocaches)| !geocaches.is_empty());
+        groups
+    }
+
+    fn gpi_name(mode: &ExportMode) -> String {
+        match mode {
+            ExportMode::Only(cache_type) => {
+                format!("{}.gpi", cache_type.to_string().to_lowercase())
+            }
+            ExportMode::All => "geocaches.gpi".to_string(),
+            ExportMode::Found => "found.gpi".to_string(),
+        }
+    }
+
+    fn readme(groups: &[(ExportMode, Vec<Geocache>)], snapshot: &DateTime<Utc>) -> String {
+        let mut readme = format!(
+            "Geocaches from cachecache, snapshot {}\n\
+             Copy the GPI files to /Garmin/POI on the device.\n\n",
+            snapshot.to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+        for (mode, geocaches) in groups {
+            readme.push_str(&format!(
+                "{:<20} {:<28} {:>5}\n",
+                Self::gpi_name(mode),
+                mode.category(),
+                geocaches.len()
+            ));
+        }
+        readme
     }
 
     fn code(gc: &Geocache) -> String {
@@ -111,26 +791,27 @@ impl Garmin {
         String::from(&x.to_string()[..1]).to_ascii_uppercase()
     }
 
-    fn skill(gc: &Geocache) -> String {
-        format!("{:.1}/{:.1}", gc.difficulty, gc.terrain)
-    }
-
-    fn description(gc: &Geocache) -> String {
-        let hint = Self::hint(gc);
-        let newline = if hint.len() > 0 { "\n" } else { "" };
-        let description = format!("{}{}{}", Self::name(gc), newline, hint);
-        description.chars().into_iter().take(100).collect()
+    fn listing(gc: &Geocache) -> String {
+        let html = format!("{}<p>{}", gc.short_description, gc.long_description);
+        // clean() would collapse the line breaks
+        html2text(&html, Some(Self::LISTING_LENGTH))
+            .lines()
+            .map(Self::clean)
+            .collect::<Vec<_>>()
+            .join("\n")
     }
 
     fn hint(gc: &Geocache) -> String {
-        Self::clean(&gc.encoded_hints)
+        Self::clean(&gc.decoded_hint())
     }
 
     fn name(gc: &Geocache) -> String {
         Self::clean(&gc.name)
     }
 
-    fn clean(str: &String) -> String {
+    /// ASCII only, Garmin devices show anything else as garbage. German umlauts keep their
+    /// usual spelling, other letters lose their accents, symbols and emoji are dropped.
+    fn clean(str: &str) -> String {
         lazy_static::lazy_static! {
             static ref PATTERN_WHITESPACE: Regex = Regex::new(r"\s{2,}").unwrap();
             static ref PATTERN_ALLOWED: Regex = Regex::new(r"[^\w;:?!,.\-=_/@$%*+() |\n]").unwrap();
@@ -144,10 +825,19 @@ impl Garmin {
             .replace("Ö", "OE")
             .replace("Ü", "UE")
             .replace("ß", "ss");
-        let clean2 = PATTERN_ALLOWED.replace_all(&clean1, "");
-        let clean3 = PATTERN_WHITESPACE.replace_all(&clean2, " ");
+        let clean2: String = clean1
+            .chars()
+            .map(|c| match c {
+                c if c.is_ascii() => c.to_string(),
+                c if c.is_alphabetic() => deunicode::deunicode_char(c).unwrap_or("").to_string(),
+                c if c.is_whitespace() => " ".to_string(),
+                _ => String::new(),
+            })
+            .collect();
+        let clean3 = PATTERN_ALLOWED.replace_all(&clean2, "");
+        let clean4 = PATTERN_WHITESPACE.replace_all(&clean3, " ");
 
-        return String::from(clean3);
+        String::from(clean4)
     }
 }
 
@@ -157,7 +847,279 @@ mod tests {
 
     #[test]
     fn clean_removes_unicode() {
-        let cleaned = Garmin::clean(&String::from("smile 🙂 for me"));
+        let cleaned = Garmin::clean("smile 🙂 for me");
         assert_eq!(cleaned, String::from("smile for me"));
     }
+
+    #[test]
+    fn clean_transliterates() {
+        assert_eq!(
+            Garmin::clean("Église Notre-Dame à Besançon"),
+            "Eglise Notre-Dame a Besancon"
+        );
+        assert_eq!(Garmin::clean("Černý důl, Šumava"), "Cerny dul, Sumava");
+        assert_eq!(
+            Garmin::clean("Ærøskøbing Ålesund Göteborg"),
+            "AEroskobing Alesund Goeteborg"
+        );
+        assert_eq!(Garmin::clean("Grüße aus Köln"), "Gruesse aus Koeln");
+    }
+
+    #[test]
+    fn approximate_geocaches_are_marked() {
+        let gc = Geocache::approximate(
+            "GC3Y133".to_string(),
+            crate::gcgeo::Coordinate { lat: 1.0, lon: 2.0 },
+        );
+        let templates = PoiTemplates::default();
+        assert_eq!(templates.title(&gc), "3Y133 ~approx");
+        assert!(templates.description(&gc).contains("approximate"));
+    }
+
+    #[test]
+    fn description_has_decoded_hint() {
+        let mut gc = Geocache::premium("GC3Y133".to_string());
+        gc.name = "Berg auf Berg ab".to_string();
+        gc.encoded_hints = "Zntargvfpu".to_string();
+        assert_eq!(
+            PoiTemplates::default().description(&gc),
+            "Berg auf Berg ab\nMagnetisch"
+        );
+    }
+
+    #[test]
+    fn titles_follow_the_template() {
+        let mut gc = Geocache::premium("GC3Y133".to_string());
+        gc.approximate = false;
+        gc.name = "Berg auf Berg ab".to_string();
+        gc.cache_type = CacheType::Traditional;
+        gc.size = ContainerSize::Micro;
+        gc.difficulty = 2.0;
+        gc.terrain = 2.5;
+        assert_eq!(PoiTemplates::default().title(&gc), "3Y133 MT 2.0/2.5");
+        assert_eq!(PoiTemplates::default().description(&gc), "Berg auf Berg ab");
+
+        let templates =
+            PoiTemplates::new(Some("{name} ({d}/{t})"), Some("{type}, {size}")).unwrap();
+        assert_eq!(templates.title(&gc), "Berg auf Berg ab (2.0/2.5)");
+        assert_eq!(templates.description(&gc), "Traditional Cache, Micro");
+
+        gc.name = "x".repeat(200);
+        assert_eq!(templates.title(&gc).chars().count(), 100);
+        assert!(PoiTemplates::new(Some("{owner}"), None).is_err());
+        assert!(PoiTemplates::new(Some("{code"), None).is_err());
+        assert!(PoiTemplates::new(None, Some(&"x".repeat(101))).is_err());
+    }
+
+    #[test]
+    fn listing_is_plain_text() {
+        let mut gc = Geocache::premium("GC3Y133".to_string());
+        gc.short_description = "Ein kurzes Rätsel".to_string();
+        gc.long_description = "<p>Peile <b>ABC</b> Grad</p>".to_string();
+        assert_eq!(Garmin::listing(&gc), "Ein kurzes Raetsel\n\nPeile ABC Grad");
+    }
+
+    #[test]
+    fn gpx_records_snapshot_time() {
+        let gc = Geocache::approximate(
+            "GC3Y133".to_string(),
+            crate::gcgeo::Coordinate { lat: 1.0, lon: 2.0 },
+        );
+        let snapshot = DateTime::from_timestamp(1_717_243_200, 0).unwrap();
+        let mut output = Vec::new();
+        Garmin::gpx(
+            vec![gc],
+            &ExportMode::default(),
+            &GpxFlavor::default(),
+            &snapshot,
+            &mut output,
+        )
+        .unwrap();
+        let gpx = String::from_utf8(output).unwrap();
+        assert!(gpx.contains("<time>2024-06-01T12:00:00"));
+    }
+
+    #[test]
+    fn gpx_has_groundspeak_extension() {
+        let mut gc = Geocache::premium("GC3Y133".to_string());
+        gc.is_premium = false;
+        gc.available = true;
+        gc.name = "Berg & Tal".to_string();
+        gc.cache_type = CacheType::Traditional;
+        gc.size = ContainerSize::Small;
+        gc.difficulty = 1.5;
+        gc.terrain = 2.0;
+        gc.encoded_hints = "Zntargvfpu".to_string();
+        gc.long_description = "<p>Listing</p>".to_string();
+        gc.logs = vec![GeocacheLog {
+            text: "TFTC".to_string(),
+            timestamp: "2024-05-20T10:00:00+02:00".to_string(),
+            log_type: LogType::DidNotFind,
+        }];
+        let snapshot = DateTime::from_timestamp(1_717_243_200, 0).unwrap();
+        let mut output = Vec::new();
+        Garmin::gpx(
+            vec![gc],
+            &ExportMode::default(),
+            &GpxFlavor::default(),
+            &snapshot,
+            &mut output,
+        )
+        .unwrap();
+        let gpx = String::from_utf8(output).unwrap();
+
+        assert!(gpx.contains(r#"xmlns:groundspeak="http://www.groundspeak.com/cache/1/0/1""#));
+        assert!(gpx.contains("<name>GC3Y133</name>"));
+        assert!(
+            gpx.contains(r#"<groundspeak:cache id="3224439" available="True" archived="False">"#)
+        );
+        assert!(gpx.contains("<groundspeak:name>Berg &amp; Tal</groundspeak:name>"));
+        assert!(gpx.contains("<groundspeak:type>Traditional Cache</groundspeak:type>"));
+        assert!(gpx.contains("<groundspeak:container>Small</groundspeak:container>"));
+        assert!(gpx.contains("<groundspeak:difficulty>1.5</groundspeak:difficulty>"));
+        assert!(gpx.contains("<groundspeak:terrain>2</groundspeak:terrain>"));
+        assert!(gpx.contains("<groundspeak:encoded_hints>Magnetisch</groundspeak:encoded_hints>"));
+        assert!(gpx.contains("&lt;p&gt;Listing&lt;/p&gt;"));
+        assert!(gpx.contains("<groundspeak:type>Didn't find it</groundspeak:type>"));
+        assert!(xml::EventReader::new(gpx.as_bytes())
+            .into_iter()
+            .all(|event| event.is_ok()));
+    }
+
+    #[test]
+    fn gpx_flavors_add_app_extensions() {
+        let mut gc = Geocache::premium("GC3Y133".to_string());
+        gc.cache_type = CacheType::Mystery;
+        gc.has_solution_checker = true;
+        let snapshot = DateTime::from_timestamp(1_717_243_200, 0).unwrap();
+        let gpx = |flavor: &str| {
+            let mut output = Vec::new();
+            Garmin::gpx(
+                vec![gc.clone()],
+                &ExportMode::All,
+                &flavor.parse().unwrap(),
+                &snapshot,
+                &mut output,
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        let locus = gpx("locus");
+        assert!(locus.contains(r#"<locus:link href="https://coord.info/GC3Y133">"#));
+        assert!(locus.contains("<locus:icon>file:mystery.png</locus:icon>"));
+        assert!(locus.contains("<locus:text>Solution checker</locus:text>"));
+        let osmand = gpx("OsmAnd");
+        assert!(osmand.contains(r#"xmlns:osmand="https://osmand.net""#));
+        assert!(osmand.contains("<osmand:color>#0000ff</osmand:color>"));
+        let pq = gpx("pq");
+        assert!(!pq.contains("osmand"));
+        assert!(pq.contains(r#"<link href="https://coord.info/GC3Y133">"#));
+        assert!("garmin".parse::<GpxFlavor>().is_err());
+    }
+
+    #[test]
+    fn numeric_ids() {
+        assert_eq!(Garmin::numeric_id("GCFFFF"), Some(65535));
+        assert_eq!(Garmin::numeric_id("GCG000"), Some(65536));
+        assert_eq!(Garmin::numeric_id("GC1"), Some(1));
+        assert_eq!(Garmin::numeric_id("OC1234"), None);
+    }
+
+    #[test]
+    fn all_types_get_their_symbol() {
+        let mut tradi = Geocache::premium("GC1".to_string());
+        tradi.cache_type = CacheType::Traditional;
+        let mut mystery = Geocache::premium("GC2".to_string());
+        mystery.cache_type = CacheType::Mystery;
+        let snapshot = DateTime::from_timestamp(1_717_243_200, 0).unwrap();
+
+        let mut output = Vec::new();
+        Garmin::gpx(
+            vec![tradi.clone(), mystery.clone()],
+            &"all".parse().unwrap(),
+            &GpxFlavor::default(),
+            &snapshot,
+            &mut output,
+        )
+        .unwrap();
+        let gpx = String::from_utf8(output).unwrap();
+        assert!(gpx.contains("<sym>Geocache</sym>"));
+        assert!(gpx.contains("<sym>Unknown Cache</sym>"));
+
+        let mut output = Vec::new();
+        Garmin::gpx(
+            vec![tradi, mystery],
+            &"mystery".parse().unwrap(),
+            &GpxFlavor::default(),
+            &snapshot,
+            &mut output,
+        )
+        .unwrap();
+        let gpx = String::from_utf8(output).unwrap();
+        assert!(!gpx.contains("<name>GC1</name>"));
+        assert!(gpx.contains("<name>GC2</name>"));
+        assert!("multicache".parse::<ExportMode>().is_err());
+        assert_eq!(ExportMode::default().file_suffix(), "tradis");
+        assert_eq!(
+            "mystery".parse::<ExportMode>().unwrap().file_suffix(),
+            "mystery"
+        );
+    }
+
+    #[test]
+    fn zip_has_one_gpi_per_type() {
+        let mut tradi = Geocache::premium("GC1".to_string());
+        tradi.cache_type = CacheType::Traditional;
+        let mut mystery = Geocache::premium("GC2".to_string());
+        mystery.cache_type = CacheType::Mystery;
+        let mut found = Geocache::premium("GC3".to_string());
+        found.cache_type = CacheType::Mystery;
+        let found_codes = HashSet::from(["GC3".to_string()]);
+
+        let groups = Garmin::poi_groups(vec![tradi, mystery, found], &found_codes);
+        let names: Vec<_> = groups
+            .iter()
+            .map(|(mode, geocaches)| (Garmin::gpi_name(mode), geocaches.len()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("traditional.gpi".to_string(), 1),
+                ("mystery.gpi".to_string(), 1),
+                ("found.gpi".to_string(), 1)
+            ]
+        );
+
+        let snapshot = DateTime::from_timestamp(1_717_243_200, 0).unwrap();
+        let readme = Garmin::readme(&groups, &snapshot);
+        assert!(readme.contains("snapshot 2024-06-01T12:00:00Z"));
+        assert!(readme.contains("mystery.gpi"));
+        assert!(readme.contains("Unknown Cache"));
+    }
+
+    #[test]
+    fn icons_fall_back_to_default() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("mystery.bmp"), b"BM").unwrap();
+        let icons = Icons::new(dir.path().to_str());
+        assert_eq!(
+            icons.for_mode(&"mystery".parse().unwrap()),
+            dir.path().join("mystery.bmp")
+        );
+        assert_eq!(
+            icons.for_mode(&ExportMode::Found),
+            PathBuf::from("image.bmp")
+        );
+
+        std::fs::write(dir.path().join("default.bmp"), b"BM").unwrap();
+        assert_eq!(
+            icons.for_mode(&ExportMode::default()),
+            dir.path().join("default.bmp")
+        );
+        assert_eq!(
+            Icons::default().for_mode(&ExportMode::All),
+            PathBuf::from("image.bmp")
+        );
+    }
 }
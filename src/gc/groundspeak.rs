@@ -1,19 +1,31 @@
 use std::time::Duration;
 
-use chrono::{NaiveDateTime, TimeZone};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 use chrono_tz::Tz;
 use log::{debug, info};
 use rand::Rng;
+use regex::Regex;
 use thiserror::Error;
-use tokio::time::sleep;
+use tokio::sync::Mutex;
+use tokio::time::{sleep_until, Instant};
 
+use crate::config::Config;
+use crate::gc::fixture::FixtureMode;
 use crate::gc::utfgrid::UtfGrid;
-use crate::gcgeo::{CacheType, ContainerSize, Coordinate, Geocache, GeocacheLog, LogType, Tile};
+use crate::gcgeo::{
+    Attribute, CacheType, ContainerSize, Coordinate, Geocache, GeocacheLog, LogType, Tile,
+};
 
 pub const BATCH_SIZE: usize = 50;
 
+/// Earliest time the next Groundspeak request may go out, shared by all requests of the process.
+static NEXT_REQUEST: Mutex<Option<Instant>> = Mutex::const_new(None);
+
 pub struct Groundspeak {
     client: reqwest::Client,
+    fixtures: FixtureMode,
+    user_agent: String,
+    user_agent_fetch: String,
 }
 
 pub type GcCodes = Vec<GcCode>;
@@ -24,6 +36,16 @@ pub struct GcCode {
     pub approx_coord: Option<Coordinate>,
 }
 
+/// Result of a (conditional) tile info request.
+pub enum TileInfo {
+    /// The tile did not change since the given ETag/timestamp.
+    NotModified,
+    Modified {
+        codes: GcCodes,
+        etag: Option<String>,
+    },
+}
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("request error")]
@@ -36,28 +58,54 @@ pub enum Error {
     Chrono(#[from] chrono::ParseError),
     #[error("chrono-tz")]
     ChronoTz(#[from] chrono_tz::ParseError),
+    #[error("io")]
+    Io(#[from] std::io::Error),
+    #[error("http status {0}")]
+    Status(u16),
     #[error("unknown error")]
     Unknown,
 }
 
 impl Groundspeak {
     const FETCH_URL: &'static str = "https://api.groundspeak.com/v1.0/geocaches";
+    const IMAGES_TAKE: usize = 50;
+    const LOG_URL: &'static str = "https://api.groundspeak.com/v1.0/geocachelogs";
 
-    const USER_AGENT: &'static str = "User-Agent: Mozilla/6.0 (Macintosh; Intel Mac OS X 10.15; rv:109.0) Gecko/20100101 Firefox/112.0";
-
-    const USER_AGENT_FETCH: &'static str = env!("USERAGENT");
-
-    //const FETCH_FIELDS: &'static str = "referenceCode,ianaTimezoneId,name,postedCoordinates,geocacheType,geocacheSize,difficulty,terrain,userData,favoritePoints,placedDate,eventEndDate,ownerAlias,owner,isPremiumOnly,userData,lastVisitedDate,status,hasSolutionChecker";
+    //const FETCH_FIELDS: &'static str = "referenceCode,ianaTimezoneId,name,postedCoordinates,geocacheType,geocacheSize,difficulty,terrain,userData,favoritePoints,placedDate,eventEndDate,ownerAlias,ownerAlias,owner,isPremiumOnly,userData,lastVisitedDate,status,hasSolutionChecker";
     const EXPAND_FIELDS: &'static str = "geocachelogs:5";
-    const FETCH_FIELDS: &'static str = "referenceCode,name,postedCoordinates,geocacheType,geocacheSize,difficulty,terrain,favoritePoints,placedDate,isPremiumOnly,lastVisitedDate,status,shortDescription,longDescription,hints,additionalWaypoints,geocachelogs[loggedDate,ianaTimezoneId,text,geocacheLogType[id]]";
+    const FETCH_FIELDS: &'static str = "referenceCode,name,postedCoordinates,geocacheType,geocacheSize,difficulty,terrain,favoritePoints,placedDate,eventEndDate,isPremiumOnly,lastVisitedDate,status,url,hasSolutionChecker,attributes,shortDescription,longDescription,hints,additionalWaypoints,geocachelogs[loggedDate,ianaTimezoneId,text,geocacheLogType[id]]";
 
-    pub fn new() -> Self {
+    pub fn new(client: reqwest::Client, fixtures: FixtureMode, config: &Config) -> Self {
         Self {
-            client: reqwest::Client::new(),
+            client,
+            fixtures,
+            user_agent: config.tile_user_agent.clone(),
+            user_agent_fetch: config.fetch_user_agent.clone(),
         }
     }
 
-    pub async fn discover(&self, tile: &Tile) -> Result<GcCodes, Error> {
+    /// Wait for the next free request slot, at most one request per second even with parallel callers.
+    async fn throttle(&self) {
+        if self.fixtures.is_replay() {
+            return;
+        }
+        let slot = {
+            let mut next = NEXT_REQUEST.lock().await;
+            let now = Instant::now();
+            let slot = next.map_or(now, |next| next.max(now));
+            *next = Some(slot + Duration::from_secs(1));
+            slot
+        };
+        sleep_until(slot).await;
+    }
+
+    /// Discover a tile, sending If-None-Match/If-Modified-Since if we know a previous version.
+    pub async fn discover(
+        &self,
+        tile: &Tile,
+        etag: Option<&str>,
+        last_modified: Option<&DateTime<Utc>>,
+    ) -> Result<TileInfo, Error> {
         debug!("Discovering {}", tile);
 
         let base_url = format!(
@@ -79,32 +127,51 @@ impl Groundspeak {
             tile.z,
         );
 
-        self.client
-            .get(image_url)
-            .header(reqwest::header::USER_AGENT, Self::USER_AGENT)
-            .header(reqwest::header::ACCEPT, "*/*")
-            .send()
-            .await?;
+        if !self.fixtures.is_replay() {
+            self.client
+                .get(image_url)
+                .header(reqwest::header::USER_AGENT, &self.user_agent)
+                .header(reqwest::header::ACCEPT, "*/*")
+                .send()
+                .await?;
+        }
 
-        let response = self
+        let mut request = self
             .client
             .get(info_url)
-            .header(reqwest::header::USER_AGENT, Self::USER_AGENT)
-            .header(reqwest::header::ACCEPT, "application/json")
-            .send()
-            .await?;
+            .header(reqwest::header::USER_AGENT, &self.user_agent)
+            .header(reqwest::header::ACCEPT, "application/json");
+        if let Some(etag) = etag {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(ts) = last_modified {
+            request = request.header(
+                reqwest::header::IF_MODIFIED_SINCE,
+                ts.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            );
+        }
+        let key = format!("tile-{}-{}-{}", tile.z, tile.x, tile.y);
+        let response = self.fixtures.send(&key, request).await?;
 
-        sleep(Duration::from_secs(1)).await;
+        self.throttle().await;
 
-        debug!("tile response {:#?}", response);
-        if response.status() == 204 {
+        debug!("tile response {} {:?}", response.status, response.etag);
+        if response.status == 304 {
+            info!("Discover {} -> not modified", tile);
+            return Ok(TileInfo::NotModified);
+        }
+        let etag = response.etag;
+        if response.status == 204 {
             info!("Discover {} -> 0", tile);
-            return Ok(vec![]);
+            return Ok(TileInfo::Modified {
+                codes: vec![],
+                etag,
+            });
         }
-        let grid = response.json::<UtfGrid>().await?;
-        let codes = grid.parse(&tile).await?;
+        let grid: UtfGrid = serde_json::from_str(&response.body)?;
+        let codes = grid.parse(tile).await?;
 
-        Ok(codes)
+        Ok(TileInfo::Modified { codes, etag })
     }
 
     pub async fn fetch(
@@ -118,32 +185,127 @@ impl Groundspeak {
         debug!("fetch chunk {}", codes.len());
         let codes_str: Vec<&str> = codes.iter().map(|x| x.as_str()).collect();
         let comma_separated_codes = codes_str.join(",");
-        let response = self
+        let key = format!(
+            "fetch-{}-{}-{}",
+            codes_str.first().unwrap_or(&""),
+            codes_str.last().unwrap_or(&""),
+            codes_str.len()
+        );
+        let request = self
             .client
             .get(Groundspeak::FETCH_URL)
             .header(reqwest::header::ACCEPT, "*/*")
             .header(reqwest::header::ACCEPT_LANGUAGE, "en-US;q=1")
-            .header(reqwest::header::USER_AGENT, Groundspeak::USER_AGENT_FETCH)
+            .header(reqwest::header::USER_AGENT, &self.user_agent_fetch)
             .bearer_auth(token)
             .query(&[
                 ("referenceCodes", comma_separated_codes),
                 ("lite", "true".to_string()),
                 ("fields", Self::FETCH_FIELDS.to_string()),
                 ("expand", Self::EXPAND_FIELDS.to_string()),
-            ])
-            .send()
-            .await?;
-        debug!("fetch status {}", response.status().as_str());
-        let json: serde_json::Value = serde_json::from_slice(&response.bytes().await?)?;
+            ]);
+        let response = self.fixtures.send(&key, request).await?;
+        debug!("fetch status {}", response.status);
+        let json: serde_json::Value = serde_json::from_str(&response.body)?;
         debug!("fetch json {:#?}", json);
 
-        sleep(Duration::from_secs(1)).await;
+        self.throttle().await;
 
         let geocaches = json.as_array().ok_or(Error::JsonRaw)?.clone();
         debug!("fetch geocaches {}", geocaches.len());
 
         Ok(geocaches)
     }
+
+    /// Fetch the image gallery (e.g. spoiler photos) of a geocache.
+    pub async fn fetch_images(&self, token: &str, code: &str) -> Result<Vec<String>, Error> {
+        debug!("fetch images {}", code);
+        let request = self
+            .client
+            .get(format!("{}/{}/images", Groundspeak::FETCH_URL, code))
+            .header(reqwest::header::ACCEPT, "*/*")
+            .header(reqwest::header::USER_AGENT, &self.user_agent_fetch)
+            .bearer_auth(token)
+            .query(&[
+                ("take", Self::IMAGES_TAKE.to_string()),
+                ("fields", "url".to_string()),
+            ]);
+        let response = self
+            .fixtures
+            .send(&format!("images-{}", code), request)
+            .await?;
+        let json: serde_json::Value = serde_json::from_str(&response.body)?;
+
+        self.throttle().await;
+
+        let urls = json
+            .as_array()
+            .ok_or(Error::JsonRaw)?
+            .iter()
+            .filter_map(|image| image["url"].as_str().map(String::from))
+            .collect();
+        Ok(urls)
+    }
+
+    /// Post a log (found, DNF, note) for a geocache, returns the reference code of the new log.
+    pub async fn post_log(
+        &self,
+        token: &str,
+        code: &str,
+        log_type: &LogType,
+        text: &str,
+        date: &DateTime<Utc>,
+    ) -> Result<String, Error> {
+        debug!("post {:?} log for {}", log_type, code);
+        let log_type_id = log_type.id().ok_or(Error::Unknown)?;
+        let request = self
+            .client
+            .post(Groundspeak::LOG_URL)
+            .header(reqwest::header::ACCEPT, "*/*")
+            .header(reqwest::header::USER_AGENT, &self.user_agent_fetch)
+            .bearer_auth(token)
+            .json(&serde_json::json!({
+                "geocacheCode": code,
+                "geocacheLogType": { "id": log_type_id },
+                "loggedDate": date.format("%Y-%m-%dT%H:%M:%S").to_string(),
+                "text": text,
+            }));
+        let response = self
+            .fixtures
+            .send(&format!("log-{}-{}", code, date.timestamp()), request)
+            .await?;
+        if !(200..300).contains(&response.status) {
+            return Err(Error::Status(response.status));
+        }
+        let json: serde_json::Value = serde_json::from_str(&response.body)?;
+        Ok(json["referenceCode"]
+            .as_str()
+            .ok_or(Error::JsonRaw)?
+            .to_string())
+    }
+}
+
+/// Build a HTTP client honoring the proxy and TLS settings.
+pub fn http_client(config: &Config) -> Result<reqwest::Client, Error> {
+    let mut builder = reqwest::Client::builder();
+    #[cfg(feature = "rustls")]
+    {
+        builder = builder.use_rustls_tls();
+    }
+    if let Some(proxy) = &config.proxy {
+        info!("Using proxy {}", proxy);
+        let no_proxy = config
+            .no_proxy
+            .as_deref()
+            .and_then(reqwest::NoProxy::from_string);
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?.no_proxy(no_proxy));
+    }
+    for path in &config.root_certificates {
+        info!("Adding root certificate {}", path);
+        let pem = std::fs::read(path)?;
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+    }
+    Ok(builder.build()?)
 }
 
 pub fn parse(v: &serde_json::Value) -> Result<Geocache, Error> {
@@ -166,23 +328,57 @@ pub fn parse(v: &serde_json::Value) -> Result<Geocache, Error> {
     let lon = v["postedCoordinates"]["longitude"]
         .as_f64()
         .ok_or(Error::JsonRaw)?;
-    /* not availble for lite=true
-    let short_description = String::from(v["shortDescription"].as_str().ok_or(Error::JsonRaw)?);
-    let long_description = String::from(v["longDescription"].as_str().ok_or(Error::JsonRaw)?);
-    let encoded_hints = String::from(v["hints"].as_str().ok_or(Error::JsonRaw)?);
-     */
-    let short_description = String::new();
-    let long_description = String::new();
-    let encoded_hints = String::new();
-
-    let size = ContainerSize::from(v["geocacheSize"]["id"].as_u64().ok_or(Error::JsonRaw)?);
+    // not always available for lite=true
+    let short_description = String::from(v["shortDescription"].as_str().unwrap_or_default());
+    let long_description = String::from(v["longDescription"].as_str().unwrap_or_default());
+    let encoded_hints = String::from(v["hints"].as_str().unwrap_or_default());
+
+    // by id, by name for ids we don't know yet
+    let size = match v["geocacheSize"]["id"].as_u64().map(ContainerSize::from) {
+        Some(ContainerSize::Unknown) | None => v["geocacheSize"]["name"]
+            .as_str()
+            .and_then(|name| name.parse().ok())
+            .unwrap_or(ContainerSize::Unknown),
+        Some(size) => size,
+    };
     let cache_type = CacheType::from(v["geocacheType"]["id"].as_u64().ok_or(Error::JsonRaw)?);
-    let available = v["status"].as_str().ok_or(Error::JsonRaw)? == "Active";
-    // TODO archived?
-    let archived = false; //v["Archived"].as_bool().ok_or(Error::JsonRaw)?;
-                          // not available for lite=true
-                          // let logs = v["geocacheLogs"].as_array().ok_or(Error::JsonRaw)?.iter().map(parse_geocache_log).collect::<Result<Vec<GeocacheLog>, Error>>()?;
-    let logs = vec![];
+    let status = v["status"].as_str().ok_or(Error::JsonRaw)?;
+    let available = status == "Active";
+    let archived = status == "Archived";
+    let placed = v["placedDate"]
+        .as_str()
+        .and_then(|date| NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S%.f").ok())
+        .map(|date| date.date());
+    let event_end = v["eventEndDate"]
+        .as_str()
+        .and_then(|date| NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S%.f").ok())
+        .map(|date| date.date());
+    let last_visited = v["lastVisitedDate"]
+        .as_str()
+        .and_then(|date| NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S%.f").ok())
+        .map(|date| date.date());
+    let url = v["url"].as_str().map(String::from);
+    let has_solution_checker = v["hasSolutionChecker"].as_bool().unwrap_or(false);
+    let owner = v["ownerAlias"].as_str().map(String::from);
+    let favorite_points = v["favoritePoints"].as_u64().unwrap_or(0) as u32;
+    let cartridge = if cache_type == CacheType::Wherigo {
+        parse_cartridge(&long_description)
+    } else {
+        None
+    };
+    let attributes = v["attributes"]
+        .as_array()
+        .map(|attributes| attributes.iter().filter_map(parse_attribute).collect())
+        .unwrap_or_default();
+    // not always available for lite=true, so take whatever logs we get
+    let logs = v["geocacheLogs"]
+        .as_array()
+        .map(|logs| {
+            logs.iter()
+                .filter_map(|log| parse_geocache_log(log).ok())
+                .collect()
+        })
+        .unwrap_or_default();
 
     Ok(Geocache {
         code,
@@ -199,6 +395,37 @@ pub fn parse(v: &serde_json::Value) -> Result<Geocache, Error> {
         archived,
         available,
         logs,
+        placed,
+        event_end,
+        approximate: false,
+        url,
+        has_solution_checker,
+        cartridge,
+        favorite_points,
+        owner,
+        last_visited,
+        attributes,
+    })
+}
+
+fn parse_attribute(v: &serde_json::Value) -> Option<Attribute> {
+    Some(Attribute {
+        id: v["id"].as_u64()?,
+        name: v["name"].as_str()?.to_string(),
+        is_on: v["isOn"].as_bool().unwrap_or(true),
+    })
+}
+
+/// The cartridge link in a Wherigo listing, the API has no field for it.
+fn parse_cartridge(html: &str) -> Option<String> {
+    lazy_static::lazy_static! {
+        static ref PATTERN_CARTRIDGE: Regex = Regex::new(r"(?i)wherigo\.com/cartridge/(?:details|download)\.aspx\?CGUID=([0-9a-f-]{36})").unwrap();
+    }
+    PATTERN_CARTRIDGE.captures(html).map(|captures| {
+        format!(
+            "https://www.wherigo.com/cartridge/details.aspx?CGUID={}",
+            &captures[1]
+        )
     })
 }
 
@@ -222,12 +449,57 @@ fn parse_geocache_log(v: &serde_json::Value) -> Result<GeocacheLog, Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::gcgeo::Region;
+
+    fn replay() -> Groundspeak {
+        let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        Groundspeak::new(
+            reqwest::Client::new(),
+            FixtureMode::Replay(fixtures),
+            &Config::default(),
+        )
+    }
 
     #[tokio::test]
     async fn test_foo() {
-        let uut = Groundspeak::new();
+        let uut = replay();
         let tile = Tile::from_coordinates(51.34469577842422, 12.374765732990399, 12);
-        uut.discover(&tile).await.unwrap();
+        let info = uut.discover(&tile, None, None).await.unwrap();
+        match info {
+            TileInfo::Modified { mut codes, etag } => {
+                codes.sort_by(|a, b| a.code.cmp(&b.code));
+                assert_eq!(etag.as_deref(), Some("\"5f3c-1a2b\""));
+                assert_eq!(codes.len(), 2);
+                assert_eq!(codes[0].code, "GC1BXN4");
+                let coord = codes[0].approx_coord.as_ref().unwrap();
+                assert!(tile.bbox().contains(coord));
+            }
+            TileInfo::NotModified => panic!("expected tile data"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_replay() {
+        let uut = replay();
+        let code = "GC3Y133".to_string();
+        let fetched = uut.fetch("token", vec![&code]).await.unwrap();
+        assert_eq!(fetched.len(), 1);
+        let geocache = parse(&fetched[0]).unwrap();
+        assert_eq!(geocache.code, "GC3Y133");
+        assert_eq!(geocache.cache_type, CacheType::Multi);
+    }
+
+    #[tokio::test]
+    async fn test_post_log_replay() {
+        let uut = replay();
+        let date = DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let log_code = uut
+            .post_log("token", "GC3Y133", &LogType::Found, "TFTC", &date)
+            .await
+            .unwrap();
+        assert_eq!(log_code, "GL1A2B3C4");
     }
 
     #[tokio::test]
@@ -237,5 +509,33 @@ mod tests {
         let json: serde_json::Value = serde_json::from_str(text).unwrap();
         let geocache = parse(&json).unwrap();
         assert_eq!(geocache.code, "GC3Y133");
+        assert_eq!(geocache.logs.len(), 5);
+        assert_eq!(geocache.logs[0].log_type, LogType::DidNotFind);
+        assert!(!geocache.has_solution_checker);
+        assert_eq!(geocache.cartridge, None);
+        assert_eq!(geocache.favorite_points, 0);
+        assert!(geocache.attributes.is_empty());
+        assert_eq!(
+            parse_attribute(&serde_json::json!({"id": 4, "name": "Boat", "isOn": false})),
+            Some(Attribute {
+                id: 4,
+                name: "Boat".to_string(),
+                is_on: false
+            })
+        );
+        assert_eq!(
+            geocache.last_visited,
+            chrono::NaiveDate::from_ymd_opt(2021, 5, 16)
+        );
+    }
+
+    #[test]
+    fn cartridge_from_listing() {
+        let html = r#"<a href="http://www.Wherigo.com/cartridge/download.aspx?CGUID=0d2bd6a0-5b8b-4a37-9b5c-1d2a9e5f3c11">Download</a>"#;
+        assert_eq!(
+            parse_cartridge(html).as_deref(),
+            Some("https://www.wherigo.com/cartridge/details.aspx?CGUID=0d2bd6a0-5b8b-4a37-9b5c-1d2a9e5f3c11")
+        );
+        assert_eq!(parse_cartridge("<p>no cartridge</p>"), None);
     }
 }
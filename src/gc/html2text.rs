@@ -0,0 +1,165 @@
+/// Plain text version of a listing's HTML: tags are stripped, block elements and <br> become line
+/// breaks, list items get a "- " marker. Longer texts are cut at max_len characters.
+pub fn html2text(html: &str, max_len: Option<usize>) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    let mut skip_until: Option<&str> = None;
+    while !rest.is_empty() {
+        if let Some(start) = rest.strip_prefix('<') {
+            let end = start.find('>').unwrap_or(start.len());
+            let tag = &start[..end];
+            rest = start.get(end + 1..).unwrap_or("");
+            let name = tag_name(tag);
+            if let Some(closing) = skip_until {
+                if name == closing {
+                    skip_until = None;
+                }
+                continue;
+            }
+            match name.as_str() {
+                "script" | "style" if !tag.starts_with('/') => {
+                    skip_until = Some(if name == "script" {
+                        "/script"
+                    } else {
+                        "/style"
+                    })
+                }
+                "br" => text.push('\n'),
+                "li" => text.push_str("\n- "),
+                "p" | "/p" | "div" | "/div" | "ul" | "/ul" | "ol" | "/ol" | "tr" | "/table"
+                | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "/h1" | "/h2" | "/h3" | "/h4"
+                | "/h5" | "/h6" => text.push_str("\n\n"),
+                _ => {}
+            }
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            if skip_until.is_none() {
+                text.push_str(&decode_entities(&rest[..end]));
+            }
+            rest = &rest[end..];
+        }
+    }
+    truncate(&normalize_whitespace(&text), max_len)
+}
+
+/// Lowercase tag name, closing tags keep their slash: "/p"
+fn tag_name(tag: &str) -> String {
+    let closing = tag.starts_with('/');
+    let name: String = tag
+        .trim_start_matches('/')
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    if closing {
+        format!("/{}", name)
+    } else {
+        name
+    }
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest
+            .find(';')
+            .filter(|end| *end <= 10)
+            .and_then(|end| decode_entity(&rest[1..end]).map(|c| (c, end)));
+        match entity {
+            Some((c, end)) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => {
+            let code = if let Some(hex) = entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+            {
+                u32::from_str_radix(hex, 16).ok()?
+            } else {
+                entity.strip_prefix('#')?.parse().ok()?
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+/// Collapse runs of spaces, trim every line and allow at most one empty line in a row.
+fn normalize_whitespace(text: &str) -> String {
+    let mut lines: Vec<String> = vec![];
+    let mut empty = 0;
+    for line in text.split('\n') {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            empty += 1;
+            continue;
+        }
+        if !lines.is_empty() && empty > 1 {
+            lines.push(String::new());
+        }
+        empty = 0;
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+fn truncate(text: &str, max_len: Option<usize>) -> String {
+    match max_len {
+        Some(max_len) if text.chars().count() > max_len => {
+            let mut truncated: String = text.chars().take(max_len.saturating_sub(3)).collect();
+            truncated.push_str("...");
+            truncated
+        }
+        _ => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_tags_and_keeps_structure() {
+        let html = "<p>An diesem <b>Berg</b> bin ich aufgewachsen.</p><p>Du brauchst:<ul><li>Stift</li><li>Taschenlampe &amp; Batterien</li></ul></p>Viel&nbsp;Spa&#223;!<br/>TFTC";
+        assert_eq!(
+            html2text(html, None),
+            "An diesem Berg bin ich aufgewachsen.\n\nDu brauchst:\n\n- Stift\n- Taschenlampe & Batterien\n\nViel Spaß!\nTFTC"
+        );
+    }
+
+    #[test]
+    fn skips_scripts_and_styles() {
+        let html = "<style>p { color: red; }</style>Text<SCRIPT>alert('x')</SCRIPT> & more";
+        assert_eq!(html2text(html, None), "Text & more");
+    }
+
+    #[test]
+    fn truncates() {
+        assert_eq!(
+            html2text("<p>Ein kurzes Rätsel</p>", Some(10)),
+            "Ein kur..."
+        );
+        assert_eq!(html2text("kurz", Some(10)), "kurz");
+    }
+}
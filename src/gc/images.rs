@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use chrono::Utc;
+use log::{debug, info, warn};
+use regex::Regex;
+use sqlx::Row;
+
+use super::cache::Error;
+
+/// Downloads images referenced by geocaches into a local directory, so exports work offline.
+pub struct ImageStore {
+    db: sqlx::PgPool,
+    client: reqwest::Client,
+    dir: PathBuf,
+}
+
+impl ImageStore {
+    pub fn new(pool: sqlx::PgPool, client: reqwest::Client, dir: PathBuf) -> Self {
+        Self {
+            db: pool,
+            client,
+            dir,
+        }
+    }
+
+    pub async fn init(&self) -> Result<(), Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS images (
+            url TEXT PRIMARY KEY,
+            gccode TEXT NOT NULL,
+            file TEXT NOT NULL,
+            ts TIMESTAMPTZ NOT NULL
+        )",
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Download all given images that are not stored yet, returns the url -> file mapping.
+    pub async fn store(
+        &self,
+        code: &str,
+        urls: Vec<String>,
+    ) -> Result<HashMap<String, String>, Error> {
+        let mut known = self.files(code).await?;
+        for url in urls {
+            if known.contains_key(&url) {
+                continue;
+            }
+            match self.download(&url).await {
+                Ok(file) => {
+                    sqlx::query("INSERT INTO images (url, gccode, file, ts) VALUES ($1, $2, $3, $4) ON CONFLICT (url) DO UPDATE SET file = $3, ts = $4")
+                        .bind(&url)
+                        .bind(code)
+                        .bind(&file)
+                        .bind(Utc::now())
+                        .execute(&self.db)
+                        .await?;
+                    known.insert(url, file);
+                }
+                Err(e) => warn!("Unable to download image {} for {}: {}", url, code, e),
+            }
+        }
+        Ok(known)
+    }
+
+    /// Already downloaded images of a geocache, url -> file.
+    pub async fn files(&self, code: &str) -> Result<HashMap<String, String>, Error> {
+        let rows = sqlx::query("SELECT url, file FROM images WHERE gccode = $1")
+            .bind(code)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    async fn download(&self, url: &str) -> Result<String, Error> {
+        debug!("Downloading image {}", url);
+        let response = self.client.get(url).send().await?.error_for_status()?;
+        let extension = match response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some("image/jpeg") => "jpg",
+            Some("image/png") => "png",
+            Some("image/gif") => "gif",
+            Some("image/webp") => "webp",
+            _ => "bin",
+        };
+        let bytes = response.bytes().await?;
+        let file = format!("{}.{}", uuid::Uuid::new_v4(), extension);
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.dir.join(&file), &bytes)?;
+        info!("Stored image {} as {}", url, file);
+        Ok(file)
+    }
+}
+
+/// All image sources referenced in a HTML description.
+pub fn image_urls(html: &str) -> Vec<String> {
+    lazy_static::lazy_static! {
+        static ref PATTERN_IMG: Regex = Regex::new(r#"(?i)<img[^>]*?\ssrc\s*=\s*["']([^"']+)["']"#).unwrap();
+    }
+    let mut urls: Vec<String> = PATTERN_IMG
+        .captures_iter(html)
+        .map(|c| c[1].to_string())
+        .filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+        .collect();
+    urls.dedup();
+    urls
+}
+
+/// Point image links to the local copies, e.g. for offline exports.
+pub fn rewrite_links(html: &str, files: &HashMap<String, String>, prefix: &str) -> String {
+    let mut result = html.to_string();
+    for (url, file) in files {
+        result = result.replace(url, &format!("{}{}", prefix, file));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_image_sources() {
+        let html = r#"<p>Look <IMG alt="x" src="https://img.geocaching.com/a.jpg"/> and
+            <img src='http://example.com/b.png'> but not <img src="data:image/png;base64,xyz"></p>"#;
+        assert_eq!(
+            image_urls(html),
+            vec![
+                "https://img.geocaching.com/a.jpg".to_string(),
+                "http://example.com/b.png".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn rewrites_known_links() {
+        let files = HashMap::from([(
+            "https://img.geocaching.com/a.jpg".to_string(),
+            "1234.jpg".to_string(),
+        )]);
+        let html = r#"<img src="https://img.geocaching.com/a.jpg"><img src="https://other/b.jpg">"#;
+        assert_eq!(
+            rewrite_links(html, &files, "images/"),
+            r#"<img src="images/1234.jpg"><img src="https://other/b.jpg">"#
+        );
+    }
+}
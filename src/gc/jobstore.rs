@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use rocket::serde::{Deserialize, Serialize};
+use sqlx::Row;
+
+use crate::gcgeo::GeocacheFilter;
+
+use super::budget::ApiCalls;
+use super::cache::Error;
+
+/// What survives a restart of a job: its progress and, once finished, the codes of the result
+/// and everything else needed to render the exports again.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct JobRecord {
+    pub id: String,
+    /// route, area, polygon, bbox or purge
+    pub kind: Option<String>,
+    pub message: String,
+    pub finished: Option<DateTime<Utc>>,
+    pub degraded: bool,
+    pub codes: Vec<String>,
+    /// all geocaches in the job's region, the result is selected from these by the filter
+    pub candidates: Vec<String>,
+    pub filter: GeocacheFilter,
+    /// codes the user always wanted in the result, and those never
+    pub include_codes: Vec<String>,
+    pub exclude_codes: Vec<String>,
+    /// name and codes of each leg, of the candidates
+    pub legs: Vec<(String, Vec<String>)>,
+    pub ascent: Option<f64>,
+    pub elevations: HashMap<String, f64>,
+    pub distances: HashMap<String, f64>,
+    /// meters from the track or area center, by code
+    pub offsets: HashMap<String, f64>,
+    pub track: Vec<Vec<[f64; 2]>>,
+    /// why the job failed, outermost error first
+    pub error: Vec<String>,
+    pub api_calls: ApiCalls,
+    /// name of the user who requested the job
+    pub owner: Option<String>,
+}
+
+pub struct JobStore {
+    db: sqlx::PgPool,
+}
+
+impl JobStore {
+    pub fn new(pool: sqlx::PgPool) -> Self {
+        Self { db: pool }
+    }
+
+    pub async fn init(&self) -> Result<(), Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS jobs (
+            id TEXT PRIMARY KEY,
+            created TIMESTAMPTZ NOT NULL,
+            updated TIMESTAMPTZ NOT NULL,
+            record JSON NOT NULL
+        )",
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn save(&self, record: &JobRecord, now: DateTime<Utc>) -> Result<(), Error> {
+        sqlx::query("INSERT INTO jobs (id, created, updated, record) VALUES ($1, $2, $2, $3) ON CONFLICT (id) DO UPDATE SET updated = $2, record = $3")
+            .bind(&record.id)
+            .bind(now)
+            .bind(serde_json::to_value(record)?)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<(), Error> {
+        sqlx::query("DELETE FROM jobs WHERE id = $1")
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn load(&self, id: &str) -> Result<Option<JobRecord>, Error> {
+        let row = sqlx::query("SELECT record::VARCHAR FROM jobs WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await?;
+        match row {
+            Some(row) => Ok(Some(serde_json::from_str(row.get(0))?)),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use std::io::{Seek, Write};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use gpx::errors::GpxError;
+use log::info;
+use xml::writer::{EmitterConfig, EventWriter, XmlEvent};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::gcgeo::{CacheType, Geocache};
+
+use super::cache::Error;
+use super::garmin::{ExportMode, Garmin, Icons};
+
+pub struct Kml {}
+
+impl Kml {
+    const NS: &'static str = "http://www.opengis.net/kml/2.2";
+    /// Tinted by the style color, Google Earth has no plain colored placemarks
+    const PIN: &'static str = "http://maps.google.com/mapfiles/kml/paddle/wht-blank.png";
+    /// Number of logs shown in the balloon
+    const BALLOON_LOGS: usize = 3;
+
+    /// KML with one style per cache type, opens directly in Google Earth.
+    pub fn kml<W: Write>(
+        geocaches: Vec<Geocache>,
+        mode: &ExportMode,
+        snapshot: &DateTime<Utc>,
+        writer: &mut W,
+    ) -> Result<(), Error> {
+        info!("Writing kml");
+        let geocaches: Vec<_> = geocaches
+            .into_iter()
+            .filter(|gc| mode.includes(gc))
+            .collect();
+        Self::write_document(writer, &geocaches, snapshot, |_| None)
+    }
+
+    /// KMZ, the KML zipped together with the GPI icons as placemark icons.
+    pub fn kmz<W: Write + ?Sized>(
+        geocaches: Vec<Geocache>,
+        mode: &ExportMode,
+        icons: &Icons,
+        snapshot: &DateTime<Utc>,
+        writer: &mut W,
+    ) -> Result<(), Error> {
+        info!("Writing kmz");
+        let geocaches: Vec<_> = geocaches
+            .into_iter()
+            .filter(|gc| mode.includes(gc))
+            .collect();
+        let mut zip = ZipWriter::new(tempfile::tempfile()?);
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        // Google Earth reads the first .kml in the archive
+        zip.start_file("doc.kml", options)?;
+        Self::write_document(&mut zip, &geocaches, snapshot, |cache_type| {
+            Some(Self::icon_name(cache_type))
+        })?;
+        for cache_type in Self::cache_types(&geocaches) {
+            zip.start_file(Self::icon_name(&cache_type), options)?;
+            let mut icon = std::fs::File::open(icons.for_mode(&ExportMode::Only(cache_type)))?;
+            std::io::copy(&mut icon, &mut zip)?;
+        }
+        let mut zip_file = zip.finish()?;
+        zip_file.rewind()?;
+        std::io::copy(&mut zip_file, writer)?;
+        Ok(())
+    }
+
+    fn write_document<W: Write>(
+        writer: W,
+        geocaches: &[Geocache],
+        snapshot: &DateTime<Utc>,
+        icon: impl Fn(&CacheType) -> Option<String>,
+    ) -> Result<(), Error> {
+        let mut xml = EmitterConfig::new()
+            .perform_indent(true)
+            .create_writer(writer);
+        Self::write_kml(&mut xml, geocaches, snapshot, icon).map_err(GpxError::from)?;
+        Ok(())
+    }
+
+    fn write_kml<W: Write>(
+        xml: &mut EventWriter<W>,
+        geocaches: &[Geocache],
+        snapshot: &DateTime<Utc>,
+        icon: impl Fn(&CacheType) -> Option<String>,
+    ) -> xml::writer::Result<()> {
+        xml.write(XmlEvent::start_element("kml").default_ns(Self::NS))?;
+        xml.write(XmlEvent::start_element("Document"))?;
+        Self::element(xml, "name", "cachecache")?;
+        Self::element(
+            xml,
+            "description",
+            &format!(
+                "Snapshot {}",
+                snapshot.to_rfc3339_opts(SecondsFormat::Secs, true)
+            ),
+        )?;
+        for cache_type in Self::cache_types(geocaches) {
+            Self::write_style(xml, &cache_type, icon(&cache_type))?;
+        }
+        for gc in geocaches {
+            Self::write_placemark(xml, gc)?;
+        }
+        xml.write(XmlEvent::end_element())?;
+        xml.write(XmlEvent::end_element())
+    }
+
+    /// Embedded icons are shown as they are, the pin is tinted with the type color.
+    fn write_style<W: Write>(
+        xml: &mut EventWriter<W>,
+        cache_type: &CacheType,
+        icon: Option<String>,
+    ) -> xml::writer::Result<()> {
+        let id = Self::style_id(cache_type);
+        xml.write(XmlEvent::start_element("Style").attr("id", &id))?;
+        xml.write(XmlEvent::start_element("IconStyle"))?;
+        if icon.is_none() {
+            Self::element(xml, "color", &Self::color(cache_type))?;
+        }
+        xml.write(XmlEvent::start_element("Icon"))?;
+        Self::element(xml, "href", icon.as_deref().unwrap_or(Self::PIN))?;
+        xml.write(XmlEvent::end_element())?;
+        xml.write(XmlEvent::end_element())?;
+        xml.write(XmlEvent::end_element())
+    }
+
+    fn write_placemark<W: Write>(
+        xml: &mut EventWriter<W>,
+        gc: &Geocache,
+    ) -> xml::writer::Result<()> {
+        xml.write(XmlEvent::start_element("Placemark"))?;
+        Self::element(xml, "name", &format!("{} {}", gc.code, gc.name))?;
+        Self::element(xml, "description", &Self::balloon(gc))?;
+        Self::element(
+            xml,
+            "styleUrl",
+            &format!("#{}", Self::style_id(&gc.cache_type)),
+        )?;
+        xml.write(XmlEvent::start_element("Point"))?;
+        Self::element(
+            xml,
+            "coordinates",
+            &format!("{},{}", gc.coord.lon, gc.coord.lat),
+        )?;
+        xml.write(XmlEvent::end_element())?;
+        xml.write(XmlEvent::end_element())
+    }
+
+    /// HTML for the balloon: type, D/T, hint and the latest logs.
+    fn balloon(gc: &Geocache) -> String {
+        let mut html = format!(
+            "<p><a href=\"{}\">{}</a><br/>{}, D{:.1} T{:.1}</p>",
+            gc.page_url(),
+            gc.code,
+            Garmin::type_name(&gc.cache_type),
+            gc.difficulty,
+            gc.terrain
+        );
+        if gc.approximate {
+            html.push_str("<p>Approximate position</p>");
+        }
+        for (href, title) in gc.links() {
+            html.push_str(&format!("<p><a href=\"{}\">{}</a></p>", href, title));
+        }
+        let hint = gc.decoded_hint();
+        if !hint.is_empty() {
+            html.push_str(&format!("<p><b>Hint:</b> {}</p>", Self::escape(&hint)));
+        }
+        for log in gc.logs.iter().take(Self::BALLOON_LOGS) {
+            html.push_str(&format!(
+                "<p><b>{}</b> {}<br/>{}</p>",
+                Garmin::log_type_name(&log.log_type),
+                log.timestamp.get(..10).unwrap_or(&log.timestamp),
+                Self::escape(&log.text)
+            ));
+        }
+        html
+    }
+
+    fn escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    }
+
+    fn element<W: Write>(
+        xml: &mut EventWriter<W>,
+        name: &str,
+        text: &str,
+    ) -> xml::writer::Result<()> {
+        xml.write(XmlEvent::start_element(name))?;
+        xml.write(XmlEvent::characters(text))?;
+        xml.write(XmlEvent::end_element())
+    }
+
+    /// The types present, in the order of CacheType::ALL
+    fn cache_types(geocaches: &[Geocache]) -> Vec<CacheType> {
+        CacheType::ALL
+            .iter()
+            .filter(|cache_type| geocaches.iter().any(|gc| gc.cache_type == **cache_type))
+            .cloned()
+            .collect()
+    }
+
+    fn style_id(cache_type: &CacheType) -> String {
+        cache_type.to_string().to_lowercase()
+    }
+
+    fn icon_name(cache_type: &CacheType) -> String {
+        format!("files/{}.bmp", Self::style_id(cache_type))
+    }
+
+    /// KML colors are aabbggrr
+    fn color(cache_type: &CacheType) -> String {
+        let rgb = Garmin::color(cache_type);
+        format!("ff{}{}{}", &rgb[5..7], &rgb[3..5], &rgb[1..3])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::gcgeo::{GeocacheLog, LogType};
+
+    fn geocaches() -> Vec<Geocache> {
+        let mut tradi = Geocache::premium("GC1".to_string());
+        tradi.cache_type = CacheType::Traditional;
+        tradi.name = "Berg & Tal".to_string();
+        tradi.encoded_hints = "Zntargvfpu".to_string();
+        tradi.logs = vec![GeocacheLog {
+            text: "TFTC <3".to_string(),
+            timestamp: "2024-05-20T10:00:00+02:00".to_string(),
+            log_type: LogType::Found,
+        }];
+        let mut mystery = Geocache::premium("GC2".to_string());
+        mystery.cache_type = CacheType::Mystery;
+        vec![tradi, mystery]
+    }
+
+    #[test]
+    fn placemarks_are_styled_by_type() {
+        let snapshot = DateTime::from_timestamp(1_717_243_200, 0).unwrap();
+        let mut output = Vec::new();
+        Kml::kml(geocaches(), &ExportMode::All, &snapshot, &mut output).unwrap();
+        let kml = String::from_utf8(output).unwrap();
+
+        assert!(kml.contains(r#"<Style id="traditional">"#));
+        assert!(kml.contains("<color>ffff0000</color>"));
+        assert!(kml.contains("<styleUrl>#mystery</styleUrl>"));
+        assert!(kml.contains("<name>GC1 Berg &amp; Tal</name>"));
+        assert!(kml.contains("Magnetisch"));
+        assert!(kml.contains("Found it"));
+        assert!(kml.contains("TFTC &amp;lt;3"));
+        assert!(xml::EventReader::new(kml.as_bytes())
+            .into_iter()
+            .all(|event| event.is_ok()));
+    }
+
+    #[test]
+    fn kmz_embeds_icons() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("default.bmp"), b"BM").unwrap();
+        let snapshot = DateTime::from_timestamp(1_717_243_200, 0).unwrap();
+        let mut output = Vec::new();
+        Kml::kmz(
+            geocaches(),
+            &"mystery".parse().unwrap(),
+            &Icons::new(dir.path().to_str()),
+            &snapshot,
+            &mut output,
+        )
+        .unwrap();
+
+        let mut zip = zip::ZipArchive::new(std::io::Cursor::new(output)).unwrap();
+        let names: Vec<_> = zip.file_names().map(String::from).collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&"files/mystery.bmp".to_string()));
+        let mut kml = String::new();
+        zip.by_name("doc.kml")
+            .unwrap()
+            .read_to_string(&mut kml)
+            .unwrap();
+        assert!(kml.contains("<href>files/mystery.bmp</href>"));
+        assert!(!kml.contains("<color>"));
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use rocket::serde::{Deserialize, Serialize};
+use sqlx::Row;
+
+use super::cache::Error;
+use crate::gcgeo::LogType;
+
+/// A log written on the road, waiting to be posted to Groundspeak.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LogDraft {
+    pub code: String,
+    pub log_type: LogType,
+    pub text: String,
+    pub date: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LogQueueStatus {
+    pub posted: usize,
+    pub pending: usize,
+}
+
+pub struct QueuedLog {
+    pub id: i32,
+    pub draft: LogDraft,
+}
+
+/// Logs are stored before posting, so they can be retried once Groundspeak is reachable again.
+pub struct LogQueue {
+    db: sqlx::PgPool,
+}
+
+impl LogQueue {
+    pub fn new(pool: sqlx::PgPool) -> Self {
+        Self { db: pool }
+    }
+
+    pub async fn init(&self) -> Result<(), Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS log_queue (
+            id SERIAL PRIMARY KEY,
+            gccode TEXT NOT NULL,
+            log_type INTEGER NOT NULL,
+            text TEXT NOT NULL,
+            logged TIMESTAMPTZ NOT NULL,
+            created TIMESTAMPTZ NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            last_error TEXT,
+            log_code TEXT
+        )",
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn enqueue(&self, draft: &LogDraft) -> Result<(), Error> {
+        let log_type = draft.log_type.id().ok_or(Error::Geocaching)?;
+        sqlx::query("INSERT INTO log_queue (gccode, log_type, text, logged, created) VALUES ($1, $2, $3, $4, $5)")
+            .bind(&draft.code)
+            .bind(log_type as i32)
+            .bind(&draft.text)
+            .bind(draft.date)
+            .bind(Utc::now())
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn pending(&self) -> Result<Vec<QueuedLog>, Error> {
+        let rows = sqlx::query("SELECT id, gccode, log_type, text, logged FROM log_queue WHERE log_code IS NULL ORDER BY logged")
+            .fetch_all(&self.db)
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let log_type: i32 = row.get(2);
+                QueuedLog {
+                    id: row.get(0),
+                    draft: LogDraft {
+                        code: row.get(1),
+                        log_type: LogType::from(log_type as u64),
+                        text: row.get(3),
+                        date: row.get(4),
+                    },
+                }
+            })
+            .collect())
+    }
+
+    /// Geocaches with a find among the queued or posted logs
+    pub async fn found_codes(&self) -> Result<HashSet<String>, Error> {
+        let find_types: Vec<i32> = [LogType::Found, LogType::Attended, LogType::WebcamPhotoTaken]
+            .iter()
+            .filter_map(|log_type| log_type.id())
+            .map(|id| id as i32)
+            .collect();
+        let rows = sqlx::query("SELECT DISTINCT gccode FROM log_queue WHERE log_type = ANY($1)")
+            .bind(&find_types)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    pub async fn mark_posted(&self, id: i32, log_code: &str) -> Result<(), Error> {
+        sqlx::query("UPDATE log_queue SET log_code = $2, attempts = attempts + 1, last_error = NULL WHERE id = $1")
+            .bind(id)
+            .bind(log_code)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn mark_failed(&self, id: i32, error: &str) -> Result<(), Error> {
+        sqlx::query("UPDATE log_queue SET attempts = attempts + 1, last_error = $2 WHERE id = $1")
+            .bind(id)
+            .bind(error)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+}
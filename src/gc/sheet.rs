@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use rocket::serde::Serialize;
+use tempfile::NamedTempFile;
+use tokio::process::Command;
+
+use crate::gcgeo::Geocache;
+
+use super::cache::Error;
+use super::garmin::Garmin;
+
+/// The printable cheat sheet for the car: geocaches grouped by distance along the route.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TripSheet {
+    pub ts: String,
+    pub total: usize,
+    pub sections: Vec<SheetSection>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SheetSection {
+    pub title: String,
+    pub entries: Vec<SheetEntry>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SheetEntry {
+    pub code: String,
+    pub name: String,
+    pub cache_type: &'static str,
+    pub size: String,
+    pub difficulty: f32,
+    pub terrain: f32,
+    pub coord: String,
+    pub hint: String,
+    /// kilometers along the route, if the job came from a track
+    pub km: Option<String>,
+    pub map_url: String,
+}
+
+impl TripSheet {
+    /// Kilometers of route per section
+    const SECTION_KM: f64 = 10.0;
+
+    /// distances are meters along the route by code. The geocaches keep their order, a new
+    /// section starts whenever the next one is in another stretch of the route.
+    pub fn new(
+        geocaches: &[Geocache],
+        distances: &HashMap<String, f64>,
+        ts: &DateTime<Utc>,
+    ) -> Self {
+        let mut sections: Vec<SheetSection> = vec![];
+        for gc in geocaches {
+            let distance = distances.get(&gc.code).copied();
+            let title = Self::section_title(distance);
+            if sections.last().map(|section| &section.title) != Some(&title) {
+                sections.push(SheetSection {
+                    title,
+                    entries: vec![],
+                });
+            }
+            sections
+                .last_mut()
+                .unwrap()
+                .entries
+                .push(Self::entry(gc, distance));
+        }
+        Self {
+            ts: ts.format("%Y-%m-%d %H:%M").to_string(),
+            total: sections.iter().map(|section| section.entries.len()).sum(),
+            sections,
+        }
+    }
+
+    fn section_title(distance: Option<f64>) -> String {
+        match distance {
+            Some(meters) => {
+                let from = (meters / 1000.0 / Self::SECTION_KM).floor() * Self::SECTION_KM;
+                format!("km {:.0} to {:.0}", from, from + Self::SECTION_KM)
+            }
+            None => "Geocaches".to_string(),
+        }
+    }
+
+    fn entry(gc: &Geocache, distance: Option<f64>) -> SheetEntry {
+        SheetEntry {
+            code: gc.code.clone(),
+            name: gc.name.clone(),
+            cache_type: Garmin::type_name(&gc.cache_type),
+            size: gc.size.to_string(),
+            difficulty: gc.difficulty,
+            terrain: gc.terrain,
+            coord: gc.coord.to_dm(),
+            hint: gc.decoded_hint(),
+            km: distance.map(|meters| format!("{:.1}", meters / 1000.0)),
+            map_url: format!(
+                "https://www.openstreetmap.org/?mlat={lat}&mlon={lon}#map=16/{lat}/{lon}",
+                lat = gc.coord.lat,
+                lon = gc.coord.lon
+            ),
+        }
+    }
+
+    /// Print the rendered sheet to PDF with wkhtmltopdf.
+    pub async fn pdf(html: &str) -> Result<Vec<u8>, Error> {
+        let mut html_file = tempfile::Builder::new().suffix(".html").tempfile()?;
+        let pdf_file = NamedTempFile::new()?;
+        html_file.write_all(html.as_bytes())?;
+        let output = Command::new("wkhtmltopdf")
+            .args([
+                "--quiet",
+                "--page-size",
+                "A4",
+                &html_file.path().to_string_lossy(),
+                &pdf_file.path().to_string_lossy(),
+            ])
+            .output()
+            .await?;
+        if !output.status.success() {
+            error!(
+                "wkhtmltopdf returned {}: {}",
+                output.status,
+                std::str::from_utf8(&output.stderr)?
+            );
+            return Err(Error::Unknown);
+        }
+        let pdf = tokio::fs::read(pdf_file.path()).await?;
+        info!("Printed {} bytes of PDF", pdf.len());
+        Ok(pdf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcgeo::Coordinate;
+
+    #[test]
+    fn sections_follow_the_route() {
+        let gc = |code: &str| {
+            Geocache::approximate(
+                code.to_string(),
+                Coordinate {
+                    lat: 47.9,
+                    lon: 8.5,
+                },
+            )
+        };
+        let distances = HashMap::from([
+            ("GC1".to_string(), 12_500.0),
+            ("GC2".to_string(), 3_000.0),
+            ("GC3".to_string(), 18_000.0),
+        ]);
+        let ts = DateTime::from_timestamp(1_717_243_200, 0).unwrap();
+        let sheet = TripSheet::new(
+            &[gc("GC2"), gc("GC1"), gc("GC3"), gc("GC4")],
+            &distances,
+            &ts,
+        );
+
+        let sections: Vec<(&str, Vec<&str>)> = sheet
+            .sections
+            .iter()
+            .map(|section| {
+                (
+                    section.title.as_str(),
+                    section.entries.iter().map(|e| e.code.as_str()).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            sections,
+            vec![
+                ("km 0 to 10", vec!["GC2"]),
+                ("km 10 to 20", vec!["GC1", "GC3"]),
+                ("Geocaches", vec!["GC4"]),
+            ]
+        );
+        assert_eq!(sheet.total, 4);
+        assert_eq!(sheet.sections[1].entries[0].km.as_deref(), Some("12.5"));
+
+        // e.g. sort=favorites, the order stays and the stretches repeat
+        let sheet = TripSheet::new(&[gc("GC1"), gc("GC2"), gc("GC3")], &distances, &ts);
+        let titles: Vec<&str> = sheet.sections.iter().map(|s| s.title.as_str()).collect();
+        assert_eq!(titles, vec!["km 10 to 20", "km 0 to 10", "km 10 to 20"]);
+    }
+}
@@ -1,86 +1,341 @@
+use base64::Engine;
+use chrono::{DateTime, Utc};
 use log::{error, info};
 use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, ACCEPT_LANGUAGE, CONTENT_TYPE, USER_AGENT};
-use sqlx::Row;
+use std::sync::Arc;
+
+use rocket::serde::Serialize;
+use tokio::sync::Mutex;
 
 use super::cache::Error;
+use super::tokenstore::{FileStore, KeyringStore, SettingsStore, TokenStore, TokenStoreKind};
+use crate::config::{AuthAccount, Config};
 
+/// OAuth tokens of one Groundspeak account, kept in the configured token store.
 pub struct AuthProvider {
     db: sqlx::PgPool,
+    store: Arc<dyn TokenStore>,
+    client: reqwest::Client,
+    account: String,
+    user_agent: String,
+    redirect_url: String,
+    username: String,
+    password: String,
+    /// serializes the token refreshes of this account within the process
+    refresh_lock: Mutex<()>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AuthStatus {
+    /// the access token is known and not expired
+    pub access_token_valid: bool,
+    /// no refresh token or the last refresh failed, somebody has to log in again
+    pub needs_login: bool,
+    pub access_token_expiry: Option<DateTime<Utc>>,
+    pub refresh_token_updated: Option<DateTime<Utc>>,
+    pub last_refresh: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub last_error_ts: Option<DateTime<Utc>>,
+}
+
+/// Read the exp claim of a JWT without verifying it.
+pub fn jwt_expiry(token: &str) -> Option<DateTime<Utc>> {
+    let payload = token.split('.').nth(1)?;
+    let decoded = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&decoded).ok()?;
+    DateTime::from_timestamp(claims["exp"].as_i64()?, 0)
+}
+
+/// Refresh this long before the access token actually expires.
+const EXPIRY_MARGIN_MINUTES: i64 = 5;
+
+/// True if the token expires within the margin. Tokens without a readable exp claim are used until they fail.
+fn needs_refresh(token: &str, now: DateTime<Utc>) -> bool {
+    match jwt_expiry(token) {
+        Some(expiry) => expiry - chrono::Duration::minutes(EXPIRY_MARGIN_MINUTES) <= now,
+        None => false,
+    }
+}
+
+fn authorize_url(client_id: &str, redirect_url: &str, state: &str) -> Result<String, Error> {
+    let url = reqwest::Url::parse_with_params(
+        "https://oauth.geocaching.com/oauth/authorize.aspx",
+        &[
+            ("client_id", client_id),
+            ("response_type", "code"),
+            ("scope", "*"),
+            ("redirect_uri", redirect_url),
+            ("state", state),
+        ],
+    )
+    .map_err(|_| Error::Geocaching)?;
+    Ok(url.to_string())
 }
 
 impl AuthProvider {
-    pub fn new(pool: sqlx::PgPool) -> Self {
-        Self { db: pool }
+    /// Name of the account configured through auth_username/auth_password, its settings keep the unprefixed keys
+    pub const DEFAULT_ACCOUNT: &'static str = "default";
+
+    const TOKEN_URL: &'static str = "https://oauth.geocaching.com/token";
+
+    pub fn new(pool: sqlx::PgPool, client: reqwest::Client, config: &Config) -> Self {
+        let store: Arc<dyn TokenStore> = match config.token_store {
+            TokenStoreKind::Settings => Arc::new(SettingsStore::new(pool.clone())),
+            TokenStoreKind::File => Arc::new(FileStore::new(config.token_file.clone().into())),
+            TokenStoreKind::Keyring => Arc::new(KeyringStore),
+        };
+        Self {
+            db: pool,
+            store,
+            client,
+            account: Self::DEFAULT_ACCOUNT.to_string(),
+            user_agent: config.auth_user_agent.clone(),
+            redirect_url: config.auth_redirect_url.clone(),
+            username: config.auth_username.clone(),
+            password: config.auth_password.clone(),
+            refresh_lock: Mutex::new(()),
+        }
+    }
+
+    /// Provider for one of the additional accounts, client credentials default to the main ones.
+    pub fn for_account(
+        pool: sqlx::PgPool,
+        client: reqwest::Client,
+        config: &Config,
+        account: &AuthAccount,
+    ) -> Self {
+        let main = Self::new(pool, client, config);
+        Self {
+            account: account.name.clone(),
+            username: account.username.clone().unwrap_or(main.username.clone()),
+            password: account.password.clone().unwrap_or(main.password.clone()),
+            ..main
+        }
+    }
+
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+
+    /// Settings key of this account, the default account uses the plain key.
+    fn key(&self, id: &str) -> String {
+        if self.account == Self::DEFAULT_ACCOUNT {
+            id.to_string()
+        } else {
+            format!("{}@{}", id, self.account)
+        }
     }
 
     pub async fn init(&self) -> Result<(), Error> {
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS settings (
-            id TEXT PRIMARY KEY,
-            value TEXT NOT NULL
-        )",
-        )
-        .execute(&self.db)
-        .await?;
-        Ok(())
+        self.store.init().await
     }
 
     pub async fn token(&self) -> Result<String, Error> {
-        // TODO we should probably introspect the JWT and refresh if necessary
         match self.load_access_token().await {
-            Ok(token) => Ok(token),
+            Ok(token) if !needs_refresh(&token, Utc::now()) => Ok(token),
+            Ok(_) => {
+                info!("Access token is about to expire, refreshing");
+                self.refresh().await
+            }
             Err(_) => self.refresh().await,
         }
     }
 
+    /// Refresh the access token. The refresh token is single use, so two concurrent refreshes
+    /// would invalidate each other: refreshes of an account are serialized within the process, and
+    /// across instances the stored refresh token is compared before the new tokens are written.
+    /// The Postgres advisory lock guarding that is never held across the call to Groundspeak.
     pub async fn refresh(&self) -> Result<String, Error> {
-        let refresh_token = self.load_refresh_token().await?;
-        let (new_access_token, new_refresh_token) = self.call_groundspeak(refresh_token).await?;
-        self.store_refresh_token(&new_refresh_token).await?;
-        self.store_access_token(&new_access_token).await?;
-        info!("Access token: {}", new_access_token);
-        Ok(new_access_token)
+        let seen = self.load_access_token().await.ok();
+        let _guard = self.refresh_lock.lock().await;
+
+        let tx = self.advisory_lock().await?;
+        if let Ok(current) = self.load_access_token().await {
+            if seen.as_ref() != Some(&current) && !needs_refresh(&current, Utc::now()) {
+                info!("Access token was refreshed concurrently");
+                tx.commit().await?;
+                return Ok(current);
+            }
+        }
+        let refresh_token = self.load_refresh_token().await;
+        tx.commit().await?;
+        let refresh_token = refresh_token?;
+
+        let result = self.call_groundspeak(refresh_token.clone()).await;
+
+        let tx = self.advisory_lock().await?;
+        if self.load_refresh_token().await.ok().as_ref() != Some(&refresh_token) {
+            // another instance refreshed with the same token, theirs won
+            info!("Access token was refreshed by another instance");
+            let current = self.load_access_token().await;
+            tx.commit().await?;
+            return current;
+        }
+        let result = match result {
+            Ok((new_access_token, new_refresh_token)) => self
+                .store_tokens(&new_access_token, &new_refresh_token)
+                .await
+                .map(|_| new_access_token),
+            Err(e) => {
+                self.record_error(&format!("token refresh failed: {}", e))
+                    .await;
+                // the refresh token is probably dead, only a new login helps now
+                self.store_setting("refresh_failed", "true")
+                    .await
+                    .and(Err(e))
+            }
+        };
+        tx.commit().await?;
+        if let Ok(token) = &result {
+            info!("Access token: {}", token);
+        }
+        result
     }
 
-    async fn load_refresh_token(&self) -> Result<String, Error> {
-        let result = sqlx::query("SELECT value FROM settings where id = 'refresh_token'")
-            .fetch_one(&self.db)
+    /// Lock the tokens of this account across instances until the transaction ends.
+    async fn advisory_lock(&self) -> Result<sqlx::Transaction<'_, sqlx::Postgres>, Error> {
+        let mut tx = self.db.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended($1, 0))")
+            .bind(format!("gc5-token-refresh:{}", self.account))
+            .execute(&mut *tx)
             .await?;
-        Ok(result.get(0))
+        Ok(tx)
+    }
+
+    /// Start the authorization code flow, returns the Groundspeak login page to redirect to.
+    pub async fn login_url(&self) -> Result<String, Error> {
+        let state = format!("{}:{}", self.account, uuid::Uuid::new_v4());
+        self.store_setting("oauth_state", &state).await?;
+        authorize_url(&self.username, &self.redirect_url, &state)
+    }
+
+    /// Finish the authorization code flow by exchanging the code from the callback for tokens.
+    /// Only the state of the last login_url is accepted, and only once.
+    pub async fn exchange_code(&self, code: &str, state: &str) -> Result<(), Error> {
+        if self.load_setting("oauth_state").await?.as_deref() != Some(state) {
+            self.record_error("login failed: unexpected OAuth state")
+                .await;
+            return Err(Error::Geocaching);
+        }
+        self.store.delete(&self.key("oauth_state")).await?;
+        let (access_token, refresh_token) = match self
+            .call_token_endpoint(&[
+                ("redirect_uri", self.redirect_url.as_str()),
+                ("code", code),
+                ("grant_type", "authorization_code"),
+            ])
+            .await
+        {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                self.record_error(&format!("login failed: {}", e)).await;
+                return Err(e);
+            }
+        };
+        self.store_tokens(&access_token, &refresh_token).await?;
+        info!("Logged in to Groundspeak");
+        Ok(())
+    }
+
+    pub async fn status(&self) -> Result<AuthStatus, Error> {
+        let access_token_expiry = match self.load_access_token().await {
+            Ok(token) => jwt_expiry(&token),
+            Err(_) => None,
+        };
+        let refresh_failed = self.load_setting("refresh_failed").await?.as_deref() == Some("true");
+        Ok(AuthStatus {
+            access_token_valid: access_token_expiry.is_some_and(|expiry| expiry > Utc::now()),
+            needs_login: refresh_failed || !self.is_configured().await?,
+            access_token_expiry,
+            refresh_token_updated: self.load_timestamp("refresh_token_ts").await?,
+            last_refresh: self.load_timestamp("last_refresh").await?,
+            last_error: self.load_setting("last_error").await?,
+            last_error_ts: self.load_timestamp("last_error_ts").await?,
+        })
+    }
+
+    /// Whether a refresh token was ever stored, i.e. OAuth has been set up at all.
+    pub async fn is_configured(&self) -> Result<bool, Error> {
+        Ok(self.load_setting("refresh_token").await?.is_some())
+    }
+
+    /// Remember the last error talking to Groundspeak, so it can be shown without digging through logs.
+    pub async fn record_error(&self, message: &str) {
+        let result = async {
+            self.store_setting("last_error", message).await?;
+            self.store_setting("last_error_ts", &Utc::now().to_rfc3339())
+                .await
+        }
+        .await;
+        if let Err(e) = result {
+            error!("Unable to record error {}: {}", message, e);
+        }
+    }
+
+    async fn load_setting(&self, id: &str) -> Result<Option<String>, Error> {
+        self.store.load(&self.key(id)).await
+    }
+
+    async fn load_timestamp(&self, id: &str) -> Result<Option<DateTime<Utc>>, Error> {
+        Ok(self
+            .load_setting(id)
+            .await?
+            .and_then(|value| DateTime::parse_from_rfc3339(&value).ok())
+            .map(|ts| ts.with_timezone(&Utc)))
+    }
+
+    async fn store_setting(&self, id: &str, value: &str) -> Result<(), Error> {
+        self.store.store(&self.key(id), value).await
+    }
+
+    async fn load_refresh_token(&self) -> Result<String, Error> {
+        self.load_setting("refresh_token")
+            .await?
+            .ok_or(Error::Database(sqlx::Error::RowNotFound))
     }
 
     async fn load_access_token(&self) -> Result<String, Error> {
-        let result = sqlx::query("SELECT value FROM settings where id = 'access_token'")
-            .fetch_one(&self.db)
-            .await?;
-        Ok(result.get(0))
+        self.load_setting("access_token")
+            .await?
+            .ok_or(Error::Database(sqlx::Error::RowNotFound))
     }
 
     async fn call_groundspeak(&self, refresh_token: String) -> Result<(String, String), Error> {
+        self.call_token_endpoint(&[
+            ("redirect_uri", self.redirect_url.as_str()),
+            ("refresh_token", &refresh_token),
+            ("grant_type", "refresh_token"),
+        ])
+        .await
+    }
+
+    async fn call_token_endpoint(
+        &self,
+        params: &[(&str, &str)],
+    ) -> Result<(String, String), Error> {
         // Create a HeaderMap and add the necessary headers
         let mut headers = HeaderMap::new();
         headers.insert(
             CONTENT_TYPE,
             HeaderValue::from_static("application/x-www-form-urlencoded; charset=UTF-8"),
         );
-        headers.insert(USER_AGENT, HeaderValue::from_static(env!("AUTH_USERAGENT")));
+        headers.insert(
+            USER_AGENT,
+            HeaderValue::from_str(&self.user_agent).map_err(|_| Error::Geocaching)?,
+        );
         headers.insert(ACCEPT, HeaderValue::from_static("*/*"));
         headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_static("en-us"));
 
-        // Create the body data
-        let params = [
-            ("redirect_uri", env!("AUTH_REDIRECT_URL")),
-            ("refresh_token", &refresh_token),
-            ("grant_type", "refresh_token"),
-        ];
-
         // Send the POST request
-        let client = reqwest::Client::new();
-        let res = client
-            .post("https://oauth.geocaching.com/token")
-            .basic_auth(env!("AUTH_USERNAME"), Some(env!("AUTH_PASSWORD")))
+        let res = self
+            .client
+            .post(Self::TOKEN_URL)
+            .basic_auth(&self.username, Some(&self.password))
             .headers(headers)
-            .form(&params)
+            .form(params)
             .send()
             .await?;
 
@@ -89,8 +344,14 @@ impl AuthProvider {
         // Check the status of the response
         if res.status().is_success() {
             let json: serde_json::Value = res.json().await?;
-            let new_access_token = json["access_token"].as_str().unwrap().to_string();
-            let new_refresh_token = json["refresh_token"].as_str().unwrap().to_string();
+            let new_access_token = json["access_token"]
+                .as_str()
+                .ok_or(Error::Geocaching)?
+                .to_string();
+            let new_refresh_token = json["refresh_token"]
+                .as_str()
+                .ok_or(Error::Geocaching)?
+                .to_string();
 
             info!(
                 "New access token: {}, new refresh token: {}",
@@ -98,22 +359,91 @@ impl AuthProvider {
             );
             Ok((new_access_token, new_refresh_token))
         } else {
-            error!("Unable to refresh token: {:?}", res);
+            error!("Unable to get token: {:?}", res);
             Err(Error::Geocaching)
         }
     }
 
+    async fn store_tokens(&self, access_token: &str, refresh_token: &str) -> Result<(), Error> {
+        self.store_refresh_token(refresh_token).await?;
+        self.store_access_token(access_token).await?;
+        self.store_setting("last_refresh", &Utc::now().to_rfc3339())
+            .await?;
+        self.store_setting("refresh_failed", "false").await
+    }
+
     async fn store_access_token(&self, access_token: &str) -> Result<(), Error> {
-        sqlx::query("INSERT INTO settings (id, value) VALUES ('access_token', $1) ON CONFLICT (id) DO UPDATE SET value = $1")
-            .bind(&access_token)
-            .execute(&self.db).await?;
-        Ok(())
+        self.store_setting("access_token", access_token).await
     }
 
     async fn store_refresh_token(&self, refresh_token: &str) -> Result<(), Error> {
-        sqlx::query("INSERT INTO settings (id, value) VALUES ('refresh_token', $1) ON CONFLICT (id) DO UPDATE SET value = $1")
-            .bind(&refresh_token)
-            .execute(&self.db).await?;
-        Ok(())
+        self.store_setting("refresh_token", refresh_token).await?;
+        self.store_setting("refresh_token_ts", &Utc::now().to_rfc3339())
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jwt_expiry_reads_exp_claim() {
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(r#"{"sub":"1234","exp":1700000000}"#);
+        let token = format!("eyJhbGciOiJIUzI1NiJ9.{}.signature", payload);
+        assert_eq!(
+            jwt_expiry(&token),
+            DateTime::from_timestamp(1_700_000_000, 0)
+        );
+    }
+
+    #[test]
+    fn jwt_expiry_rejects_garbage() {
+        assert_eq!(jwt_expiry("not a token"), None);
+        assert_eq!(jwt_expiry("a.!!!.c"), None);
+    }
+
+    #[test]
+    fn refreshes_shortly_before_expiry() {
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(r#"{"sub":"1234","exp":1700000000}"#);
+        let token = format!("eyJhbGciOiJIUzI1NiJ9.{}.signature", payload);
+        let expiry = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        assert!(!needs_refresh(
+            &token,
+            expiry - chrono::Duration::minutes(10)
+        ));
+        assert!(needs_refresh(&token, expiry - chrono::Duration::minutes(2)));
+        assert!(needs_refresh(&token, expiry + chrono::Duration::minutes(1)));
+        assert!(!needs_refresh("opaque", expiry));
+    }
+
+    #[test]
+    fn authorize_url_carries_redirect_and_state() {
+        let url = authorize_url("client", "https://gc.example.com/auth/callback", "abc").unwrap();
+        assert!(url.starts_with("https://oauth.geocaching.com/oauth/authorize.aspx?"));
+        assert!(url.contains("client_id=client"));
+        assert!(url.contains("redirect_uri=https%3A%2F%2Fgc.example.com%2Fauth%2Fcallback"));
+        assert!(url.contains("state=abc"));
+    }
+
+    #[tokio::test]
+    async fn additional_accounts_use_prefixed_settings() {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/gc")
+            .unwrap();
+        let config = Config::default();
+        let main = AuthProvider::new(pool.clone(), reqwest::Client::new(), &config);
+        assert_eq!(main.key("refresh_token"), "refresh_token");
+
+        let second = AuthAccount {
+            name: "second".to_string(),
+            username: None,
+            password: Some("secret".to_string()),
+        };
+        let uut = AuthProvider::for_account(pool, reqwest::Client::new(), &config, &second);
+        assert_eq!(uut.key("refresh_token"), "refresh_token@second");
+        assert_eq!(uut.password, "secret");
     }
 }
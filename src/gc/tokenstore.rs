@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use log::debug;
+use rocket::serde::Deserialize;
+use sqlx::Row;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+
+use super::cache::Error;
+
+/// Where OAuth tokens and the related bookkeeping are kept.
+#[rocket::async_trait]
+pub trait TokenStore: Send + Sync {
+    async fn init(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    async fn load(&self, key: &str) -> Result<Option<String>, Error>;
+    async fn store(&self, key: &str, value: &str) -> Result<(), Error>;
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum TokenStoreKind {
+    /// the settings table in Postgres
+    Settings,
+    /// a JSON file, see token_file
+    File,
+    /// the OS keyring through secret-tool (Linux) or security (macOS)
+    Keyring,
+}
+
+/// The settings table, shared with the other instances using the same database.
+pub struct SettingsStore {
+    db: sqlx::PgPool,
+}
+
+impl SettingsStore {
+    pub fn new(pool: sqlx::PgPool) -> Self {
+        Self { db: pool }
+    }
+}
+
+#[rocket::async_trait]
+impl TokenStore for SettingsStore {
+    async fn init(&self) -> Result<(), Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS settings (
+            id TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn load(&self, key: &str) -> Result<Option<String>, Error> {
+        let result = sqlx::query("SELECT value FROM settings where id = $1")
+            .bind(key)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(result.map(|row| row.get(0)))
+    }
+
+    async fn store(&self, key: &str, value: &str) -> Result<(), Error> {
+        sqlx::query("INSERT INTO settings (id, value) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET value = $2")
+            .bind(key)
+            .bind(value)
+            .execute(&self.db).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        sqlx::query("DELETE FROM settings WHERE id = $1")
+            .bind(key)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+}
+
+/// A JSON object in a local file, readable only by the owner.
+pub struct FileStore {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+
+    async fn read(&self) -> Result<HashMap<String, String>, Error> {
+        match tokio::fs::read(&self.path).await {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace the file atomically, so a crash never leaves half written tokens behind.
+    async fn write(&self, values: &HashMap<String, String>) -> Result<(), Error> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let data = serde_json::to_vec_pretty(values)?;
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || -> Result<(), std::io::Error> {
+            let mut file = tempfile::NamedTempFile::new_in(dir)?;
+            std::io::Write::write_all(&mut file, &data)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                file.as_file()
+                    .set_permissions(std::fs::Permissions::from_mode(0o600))?;
+            }
+            file.persist(path)?;
+            Ok(())
+        })
+        .await
+        .map_err(|_| Error::Unknown)??;
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl TokenStore for FileStore {
+    async fn load(&self, key: &str) -> Result<Option<String>, Error> {
+        let _guard = self.lock.lock().await;
+        Ok(self.read().await?.remove(key))
+    }
+
+    async fn store(&self, key: &str, value: &str) -> Result<(), Error> {
+        let _guard = self.lock.lock().await;
+        let mut values = self.read().await?;
+        values.insert(key.to_string(), value.to_string());
+        self.write(&values).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        let _guard = self.lock.lock().await;
+        let mut values = self.read().await?;
+        if values.remove(key).is_some() {
+            self.write(&values).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The OS keyring, through the secret-tool (libsecret) or security (macOS) command line tools.
+pub struct KeyringStore;
+
+impl KeyringStore {
+    const SERVICE: &'static str = "gc5";
+
+    /// Run the command with the input on stdin.
+    async fn with_stdin(command: &mut Command, input: &str) -> Result<std::process::Output, Error> {
+        let mut child = command
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input.as_bytes()).await?;
+        }
+        Ok(child.wait_with_output().await?)
+    }
+
+    fn failed(command: &str, output: &std::process::Output) -> Error {
+        Error::IO(std::io::Error::other(format!(
+            "{} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+#[rocket::async_trait]
+impl TokenStore for KeyringStore {
+    async fn load(&self, key: &str) -> Result<Option<String>, Error> {
+        let output = if cfg!(target_os = "macos") {
+            Command::new("security")
+                .args([
+                    "find-generic-password",
+                    "-s",
+                    Self::SERVICE,
+                    "-a",
+                    key,
+                    "-w",
+                ])
+                .output()
+                .await?
+        } else {
+            Command::new("secret-tool")
+                .args(["lookup", "service", Self::SERVICE, "key", key])
+                .output()
+                .await?
+        };
+        // both tools exit with an error if there is no such entry
+        if !output.status.success() {
+            debug!("No keyring entry for {}", key);
+            return Ok(None);
+        }
+        let value = String::from_utf8(output.stdout).map_err(|e| e.utf8_error())?;
+        Ok(Some(value.trim_end_matches('\n').to_string()))
+    }
+
+    async fn store(&self, key: &str, value: &str) -> Result<(), Error> {
+        // the secret is passed on stdin, so it doesn't show up in the process list
+        let output = if cfg!(target_os = "macos") {
+            // -w without a value prompts for the password and its confirmation
+            Self::with_stdin(
+                Command::new("security").args([
+                    "add-generic-password",
+                    "-U",
+                    "-s",
+                    Self::SERVICE,
+                    "-a",
+                    key,
+                    "-w",
+                ]),
+                &format!("{}\n{}\n", value, value),
+            )
+            .await?
+        } else {
+            Self::with_stdin(
+                Command::new("secret-tool")
+                    .args(["store", "--label", &format!("gc5 {}", key)])
+                    .args(["service", Self::SERVICE, "key", key]),
+                value,
+            )
+            .await?
+        };
+        if !output.status.success() {
+            return Err(Self::failed("storing keyring entry", &output));
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        let output = if cfg!(target_os = "macos") {
+            Command::new("security")
+                .args(["delete-generic-password", "-s", Self::SERVICE, "-a", key])
+                .output()
+                .await?
+        } else {
+            Command::new("secret-tool")
+                .args(["clear", "service", Self::SERVICE, "key", key])
+                .output()
+                .await?
+        };
+        if !output.status.success() {
+            debug!("Unable to delete keyring entry {}", key);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_store_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.json");
+        let uut = FileStore::new(path.clone());
+        assert_eq!(uut.load("refresh_token").await.unwrap(), None);
+
+        uut.store("refresh_token", "abc").await.unwrap();
+        uut.store("access_token@second", "def").await.unwrap();
+        assert_eq!(
+            FileStore::new(path.clone())
+                .load("refresh_token")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("abc")
+        );
+
+        uut.delete("refresh_token").await.unwrap();
+        assert_eq!(uut.load("refresh_token").await.unwrap(), None);
+        assert_eq!(
+            uut.load("access_token@second").await.unwrap().as_deref(),
+            Some("def")
+        );
+    }
+}
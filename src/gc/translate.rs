@@ -0,0 +1,180 @@
+use chrono::Utc;
+use log::{debug, info};
+use sqlx::Row;
+
+use crate::config::Config;
+use crate::gcgeo::{rot13, Geocache};
+
+use super::cache::Error;
+
+/// Translates hints and descriptions through a LibreTranslate compatible API, results are cached per geocache.
+pub struct Translator {
+    db: sqlx::PgPool,
+    client: reqwest::Client,
+    url: Option<String>,
+    api_key: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Text,
+    Html,
+}
+
+impl Format {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Format::Text => "text",
+            Format::Html => "html",
+        }
+    }
+}
+
+impl Translator {
+    pub fn new(pool: sqlx::PgPool, client: reqwest::Client, config: &Config) -> Self {
+        Self {
+            db: pool,
+            client,
+            url: config.translate_url.clone(),
+            api_key: config.translate_api_key.clone(),
+        }
+    }
+
+    pub async fn init(&self) -> Result<(), Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS translations (
+            gccode TEXT NOT NULL,
+            field TEXT NOT NULL,
+            lang TEXT NOT NULL,
+            original TEXT NOT NULL,
+            translated TEXT NOT NULL,
+            ts TIMESTAMPTZ NOT NULL,
+            PRIMARY KEY (gccode, field, lang)
+        )",
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.url.is_some()
+    }
+
+    /// Replace hint and descriptions with their translation into lang.
+    pub async fn translate(&self, geocache: &mut Geocache, lang: &str) -> Result<(), Error> {
+        let code = geocache.code.clone();
+        // hints are translated in plain text and encoded again afterwards
+        let mut hint = geocache.decoded_hint();
+        for (field, text, format) in [
+            ("hint", &mut hint, Format::Text),
+            (
+                "short_description",
+                &mut geocache.short_description,
+                Format::Html,
+            ),
+            (
+                "long_description",
+                &mut geocache.long_description,
+                Format::Html,
+            ),
+        ] {
+            if text.trim().is_empty() {
+                continue;
+            }
+            *text = self
+                .translate_field(&code, field, text, format, lang)
+                .await?;
+        }
+        geocache.encoded_hints = rot13(&hint);
+        Ok(())
+    }
+
+    async fn translate_field(
+        &self,
+        code: &str,
+        field: &str,
+        original: &str,
+        format: Format,
+        lang: &str,
+    ) -> Result<String, Error> {
+        let cached = sqlx::query(
+            "SELECT translated FROM translations WHERE gccode = $1 AND field = $2 AND lang = $3 AND original = $4",
+        )
+        .bind(code)
+        .bind(field)
+        .bind(lang)
+        .bind(original)
+        .fetch_optional(&self.db)
+        .await?;
+        if let Some(row) = cached {
+            debug!("Using cached {} translation of {} {}", lang, code, field);
+            return Ok(row.get(0));
+        }
+
+        let translated = self.call(original, format, lang).await?;
+        sqlx::query("INSERT INTO translations (gccode, field, lang, original, translated, ts) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (gccode, field, lang) DO UPDATE SET original = $4, translated = $5, ts = $6")
+            .bind(code)
+            .bind(field)
+            .bind(lang)
+            .bind(original)
+            .bind(&translated)
+            .bind(Utc::now())
+            .execute(&self.db)
+            .await?;
+        info!("Translated {} {} to {}", code, field, lang);
+        Ok(translated)
+    }
+
+    async fn call(&self, text: &str, format: Format, lang: &str) -> Result<String, Error> {
+        let url = self.url.as_ref().ok_or(Error::Unknown)?;
+        let response: serde_json::Value = self
+            .client
+            .post(url)
+            .json(&request_body(text, format, lang, self.api_key.as_deref()))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        response["translatedText"]
+            .as_str()
+            .map(String::from)
+            .ok_or(Error::Unknown)
+    }
+}
+
+fn request_body(
+    text: &str,
+    format: Format,
+    lang: &str,
+    api_key: Option<&str>,
+) -> serde_json::Value {
+    let mut body = serde_json::json!({
+        "q": text,
+        "source": "auto",
+        "target": lang,
+        "format": format.as_str(),
+    });
+    if let Some(api_key) = api_key {
+        body["api_key"] = serde_json::Value::from(api_key);
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_body_includes_api_key_only_if_set() {
+        let body = request_body("Unter dem Stein", Format::Text, "en", None);
+        assert_eq!(body["target"], "en");
+        assert_eq!(body["format"], "text");
+        assert!(body.get("api_key").is_none());
+
+        let body = request_body("<p>Hallo</p>", Format::Html, "fr", Some("secret"));
+        assert_eq!(body["format"], "html");
+        assert_eq!(body["api_key"], "secret");
+    }
+}
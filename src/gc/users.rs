@@ -0,0 +1,94 @@
+use chrono::{DateTime, Utc};
+use rand::distributions::{Alphanumeric, DistString};
+use rocket::serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::Row;
+
+use super::cache::Error;
+
+/// Someone allowed to use the service, identified by an API key.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct User {
+    pub name: String,
+    pub admin: bool,
+    /// jobs the user may start per hour, unlimited if not set
+    pub jobs_per_hour: Option<u32>,
+}
+
+/// Users and their API keys. Only a hash of each key is stored, the key itself is shown once
+/// when the user is created.
+pub struct UserStore {
+    db: sqlx::PgPool,
+}
+
+impl UserStore {
+    const KEY_LENGTH: usize = 32;
+
+    pub fn new(pool: sqlx::PgPool) -> Self {
+        Self { db: pool }
+    }
+
+    pub async fn init(&self) -> Result<(), Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS users (
+            name TEXT PRIMARY KEY,
+            key_hash TEXT NOT NULL UNIQUE,
+            admin BOOLEAN NOT NULL,
+            jobs_per_hour INTEGER,
+            created TIMESTAMPTZ NOT NULL
+        )",
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Add a user, or give an existing one a new key. Returns the new key.
+    pub async fn create(&self, user: &User, now: DateTime<Utc>) -> Result<String, Error> {
+        let key = Alphanumeric.sample_string(&mut rand::thread_rng(), Self::KEY_LENGTH);
+        sqlx::query("INSERT INTO users (name, key_hash, admin, jobs_per_hour, created) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (name) DO UPDATE SET key_hash = $2, admin = $3, jobs_per_hour = $4")
+            .bind(&user.name)
+            .bind(key_hash(&key))
+            .bind(user.admin)
+            .bind(user.jobs_per_hour.map(|jobs| jobs as i32))
+            .bind(now)
+            .execute(&self.db)
+            .await?;
+        Ok(key)
+    }
+
+    pub async fn by_key(&self, key: &str) -> Result<Option<User>, Error> {
+        let row = sqlx::query("SELECT name, admin, jobs_per_hour FROM users WHERE key_hash = $1")
+            .bind(key_hash(key))
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(row.map(|row| User {
+            name: row.get(0),
+            admin: row.get(1),
+            jobs_per_hour: row.get::<Option<i32>, _>(2).map(|jobs| jobs.max(0) as u32),
+        }))
+    }
+}
+
+/// Hex SHA-256, the keys are random enough that they need no salt.
+pub fn key_hash(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes() {
+        assert_eq!(
+            key_hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_ne!(key_hash("abc"), key_hash("abd"));
+    }
+}
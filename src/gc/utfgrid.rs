@@ -27,10 +27,6 @@ impl UtfGrid {
         self.grid.len()
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
-        self.data.iter().map(|(k, v)| (k, &v[0].i))
-    }
-
     fn extract_x_y(key: &str) -> (u8, u8) {
         // or regex?
         let parts: Vec<&str> = key
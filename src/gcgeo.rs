@@ -1,10 +1,22 @@
+pub use bbox::*;
+pub use cluster::*;
 pub use coordinate::*;
+pub use filter::GeocacheFilter;
 pub use geocache::*;
+pub use health::Health;
+pub use polygon::*;
+pub use region::*;
 pub use tile::*;
 pub use track::*;
 
 // is this idiomatic?
+mod bbox;
+mod cluster;
 mod coordinate;
+mod filter;
 mod geocache;
+mod health;
+mod polygon;
+mod region;
 mod tile;
 mod track;
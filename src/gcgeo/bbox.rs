@@ -0,0 +1,130 @@
+use std::{fmt, str::FromStr};
+
+use geo::{coord, Polygon, Rect};
+
+use super::{Coordinate, Region, Tile};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BBox {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+impl fmt::Display for BBox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{}",
+            self.min_lat, self.min_lon, self.max_lat, self.max_lon
+        )
+    }
+}
+
+/// Parses "minLat,minLon,maxLat,maxLon"
+impl FromStr for BBox {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s
+            .split(',')
+            .map(|part| part.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|e| format!("invalid bounding box {}: {}", s, e))?;
+        if parts.len() != 4 {
+            return Err(format!(
+                "invalid bounding box {}: expected minLat,minLon,maxLat,maxLon",
+                s
+            ));
+        }
+        let bbox = BBox {
+            min_lat: parts[0],
+            min_lon: parts[1],
+            max_lat: parts[2],
+            max_lon: parts[3],
+        };
+        if bbox.min_lat > bbox.max_lat
+            || bbox.min_lon > bbox.max_lon
+            || bbox.min_lat < -90.0
+            || bbox.max_lat > 90.0
+            || bbox.min_lon < -180.0
+            || bbox.max_lon > 180.0
+        {
+            return Err(format!("invalid bounding box {}", s));
+        }
+        Ok(bbox)
+    }
+}
+
+impl BBox {
+    pub fn polygon(&self) -> Polygon {
+        Rect::new(
+            coord! { x: self.min_lon, y: self.min_lat },
+            coord! { x: self.max_lon, y: self.max_lat },
+        )
+        .to_polygon()
+    }
+
+    /// All tiles at zoom level z that overlap the bounding box.
+    pub fn tiles(&self, z: u8) -> Vec<Tile> {
+        let top_left = Tile::from_coordinates(self.max_lat, self.min_lon, z);
+        let bottom_right = Tile::from_coordinates(self.min_lat, self.max_lon, z);
+        let mut result = Vec::new();
+        for x in top_left.x..=bottom_right.x {
+            for y in top_left.y..=bottom_right.y {
+                result.push(Tile { x, y, z });
+            }
+        }
+        result
+    }
+}
+
+impl Region for BBox {
+    fn contains(&self, coord: &Coordinate) -> bool {
+        coord.lat >= self.min_lat
+            && coord.lat <= self.max_lat
+            && coord.lon >= self.min_lon
+            && coord.lon <= self.max_lon
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let bbox: BBox = "47.9,8.4, 48.0,8.6".parse().unwrap();
+        assert_eq!(bbox.min_lat, 47.9);
+        assert_eq!(bbox.max_lon, 8.6);
+        assert!("48.0,8.4,47.9,8.6".parse::<BBox>().is_err());
+        assert!("47.9,8.4,48.0".parse::<BBox>().is_err());
+        assert!("a,b,c,d".parse::<BBox>().is_err());
+    }
+
+    #[test]
+    fn contains() {
+        let bbox: BBox = "47.9,8.4,48.0,8.6".parse().unwrap();
+        assert!(bbox.contains(&Coordinate {
+            lat: 47.95,
+            lon: 8.5
+        }));
+        assert!(!bbox.contains(&Coordinate {
+            lat: 48.05,
+            lon: 8.5
+        }));
+    }
+
+    #[test]
+    fn tiles_cover_bbox() {
+        let bbox: BBox = "47.94,8.50,47.96,8.52".parse().unwrap();
+        let tiles = bbox.tiles(14);
+        assert_eq!(tiles.len(), 4);
+        assert!(tiles.contains(&Tile {
+            x: 8579,
+            y: 5698,
+            z: 14
+        }));
+    }
+}
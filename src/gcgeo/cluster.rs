@@ -0,0 +1,69 @@
+use std::collections::BTreeMap;
+
+use super::{Coordinate, Geocache, Tile};
+
+/// Nearby geocaches shown as one point, for maps that can't cope with thousands of markers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cluster {
+    /// mean position of the geocaches
+    pub coord: Coordinate,
+    pub codes: Vec<String>,
+}
+
+impl Cluster {
+    pub fn count(&self) -> usize {
+        self.codes.len()
+    }
+}
+
+/// Group geocaches by the tile at zoom level z they are in, so the clusters get finer when
+/// zooming in. The order is stable, by tile.
+pub fn cluster(geocaches: &[Geocache], z: u8) -> Vec<Cluster> {
+    let mut cells: BTreeMap<(u32, u32), Vec<&Geocache>> = BTreeMap::new();
+    for gc in geocaches {
+        let tile = Tile::from_coordinates(gc.coord.lat, gc.coord.lon, z);
+        cells.entry((tile.y, tile.x)).or_default().push(gc);
+    }
+    cells
+        .into_values()
+        .map(|members| {
+            let n = members.len() as f64;
+            Cluster {
+                coord: Coordinate {
+                    lat: members.iter().map(|gc| gc.coord.lat).sum::<f64>() / n,
+                    lon: members.iter().map(|gc| gc.coord.lon).sum::<f64>() / n,
+                },
+                codes: members.iter().map(|gc| gc.code.clone()).collect(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn geocache(code: &str, lat: f64, lon: f64) -> Geocache {
+        Geocache::approximate(code.to_string(), Coordinate { lat, lon })
+    }
+
+    #[test]
+    fn clusters_by_tile() {
+        let geocaches = vec![
+            geocache("GC1", 47.901, 8.501),
+            geocache("GC2", 47.903, 8.503),
+            geocache("GC3", 48.5, 9.5),
+        ];
+
+        let clusters = cluster(&geocaches, 10);
+        assert_eq!(clusters.len(), 2);
+        let pair = clusters.iter().find(|c| c.count() == 2).unwrap();
+        assert_eq!(pair.codes, vec!["GC1", "GC2"]);
+        assert!((pair.coord.lat - 47.902).abs() < 1e-9);
+        assert!((pair.coord.lon - 8.502).abs() < 1e-9);
+
+        assert_eq!(cluster(&geocaches, 0).len(), 1);
+        assert_eq!(cluster(&geocaches, 18).len(), 3);
+        assert!(cluster(&[], 10).is_empty());
+    }
+}
@@ -1,8 +1,8 @@
-use std::{f64::consts::PI, fmt};
+use std::{f64::consts::PI, fmt, str::FromStr};
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Coordinate {
     pub lat: f64,
     pub lon: f64,
@@ -14,6 +14,89 @@ impl fmt::Display for Coordinate {
     }
 }
 
+/// Parses what people type: decimal degrees ("47.94615, 8.50205" or "N 47.94615 E 8.50205"),
+/// degrees and decimal minutes ("N 47° 56.769 E 008° 30.123") or DMS ("N 47° 56' 46.1\" E 8° 30' 7.4\"").
+impl FromStr for Coordinate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| format!("invalid coordinate {}: {}", s, reason);
+
+        let mut hemispheres = vec![];
+        let mut numbers: Vec<Vec<f64>> = vec![vec![]];
+        let mut rest = s.trim();
+        while let Some(c) = rest.chars().next() {
+            if c.is_ascii_digit() || c == '-' || c == '.' {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+                    .unwrap_or(rest.len());
+                let number = rest[..end]
+                    .parse::<f64>()
+                    .map_err(|_| invalid("bad number"))?;
+                numbers.last_mut().unwrap().push(number);
+                rest = &rest[end..];
+                continue;
+            }
+            match c.to_ascii_uppercase() {
+                'N' | 'S' | 'E' | 'W' => {
+                    if !numbers.last().unwrap().is_empty() {
+                        numbers.push(vec![]);
+                    }
+                    hemispheres.push(c.to_ascii_uppercase());
+                }
+                ',' | ';' if !numbers.last().unwrap().is_empty() => numbers.push(vec![]),
+                c if c.is_whitespace() || "°'\"′″,;".contains(c) => {}
+                _ => return Err(invalid("unexpected character")),
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+        numbers.retain(|parts| !parts.is_empty());
+
+        // without separators, split the numbers evenly: "47 56.769 8 30.123"
+        if numbers.len() == 1 && numbers[0].len().is_multiple_of(2) {
+            let half = numbers[0].len() / 2;
+            let lon = numbers[0].split_off(half);
+            numbers.push(lon);
+        }
+        let [lat, lon] = numbers.as_slice() else {
+            return Err(invalid("expected latitude and longitude"));
+        };
+        let (lat_sign, lon_sign) = match hemispheres.as_slice() {
+            [] => (1.0, 1.0),
+            [lat, lon] if "NS".contains(*lat) && "EW".contains(*lon) => (
+                if *lat == 'S' { -1.0 } else { 1.0 },
+                if *lon == 'W' { -1.0 } else { 1.0 },
+            ),
+            _ => return Err(invalid("expected N/S followed by E/W")),
+        };
+        let lat = lat_sign * degrees(lat).ok_or_else(|| invalid("bad latitude"))?;
+        let lon = lon_sign * degrees(lon).ok_or_else(|| invalid("bad longitude"))?;
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+            return Err(invalid("out of range"));
+        }
+        Ok(Coordinate { lat, lon })
+    }
+}
+
+/// Decimal degrees from [degrees], [degrees, minutes] or [degrees, minutes, seconds].
+fn degrees(parts: &[f64]) -> Option<f64> {
+    let (sign, degrees) = match parts.first() {
+        Some(d) if d.is_sign_negative() => (-1.0, -d),
+        Some(d) => (1.0, *d),
+        None => return None,
+    };
+    let minutes = parts.get(1).copied().unwrap_or(0.0);
+    let seconds = parts.get(2).copied().unwrap_or(0.0);
+    if parts.len() > 3
+        || !(0.0..60.0).contains(&minutes)
+        || !(0.0..60.0).contains(&seconds)
+        || (parts.len() > 1 && degrees.fract() != 0.0)
+    {
+        return None;
+    }
+    Some(sign * (degrees + minutes / 60.0 + seconds / 3600.0))
+}
+
 impl Coordinate {
     const EARTH_RADIUS: u32 = 6_371_000;
     // radius of earth in meters
@@ -56,4 +139,110 @@ impl Coordinate {
 
         Self::EARTH_RADIUS as f64 * c // in metres
     }
+
+    /// Degrees and decimal minutes, the way geocaching.com shows coordinates: N 47° 56.769 E 008° 30.123
+    pub fn to_dm(&self) -> String {
+        let dm = |value: f64, width: usize| {
+            let thousandths = (value.abs() * 60_000.0).round() as u64;
+            format!(
+                "{:0width$}° {:02}.{:03}",
+                thousandths / 60_000,
+                thousandths % 60_000 / 1000,
+                thousandths % 1000,
+                width = width
+            )
+        };
+        format!(
+            "{} {} {} {}",
+            if self.lat < 0.0 { 'S' } else { 'N' },
+            dm(self.lat, 2),
+            if self.lon < 0.0 { 'W' } else { 'E' },
+            dm(self.lon, 3)
+        )
+    }
+
+    /// Degrees, minutes and seconds: N 47° 56' 46.1" E 8° 30' 7.4"
+    pub fn to_dms(&self) -> String {
+        let dms = |value: f64| {
+            let tenths = (value.abs() * 36_000.0).round() as u64;
+            format!(
+                "{}° {}' {}.{}\"",
+                tenths / 36_000,
+                tenths % 36_000 / 600,
+                tenths % 600 / 10,
+                tenths % 10
+            )
+        };
+        format!(
+            "{} {} {} {}",
+            if self.lat < 0.0 { 'S' } else { 'N' },
+            dms(self.lat),
+            if self.lon < 0.0 { 'W' } else { 'E' },
+            dms(self.lon)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_approx_eq::assert_approx_eq;
+
+    use super::*;
+
+    fn parse(s: &str) -> Coordinate {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_formats() {
+        for s in [
+            "47.94615, 8.50205",
+            "47.94615 8.50205",
+            "N 47.94615 E 8.50205",
+            "N 47° 56.769 E 008° 30.123",
+            "n47 56.769 e8 30.123",
+            "47 56.769 8 30.123",
+            "N 47° 56' 46.14\" E 8° 30' 7.38\"",
+        ] {
+            let coord = parse(s);
+            assert_approx_eq!(coord.lat, 47.94615, 1e-5);
+            assert_approx_eq!(coord.lon, 8.50205, 1e-5);
+        }
+        let coord = parse("S 33° 51.510 W 151° 12.690");
+        assert_approx_eq!(coord.lat, -33.8585, 1e-5);
+        assert_approx_eq!(coord.lon, -151.2115, 1e-5);
+        let coord = parse("-33.8585, -151.2115");
+        assert_approx_eq!(coord.lon, -151.2115, 1e-5);
+    }
+
+    #[test]
+    fn parse_rejects_garbage() {
+        for s in [
+            "",
+            "47.9",
+            "N 47° 66.769 E 008° 30.123",
+            "E 8.5 N 47.9",
+            "N 95 E 8",
+            "47.9, 8.5, 1",
+            "somewhere",
+        ] {
+            assert!(s.parse::<Coordinate>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn format() {
+        let coord = Coordinate {
+            lat: 47.94615,
+            lon: 8.50205,
+        };
+        assert_eq!(coord.to_dm(), "N 47° 56.769 E 008° 30.123");
+        assert_eq!(coord.to_dms(), "N 47° 56' 46.1\" E 8° 30' 7.4\"");
+        let coord = Coordinate {
+            lat: -33.999999,
+            lon: -151.2115,
+        };
+        assert_eq!(coord.to_dm(), "S 34° 00.000 W 151° 12.690");
+        assert_eq!(parse(&coord.to_dm()).to_dm(), coord.to_dm());
+    }
 }
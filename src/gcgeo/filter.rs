@@ -0,0 +1,486 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use super::{CacheType, ContainerSize, Geocache};
+
+/// Which geocaches in a job's region make it into the result. Kept with the job, so the result
+/// can be refined later without fetching again.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GeocacheFilter {
+    /// only these types, all if empty
+    pub types: Vec<CacheType>,
+    pub min_d: Option<f32>,
+    pub max_d: Option<f32>,
+    pub min_t: Option<f32>,
+    pub max_t: Option<f32>,
+    /// only these container sizes, all if empty
+    pub sizes: Vec<ContainerSize>,
+    /// e.g. micro for kids, who want something to trade
+    pub exclude_sizes: Vec<ContainerSize>,
+    /// attributes the geocache must have, by name or id
+    pub attributes: Vec<String>,
+    /// e.g. "boat"
+    pub exclude_attributes: Vec<String>,
+    /// the greatest hits only
+    pub min_favorites: Option<u32>,
+    pub exclude_premium: bool,
+    /// skip archived and disabled geocaches
+    pub exclude_inactive: bool,
+    /// skip geocaches hidden by these usernames, case ignored
+    pub exclude_owners: Vec<String>,
+    /// skip geocaches with a find logged through this service
+    pub exclude_found: bool,
+    /// skip geocaches whose newest logs are more DNFs than this in a row
+    pub max_consecutive_dnf: Option<usize>,
+    /// skip geocaches nobody found in this many days, they are likely gone
+    pub found_within_days: Option<i64>,
+    /// with either set, only events that take place during the trip, other geocaches stay
+    pub events_from: Option<NaiveDate>,
+    pub events_until: Option<NaiveDate>,
+    /// search in the name and descriptions
+    pub query: Option<TextQuery>,
+    /// farthest a geocache may be from the track or the center of the area
+    pub max_distance_m: Option<f64>,
+}
+
+impl GeocacheFilter {
+    /// Easy traditionals that are there and can be logged, for short stops along a track.
+    pub fn quick_stop() -> Self {
+        Self {
+            types: vec![CacheType::Traditional],
+            max_d: Some(3.0),
+            max_t: Some(3.0),
+            exclude_premium: true,
+            exclude_inactive: true,
+            max_consecutive_dnf: Some(1),
+            ..Self::default()
+        }
+    }
+
+    /// Names of the built-in presets for profile=.
+    pub const PRESETS: [&'static str; 4] = ["quick-stop", "hiking", "puzzle-tour", "kid-friendly"];
+
+    /// A built-in preset by name.
+    pub fn preset(name: &str) -> Option<Self> {
+        let active = Self {
+            exclude_premium: true,
+            exclude_inactive: true,
+            max_consecutive_dnf: Some(2),
+            ..Self::default()
+        };
+        match name {
+            "quick-stop" => Some(Self::quick_stop()),
+            "hiking" => Some(Self {
+                types: vec![
+                    CacheType::Traditional,
+                    CacheType::Multi,
+                    CacheType::Earth,
+                    CacheType::Letterbox,
+                    CacheType::Virtual,
+                ],
+                ..active
+            }),
+            "puzzle-tour" => Some(Self {
+                types: vec![
+                    CacheType::Mystery,
+                    CacheType::Multi,
+                    CacheType::Wherigo,
+                    CacheType::Letterbox,
+                ],
+                ..active
+            }),
+            // containers big enough to trade toys, on paths a stroller can take
+            "kid-friendly" => Some(Self {
+                types: vec![
+                    CacheType::Traditional,
+                    CacheType::Multi,
+                    CacheType::Letterbox,
+                ],
+                max_d: Some(2.0),
+                max_t: Some(2.0),
+                sizes: vec![
+                    ContainerSize::Small,
+                    ContainerSize::Regular,
+                    ContainerSize::Large,
+                ],
+                ..active
+            }),
+            _ => None,
+        }
+    }
+
+    /// The same filter with its lists sorted and without duplicates, so filters that select the
+    /// same geocaches compare equal.
+    pub fn normalized(&self) -> Self {
+        fn sorted<T: fmt::Debug>(mut values: Vec<T>) -> Vec<T> {
+            values.sort_by_cached_key(|value| format!("{:?}", value));
+            values.dedup_by(|a, b| format!("{:?}", a) == format!("{:?}", b));
+            values
+        }
+        Self {
+            types: sorted(self.types.clone()),
+            sizes: sorted(self.sizes.clone()),
+            exclude_sizes: sorted(self.exclude_sizes.clone()),
+            attributes: sorted(self.attributes.clone()),
+            exclude_attributes: sorted(self.exclude_attributes.clone()),
+            exclude_owners: sorted(
+                self.exclude_owners
+                    .iter()
+                    .map(|owner| owner.to_lowercase())
+                    .collect(),
+            ),
+            ..self.clone()
+        }
+    }
+
+    /// found tells whether the geocache has a find logged through this service, distance_m is
+    /// how far it is from the track or area center if the job knows that. now is when the job
+    /// selects, found_within_days counts back from there.
+    pub fn matches(
+        &self,
+        gc: &Geocache,
+        found: bool,
+        distance_m: Option<f64>,
+        now: DateTime<Utc>,
+    ) -> bool {
+        (self.types.is_empty() || self.types.contains(&gc.cache_type))
+            && self.min_d.is_none_or(|min| gc.difficulty >= min)
+            && self.max_d.is_none_or(|max| gc.difficulty <= max)
+            && self.min_t.is_none_or(|min| gc.terrain >= min)
+            && self.max_t.is_none_or(|max| gc.terrain <= max)
+            && (self.sizes.is_empty() || self.sizes.contains(&gc.size))
+            && !self.exclude_sizes.contains(&gc.size)
+            && self
+                .attributes
+                .iter()
+                .all(|attribute| gc.has_attribute(attribute))
+            && !self
+                .exclude_attributes
+                .iter()
+                .any(|attribute| gc.has_attribute(attribute))
+            && self
+                .min_favorites
+                .is_none_or(|min| gc.favorite_points >= min)
+            && !(self.exclude_premium && gc.is_premium)
+            && !(self.exclude_inactive && (gc.archived || !gc.available))
+            && !gc.owner.as_ref().is_some_and(|owner| {
+                self.exclude_owners
+                    .iter()
+                    .any(|excluded| excluded.eq_ignore_ascii_case(owner))
+            })
+            && !(self.exclude_found && found)
+            && self
+                .max_consecutive_dnf
+                .is_none_or(|max| gc.dnf_streak() <= max)
+            && self.found_within_days.is_none_or(|days| {
+                gc.last_found()
+                    .is_some_and(|ts| now - ts <= Duration::days(days))
+            })
+            && (self.events_from.is_none() && self.events_until.is_none()
+                || gc
+                    .event_during(self.events_from, self.events_until)
+                    .unwrap_or(!gc.cache_type.is_event()))
+            && self.query.as_ref().is_none_or(|query| query.matches(gc))
+            && self
+                .max_distance_m
+                .zip(distance_m)
+                .is_none_or(|(max, distance)| distance <= max)
+    }
+}
+
+/// A case insensitive substring, or a regex between slashes, e.g. /^drive-in #\d+/.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TextQuery {
+    query: String,
+    regex: Regex,
+}
+
+impl TextQuery {
+    pub fn matches(&self, gc: &Geocache) -> bool {
+        self.regex.is_match(&gc.name)
+            || self.regex.is_match(&gc.short_description)
+            || self.regex.is_match(&gc.long_description)
+    }
+}
+
+impl FromStr for TextQuery {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = match s.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+            Some(pattern) => pattern.to_string(),
+            None => format!("(?i){}", regex::escape(s)),
+        };
+        let regex = Regex::new(&pattern).map_err(|e| format!("invalid query {}: {}", s, e))?;
+        Ok(Self {
+            query: s.to_string(),
+            regex,
+        })
+    }
+}
+
+impl TryFrom<String> for TextQuery {
+    type Error = String;
+
+    fn try_from(query: String) -> Result<Self, Self::Error> {
+        query.parse()
+    }
+}
+
+impl From<TextQuery> for String {
+    fn from(query: TextQuery) -> Self {
+        query.query
+    }
+}
+
+impl PartialEq for TextQuery {
+    fn eq(&self, other: &Self) -> bool {
+        self.query == other.query
+    }
+}
+
+impl fmt::Debug for TextQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::gcgeo::{Attribute, GeocacheLog, LogType};
+
+    #[test]
+    fn quick_stop() {
+        let mut gc = Geocache::premium("GC1".to_string());
+        assert!(GeocacheFilter::default().matches(&gc, false, None, Utc::now()));
+        assert!(!GeocacheFilter::quick_stop().matches(&gc, false, None, Utc::now()));
+
+        gc.is_premium = false;
+        gc.available = true;
+        gc.cache_type = CacheType::Traditional;
+        gc.difficulty = 2.0;
+        assert!(GeocacheFilter::quick_stop().matches(&gc, false, None, Utc::now()));
+        gc.terrain = 3.5;
+        assert!(!GeocacheFilter::quick_stop().matches(&gc, false, None, Utc::now()));
+        gc.terrain = 1.0;
+        gc.cache_type = CacheType::Multi;
+        assert!(!GeocacheFilter::quick_stop().matches(&gc, false, None, Utc::now()));
+    }
+
+    #[test]
+    fn presets() {
+        for name in GeocacheFilter::PRESETS {
+            assert!(GeocacheFilter::preset(name).is_some(), "{}", name);
+        }
+        assert_eq!(
+            GeocacheFilter::preset("quick-stop"),
+            Some(GeocacheFilter::quick_stop())
+        );
+        assert_eq!(GeocacheFilter::preset("quick_stop"), None);
+    }
+
+    #[test]
+    fn dnf_streak() {
+        let mut gc = Geocache::premium("GC1".to_string());
+        gc.is_premium = false;
+        gc.available = true;
+        gc.cache_type = CacheType::Traditional;
+        gc.difficulty = 1.5;
+        gc.terrain = 1.5;
+        let log = |timestamp: &str, log_type| GeocacheLog {
+            text: String::new(),
+            timestamp: timestamp.to_string(),
+            log_type,
+        };
+        gc.logs = vec![
+            log("2024-05-20T10:00:00+02:00", LogType::DidNotFind),
+            log("2024-05-01T10:00:00+02:00", LogType::Found),
+        ];
+        assert!(GeocacheFilter::quick_stop().matches(&gc, false, None, Utc::now()));
+        gc.logs
+            .insert(0, log("2024-05-21T10:00:00+02:00", LogType::DidNotFind));
+        assert!(!GeocacheFilter::quick_stop().matches(&gc, false, None, Utc::now()));
+        let anyway = GeocacheFilter {
+            max_consecutive_dnf: None,
+            ..GeocacheFilter::quick_stop()
+        };
+        assert!(anyway.matches(&gc, false, None, Utc::now()));
+    }
+
+    #[test]
+    fn sizes() {
+        let mut gc = Geocache::premium("GC1".to_string());
+        gc.size = ContainerSize::Micro;
+        let kids = GeocacheFilter {
+            exclude_sizes: vec!["nano".parse().unwrap()],
+            ..GeocacheFilter::default()
+        };
+        assert!(!kids.matches(&gc, false, None, Utc::now()));
+        gc.size = ContainerSize::Regular;
+        assert!(kids.matches(&gc, false, None, Utc::now()));
+
+        let large = GeocacheFilter {
+            sizes: vec![ContainerSize::Large],
+            ..GeocacheFilter::default()
+        };
+        assert!(!large.matches(&gc, false, None, Utc::now()));
+        assert_eq!("Not chosen".parse(), Ok(ContainerSize::NotChosen));
+        assert!("huge".parse::<ContainerSize>().is_err());
+    }
+
+    #[test]
+    fn attributes() {
+        let mut gc = Geocache::premium("GC1".to_string());
+        gc.attributes = vec![
+            Attribute {
+                id: 13,
+                name: "Available 24-7".to_string(),
+                is_on: true,
+            },
+            Attribute {
+                id: 4,
+                name: "Boat".to_string(),
+                is_on: false,
+            },
+        ];
+        let filter = GeocacheFilter {
+            attributes: vec!["available 24/7".to_string()],
+            exclude_attributes: vec!["boat".to_string()],
+            ..GeocacheFilter::default()
+        };
+        assert!(filter.matches(&gc, false, None, Utc::now()));
+        gc.attributes[1].is_on = true;
+        assert!(!filter.matches(&gc, false, None, Utc::now()));
+        gc.attributes.remove(1);
+        gc.attributes[0].is_on = false;
+        assert!(!filter.matches(&gc, false, None, Utc::now()));
+        gc.attributes[0].is_on = true;
+        let by_id = GeocacheFilter {
+            attributes: vec!["13".to_string()],
+            ..GeocacheFilter::default()
+        };
+        assert!(by_id.matches(&gc, false, None, Utc::now()));
+    }
+
+    #[test]
+    fn owners() {
+        let mut gc = Geocache::premium("GC1".to_string());
+        let not_mine = GeocacheFilter {
+            exclude_owners: vec!["foobert".to_string()],
+            ..GeocacheFilter::default()
+        };
+        assert!(not_mine.matches(&gc, false, None, Utc::now()));
+        gc.owner = Some("FooBert".to_string());
+        assert!(!not_mine.matches(&gc, false, None, Utc::now()));
+        gc.owner = Some("someone".to_string());
+        assert!(not_mine.matches(&gc, false, None, Utc::now()));
+    }
+
+    #[test]
+    fn favorites() {
+        let mut gc = Geocache::premium("GC1".to_string());
+        let hits = GeocacheFilter {
+            min_favorites: Some(10),
+            ..GeocacheFilter::default()
+        };
+        assert!(!hits.matches(&gc, false, None, Utc::now()));
+        gc.favorite_points = 10;
+        assert!(hits.matches(&gc, false, None, Utc::now()));
+    }
+
+    #[test]
+    fn query() {
+        let mut gc = Geocache::premium("GC1".to_string());
+        gc.name = "Drive-In #12".to_string();
+        let drive_in = GeocacheFilter {
+            query: Some("drive-in".parse().unwrap()),
+            ..GeocacheFilter::default()
+        };
+        assert!(drive_in.matches(&gc, false, None, Utc::now()));
+        let numbered = GeocacheFilter {
+            query: Some(r"/#\d+$/".parse().unwrap()),
+            ..GeocacheFilter::default()
+        };
+        assert!(numbered.matches(&gc, false, None, Utc::now()));
+        gc.name = "Drive-In".to_string();
+        assert!(!numbered.matches(&gc, false, None, Utc::now()));
+        assert!("/(/".parse::<TextQuery>().is_err());
+
+        let json = serde_json::to_string(&drive_in).unwrap();
+        assert_eq!(
+            serde_json::from_str::<GeocacheFilter>(&json).unwrap(),
+            drive_in
+        );
+    }
+
+    #[test]
+    fn events_during_trip() {
+        let date = |day| NaiveDate::from_ymd_opt(2024, 6, day);
+        let trip = GeocacheFilter {
+            events_from: date(10),
+            events_until: date(14),
+            ..GeocacheFilter::default()
+        };
+        let mut gc = Geocache::premium("GC1".to_string());
+        gc.cache_type = CacheType::Traditional;
+        assert!(trip.matches(&gc, false, None, Utc::now()));
+        gc.cache_type = CacheType::Event;
+        assert!(!trip.matches(&gc, false, None, Utc::now()));
+        gc.placed = date(9);
+        assert!(!trip.matches(&gc, false, None, Utc::now()));
+        gc.event_end = date(10);
+        assert!(trip.matches(&gc, false, None, Utc::now()));
+        gc.cache_type = CacheType::MegaEvent;
+        gc.placed = date(14);
+        gc.event_end = None;
+        assert!(trip.matches(&gc, false, None, Utc::now()));
+        gc.placed = date(15);
+        assert!(!trip.matches(&gc, false, None, Utc::now()));
+    }
+
+    #[test]
+    fn found_recently() {
+        let mut gc = Geocache::premium("GC1".to_string());
+        let recent = GeocacheFilter {
+            found_within_days: Some(365),
+            ..GeocacheFilter::default()
+        };
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        assert!(!recent.matches(&gc, false, None, now));
+        gc.last_visited = Some((now - Duration::days(400)).date_naive());
+        assert!(!recent.matches(&gc, false, None, now));
+        gc.last_visited = Some((now - Duration::days(30)).date_naive());
+        assert!(recent.matches(&gc, false, None, now));
+        // a year later the same find is too old
+        assert!(!recent.matches(&gc, false, None, now + Duration::days(365)));
+    }
+
+    #[test]
+    fn found_and_distance() {
+        let gc = Geocache::premium("GC1".to_string());
+        let filter = GeocacheFilter {
+            exclude_found: true,
+            max_distance_m: Some(50.0),
+            min_d: Some(1.0),
+            ..GeocacheFilter::default()
+        };
+        assert!(!filter.matches(&gc, false, None, Utc::now()));
+        let filter = GeocacheFilter {
+            min_d: None,
+            ..filter
+        };
+        assert!(filter.matches(&gc, false, None, Utc::now()));
+        assert!(filter.matches(&gc, false, Some(50.0), Utc::now()));
+        assert!(!filter.matches(&gc, false, Some(80.0), Utc::now()));
+        assert!(!filter.matches(&gc, true, Some(10.0), Utc::now()));
+    }
+}
@@ -1,10 +1,13 @@
+use std::collections::HashSet;
 use std::fmt;
+use std::str::FromStr;
 
-use serde::Serialize;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
 
 use super::Coordinate;
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Geocache {
     pub code: String,
     pub name: String,
@@ -20,17 +23,62 @@ pub struct Geocache {
     pub archived: bool,
     pub available: bool,
     pub logs: Vec<GeocacheLog>,
+    /// hidden date, for events the day of the event
+    pub placed: Option<NaiveDate>,
+    /// last day of events that take more than one day
+    pub event_end: Option<NaiveDate>,
+    /// only known from the public map tiles, coordinates are approximate and details are missing
+    pub approximate: bool,
+    /// geocache page as reported by the API
+    pub url: Option<String>,
+    /// the listing has a solution checker for the final coordinates
+    pub has_solution_checker: bool,
+    /// wherigo.com download page for Wherigo caches, taken from the listing
+    pub cartridge: Option<String>,
+    pub favorite_points: u32,
+    /// username of the owner as shown on the listing
+    pub owner: Option<String>,
+    /// day of the last find as reported by the API, the logs we have may not go back that far
+    pub last_visited: Option<NaiveDate>,
+    pub attributes: Vec<Attribute>,
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Clone)]
+/// A listing attribute, is_on false means the negation, e.g. "no boat required".
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct Attribute {
+    /// Groundspeak attribute id
+    pub id: u64,
+    pub name: String,
+    pub is_on: bool,
+}
+
+impl Attribute {
+    /// The attribute id or its name, case and anything but letters and digits ignored, e.g.
+    /// "available 24/7" for "Available 24-7".
+    pub fn is(&self, name_or_id: &str) -> bool {
+        let simple = |s: &str| -> String {
+            s.chars()
+                .filter(|c| c.is_alphanumeric())
+                .flat_map(char::to_lowercase)
+                .collect()
+        };
+        match name_or_id.trim().parse::<u64>() {
+            Ok(id) => id == self.id,
+            Err(_) => simple(&self.name) == simple(name_or_id),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub enum ContainerSize {
-    Nano,
     Micro,
     Small,
     Regular,
     Large,
     Other,
     Virtual,
+    /// the owner didn't pick a size
+    NotChosen,
     Unknown,
 }
 
@@ -53,6 +101,44 @@ impl fmt::Display for Geocache {
 }
 
 impl Geocache {
+    /// The longest codes in use have 7 characters after the GC
+    const MAX_CODE_LENGTH: usize = 9;
+
+    /// The code in upper case, if it is one: GC followed by letters and digits, e.g. gc1bxn4.
+    pub fn parse_code(code: &str) -> Result<String, String> {
+        let upper = code.trim().to_ascii_uppercase();
+        let valid = upper.strip_prefix("GC").is_some_and(|rest| {
+            !rest.is_empty() && rest.chars().all(|c| c.is_ascii_alphanumeric())
+        }) && upper.len() <= Self::MAX_CODE_LENGTH;
+        if valid {
+            Ok(upper)
+        } else {
+            Err(format!("invalid geocache code {}", code))
+        }
+    }
+
+    /// Codes from a JSON array or one per line (commas and spaces work too), e.g. copied from a
+    /// bookmark list. Duplicates are dropped, the first invalid code is an error.
+    pub fn parse_codes(list: &str) -> Result<Vec<String>, String> {
+        let codes: Vec<String> = if list.trim_start().starts_with('[') {
+            serde_json::from_str(list).map_err(|e| format!("invalid list of codes: {}", e))?
+        } else {
+            list.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|code| !code.is_empty())
+                .map(String::from)
+                .collect()
+        };
+        let mut seen = HashSet::new();
+        let mut result = vec![];
+        for code in codes {
+            let code = Self::parse_code(&code)?;
+            if seen.insert(code.clone()) {
+                result.push(code);
+            }
+        }
+        Ok(result)
+    }
+
     pub fn premium(code: String) -> Geocache {
         Self {
             code,
@@ -69,14 +155,111 @@ impl Geocache {
             size: ContainerSize::Unknown,
             cache_type: CacheType::Unknown,
             logs: vec![],
+            placed: None,
+            event_end: None,
+            approximate: false,
+            url: None,
+            has_solution_checker: false,
+            cartridge: None,
+            favorite_points: 0,
+            owner: None,
+            last_visited: None,
+            attributes: vec![],
+        }
+    }
+
+    /// Placeholder for a geocache that was only discovered on the map tiles, without API access.
+    pub fn approximate(code: String, coord: Coordinate) -> Geocache {
+        Self {
+            name: format!("{} (approximate position)", code),
+            is_premium: false,
+            available: true,
+            coord,
+            approximate: true,
+            ..Self::premium(code)
         }
     }
+
+    /// The geocache page, coord.info if the API didn't tell us.
+    pub fn page_url(&self) -> String {
+        self.url
+            .clone()
+            .unwrap_or_else(|| format!("https://coord.info/{}", self.code))
+    }
+
+    /// Links worth having in an export besides the geocache page.
+    pub fn links(&self) -> Vec<(String, &'static str)> {
+        let mut links = vec![];
+        if self.has_solution_checker {
+            links.push((self.page_url(), "Solution checker"));
+        }
+        if let Some(cartridge) = &self.cartridge {
+            links.push((cartridge.clone(), "Wherigo cartridge"));
+        }
+        links
+    }
+
+    /// The hint with ROT13 undone.
+    pub fn decoded_hint(&self) -> String {
+        rot13(&self.encoded_hints)
+    }
+
+    /// Whether an event takes place on any day from..=until, None if this isn't an event or its
+    /// date is unknown.
+    pub fn event_during(&self, from: Option<NaiveDate>, until: Option<NaiveDate>) -> Option<bool> {
+        if !self.cache_type.is_event() {
+            return None;
+        }
+        let start = self.placed?;
+        let end = self.event_end.unwrap_or(start).max(start);
+        Some(from.is_none_or(|from| end >= from) && until.is_none_or(|until| start <= until))
+    }
+
+    /// The attribute is set, and not negated.
+    pub fn has_attribute(&self, name_or_id: &str) -> bool {
+        self.attributes
+            .iter()
+            .any(|attribute| attribute.is_on && attribute.is(name_or_id))
+    }
+}
+
+/// ROT13 as used for geocache hints, text in [brackets] is plain by convention and stays as is.
+pub fn rot13(text: &str) -> String {
+    let mut plain = false;
+    text.chars()
+        .map(|c| match c {
+            '[' => {
+                plain = true;
+                c
+            }
+            ']' => {
+                plain = false;
+                c
+            }
+            _ if plain => c,
+            'a'..='m' | 'A'..='M' => (c as u8 + 13) as char,
+            'n'..='z' | 'N'..='Z' => (c as u8 - 13) as char,
+            _ => c,
+        })
+        .collect()
 }
 
 impl ContainerSize {
+    pub const ALL: [ContainerSize; 8] = [
+        Self::Micro,
+        Self::Small,
+        Self::Regular,
+        Self::Large,
+        Self::Other,
+        Self::Virtual,
+        Self::NotChosen,
+        Self::Unknown,
+    ];
+
+    /// Groundspeak geocacheSize id
     pub fn from(size: u64) -> Self {
         match size {
-            1 => Self::Unknown, // "not chosen" wtf
+            1 => Self::NotChosen,
             2 => Self::Micro,
             3 => Self::Regular,
             4 => Self::Large,
@@ -88,7 +271,28 @@ impl ContainerSize {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Clone)]
+/// Case insensitive variant name, spaces and underscores ignored, e.g. "not chosen". Nano
+/// containers are listed as micro.
+impl FromStr for ContainerSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name: String = s
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '_')
+            .collect();
+        if name.eq_ignore_ascii_case("nano") {
+            return Ok(Self::Micro);
+        }
+        Self::ALL
+            .iter()
+            .find(|size| size.to_string().eq_ignore_ascii_case(&name))
+            .cloned()
+            .ok_or_else(|| format!("unknown container size {}", s))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub enum CacheType {
     Traditional,
     Multi,
@@ -109,7 +313,48 @@ pub enum CacheType {
     Unknown,
 }
 
+/// Case insensitive variant name, e.g. "mystery"
+impl FromStr for CacheType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|cache_type| cache_type.to_string().eq_ignore_ascii_case(s.trim()))
+            .cloned()
+            .ok_or_else(|| format!("unknown cache type {}", s))
+    }
+}
+
 impl CacheType {
+    pub const ALL: [CacheType; 17] = [
+        Self::Traditional,
+        Self::Multi,
+        Self::Earth,
+        Self::Webcam,
+        Self::Mystery,
+        Self::Wherigo,
+        Self::Event,
+        Self::Virtual,
+        Self::Letterbox,
+        Self::Cito,
+        Self::Ape,
+        Self::MegaEvent,
+        Self::GigaEvent,
+        Self::GpsAdventures,
+        Self::Headquarter,
+        Self::Waypoint,
+        Self::Unknown,
+    ];
+
+    /// Happens at a given date, not worth a visit before or after.
+    pub fn is_event(&self) -> bool {
+        matches!(
+            self,
+            Self::Event | Self::MegaEvent | Self::GigaEvent | Self::Cito
+        )
+    }
+
     pub fn from(cache_type: u64) -> Self {
         match cache_type {
             2 => Self::Traditional,
@@ -133,27 +378,182 @@ impl CacheType {
     }
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub struct GeocacheLog {
     pub text: String,
     pub timestamp: String,
     pub log_type: LogType,
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Clone)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub enum LogType {
     Found,
     DidNotFind,
     WriteNote,
+    WillAttend,
+    Attended,
+    WebcamPhotoTaken,
+    NeedsMaintenance,
+    NeedsArchived,
+    OwnerMaintenance,
+    TemporarilyDisabled,
+    Enabled,
+    Archived,
+    Unarchived,
+    Published,
+    Retracted,
+    UpdateCoordinates,
+    ReviewerNote,
+    Announcement,
     Unknown,
 }
 
 impl LogType {
-    pub fn from(cache_type: u64) -> Self {
-        match cache_type {
+    /// Groundspeak geocacheLogType id
+    pub fn from(log_type: u64) -> Self {
+        match log_type {
             2 => Self::Found,
             3 => Self::DidNotFind,
+            4 => Self::WriteNote,
+            9 => Self::WillAttend,
+            10 => Self::Attended,
+            11 => Self::WebcamPhotoTaken,
+            45 => Self::NeedsMaintenance,
+            7 => Self::NeedsArchived,
+            46 => Self::OwnerMaintenance,
+            22 => Self::TemporarilyDisabled,
+            23 => Self::Enabled,
+            5 | 6 => Self::Archived,
+            1 | 12 => Self::Unarchived,
+            24 => Self::Published,
+            25 => Self::Retracted,
+            47 => Self::UpdateCoordinates,
+            18 | 68 => Self::ReviewerNote,
+            74 => Self::Announcement,
             _ => Self::Unknown,
         }
     }
+
+    pub fn id(&self) -> Option<u64> {
+        match self {
+            Self::Found => Some(2),
+            Self::DidNotFind => Some(3),
+            Self::WriteNote => Some(4),
+            Self::WillAttend => Some(9),
+            Self::Attended => Some(10),
+            Self::WebcamPhotoTaken => Some(11),
+            Self::NeedsMaintenance => Some(45),
+            Self::NeedsArchived => Some(7),
+            Self::OwnerMaintenance => Some(46),
+            Self::TemporarilyDisabled => Some(22),
+            Self::Enabled => Some(23),
+            Self::Archived => Some(5),
+            Self::Unarchived => Some(1),
+            Self::Published => Some(24),
+            Self::Retracted => Some(25),
+            Self::UpdateCoordinates => Some(47),
+            Self::ReviewerNote => Some(68),
+            Self::Announcement => Some(74),
+            Self::Unknown => None,
+        }
+    }
+
+    /// Somebody signed the log book (or the event/webcam equivalent).
+    pub fn is_find(&self) -> bool {
+        matches!(self, Self::Found | Self::Attended | Self::WebcamPhotoTaken)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes() {
+        assert_eq!(Geocache::parse_code(" gc1bxn4").unwrap(), "GC1BXN4");
+        assert_eq!(Geocache::parse_code("GC12").unwrap(), "GC12");
+        assert!(Geocache::parse_code("GC").is_err());
+        assert!(Geocache::parse_code("TB1234").is_err());
+        assert!(Geocache::parse_code("GC12-34").is_err());
+        assert!(Geocache::parse_code("GC12345678").is_err());
+
+        assert_eq!(
+            Geocache::parse_codes("GC1BXN4\r\ngc12, GC1BXN4\n\n").unwrap(),
+            vec!["GC1BXN4", "GC12"]
+        );
+        assert_eq!(
+            Geocache::parse_codes(r#"["GC12", "gc13"]"#).unwrap(),
+            vec!["GC12", "GC13"]
+        );
+        assert!(Geocache::parse_codes("GC12\nTB34").is_err());
+        assert!(Geocache::parse_codes("[GC12]").is_err());
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let mut gc = Geocache::approximate(
+            "GC1".to_string(),
+            Coordinate {
+                lat: 48.0,
+                lon: 8.5,
+            },
+        );
+        gc.size = ContainerSize::Small;
+        gc.cache_type = CacheType::Multi;
+        gc.placed = NaiveDate::from_ymd_opt(2012, 10, 2);
+        gc.logs = vec![GeocacheLog {
+            text: "TFTC".to_string(),
+            timestamp: "2024-05-20T10:00:00+02:00".to_string(),
+            log_type: LogType::Found,
+        }];
+        let json = serde_json::to_string(&gc).unwrap();
+        let parsed: Geocache = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, gc);
+    }
+
+    #[test]
+    fn decode_hint() {
+        let mut gc = Geocache::premium("GC1".to_string());
+        gc.encoded_hints = "Zntargvfpu, [Stage 2] hagre Fgrva! 42".to_string();
+        assert_eq!(gc.decoded_hint(), "Magnetisch, [Stage 2] unter Stein! 42");
+    }
+
+    #[test]
+    fn container_sizes() {
+        for (id, size) in [
+            (1, ContainerSize::NotChosen),
+            (2, ContainerSize::Micro),
+            (3, ContainerSize::Regular),
+            (4, ContainerSize::Large),
+            (5, ContainerSize::Virtual),
+            (6, ContainerSize::Other),
+            (8, ContainerSize::Small),
+            (7, ContainerSize::Unknown),
+            (0, ContainerSize::Unknown),
+        ] {
+            assert_eq!(ContainerSize::from(id), size, "size id {}", id);
+        }
+    }
+
+    #[test]
+    fn log_types_round_trip() {
+        for id in [
+            1, 2, 3, 4, 5, 7, 9, 10, 11, 22, 23, 24, 25, 45, 46, 47, 68, 74,
+        ] {
+            let log_type = LogType::from(id);
+            assert_ne!(log_type, LogType::Unknown, "log type id {}", id);
+            assert_eq!(log_type.id(), Some(id));
+        }
+        assert_eq!(LogType::from(6), LogType::Archived);
+        assert_eq!(LogType::from(18), LogType::ReviewerNote);
+        assert!(LogType::Attended.is_find());
+        assert!(!LogType::WriteNote.is_find());
+    }
+
+    #[test]
+    fn cache_types_from_str() {
+        assert_eq!("mystery".parse(), Ok(CacheType::Mystery));
+        assert_eq!(" MegaEvent".parse(), Ok(CacheType::MegaEvent));
+        assert!("tradi".parse::<CacheType>().is_err());
+    }
 }
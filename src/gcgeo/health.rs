@@ -0,0 +1,222 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use super::{Geocache, LogType};
+
+/// Rough guess whether a geocache is still there, based on its status and recent logs.
+#[derive(Debug, PartialEq, Eq, Serialize, Clone, Copy)]
+pub enum Health {
+    /// found recently
+    Good,
+    /// no finds for a while, or an open needs maintenance log
+    Stale,
+    /// disabled, archived or a streak of DNFs
+    Bad,
+    /// no logs to judge from
+    Unknown,
+}
+
+impl Health {
+    const STALE_AFTER_DAYS: i64 = 183;
+    const DNF_STREAK: usize = 2;
+
+    pub fn color(&self) -> &'static str {
+        match self {
+            Health::Good => "#2e7d32",
+            Health::Stale => "#f9a825",
+            Health::Bad => "#c62828",
+            Health::Unknown => "#000000",
+        }
+    }
+}
+
+/// Parses the lowercase names, e.g. "good" or "stale"
+impl FromStr for Health {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "good" => Ok(Health::Good),
+            "stale" => Ok(Health::Stale),
+            "bad" => Ok(Health::Bad),
+            "unknown" => Ok(Health::Unknown),
+            _ => Err(format!("invalid health {}", s)),
+        }
+    }
+}
+
+impl Geocache {
+    pub fn health(&self, now: DateTime<Utc>) -> Health {
+        if !self.available || self.archived {
+            return Health::Bad;
+        }
+
+        let logs = self.logs_newest_first();
+        if logs.is_empty() {
+            return Health::Unknown;
+        }
+
+        if self.dnf_streak() >= Health::DNF_STREAK {
+            return Health::Bad;
+        }
+
+        match self.last_found() {
+            Some(_) if self.needs_maintenance() => Health::Stale,
+            Some(ts) if now - ts <= Duration::days(Health::STALE_AFTER_DAYS) => Health::Good,
+            _ => Health::Stale,
+        }
+    }
+
+    /// The newest find in the logs or the last visited date, whichever is later.
+    pub fn last_found(&self) -> Option<DateTime<Utc>> {
+        let logged = self
+            .logs_newest_first()
+            .iter()
+            .find(|(_, log_type)| log_type.is_find())
+            .map(|(ts, _)| *ts);
+        let visited = self
+            .last_visited
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .map(|date| date.and_utc());
+        logged.max(visited)
+    }
+
+    /// A needs maintenance log that the owner hasn't answered with a maintenance log yet.
+    pub fn needs_maintenance(&self) -> bool {
+        self.logs_newest_first()
+            .iter()
+            .map(|(_, log_type)| log_type)
+            .find(|log_type| {
+                matches!(
+                    log_type,
+                    LogType::NeedsMaintenance | LogType::OwnerMaintenance | LogType::Enabled
+                )
+            })
+            .is_some_and(|log_type| **log_type == LogType::NeedsMaintenance)
+    }
+
+    /// Number of DNFs since the last other log.
+    pub fn dnf_streak(&self) -> usize {
+        self.logs_newest_first()
+            .iter()
+            .take_while(|(_, log_type)| **log_type == LogType::DidNotFind)
+            .count()
+    }
+
+    fn logs_newest_first(&self) -> Vec<(DateTime<Utc>, &LogType)> {
+        let mut logs: Vec<(DateTime<Utc>, &LogType)> = self
+            .logs
+            .iter()
+            .filter_map(|log| {
+                DateTime::parse_from_rfc3339(&log.timestamp)
+                    .ok()
+                    .map(|ts| (ts.with_timezone(&Utc), &log.log_type))
+            })
+            .collect();
+        logs.sort_by_key(|(ts, _)| std::cmp::Reverse(*ts));
+        logs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::super::GeocacheLog;
+    use super::*;
+
+    fn geocache(logs: Vec<(&str, LogType)>) -> Geocache {
+        let mut gc = Geocache::premium("GC1".to_string());
+        gc.is_premium = false;
+        gc.available = true;
+        gc.logs = logs
+            .into_iter()
+            .map(|(timestamp, log_type)| GeocacheLog {
+                text: String::new(),
+                timestamp: timestamp.to_string(),
+                log_type,
+            })
+            .collect();
+        gc
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn recently_found_is_good() {
+        let gc = geocache(vec![
+            ("2024-05-20T10:00:00+02:00", LogType::Found),
+            ("2024-04-01T10:00:00+02:00", LogType::DidNotFind),
+        ]);
+        assert_eq!(gc.health(now()), Health::Good);
+    }
+
+    #[test]
+    fn old_finds_are_stale() {
+        let gc = geocache(vec![("2023-06-01T10:00:00+02:00", LogType::Found)]);
+        assert_eq!(gc.health(now()), Health::Stale);
+    }
+
+    #[test]
+    fn dnf_streak_is_bad() {
+        let gc = geocache(vec![
+            ("2024-05-01T10:00:00+02:00", LogType::DidNotFind),
+            ("2024-05-20T10:00:00+02:00", LogType::DidNotFind),
+            ("2024-03-01T10:00:00+02:00", LogType::Found),
+        ]);
+        assert_eq!(gc.health(now()), Health::Bad);
+        assert_eq!(gc.dnf_streak(), 2);
+    }
+
+    #[test]
+    fn open_needs_maintenance_is_stale() {
+        let gc = geocache(vec![
+            ("2024-05-20T10:00:00+02:00", LogType::NeedsMaintenance),
+            ("2024-05-19T10:00:00+02:00", LogType::Found),
+        ]);
+        assert!(gc.needs_maintenance());
+        assert_eq!(gc.health(now()), Health::Stale);
+
+        let gc = geocache(vec![
+            ("2024-05-20T10:00:00+02:00", LogType::NeedsMaintenance),
+            ("2024-05-21T10:00:00+02:00", LogType::OwnerMaintenance),
+            ("2024-05-22T10:00:00+02:00", LogType::Found),
+        ]);
+        assert!(!gc.needs_maintenance());
+        assert_eq!(gc.health(now()), Health::Good);
+    }
+
+    #[test]
+    fn last_visited_counts_as_find() {
+        let mut gc = geocache(vec![("2023-06-01T10:00:00+02:00", LogType::Found)]);
+        gc.last_visited = chrono::NaiveDate::from_ymd_opt(2024, 5, 1);
+        assert_eq!(
+            gc.last_found(),
+            Some(Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap())
+        );
+        assert_eq!(gc.health(now()), Health::Good);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(" Good".parse(), Ok(Health::Good));
+        assert_eq!("stale".parse(), Ok(Health::Stale));
+        assert!("missing".parse::<Health>().is_err());
+    }
+
+    #[test]
+    fn disabled_is_bad() {
+        let mut gc = geocache(vec![("2024-05-20T10:00:00+02:00", LogType::Found)]);
+        gc.available = false;
+        assert_eq!(gc.health(now()), Health::Bad);
+    }
+
+    #[test]
+    fn no_logs_is_unknown() {
+        assert_eq!(geocache(vec![]).health(now()), Health::Unknown);
+    }
+}
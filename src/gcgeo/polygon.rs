@@ -0,0 +1,192 @@
+use std::str::FromStr;
+
+use geo::{BoundingRect, Intersects, MultiPolygon};
+use geojson::GeoJson;
+
+use super::{BBox, Coordinate, Region, Tile};
+
+/// A drawn area, read from a GeoJSON Polygon or MultiPolygon.
+#[derive(Debug, Clone)]
+pub struct Polygon {
+    polygons: MultiPolygon,
+}
+
+/// Parses GeoJSON, either a bare geometry, a Feature or a FeatureCollection. All (Multi)Polygons
+/// found are combined into one area.
+impl FromStr for Polygon {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let geojson: GeoJson = s.parse().map_err(|e| format!("invalid GeoJSON: {}", e))?;
+        let geometries: Vec<geojson::Geometry> = match geojson {
+            GeoJson::Geometry(geometry) => vec![geometry],
+            GeoJson::Feature(feature) => feature.geometry.into_iter().collect(),
+            GeoJson::FeatureCollection(collection) => collection
+                .features
+                .into_iter()
+                .filter_map(|feature| feature.geometry)
+                .collect(),
+        };
+
+        let mut polygons = vec![];
+        for geometry in geometries {
+            match geometry.value {
+                value @ geojson::Value::Polygon(_) => polygons.push(
+                    geo::Polygon::try_from(value).map_err(|e| format!("invalid polygon: {}", e))?,
+                ),
+                value @ geojson::Value::MultiPolygon(_) => polygons.extend(
+                    MultiPolygon::try_from(value).map_err(|e| format!("invalid polygon: {}", e))?,
+                ),
+                _ => {}
+            }
+        }
+        if polygons.is_empty() {
+            return Err("no polygon found in GeoJSON".to_string());
+        }
+        Ok(Self {
+            polygons: MultiPolygon::new(polygons),
+        })
+    }
+}
+
+fn bounds(polygons: &impl BoundingRect<f64, Output = Option<geo::Rect>>) -> BBox {
+    // never None, a parsed polygon has at least one point
+    let rect = polygons.bounding_rect().unwrap();
+    BBox {
+        min_lat: rect.min().y,
+        min_lon: rect.min().x,
+        max_lat: rect.max().y,
+        max_lon: rect.max().x,
+    }
+}
+
+impl Polygon {
+    pub fn bbox(&self) -> BBox {
+        bounds(&self.polygons)
+    }
+
+    /// All tiles at zoom level z that overlap the polygon.
+    pub fn tiles(&self, z: u8) -> Vec<Tile> {
+        // the bounding boxes are a cheap check before the exact one, parts of a multipolygon
+        // can be far apart
+        let parts: Vec<(BBox, &geo::Polygon)> = self
+            .polygons
+            .iter()
+            .map(|polygon| (bounds(polygon), polygon))
+            .collect();
+        self.bbox()
+            .tiles(z)
+            .into_iter()
+            .filter(|tile| {
+                parts.iter().any(|(bbox, polygon)| {
+                    tile.intersects(bbox) && tile.intersects_polygon(polygon)
+                })
+            })
+            .collect()
+    }
+
+    /// The rings of all polygons as lon/lat pairs, each exterior followed by its holes.
+    pub fn positions(&self) -> Vec<Vec<[f64; 2]>> {
+        self.polygons
+            .iter()
+            .flat_map(|polygon| std::iter::once(polygon.exterior()).chain(polygon.interiors()))
+            .map(|ring| ring.coords().map(|c| [c.x, c.y]).collect())
+            .collect()
+    }
+}
+
+impl Region for Polygon {
+    fn contains(&self, coord: &Coordinate) -> bool {
+        // intersects rather than contains, so points on the boundary count as inside
+        self.polygons
+            .intersects(&geo::point! { x: coord.lon, y: coord.lat })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRIANGLE: &str = r#"{"type": "Feature", "properties": {}, "geometry": {"type": "Polygon", "coordinates": [[[8.40, 47.90], [8.60, 47.90], [8.40, 48.10], [8.40, 47.90]]]}}"#;
+
+    #[test]
+    fn parse() {
+        let polygon: Polygon = TRIANGLE.parse().unwrap();
+        assert_eq!(
+            polygon.bbox(),
+            BBox {
+                min_lat: 47.9,
+                min_lon: 8.4,
+                max_lat: 48.1,
+                max_lon: 8.6
+            }
+        );
+        assert!(r#"{"type": "Point", "coordinates": [8.4, 47.9]}"#.parse::<Polygon>().is_err());
+        assert!("not json".parse::<Polygon>().is_err());
+    }
+
+    #[test]
+    fn contains() {
+        let polygon: Polygon = TRIANGLE.parse().unwrap();
+        assert!(polygon.contains(&Coordinate {
+            lat: 47.95,
+            lon: 8.45
+        }));
+        assert!(polygon.contains(&Coordinate {
+            lat: 47.9,
+            lon: 8.5
+        }));
+        assert!(!polygon.contains(&Coordinate {
+            lat: 48.05,
+            lon: 8.55
+        }));
+    }
+
+    #[test]
+    fn multipolygon_with_hole() {
+        let polygon: Polygon = r#"{"type": "MultiPolygon", "coordinates": [
+            [[[8.40, 47.90], [8.60, 47.90], [8.60, 48.10], [8.40, 48.10], [8.40, 47.90]],
+             [[8.45, 47.95], [8.55, 47.95], [8.55, 48.05], [8.45, 48.05], [8.45, 47.95]]],
+            [[[9.00, 47.00], [9.10, 47.00], [9.10, 47.10], [9.00, 47.00]]]]}"#
+            .parse()
+            .unwrap();
+        assert!(polygon.contains(&Coordinate {
+            lat: 47.92,
+            lon: 8.42
+        }));
+        assert!(!polygon.contains(&Coordinate {
+            lat: 48.0,
+            lon: 8.5
+        }));
+        assert!(polygon.contains(&Coordinate {
+            lat: 47.02,
+            lon: 9.05
+        }));
+        assert_eq!(polygon.bbox().max_lon, 9.1);
+    }
+
+    #[test]
+    fn tiles_skip_corners_outside() {
+        let polygon: Polygon = TRIANGLE.parse().unwrap();
+        let tiles = polygon.tiles(14);
+        let all = polygon.bbox().tiles(14);
+        assert!(tiles.len() < all.len());
+        let top_right = Tile::from_coordinates(48.09, 8.59, 14);
+        let bottom_left = Tile::from_coordinates(47.91, 8.41, 14);
+        assert!(!tiles.contains(&top_right));
+        assert!(tiles.contains(&bottom_left));
+    }
+
+    #[test]
+    fn tiles_skip_the_gap_between_parts() {
+        let polygon: Polygon = r#"{"type": "MultiPolygon", "coordinates": [
+            [[[8.40, 47.90], [8.45, 47.90], [8.45, 47.95], [8.40, 47.90]]],
+            [[[8.60, 48.05], [8.65, 48.05], [8.65, 48.10], [8.60, 48.05]]]]}"#
+            .parse()
+            .unwrap();
+        let tiles = polygon.tiles(14);
+        assert!(tiles.contains(&Tile::from_coordinates(47.901, 8.449, 14)));
+        assert!(tiles.contains(&Tile::from_coordinates(48.051, 8.649, 14)));
+        assert!(!tiles.contains(&Tile::from_coordinates(48.0, 8.52, 14)));
+    }
+}
@@ -0,0 +1,43 @@
+use super::Coordinate;
+
+/// An area geocaches are selected from, e.g. a bounding box or the corridor along a track.
+pub trait Region: Send + Sync {
+    fn contains(&self, coord: &Coordinate) -> bool;
+}
+
+/// Everything within radius meters of the center, e.g. an area job.
+#[derive(Debug, Clone)]
+pub struct Circle {
+    pub center: Coordinate,
+    pub radius: f64,
+}
+
+impl Region for Circle {
+    fn contains(&self, coord: &Coordinate) -> bool {
+        self.center.distance(coord) <= self.radius
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circle() {
+        let circle = Circle {
+            center: Coordinate {
+                lat: 47.9,
+                lon: 8.5,
+            },
+            radius: 1000.0,
+        };
+        assert!(circle.contains(&Coordinate {
+            lat: 47.905,
+            lon: 8.5
+        }));
+        assert!(!circle.contains(&Coordinate {
+            lat: 47.91,
+            lon: 8.5
+        }));
+    }
+}
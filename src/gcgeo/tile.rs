@@ -1,6 +1,10 @@
-use std::{collections::HashSet, f64::consts::PI, fmt};
+use std::f64::consts::{PI, SQRT_2};
+use std::{collections::HashSet, fmt};
 
-use super::Coordinate;
+use geo::orient::Direction;
+use geo::{GeodesicArea, Intersects, Orient, Polygon};
+
+use super::{BBox, Coordinate};
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub struct Tile {
@@ -11,26 +15,31 @@ pub struct Tile {
 
 impl fmt::Display for Tile {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}/{}/{} #{}", self.z, self.x, self.y, self.quadkey())
+        write!(
+            f,
+            "{}/{}/{} #{}",
+            self.z,
+            self.x,
+            self.y,
+            self.quadkey_string()
+        )
     }
 }
 
 impl Tile {
-    const DEFAULT_ZOOM: u8 = 12;
+    /// Zoom level area jobs discover tiles at
+    pub const DEFAULT_ZOOM: u8 = 12;
 
     pub fn from_coordinates(lat: f64, lon: f64, z: u8) -> Self {
         let lat_rad = lat * PI / 180.0;
         let n = 2_i32.pow(z as u32) as f64;
         let x = ((lon + 180.0) / 360.0 * n) as u32;
         let y = ((1.0 - (lat_rad.tan() + 1.0 / lat_rad.cos()).ln() / PI) / 2.0 * n) as u32;
-        return Self { x, y, z };
+        Self { x, y, z }
     }
 
     pub fn to_coord(&self) -> Coordinate {
-        let lon = self.x as f64 / (self.z as f64).exp2() * 360.0 - 180.0;
-        let n = PI - 2.0 * PI * self.y as f64 / (self.z as f64).exp2();
-        let lat = 180.0 / PI * (0.5 * (n.exp() - (-n).exp())).atan();
-        Coordinate { lat, lon }
+        self.utf_grid_offset(0.0, 0.0)
     }
 
     pub fn top_left(&self) -> Coordinate {
@@ -38,20 +47,64 @@ impl Tile {
     }
 
     pub fn bottom_right(&self) -> Coordinate {
-        Self {
-            x: self.x + 1,
-            y: self.y + 1,
-            z: self.z,
+        self.utf_grid_offset(1.0, 1.0)
+    }
+
+    pub fn bbox(&self) -> BBox {
+        let (top_left, bottom_right) = (self.top_left(), self.bottom_right());
+        BBox {
+            min_lat: bottom_right.lat,
+            min_lon: top_left.lon,
+            max_lat: top_left.lat,
+            max_lon: bottom_right.lon,
         }
-        .to_coord()
     }
 
-    pub fn quadkey(&self) -> u32 {
-        let mut result = 0;
-        for i in 0..self.z {
-            result |= (self.x & 1 << i) << i | (self.y & 1 << i) << (i + 1);
+    pub fn polygon(&self) -> Polygon {
+        self.bbox().polygon()
+    }
+
+    pub fn intersects(&self, bbox: &BBox) -> bool {
+        let own = self.bbox();
+        own.min_lat <= bbox.max_lat
+            && own.max_lat >= bbox.min_lat
+            && own.min_lon <= bbox.max_lon
+            && own.max_lon >= bbox.min_lon
+    }
+
+    pub fn intersects_polygon(&self, polygon: &Polygon) -> bool {
+        self.polygon().intersects(polygon)
+    }
+
+    /// Area covered by the tile in km²
+    pub fn area_km2(&self) -> f64 {
+        // geodesic area depends on the winding order, a clockwise ring is taken as "everything but"
+        self.polygon()
+            .orient(Direction::Default)
+            .geodesic_area_unsigned()
+            / 1_000_000.0
+    }
+
+    /// Interleaved x and y bits below a leading 1 bit that marks the zoom level, so tiles of
+    /// different zoom levels never share a key.
+    pub fn quadkey(&self) -> u64 {
+        let (x, y) = (self.x as u64, self.y as u64);
+        let mut result = 1 << (2 * self.z as u64);
+        for i in 0..self.z as u64 {
+            result |= (x & 1 << i) << i | (y & 1 << i) << (i + 1);
         }
-        return result;
+        result
+    }
+
+    /// Bing maps style quadkey, one digit 0-3 per zoom level: "120210233"
+    pub fn quadkey_string(&self) -> String {
+        (1..=self.z)
+            .rev()
+            .map(|i| {
+                let digit = (self.x >> (i - 1) & 1) + 2 * (self.y >> (i - 1) & 1);
+                char::from_digit(digit, 4).unwrap()
+            })
+            .collect()
     }
 
     pub fn around(&self) -> Vec<Self> {
@@ -64,28 +117,38 @@ impl Tile {
         result
     }
 
-    pub fn near(coordinate: &Coordinate, radius: f64) -> Vec<Self> {
-        // as a first approximation, use a square instead of a circle
-        let top_left = coordinate.project(radius, 315.0);
-        let bottom_right = coordinate.project(radius, 135.0);
+    /// Tiles at zoom level z covering a circle of radius meters around the coordinate. Tiles of the
+    /// enclosing square that lie completely outside the circle are left out.
+    pub fn near(coordinate: &Coordinate, radius: f64, z: u8) -> Vec<Self> {
+        // the diagonal of the enclosing square, not the radius
+        let top_left = coordinate.project(radius * SQRT_2, 315.0);
+        let bottom_right = coordinate.project(radius * SQRT_2, 135.0);
 
-        let top_left_tile = Self::from_coordinates(top_left.lat, top_left.lon, Self::DEFAULT_ZOOM);
-        let bottom_right_tile =
-            Self::from_coordinates(bottom_right.lat, bottom_right.lon, Self::DEFAULT_ZOOM);
+        let top_left_tile = Self::from_coordinates(top_left.lat, top_left.lon, z);
+        let bottom_right_tile = Self::from_coordinates(bottom_right.lat, bottom_right.lon, z);
 
         let mut result = HashSet::new();
         for x in top_left_tile.x..=bottom_right_tile.x {
             for y in top_left_tile.y..=bottom_right_tile.y {
-                result.insert(Tile {
-                    x,
-                    y,
-                    z: Self::DEFAULT_ZOOM,
-                });
+                let tile = Tile { x, y, z };
+                if tile.distance(coordinate) <= radius {
+                    result.insert(tile);
+                }
             }
         }
         result.into_iter().collect()
     }
 
+    /// Distance in meters from the coordinate to the closest point of the tile, 0 if it is inside.
+    pub fn distance(&self, coordinate: &Coordinate) -> f64 {
+        let bbox = self.bbox();
+        let closest = Coordinate {
+            lat: coordinate.lat.clamp(bbox.min_lat, bbox.max_lat),
+            lon: coordinate.lon.clamp(bbox.min_lon, bbox.max_lon),
+        };
+        closest.distance(coordinate)
+    }
+
     pub fn utf_grid_offset(&self, x: f64, y: f64) -> Coordinate {
         let lon = (self.x as f64 + x) / (self.z as f64).exp2() * 360.0 - 180.0;
         let n = PI - 2.0 * PI * (self.y as f64 + y) / (self.z as f64).exp2();
@@ -97,6 +160,7 @@ impl Tile {
 #[cfg(test)]
 mod tests {
     use assert_approx_eq::assert_approx_eq;
+    use geo::polygon;
 
     use super::*;
 
@@ -117,6 +181,116 @@ mod tests {
         assert_approx_eq!(bottom_right.lon, 8.525390625);
     }
 
+    #[test]
+    fn test_bbox_and_intersection() {
+        let uut = Tile {
+            x: 8579,
+            y: 5698,
+            z: 14,
+        };
+        let bbox = uut.bbox();
+        assert_approx_eq!(bbox.max_lat, 47.96050238891509);
+        assert_approx_eq!(bbox.max_lon, 8.525390625);
+
+        assert!(uut.intersects(&"47.95,8.52,48.0,8.6".parse().unwrap()));
+        assert!(!uut.intersects(&"47.97,8.52,48.0,8.6".parse().unwrap()));
+
+        let triangle = polygon![
+            (x: 8.51, y: 47.95),
+            (x: 8.60, y: 47.95),
+            (x: 8.60, y: 48.00),
+        ];
+        assert!(uut.intersects_polygon(&triangle));
+        let far_away = polygon![
+            (x: 9.0, y: 47.0),
+            (x: 9.1, y: 47.0),
+            (x: 9.1, y: 47.1),
+        ];
+        assert!(!uut.intersects_polygon(&far_away));
+
+        // roughly 1.64 km x 1.64 km at this latitude
+        assert!((uut.area_km2() - 2.68).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_near() {
+        let center = Coordinate {
+            lat: 47.947971,
+            lon: 8.508224,
+        };
+        // a tiny radius still needs the tile the coordinate is in
+        assert_eq!(
+            Tile::near(&center, 1.0, 14),
+            vec![Tile {
+                x: 8579,
+                y: 5698,
+                z: 14
+            }]
+        );
+
+        let square = 2 * 5 + 1;
+        let tiles = Tile::near(&center, 8000.0, 14);
+        assert!(tiles.iter().all(|tile| tile.z == 14));
+        assert!(tiles.len() < square * square);
+        assert!(tiles.contains(&Tile::from_coordinates(
+            center.project(7900.0, 0.0).lat,
+            center.lon,
+            14
+        )));
+        assert!(!tiles.contains(&Tile::from_coordinates(
+            center.project(11000.0, 315.0).lat,
+            center.project(11000.0, 315.0).lon,
+            14
+        )));
+        assert!(Tile::near(&center, 8000.0, 12).len() < tiles.len());
+    }
+
+    #[test]
+    fn test_near_is_circular() {
+        let center = Coordinate {
+            lat: 47.947971,
+            lon: 8.508224,
+        };
+        let radius = 10_000.0;
+        let tiles = Tile::near(&center, radius, 14);
+        let square = BBox {
+            min_lat: center.project(radius, 180.0).lat,
+            min_lon: center.project(radius, 270.0).lon,
+            max_lat: center.project(radius, 0.0).lat,
+            max_lon: center.project(radius, 90.0).lon,
+        }
+        .tiles(14);
+        // a circle covers pi/4 of its square, the tiles along the edge add some
+        let ratio = tiles.len() as f64 / square.len() as f64;
+        assert!(ratio < 0.9, "ratio {}", ratio);
+        assert!(tiles.iter().all(|tile| square.contains(tile)));
+
+        let inside = Tile::from_coordinates(center.lat, center.lon, 14);
+        assert_eq!(inside.distance(&center), 0.0);
+        let east = Tile::from_coordinates(center.lat, center.lon + 0.1, 14);
+        let distance = east.distance(&center);
+        assert!(distance > 5000.0 && distance < 7500.0, "{}", distance);
+    }
+
+    #[test]
+    fn test_quadkey() {
+        let tile = Tile { x: 3, y: 5, z: 3 };
+        assert_eq!(tile.quadkey_string(), "213");
+        assert_eq!(tile.quadkey(), 0b1_10_01_11);
+
+        // same x/y at different zoom levels must not collide
+        assert_ne!(
+            Tile { x: 1, y: 1, z: 12 }.quadkey(),
+            Tile { x: 1, y: 1, z: 14 }.quadkey()
+        );
+
+        // no overflow at high zoom levels
+        let deep = Tile::from_coordinates(47.947971, 8.508224, 20);
+        assert!(deep.quadkey() > u32::MAX as u64);
+        assert_eq!(deep.quadkey_string().len(), 20);
+        assert_eq!(deep.quadkey() >> 40, 1);
+    }
+
     #[test]
     fn test_from_coordinate() {
         let uut = Tile::from_coordinates(47.947971, 8.508224, 14);
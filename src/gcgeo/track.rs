@@ -1,31 +1,295 @@
-use std::{collections::HashSet, io::Error};
+use std::{collections::HashSet, fmt};
 
-use geo::{ClosestPoint, GeodesicDistance, LineString};
+use chrono::{DateTime, Duration, Utc};
+use geo::{ClosestPoint, GeodesicDistance, LineString, MultiLineString, Simplify};
 
-use super::{Coordinate, Tile};
+use geojson::GeoJson;
+use thiserror::Error;
+use xml::common::Position;
+use xml::reader::XmlEvent;
+
+use super::{Coordinate, Region, Tile};
 
 #[derive(Debug, Clone)]
 pub struct Track {
     pub tiles: Vec<Tile>,
     pub waypoints: Vec<Coordinate>,
-    line_string: LineString,
+    pub report: TrackReport,
+    /// the points as read, simplification only affects lines
+    segments: Vec<Vec<TrackPoint>>,
+    lines: MultiLineString,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackPoint {
+    pub coord: Coordinate,
+    pub time: Option<DateTime<Utc>>,
+    /// meters above sea level
+    pub elevation: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrackFormat {
+    Gpx,
+    Kml,
+    GeoJson,
+}
+
+impl TrackFormat {
+    /// Format by media type, None for generic ones like application/octet-stream.
+    pub fn from_media_type(top: &str, sub: &str) -> Option<Self> {
+        match (top, sub) {
+            ("application", "gpx+xml") | ("application", "gpx") => Some(Self::Gpx),
+            ("application", "vnd.google-earth.kml+xml") => Some(Self::Kml),
+            ("application", "geo+json") | ("application", "json") => Some(Self::GeoJson),
+            _ => None,
+        }
+    }
+
+    /// Guess from the content: JSON starts with a brace, KML has a kml root element, everything
+    /// else is treated as GPX.
+    pub fn sniff(data: &[u8]) -> Self {
+        let start = data
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .unwrap_or(data.len());
+        if data[start..].starts_with(b"{") {
+            return Self::GeoJson;
+        }
+        let head = String::from_utf8_lossy(&data[start..data.len().min(start + 1024)]);
+        if head.contains("<kml") {
+            Self::Kml
+        } else {
+            Self::Gpx
+        }
+    }
+}
+
+impl fmt::Display for TrackFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Gpx => write!(f, "GPX"),
+            Self::Kml => write!(f, "KML"),
+            Self::GeoJson => write!(f, "GeoJSON"),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TrackError {
+    #[error("no track, route or line found in the {0} file")]
+    EmptyTrack(TrackFormat),
+    #[error("invalid {format} file{}: {message}", in_line(.line))]
+    ParseError {
+        format: TrackFormat,
+        line: Option<u64>,
+        message: String,
+    },
+}
+
+fn in_line(line: &Option<u64>) -> String {
+    line.map(|line| format!(" in line {}", line))
+        .unwrap_or_default()
+}
+
+impl TrackError {
+    fn parse(format: TrackFormat, message: impl ToString) -> Self {
+        Self::ParseError {
+            format,
+            line: None,
+            message: message.to_string(),
+        }
+    }
+
+    fn xml(format: TrackFormat, e: &xml::reader::Error) -> Self {
+        Self::ParseError {
+            format,
+            line: Some(e.position().row + 1),
+            message: e.msg().to_string(),
+        }
+    }
+}
+
+/// What was found in an uploaded file and went into the track. KML and GeoJSON only know lines,
+/// those count as tracks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackReport {
+    pub format: TrackFormat,
+    pub tracks: usize,
+    pub routes: usize,
+    /// wpt elements in the file, only part of the track if requested
+    pub waypoints: usize,
+    pub waypoints_used: bool,
+    pub points: usize,
+}
+
+impl TrackReport {
+    fn lines(format: TrackFormat, lines: &[Vec<TrackPoint>]) -> Self {
+        Self {
+            format,
+            tracks: lines.len(),
+            routes: 0,
+            waypoints: 0,
+            waypoints_used: false,
+            points: lines.iter().map(Vec::len).sum(),
+        }
+    }
+}
+
+impl fmt::Display for TrackReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} with {} tracks, {} routes, {} waypoints{}, {} points",
+            self.format,
+            self.tracks,
+            self.routes,
+            self.waypoints,
+            if self.waypoints_used || self.waypoints == 0 {
+                ""
+            } else {
+                " (ignored)"
+            },
+            self.points
+        )
+    }
 }
 
 impl Track {
-    pub fn from_gpx<R: std::io::Read>(io: R) -> Result<Self, Error> {
-        let gpx = gpx::read(io).unwrap();
-        let waypoints: Vec<Coordinate> = gpx
+    const METERS_PER_DEGREE: f64 = 111_320.0;
+    const ASCENT_THRESHOLD_M: f64 = 3.0;
+
+    pub fn parse(
+        data: &[u8],
+        format: TrackFormat,
+        use_waypoints: bool,
+    ) -> Result<Self, TrackError> {
+        match format {
+            TrackFormat::Gpx => Self::from_gpx(data, use_waypoints),
+            TrackFormat::Kml => Self::from_kml(data),
+            TrackFormat::GeoJson => Self::from_geojson(
+                std::str::from_utf8(data).map_err(|e| TrackError::parse(format, e))?,
+            ),
+        }
+    }
+
+    /// Reads every track segment and route as a separate line. With use_waypoints the waypoints
+    /// are connected in file order and added as one more line, for planners that export those.
+    pub fn from_gpx(data: &[u8], use_waypoints: bool) -> Result<Self, TrackError> {
+        let gpx = gpx::read(data).map_err(|e| {
+            // the gpx errors don't tell where, but broken XML is the most common reason
+            xml::EventReader::new(data)
+                .into_iter()
+                .find_map(Result::err)
+                .map(|xml| TrackError::xml(TrackFormat::Gpx, &xml))
+                .unwrap_or_else(|| TrackError::parse(TrackFormat::Gpx, e))
+        })?;
+        let mut lines: Vec<Vec<TrackPoint>> = gpx
             .tracks
             .iter()
             .flat_map(|track| track.segments.iter())
-            .flat_map(|segment| segment.points.clone())
-            .map(|waypoint| waypoint.point())
-            .map(|p| Coordinate {
-                lat: p.y(),
-                lon: p.x(),
-            })
+            .map(|segment| to_points(&segment.points))
             .collect();
+        lines.extend(gpx.routes.iter().map(|route| to_points(&route.points)));
+        if use_waypoints {
+            lines.push(to_points(&gpx.waypoints));
+        }
+        lines.retain(|line| !line.is_empty());
+
+        let report = TrackReport {
+            format: TrackFormat::Gpx,
+            tracks: gpx.tracks.len(),
+            routes: gpx.routes.len(),
+            waypoints: gpx.waypoints.len(),
+            waypoints_used: use_waypoints,
+            points: lines.iter().map(Vec::len).sum(),
+        };
+        Self::from_lines(lines, report)
+    }
+
+    /// Reads the coordinates of all LineStrings, e.g. from Google My Maps.
+    pub fn from_kml<R: std::io::Read>(io: R) -> Result<Self, TrackError> {
+        let mut lines = vec![];
+        let mut in_line_string = false;
+        let mut in_coordinates = false;
+        let mut text = String::new();
+        for event in xml::EventReader::new(io) {
+            match event.map_err(|e| TrackError::xml(TrackFormat::Kml, &e))? {
+                XmlEvent::StartElement { name, .. } => match name.local_name.as_str() {
+                    "LineString" => in_line_string = true,
+                    "coordinates" if in_line_string => {
+                        in_coordinates = true;
+                        text.clear();
+                    }
+                    _ => {}
+                },
+                XmlEvent::Characters(chars) | XmlEvent::CData(chars) if in_coordinates => {
+                    text.push_str(&chars)
+                }
+                XmlEvent::EndElement { name } => match name.local_name.as_str() {
+                    "LineString" => in_line_string = false,
+                    "coordinates" if in_coordinates => {
+                        in_coordinates = false;
+                        lines.push(
+                            parse_kml_coordinates(&text)
+                                .map_err(|e| TrackError::parse(TrackFormat::Kml, e))?,
+                        );
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        lines.retain(|line| !line.is_empty());
+
+        let report = TrackReport::lines(TrackFormat::Kml, &lines);
+        Self::from_lines(lines, report)
+    }
+
+    /// Reads all LineStrings and MultiLineStrings, bare or in (collections of) features.
+    pub fn from_geojson(s: &str) -> Result<Self, TrackError> {
+        let geojson: GeoJson = s
+            .parse()
+            .map_err(|e| TrackError::parse(TrackFormat::GeoJson, e))?;
+        let mut lines = vec![];
+        let mut geometries: Vec<geojson::Value> = match geojson {
+            GeoJson::Geometry(geometry) => vec![geometry.value],
+            GeoJson::Feature(feature) => feature.geometry.map(|g| g.value).into_iter().collect(),
+            GeoJson::FeatureCollection(collection) => collection
+                .features
+                .into_iter()
+                .filter_map(|feature| feature.geometry.map(|g| g.value))
+                .collect(),
+        };
+        while let Some(value) = geometries.pop() {
+            match value {
+                geojson::Value::LineString(line) => lines.push(from_positions(&line)),
+                geojson::Value::MultiLineString(multi) => {
+                    lines.extend(multi.iter().map(|line| from_positions(line)))
+                }
+                geojson::Value::GeometryCollection(collection) => {
+                    geometries.extend(collection.into_iter().map(|g| g.value))
+                }
+                _ => {}
+            }
+        }
+        // popping reversed the order
+        lines.reverse();
+        lines.retain(|line| !line.is_empty());
+
+        let report = TrackReport::lines(TrackFormat::GeoJson, &lines);
+        Self::from_lines(lines, report)
+    }
 
+    fn from_lines(lines: Vec<Vec<TrackPoint>>, report: TrackReport) -> Result<Self, TrackError> {
+        if lines.is_empty() {
+            return Err(TrackError::EmptyTrack(report.format));
+        }
+        let waypoints: Vec<Coordinate> = lines
+            .iter()
+            .flatten()
+            .map(|point| point.coord.clone())
+            .collect();
         let tiles = waypoints
             .iter()
             .map(|coord| Tile::from_coordinates(coord.lat, coord.lon, 14))
@@ -34,22 +298,222 @@ impl Track {
             .into_iter()
             .collect();
 
-        let line_string = LineString::from_iter(
-            waypoints
+        let segments = lines;
+        let lines = MultiLineString::new(
+            segments
                 .iter()
-                .map(|coord| geo::coord! {x: coord.lon, y: coord.lat}),
+                .map(|line| {
+                    LineString::from_iter(
+                        line.iter()
+                            .map(|point| geo::coord! {x: point.coord.lon, y: point.coord.lat}),
+                    )
+                })
+                .collect(),
         );
 
         Ok(Track {
             tiles,
             waypoints,
-            line_string,
+            report,
+            segments,
+            lines,
         })
     }
 
+    /// Legs of about km kilometers each, for one export per stage. Each leg starts where the
+    /// previous one ended.
+    pub fn split_by_distance(&self, km: f64) -> Vec<Track> {
+        let max = km * 1000.0;
+        let mut previous: Option<Coordinate> = None;
+        let mut distance = 0.0;
+        self.split(
+            |point| {
+                distance += previous
+                    .as_ref()
+                    .map_or(0.0, |previous| previous.distance(&point.coord));
+                previous = Some(point.coord.clone());
+                if distance >= max {
+                    distance = 0.0;
+                    true
+                } else {
+                    false
+                }
+            },
+            true,
+        )
+    }
+
+    /// A new leg wherever the recording pauses for longer than gap, e.g. overnight on a multi-day
+    /// tour. Tracks without timestamps stay in one piece, reversed tracks split the same way.
+    pub fn split_by_time_gap(&self, gap: Duration) -> Vec<Track> {
+        let mut previous: Option<DateTime<Utc>> = None;
+        self.split(
+            |point| match point.time {
+                Some(time) => {
+                    let cut = previous.is_some_and(|previous| (time - previous).abs() > gap);
+                    previous = Some(time);
+                    cut
+                }
+                None => false,
+            },
+            false,
+        )
+    }
+
+    /// The same way in the opposite direction, e.g. to number legs the way the tour is actually
+    /// done. Built from the points as read, so a simplification is undone.
+    pub fn reversed(&self) -> Self {
+        let lines = self
+            .segments
+            .iter()
+            .rev()
+            .map(|segment| segment.iter().rev().cloned().collect())
+            .collect();
+        // never empty, the track wasn't either
+        Self::from_lines(lines, self.report.clone()).unwrap()
+    }
+
+    /// Whether the track ends within tolerance_m meters of where it started, like round trips and
+    /// out-and-back routes.
+    pub fn is_loop(&self, tolerance_m: f64) -> bool {
+        match (self.waypoints.first(), self.waypoints.last()) {
+            (Some(first), Some(last)) if self.waypoints.len() > 2 => {
+                first.distance(last) <= tolerance_m
+            }
+            _ => false,
+        }
+    }
+
+    /// Length in meters, gaps between segments not included.
+    pub fn length(&self) -> f64 {
+        self.segments
+            .iter()
+            .flat_map(|segment| segment.windows(2))
+            .map(|pair| pair[0].coord.distance(&pair[1].coord))
+            .sum()
+    }
+
+    /// Total climb in meters, None without elevation data. Differences below ASCENT_THRESHOLD_M
+    /// are GPS noise rather than hills and don't count.
+    pub fn ascent(&self) -> Option<f64> {
+        let mut elevations = self
+            .segments
+            .iter()
+            .flatten()
+            .filter_map(|point| point.elevation);
+        let mut low = elevations.next()?;
+        let mut ascent = 0.0;
+        for elevation in elevations {
+            if elevation > low + Self::ASCENT_THRESHOLD_M {
+                ascent += elevation - low;
+                low = elevation;
+            } else if elevation < low {
+                low = elevation;
+            }
+        }
+        Some(ascent)
+    }
+
+    /// Elevation of the track point closest to coord, if the track has elevation data.
+    pub fn elevation_at(&self, coord: &Coordinate) -> Option<f64> {
+        self.segments
+            .iter()
+            .flatten()
+            .filter_map(|point| point.elevation.map(|e| (point.coord.distance(coord), e)))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, elevation)| elevation)
+    }
+
+    /// Meters along the track to the point closest to coord, gaps between segments not included.
+    pub fn distance_along(&self, coord: &Coordinate) -> Option<f64> {
+        let mut along = 0.0;
+        let mut closest: Option<(f64, f64)> = None;
+        for segment in &self.segments {
+            for (index, point) in segment.iter().enumerate() {
+                if index > 0 {
+                    along += segment[index - 1].coord.distance(&point.coord);
+                }
+                let distance = point.coord.distance(coord);
+                if closest.is_none_or(|(best, _)| distance < best) {
+                    closest = Some((distance, along));
+                }
+            }
+        }
+        closest.map(|(_, along)| along)
+    }
+
+    /// Time of the first point that has one.
+    pub fn start_time(&self) -> Option<DateTime<Utc>> {
+        self.segments.iter().flatten().find_map(|point| point.time)
+    }
+
+    /// Cut into legs before every point cut returns true for. With overlap the point also ends
+    /// the previous leg, so the legs connect.
+    fn split<F>(&self, mut cut: F, overlap: bool) -> Vec<Track>
+    where
+        F: FnMut(&TrackPoint) -> bool,
+    {
+        let mut legs = vec![];
+        let mut leg: Vec<Vec<TrackPoint>> = vec![];
+        for segment in &self.segments {
+            let mut line: Vec<TrackPoint> = vec![];
+            for point in segment {
+                if cut(point) && !(leg.is_empty() && line.is_empty()) {
+                    if overlap && !line.is_empty() {
+                        line.push(point.clone());
+                    }
+                    if !line.is_empty() {
+                        leg.push(std::mem::take(&mut line));
+                    }
+                    legs.push(std::mem::take(&mut leg));
+                }
+                line.push(point.clone());
+            }
+            if !line.is_empty() {
+                leg.push(line);
+            }
+        }
+        if !leg.is_empty() {
+            legs.push(leg);
+        }
+        legs.into_iter()
+            .filter_map(|lines| {
+                let report = TrackReport::lines(self.report.format, &lines);
+                Self::from_lines(lines, report).ok()
+            })
+            .collect()
+    }
+
+    /// Douglas-Peucker simplification of the line geocaches are compared against, points closer
+    /// than epsilon_m meters to the simplified line are dropped. The tiles are kept as they are.
+    pub fn simplified(self, epsilon_m: f64) -> Self {
+        if epsilon_m <= 0.0 {
+            return self;
+        }
+        // good enough in the latitudes people go geocaching, a degree of longitude shrinks further north
+        let epsilon = epsilon_m / Self::METERS_PER_DEGREE;
+        Self {
+            lines: self.lines.simplify(&epsilon),
+            ..self
+        }
+    }
+
+    /// The lines geocaches are compared against as [lon, lat] positions, e.g. to draw the track.
+    pub fn positions(&self) -> Vec<Vec<[f64; 2]>> {
+        self.lines
+            .iter()
+            .map(|line| line.coords().map(|c| [c.x, c.y]).collect())
+            .collect()
+    }
+
+    /// Points in the lines geocaches are compared against, after simplification.
+    pub fn point_count(&self) -> usize {
+        self.lines.iter().map(|line| line.0.len()).sum()
+    }
+
     pub fn near(&self, coord: &Coordinate) -> u16 {
         let other = geo::point! { x: coord.lon, y: coord.lat };
-        let closest = self.line_string.closest_point(&other);
+        let closest = self.lines.closest_point(&other);
         let distance = match closest {
             geo::Closest::SinglePoint(p) => p.geodesic_distance(&other),
             geo::Closest::Intersection(p) => p.geodesic_distance(&other),
@@ -59,3 +523,388 @@ impl Track {
         distance as u16
     }
 }
+
+fn to_points(points: &[gpx::Waypoint]) -> Vec<TrackPoint> {
+    points
+        .iter()
+        .map(|waypoint| {
+            let p = waypoint.point();
+            TrackPoint {
+                coord: Coordinate {
+                    lat: p.y(),
+                    lon: p.x(),
+                },
+                time: waypoint.time.and_then(|time| {
+                    let time: time::OffsetDateTime = time.into();
+                    DateTime::from_timestamp(time.unix_timestamp(), time.nanosecond())
+                }),
+                elevation: waypoint.elevation,
+            }
+        })
+        .collect()
+}
+
+/// GeoJSON positions are [lon, lat, (elevation)]
+fn from_positions(positions: &[geojson::Position]) -> Vec<TrackPoint> {
+    positions
+        .iter()
+        .filter(|position| position.len() >= 2)
+        .map(|position| TrackPoint {
+            coord: Coordinate {
+                lat: position[1],
+                lon: position[0],
+            },
+            time: None,
+            elevation: position.get(2).copied(),
+        })
+        .collect()
+}
+
+/// KML coordinates are whitespace separated tuples of lon,lat[,alt]
+fn parse_kml_coordinates(text: &str) -> Result<Vec<TrackPoint>, String> {
+    text.split_whitespace()
+        .map(|tuple| {
+            let mut parts = tuple.split(',').map(str::parse::<f64>);
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(Ok(lon)), Some(Ok(lat)), elevation) => Ok(TrackPoint {
+                    coord: Coordinate { lat, lon },
+                    time: None,
+                    // Google My Maps writes 0 for "unknown"
+                    elevation: elevation.and_then(Result::ok).filter(|e| *e != 0.0),
+                }),
+                _ => Err(format!("invalid coordinate {}", tuple)),
+            }
+        })
+        .collect()
+}
+
+/// Everything within distance meters of a track.
+#[derive(Debug, Clone)]
+pub struct Corridor {
+    track: Track,
+    distance: u16,
+}
+
+impl Corridor {
+    pub fn new(track: Track, distance: u16) -> Self {
+        Self { track, distance }
+    }
+
+    pub fn track(&self) -> &Track {
+        &self.track
+    }
+}
+
+impl Region for Corridor {
+    fn contains(&self, coord: &Coordinate) -> bool {
+        self.track.near(coord) <= self.distance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simplification_keeps_the_shape() {
+        // a straight line with some jitter and one real corner
+        let mut trkpts = String::new();
+        for i in 0..=100 {
+            let jitter = if i % 2 == 0 { 0.00001 } else { -0.00001 };
+            trkpts.push_str(&format!(
+                r#"<trkpt lat="{}" lon="{}"></trkpt>"#,
+                47.9 + jitter,
+                8.5 + i as f64 * 0.001
+            ));
+        }
+        trkpts.push_str(r#"<trkpt lat="48.0" lon="8.6"></trkpt>"#);
+        let gpx = format!(
+            r#"<?xml version="1.0"?><gpx version="1.1" creator="test"><trk><trkseg>{}</trkseg></trk></gpx>"#,
+            trkpts
+        );
+
+        let track = Track::from_gpx(gpx.as_bytes(), false).unwrap();
+        assert_eq!(track.point_count(), 102);
+        let simplified = track.clone().simplified(10.0);
+        assert_eq!(simplified.point_count(), 3);
+        assert_eq!(simplified.positions()[0].len(), 3);
+        assert_eq!(simplified.tiles.len(), track.tiles.len());
+
+        let beside = Coordinate {
+            lat: 47.9005,
+            lon: 8.55,
+        };
+        assert!(track.near(&beside).abs_diff(simplified.near(&beside)) <= 10);
+        assert_eq!(track.clone().simplified(0.0).point_count(), 102);
+    }
+
+    #[test]
+    fn merges_tracks_routes_and_waypoints() {
+        let gpx = r#"<?xml version="1.0"?>
+<gpx version="1.1" creator="test">
+  <wpt lat="47.95" lon="8.40"></wpt>
+  <wpt lat="47.95" lon="8.45"></wpt>
+  <rte><rtept lat="47.90" lon="8.50"></rtept><rtept lat="47.90" lon="8.55"></rtept></rte>
+  <trk><trkseg><trkpt lat="47.80" lon="8.50"></trkpt><trkpt lat="47.80" lon="8.55"></trkpt></trkseg></trk>
+</gpx>"#;
+        let on_route = Coordinate {
+            lat: 47.9,
+            lon: 8.52,
+        };
+        let between_waypoints = Coordinate {
+            lat: 47.95,
+            lon: 8.42,
+        };
+        // the gap between the route and the track is not part of the track
+        let between_lines = Coordinate {
+            lat: 47.85,
+            lon: 8.5,
+        };
+
+        assert_eq!(TrackFormat::sniff(gpx.as_bytes()), TrackFormat::Gpx);
+        let track = Track::from_gpx(gpx.as_bytes(), false).unwrap();
+        assert_eq!(
+            track.report,
+            TrackReport {
+                format: TrackFormat::Gpx,
+                tracks: 1,
+                routes: 1,
+                waypoints: 2,
+                waypoints_used: false,
+                points: 4,
+            }
+        );
+        assert_eq!(
+            track.report.to_string(),
+            "GPX with 1 tracks, 1 routes, 2 waypoints (ignored), 4 points"
+        );
+        assert!(track.near(&on_route) < 10);
+        assert!(track.near(&between_waypoints) > 1000);
+        assert!(track.near(&between_lines) > 1000);
+
+        let track = Track::from_gpx(gpx.as_bytes(), true).unwrap();
+        assert_eq!(track.report.points, 6);
+        assert!(track.near(&between_waypoints) < 10);
+    }
+
+    #[test]
+    fn from_kml() {
+        let kml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<kml xmlns="http://www.opengis.net/kml/2.2">
+  <Document>
+    <Placemark><Point><coordinates>8.40,47.95,0</coordinates></Point></Placemark>
+    <Placemark>
+      <LineString>
+        <coordinates>
+          8.50,47.90,0 8.55,47.90,0
+        </coordinates>
+      </LineString>
+    </Placemark>
+  </Document>
+</kml>"#;
+        assert_eq!(TrackFormat::sniff(kml.as_bytes()), TrackFormat::Kml);
+        let track = Track::parse(kml.as_bytes(), TrackFormat::Kml, false).unwrap();
+        assert_eq!(track.report.format, TrackFormat::Kml);
+        assert_eq!(track.report.tracks, 1);
+        assert_eq!(track.report.points, 2);
+        assert!(
+            track.near(&Coordinate {
+                lat: 47.9,
+                lon: 8.52
+            }) < 10
+        );
+        assert!(Track::from_kml(
+            r#"<kml><LineString><coordinates>8.5;47.9</coordinates></LineString></kml>"#.as_bytes()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn from_geojson() {
+        let geojson = r#"
+{"type": "FeatureCollection", "features": [
+  {"type": "Feature", "properties": {}, "geometry": {"type": "Point", "coordinates": [8.40, 47.95]}},
+  {"type": "Feature", "properties": {}, "geometry": {"type": "LineString", "coordinates": [[8.50, 47.90], [8.55, 47.90]]}},
+  {"type": "Feature", "properties": {}, "geometry": {"type": "MultiLineString", "coordinates": [[[8.50, 47.80], [8.55, 47.80, 500]]]}}
+]}"#;
+        assert_eq!(TrackFormat::sniff(geojson.as_bytes()), TrackFormat::GeoJson);
+        assert_eq!(
+            TrackFormat::from_media_type("application", "geo+json"),
+            Some(TrackFormat::GeoJson)
+        );
+        let track = Track::parse(geojson.as_bytes(), TrackFormat::GeoJson, false).unwrap();
+        assert_eq!(track.report.tracks, 2);
+        assert_eq!(track.report.points, 4);
+        assert!(
+            track.near(&Coordinate {
+                lat: 47.8,
+                lon: 8.52
+            }) < 10
+        );
+        assert!(Track::from_geojson("[").is_err());
+    }
+
+    #[test]
+    fn invalid_uploads() {
+        let broken = "<?xml version=\"1.0\"?>\n<gpx version=\"1.1\" creator=\"test\">\n<trk><trkseg>\n<trkpt lat=\"47.9\" lon=8.5></trkpt>\n</gpx>";
+        match Track::from_gpx(broken.as_bytes(), false) {
+            Err(TrackError::ParseError {
+                format: TrackFormat::Gpx,
+                line: Some(4),
+                ..
+            }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let empty = r#"<?xml version="1.0"?><gpx version="1.1" creator="test"><wpt lat="47.9" lon="8.5"></wpt></gpx>"#;
+        let e = Track::from_gpx(empty.as_bytes(), false).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "no track, route or line found in the GPX file"
+        );
+        assert!(Track::from_gpx(empty.as_bytes(), true).is_ok());
+        assert!(matches!(
+            Track::parse(b"\xff{", TrackFormat::GeoJson, false),
+            Err(TrackError::ParseError { line: None, .. })
+        ));
+    }
+
+    fn gpx_with_times(days: &[&[(f64, &str)]]) -> String {
+        let mut trks = String::new();
+        for day in days {
+            trks.push_str("<trk><trkseg>");
+            for (lon, time) in day.iter() {
+                trks.push_str(&format!(
+                    r#"<trkpt lat="47.9" lon="{}"><time>{}</time></trkpt>"#,
+                    lon, time
+                ));
+            }
+            trks.push_str("</trkseg></trk>");
+        }
+        format!(
+            r#"<?xml version="1.0"?><gpx version="1.1" creator="test">{}</gpx>"#,
+            trks
+        )
+    }
+
+    #[test]
+    fn split_by_time_gap() {
+        let gpx = gpx_with_times(&[
+            &[
+                (8.50, "2024-06-01T08:00:00Z"),
+                (8.55, "2024-06-01T12:00:00Z"),
+            ],
+            &[
+                (8.55, "2024-06-01T16:00:00Z"),
+                (8.60, "2024-06-01T18:00:00Z"),
+            ],
+            &[
+                (8.60, "2024-06-02T08:00:00Z"),
+                (8.65, "2024-06-02T12:00:00Z"),
+            ],
+        ]);
+        let track = Track::from_gpx(gpx.as_bytes(), false).unwrap();
+        let legs = track.split_by_time_gap(Duration::hours(6));
+        assert_eq!(legs.len(), 2);
+        assert_eq!(legs[0].report.points, 4);
+        assert_eq!(legs[0].report.tracks, 2);
+        assert_eq!(
+            legs[1].start_time().unwrap().to_rfc3339(),
+            "2024-06-02T08:00:00+00:00"
+        );
+
+        let untimed = Track::from_gpx(
+            r#"<?xml version="1.0"?><gpx version="1.1" creator="test"><trk><trkseg><trkpt lat="47.9" lon="8.5"></trkpt><trkpt lat="47.9" lon="8.6"></trkpt></trkseg></trk></gpx>"#.as_bytes(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(untimed.split_by_time_gap(Duration::hours(6)).len(), 1);
+    }
+
+    #[test]
+    fn split_by_distance() {
+        // 0.01 degrees of longitude are about 750 m at 47.9 N
+        let points: Vec<(f64, &str)> = (0..=20)
+            .map(|i| (8.5 + i as f64 * 0.01, "2024-06-01T08:00:00Z"))
+            .collect();
+        let track = Track::from_gpx(gpx_with_times(&[&points]).as_bytes(), false).unwrap();
+        let legs = track.split_by_distance(5.0);
+        assert_eq!(legs.len(), 3);
+        // legs connect
+        assert_eq!(legs[0].waypoints.last(), legs[1].waypoints.first());
+        assert_eq!(legs[0].report.points, 8);
+        assert_eq!(
+            legs.iter().map(|leg| leg.report.points).sum::<usize>(),
+            21 + 2
+        );
+    }
+
+    #[test]
+    fn reverse_and_loop() {
+        let gpx = gpx_with_times(&[
+            &[
+                (8.50, "2024-06-01T08:00:00Z"),
+                (8.55, "2024-06-01T09:00:00Z"),
+            ],
+            &[
+                (8.55, "2024-06-01T10:00:00Z"),
+                (8.501, "2024-06-01T11:00:00Z"),
+            ],
+        ]);
+        let track = Track::from_gpx(gpx.as_bytes(), false).unwrap();
+        assert!(track.is_loop(100.0));
+        assert!(!track.is_loop(10.0));
+        assert!((track.length() - 7400.0).abs() < 100.0);
+        assert_eq!(track.distance_along(&track.waypoints[0]), Some(0.0));
+        let end = track.distance_along(track.waypoints.last().unwrap());
+        assert!((end.unwrap() - track.length()).abs() < 1.0);
+
+        let reversed = track.reversed();
+        assert_eq!(reversed.waypoints.first(), track.waypoints.last());
+        assert_eq!(reversed.waypoints.last(), track.waypoints.first());
+        assert_eq!(
+            reversed.start_time().unwrap().to_rfc3339(),
+            "2024-06-01T11:00:00+00:00"
+        );
+        assert_eq!(reversed.report, track.report);
+        assert_eq!(
+            reversed.split_by_distance(1.0).len(),
+            track.split_by_distance(1.0).len()
+        );
+        assert_eq!(reversed.split_by_time_gap(Duration::minutes(90)).len(), 1);
+        assert_eq!(reversed.split_by_time_gap(Duration::minutes(30)).len(), 4);
+    }
+
+    #[test]
+    fn elevation() {
+        let gpx = r#"<?xml version="1.0"?><gpx version="1.1" creator="test"><trk><trkseg>
+<trkpt lat="47.9" lon="8.50"><ele>500</ele></trkpt>
+<trkpt lat="47.9" lon="8.51"><ele>502</ele></trkpt>
+<trkpt lat="47.9" lon="8.52"><ele>501</ele></trkpt>
+<trkpt lat="47.9" lon="8.53"><ele>600</ele></trkpt>
+<trkpt lat="47.9" lon="8.54"><ele>550</ele></trkpt>
+<trkpt lat="47.9" lon="8.55"><ele>580</ele></trkpt>
+</trkseg></trk></gpx>"#;
+        let track = Track::from_gpx(gpx.as_bytes(), false).unwrap();
+        assert_eq!(track.ascent(), Some(130.0));
+        assert_eq!(
+            track.elevation_at(&Coordinate {
+                lat: 47.901,
+                lon: 8.531
+            }),
+            Some(600.0)
+        );
+
+        let kml =
+            "<kml><LineString><coordinates>8.5,47.9,0 8.6,47.9,0</coordinates></LineString></kml>";
+        let flat = Track::from_kml(kml.as_bytes()).unwrap();
+        assert_eq!(flat.ascent(), None);
+        assert_eq!(
+            flat.elevation_at(&Coordinate {
+                lat: 47.9,
+                lon: 8.5
+            }),
+            None
+        );
+    }
+}
@@ -1,19 +1,210 @@
-use std::collections::HashMap;
+use std::cmp::{self, Reverse};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::panic::AssertUnwindSafe;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
-use crate::gc::groundspeak::GcCode;
-use crate::gcgeo::{Geocache, Tile};
+use chrono::{DateTime, Utc};
+use futures::future::ready;
+use futures::FutureExt;
+use futures::{stream, StreamExt};
+use rocket::serde::Serialize;
+use tokio::sync::Notify;
+use tokio::task::{AbortHandle, JoinHandle};
+
+use crate::gc::groundspeak::{GcCode, BATCH_SIZE};
+use crate::gc::{ApiCalls, ApiUsage, Error, JobRecord};
+use crate::gcgeo::{BBox, Geocache, GeocacheFilter, Region, Tile};
+use crate::notify::{Callback, Notifier};
 use crate::Cache;
 
+/// Identical requests within this many minutes get the existing job instead of a new one
+const REUSE_MINUTES: i64 = 60;
+
 pub struct JobQueue {
     jobs: Mutex<HashMap<String, Arc<Job>>>,
+    /// the spawned work of each job, aborted when the job is removed
+    tasks: Mutex<HashMap<String, AbortHandle>>,
+    slots: Arc<Slots>,
+    notifier: Arc<Notifier>,
+}
+
+/// The slot of a spawned job, given back (or the place in the queue given up) when the work is
+/// done or aborted.
+struct SlotGuard {
+    slots: Arc<Slots>,
+    id: String,
+    acquired: bool,
+}
+
+impl SlotGuard {
+    async fn acquire(slots: Arc<Slots>, id: &str) -> Self {
+        let mut guard = Self {
+            slots,
+            id: id.to_string(),
+            acquired: false,
+        };
+        guard.slots.acquire(id).await;
+        guard.acquired = true;
+        guard
+    }
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        if self.acquired {
+            self.slots.release();
+        } else {
+            self.slots.dequeue(&self.id);
+        }
+    }
+}
+
+/// Hands out the slots for running jobs, by priority and then in the order jobs were spawned.
+struct Slots {
+    state: Mutex<SlotState>,
+    /// signalled whenever a slot is taken or given back, waiters check whether it's their turn
+    changed: Notify,
+}
+
+struct SlotState {
+    free: usize,
+    /// priority, sequence number and id of the jobs waiting for a slot, next first
+    pending: Vec<(Priority, u64, String)>,
+    next_seq: u64,
+}
+
+impl Slots {
+    fn new(slots: usize) -> Self {
+        Self {
+            state: Mutex::new(SlotState {
+                free: slots,
+                pending: vec![],
+                next_seq: 0,
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    fn enqueue(&self, id: &str, priority: Priority) {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.pending.push((priority, seq, id.to_string()));
+        state.pending.sort();
+    }
+
+    /// Wait until the job is next in line and a slot is free.
+    async fn acquire(&self, id: &str) {
+        loop {
+            // registered before checking, so a release in between isn't missed
+            let changed = self.changed.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.free > 0 && state.pending.first().is_some_and(|(_, _, next)| next == id) {
+                    state.free -= 1;
+                    state.pending.remove(0);
+                    drop(state);
+                    // the next job may get another free slot
+                    self.changed.notify_waiters();
+                    return;
+                }
+            }
+            changed.await;
+        }
+    }
+
+    fn release(&self) {
+        self.state.lock().unwrap().free += 1;
+        self.changed.notify_waiters();
+    }
+
+    /// Drop a job that is still waiting, the one behind it may be next now.
+    fn dequeue(&self, id: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .pending
+            .retain(|(_, _, pending)| pending != id);
+        self.changed.notify_waiters();
+    }
+
+    /// 1 is next
+    fn position(&self, id: &str) -> Option<usize> {
+        self.state
+            .lock()
+            .unwrap()
+            .pending
+            .iter()
+            .position(|(_, _, pending)| pending == id)
+            .map(|index| index + 1)
+    }
 }
 
 impl JobQueue {
-    pub fn new() -> Self {
+    pub fn new(max_parallel: usize, notifier: Notifier) -> Self {
         Self {
             jobs: Mutex::new(HashMap::new()),
+            tasks: Mutex::new(HashMap::new()),
+            slots: Arc::new(Slots::new(max_parallel.max(1))),
+            notifier: Arc::new(notifier),
+        }
+    }
+
+    /// Run the work of a job once a slot is free and call its callback when done. Slots go to
+    /// the waiting job with the highest priority, the oldest one first. A panic in the work fails
+    /// the job, in memory only: after a restart it shows as interrupted.
+    pub fn spawn<F>(&self, job: Arc<Job>, work: F) -> JoinHandle<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.slots.enqueue(&job.id, job.priority);
+        let slots = self.slots.clone();
+        let notifier = self.notifier.clone();
+        let id = job.id.clone();
+        let handle = tokio::task::spawn(async move {
+            let slot = SlotGuard::acquire(slots, &job.id).await;
+            let result = AssertUnwindSafe(work).catch_unwind().await;
+            drop(slot);
+            if let Err(panic) = result {
+                let reason = panic
+                    .downcast_ref::<&str>()
+                    .map(|reason| reason.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                job.fail(vec![reason]);
+            }
+            notifier.notify(&job, &job.status()).await;
+        });
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|_, task| !task.is_finished());
+        tasks.insert(id, handle.abort_handle());
+        handle
+    }
+
+    /// Forget the job, stopping its work if it's still queued or running.
+    pub fn remove(&self, id: &str) -> Option<Arc<Job>> {
+        if let Some(task) = self.tasks.lock().unwrap().remove(id) {
+            task.abort();
+        }
+        self.jobs.lock().unwrap().remove(id)
+    }
+
+    /// The job's status including its place in the queue, 1 is next.
+    pub fn status(&self, job: &Job) -> JobStatus {
+        let mut status = job.status();
+        status.queue_position = self.slots.position(&job.id);
+        if let Some(position) = status.queue_position {
+            status.message = format!(
+                "Waiting for a free slot, position {} in the queue",
+                position
+            );
         }
+        status
     }
 
     pub fn add(&self, job: Arc<Job>) {
@@ -27,40 +218,658 @@ impl JobQueue {
     pub fn list(&self) -> Vec<Arc<Job>> {
         self.jobs.lock().unwrap().values().cloned().collect()
     }
+
+    /// The jobs of one user, or those nobody owns.
+    pub fn list_for(&self, owner: Option<&str>) -> Vec<Arc<Job>> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| job.owner.as_deref() == owner)
+            .cloned()
+            .collect()
+    }
+
+    /// Number of jobs the user started since then.
+    pub fn started_since(&self, owner: &str, since: DateTime<Utc>) -> usize {
+        self.list_for(Some(owner))
+            .iter()
+            .filter(|job| job.status().started_at >= since)
+            .count()
+    }
+
+    /// The newest job of the same user for the same input started within the last
+    /// REUSE_MINUTES, running or finished.
+    pub fn find_identical(&self, key: InputKey, owner: Option<&str>) -> Option<Arc<Job>> {
+        let since = Utc::now() - chrono::Duration::minutes(REUSE_MINUTES);
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| job.input_key == Some(key) && job.owner.as_deref() == owner)
+            .filter(|job| !job.has_failed() && !job.state.lock().unwrap().refined)
+            .map(|job| (job.status().started_at, job))
+            .filter(|(started_at, _)| *started_at >= since)
+            .max_by_key(|(started_at, _)| *started_at)
+            .map(|(_, job)| job.clone())
+    }
+
+    /// The job from memory, or as it was saved before the last restart.
+    pub async fn restore(&self, id: &str, cache: &Cache) -> Result<Option<Arc<Job>>, Error> {
+        if let Some(job) = self.get(id) {
+            return Ok(Some(job));
+        }
+        let Some((record, geocaches)) = cache.load_job(id).await? else {
+            return Ok(None);
+        };
+        info!("Restoring job {} with {} geocaches", id, geocaches.len());
+        let job = Arc::new(Job::restore(record, geocaches));
+        self.add(job.clone());
+        Ok(Some(job))
+    }
+}
+
+/// What went wrong and why, outermost first.
+fn error_chain(context: String, error: &dyn std::error::Error) -> Vec<String> {
+    let mut chain = vec![context, error.to_string()];
+    let mut source = error.source();
+    while let Some(error) = source {
+        chain.push(error.to_string());
+        source = error.source();
+    }
+    chain
+}
+
+/// Pre-filter that skips codes whose approximate position is outside the region, before fetching them.
+pub fn approx_within<R: Region>(region: R) -> impl Fn(&GcCode) -> bool {
+    move |code| {
+        code.approx_coord
+            .as_ref()
+            .is_none_or(|coord| region.contains(coord))
+    }
+}
+
+/// Result set captured when a job finished. Exports are rendered from this rather than the live
+/// cache, so a background refresh can't mix data of different ages into one export.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub ts: DateTime<Utc>,
+    pub geocaches: Vec<Geocache>,
+    pub legs: Vec<Leg>,
+    /// total climb of the track in meters
+    pub ascent: Option<f64>,
+    /// elevation of the track point closest to each geocache, by code
+    pub elevations: HashMap<String, f64>,
+    /// meters along the track to the point closest to each geocache, by code
+    pub distances: HashMap<String, f64>,
+    /// meters from the track or the area center to each geocache, by code
+    pub offsets: HashMap<String, f64>,
+    /// lines of the track the job was computed for, as [lon, lat] positions
+    pub track: Vec<Vec<[f64; 2]>>,
+}
+
+/// Part of a job's result that can be downloaded on its own, e.g. one day of a multi-day tour.
+/// Geocaches close to where two legs meet belong to both.
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Leg {
+    pub name: String,
+    pub codes: Vec<String>,
+}
+
+impl Snapshot {
+    /// Geocaches that aren't the result of a job, e.g. looked up by code.
+    pub fn of(geocaches: Vec<Geocache>, ts: DateTime<Utc>) -> Self {
+        Self {
+            ts,
+            geocaches,
+            legs: vec![],
+            ascent: None,
+            elevations: HashMap::new(),
+            distances: HashMap::new(),
+            offsets: HashMap::new(),
+            track: vec![],
+        }
+    }
+
+    /// Only the geocaches of one leg, None if there is no such leg.
+    pub fn leg(self, index: usize) -> Option<Snapshot> {
+        let leg = self.legs.get(index)?;
+        let codes: HashSet<&String> = leg.codes.iter().collect();
+        let geocaches = self
+            .geocaches
+            .iter()
+            .filter(|gc| codes.contains(&gc.code))
+            .cloned()
+            .collect();
+        Some(Snapshot {
+            ts: self.ts,
+            geocaches,
+            legs: vec![],
+            ascent: None,
+            elevations: self.elevations,
+            distances: self.distances,
+            offsets: self.offsets,
+            track: vec![],
+        })
+    }
+}
+
+/// Order of the geocaches in a rendered result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    /// closest to the track or area center first
+    Distance,
+    /// most favorite points first
+    Favorites,
+    /// easiest first, by difficulty and then terrain
+    Difficulty,
+    /// along the track from its start
+    RouteOrder,
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "distance" => Ok(Self::Distance),
+            "favorites" => Ok(Self::Favorites),
+            "difficulty" => Ok(Self::Difficulty),
+            "route_order" => Ok(Self::RouteOrder),
+            _ => Err(format!("unknown sort order {}", s)),
+        }
+    }
+}
+
+impl SortOrder {
+    /// Sort in place, geocaches without a distance go last.
+    pub fn sort(
+        &self,
+        geocaches: &mut [Geocache],
+        distances: &HashMap<String, f64>,
+        offsets: &HashMap<String, f64>,
+    ) {
+        let by_meters = |meters: &HashMap<String, f64>, a: &Geocache, b: &Geocache| match (
+            meters.get(&a.code),
+            meters.get(&b.code),
+        ) {
+            (Some(a), Some(b)) => a.total_cmp(b),
+            (Some(_), None) => cmp::Ordering::Less,
+            (None, Some(_)) => cmp::Ordering::Greater,
+            (None, None) => cmp::Ordering::Equal,
+        };
+        match self {
+            Self::Distance => geocaches.sort_by(|a, b| by_meters(offsets, a, b)),
+            Self::RouteOrder => geocaches.sort_by(|a, b| by_meters(distances, a, b)),
+            Self::Favorites => geocaches.sort_by_key(|gc| Reverse(gc.favorite_points)),
+            Self::Difficulty => geocaches.sort_by(|a, b| {
+                a.difficulty
+                    .total_cmp(&b.difficulty)
+                    .then(a.terrain.total_cmp(&b.terrain))
+            }),
+        }
+    }
+}
+
+/// Codes the user explicitly wants in or out of a job's result, regardless of filters.
+#[derive(Debug, Clone, Default)]
+pub struct CodeSelection {
+    pub include: Vec<String>,
+    pub exclude: HashSet<String>,
+}
+
+impl CodeSelection {
+    /// Build from comma separated lists, e.g. "GC1234, gc5678".
+    pub fn parse(include: Option<&str>, exclude: Option<&str>) -> Self {
+        let split = |codes: Option<&str>| {
+            codes
+                .unwrap_or_default()
+                .split(',')
+                .map(|code| code.trim().to_ascii_uppercase())
+                .filter(|code| !code.is_empty())
+                .collect::<Vec<String>>()
+        };
+        let mut seen = HashSet::new();
+        let mut include = split(include);
+        include.retain(|code| seen.insert(code.clone()));
+        Self {
+            include,
+            exclude: split(exclude).into_iter().collect(),
+        }
+    }
+
+    fn is_included(&self, code: &str) -> bool {
+        self.include.iter().any(|included| included == code)
+    }
+}
+
+/// Which waiting job gets the next free slot. By default small jobs go first, so refreshing a
+/// few geocaches doesn't wait for a huge area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    /// Jobs covering up to this many tiles are small
+    const SMALL_TILES: usize = 16;
+    /// Jobs covering more than this many tiles are large
+    const LARGE_TILES: usize = 256;
+
+    pub fn for_tiles(tiles: usize) -> Self {
+        if tiles <= Self::SMALL_TILES {
+            Self::High
+        } else if tiles <= Self::LARGE_TILES {
+            Self::Normal
+        } else {
+            Self::Low
+        }
+    }
+}
+
+/// high, normal or low
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "high" => Ok(Self::High),
+            "normal" => Ok(Self::Normal),
+            "low" => Ok(Self::Low),
+            _ => Err(format!("unknown priority {}", s)),
+        }
+    }
+}
+
+/// Fingerprint of a job's input, identical requests get the same key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputKey(u64);
+
+impl InputKey {
+    /// Positions are rounded to about 10 cm and the codes are compared regardless of order.
+    /// Everything else that changes the result goes into options.
+    pub fn new(
+        kind: &str,
+        positions: &[Vec<[f64; 2]>],
+        options: &str,
+        selection: &CodeSelection,
+        filter: &GeocacheFilter,
+    ) -> Self {
+        let mut hasher = DefaultHasher::new();
+        kind.hash(&mut hasher);
+        for line in positions {
+            line.len().hash(&mut hasher);
+            for value in line.iter().flatten() {
+                ((value * 1e6).round() as i64).hash(&mut hasher);
+            }
+        }
+        options.hash(&mut hasher);
+        let mut include = selection.include.clone();
+        include.sort();
+        include.hash(&mut hasher);
+        let mut exclude: Vec<&String> = selection.exclude.iter().collect();
+        exclude.sort();
+        exclude.hash(&mut hasher);
+        format!("{:?}", filter.normalized()).hash(&mut hasher);
+        Self(hasher.finish())
+    }
+}
+
+/// What a job is busy with. Discovery and fetching overlap, the job counts as fetching once all
+/// tiles are discovered.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Phase {
+    Queued,
+    Discover,
+    Fetch,
+    Purge,
+    Finished,
+    /// still running when the server restarted
+    Interrupted,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+struct Progress {
+    phase: Phase,
+    /// tiles discovered or geocaches fetched, depending on the phase
+    current: usize,
+    total: usize,
+    started_at: DateTime<Utc>,
+    phase_started_at: DateTime<Utc>,
+}
+
+impl Progress {
+    fn new(phase: Phase) -> Self {
+        let now = Utc::now();
+        Self {
+            phase,
+            current: 0,
+            total: 0,
+            started_at: now,
+            phase_started_at: now,
+        }
+    }
+
+    fn percent(&self) -> Option<f64> {
+        (self.total > 0).then(|| 100.0 * self.current as f64 / self.total as f64)
+    }
+
+    /// End of the current phase if it keeps the pace it had so far.
+    fn eta(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if self.current == 0 || self.current > self.total {
+            return None;
+        }
+        let elapsed = (now - self.phase_started_at).num_milliseconds() as f64;
+        let remaining = elapsed * (self.total - self.current) as f64 / self.current as f64;
+        Some(now + chrono::Duration::milliseconds(remaining as i64))
+    }
+}
+
+/// Progress of a job as reported to API clients.
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct JobStatus {
+    pub id: String,
+    pub message: String,
+    pub phase: Phase,
+    pub current: usize,
+    pub total: usize,
+    pub percent: Option<f64>,
+    pub started_at: DateTime<Utc>,
+    pub eta: Option<DateTime<Utc>>,
+    /// waiting for a free slot, 1 is next
+    pub queue_position: Option<usize>,
+    /// why the job failed, outermost error first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub error: Vec<String>,
+    /// Groundspeak calls the job made so far
+    pub api_calls: ApiCalls,
+    pub priority: Priority,
 }
 
 pub struct Job {
     pub id: String,
+    /// what the job was computed for, e.g. route or area, names its downloads
+    pub kind: String,
+    selection: CodeSelection,
+    legs: Vec<(String, Box<dyn Region>)>,
+    ascent: Option<f64>,
+    track: Vec<Vec<[f64; 2]>>,
+    callback: Option<Callback>,
+    input_key: Option<InputKey>,
+    priority: Priority,
+    /// name of the user who requested the job, None without API keys
+    pub owner: Option<String>,
+    usage: ApiUsage,
     state: Mutex<JobState>,
 }
 
 struct JobState {
     message: String,
+    progress: Progress,
     geocaches: Vec<Geocache>,
+    /// codes of all geocaches in the region, whether the filter selected them or not
+    candidates: Vec<String>,
+    filter: GeocacheFilter,
+    elevations: HashMap<String, f64>,
+    distances: HashMap<String, f64>,
+    /// meters from the track or area center, by code
+    offsets: HashMap<String, f64>,
+    degraded: bool,
+    /// selected again with another filter, the result no longer matches the input key
+    refined: bool,
+    finished: Option<DateTime<Utc>>,
+    /// the candidates in each leg
+    legs: Vec<Leg>,
+    /// why the job failed, outermost error first
+    error: Vec<String>,
 }
 
 impl JobState {
     fn new() -> Self {
         Self {
             message: String::new(),
+            progress: Progress::new(Phase::Queued),
             geocaches: Vec::new(),
+            candidates: Vec::new(),
+            filter: GeocacheFilter::default(),
+            elevations: HashMap::new(),
+            distances: HashMap::new(),
+            offsets: HashMap::new(),
+            degraded: false,
+            refined: false,
+            finished: None,
+            legs: Vec::new(),
+            error: Vec::new(),
         }
     }
 }
 
 impl Job {
+    /// Number of tiles discovered in parallel
+    const DISCOVER_CONCURRENCY: usize = 4;
+    /// Kind of jobs that don't tell, and of jobs saved before there were kinds
+    const DEFAULT_KIND: &'static str = "geocaches";
+
     pub fn new() -> Self {
+        Self::with_selection(CodeSelection::default())
+    }
+
+    pub fn with_selection(selection: CodeSelection) -> Self {
         Self {
             id: uuid::Uuid::new_v4().to_string(),
+            kind: Self::DEFAULT_KIND.to_string(),
+            selection,
+            legs: vec![],
+            ascent: None,
+            track: vec![],
+            callback: None,
+            input_key: None,
+            priority: Priority::Normal,
+            owner: None,
+            usage: ApiUsage::default(),
             state: Mutex::new(JobState::new()),
         }
     }
 
-    pub async fn process(&self, tiles: Vec<Tile>, cache: &Cache) {
-        self.process_filtered(tiles, cache, |_| true, |_| true)
-            .await;
+    /// A job saved before a restart. Jobs that were still running then are reported as
+    /// interrupted, they have to be started again.
+    pub fn restore(record: JobRecord, geocaches: Vec<Geocache>) -> Self {
+        let (message, phase) = match record.finished {
+            Some(_) => (record.message, Phase::Finished),
+            None if !record.error.is_empty() => (record.message, Phase::Failed),
+            None => (
+                format!(
+                    "Interrupted by a restart ({}), please start the job again",
+                    record.message
+                ),
+                Phase::Interrupted,
+            ),
+        };
+        // records from before refining was possible only know the selected codes
+        let candidates = if record.candidates.is_empty() {
+            record.codes
+        } else {
+            record.candidates
+        };
+        let state = JobState {
+            message,
+            progress: Progress::new(phase),
+            geocaches,
+            candidates,
+            filter: record.filter,
+            elevations: record.elevations,
+            distances: record.distances,
+            offsets: record.offsets,
+            degraded: record.degraded,
+            refined: false,
+            finished: record.finished,
+            legs: record
+                .legs
+                .into_iter()
+                .map(|(name, codes)| Leg { name, codes })
+                .collect(),
+            error: record.error,
+        };
+        Self {
+            id: record.id,
+            kind: record
+                .kind
+                .unwrap_or_else(|| Self::DEFAULT_KIND.to_string()),
+            selection: CodeSelection {
+                include: record.include_codes,
+                exclude: record.exclude_codes.into_iter().collect(),
+            },
+            legs: vec![],
+            ascent: record.ascent,
+            track: record.track,
+            callback: None,
+            input_key: None,
+            priority: Priority::Normal,
+            owner: record.owner,
+            usage: record.api_calls.into(),
+            state: Mutex::new(state),
+        }
+    }
+
+    /// What is saved to survive a restart, the geocaches only by code.
+    pub fn record(&self) -> JobRecord {
+        let state = self.state.lock().unwrap();
+        JobRecord {
+            id: self.id.clone(),
+            kind: Some(self.kind.clone()),
+            message: state.message.clone(),
+            finished: state.finished,
+            degraded: state.degraded,
+            codes: state.geocaches.iter().map(|gc| gc.code.clone()).collect(),
+            candidates: state.candidates.clone(),
+            filter: state.filter.clone(),
+            include_codes: self.selection.include.clone(),
+            exclude_codes: {
+                let mut codes: Vec<String> = self.selection.exclude.iter().cloned().collect();
+                codes.sort();
+                codes
+            },
+            legs: state
+                .legs
+                .iter()
+                .map(|leg| (leg.name.clone(), leg.codes.clone()))
+                .collect(),
+            ascent: self.ascent,
+            elevations: state.elevations.clone(),
+            distances: state.distances.clone(),
+            offsets: state.offsets.clone(),
+            track: self.track.clone(),
+            error: state.error.clone(),
+            api_calls: self.usage.calls(),
+            owner: self.owner.clone(),
+        }
+    }
+
+    async fn save(&self, cache: &Cache) {
+        if let Err(e) = cache.save_job(&self.record()).await {
+            error!("Unable to save job {}: {}", self.id, e);
+        }
+    }
+
+    /// Group the result into named legs, each geocache goes into every leg region containing it.
+    pub fn with_legs(mut self, legs: Vec<(String, Box<dyn Region>)>) -> Self {
+        self.state.get_mut().unwrap().legs = legs
+            .iter()
+            .map(|(name, _)| Leg {
+                name: name.clone(),
+                codes: vec![],
+            })
+            .collect();
+        Self { legs, ..self }
+    }
+
+    /// Only geocaches matching the filter make it into the result, unless explicitly included.
+    pub fn with_filter(mut self, filter: GeocacheFilter) -> Self {
+        self.state.get_mut().unwrap().filter = filter;
+        self
+    }
+
+    pub fn with_ascent(self, ascent: Option<f64>) -> Self {
+        Self { ascent, ..self }
+    }
+
+    pub fn with_track(self, track: Vec<Vec<[f64; 2]>>) -> Self {
+        Self { track, ..self }
+    }
+
+    /// Report the result here once the job is done.
+    pub fn with_callback(self, callback: Option<Callback>) -> Self {
+        Self { callback, ..self }
+    }
+
+    pub fn callback(&self) -> Option<&Callback> {
+        self.callback.as_ref()
+    }
+
+    pub fn with_priority(self, priority: Priority) -> Self {
+        Self { priority, ..self }
+    }
+
+    pub fn with_owner(self, owner: Option<String>) -> Self {
+        Self { owner, ..self }
+    }
+
+    pub fn with_kind(self, kind: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            ..self
+        }
+    }
+
+    /// Name for downloads of the snapshot without the extension, e.g. route-2024-06-01
+    pub fn file_stem(&self, snapshot: &Snapshot) -> String {
+        format!("{}-{}", self.kind, snapshot.ts.format("%Y-%m-%d"))
+    }
+
+    /// Lets identical requests find this job, see JobQueue::find_identical.
+    pub fn with_input_key(self, input_key: InputKey) -> Self {
+        Self {
+            input_key: Some(input_key),
+            ..self
+        }
+    }
+
+    /// Remember the track's elevation next to a geocache, called from the post filter.
+    pub fn set_elevation(&self, code: &str, elevation: f64) {
+        self.state
+            .lock()
+            .unwrap()
+            .elevations
+            .insert(code.to_string(), elevation);
+    }
+
+    /// Remember how far a geocache is from the track or area center, called from the post filter.
+    pub fn set_offset(&self, code: &str, offset: f64) {
+        self.state
+            .lock()
+            .unwrap()
+            .offsets
+            .insert(code.to_string(), offset);
     }
 
+    /// Remember how far along the track a geocache is, called from the post filter.
+    pub fn set_distance(&self, code: &str, distance: f64) {
+        self.state
+            .lock()
+            .unwrap()
+            .distances
+            .insert(code.to_string(), distance);
+    }
+
+    /// Discover tiles and fetch geocaches as a stream, so codes are fetched as soon as their tile
+    /// is discovered and results accumulate while the job is still running.
     pub async fn process_filtered<PRE, POST>(
         &self,
         tiles: Vec<Tile>,
@@ -72,55 +881,659 @@ impl Job {
         POST: Fn(&Geocache) -> bool,
     {
         info!("Processing job {}", self.id);
-        let mut codes: Vec<String> = Vec::new();
+        self.save(cache).await;
         let tile_len = tiles.len();
-        for (index, tile) in tiles.iter().enumerate() {
+        self.set_progress(Phase::Discover, 0, tile_len);
+        let found = AtomicUsize::new(0);
+        let discovery_only = cache.is_discovery_only().await.unwrap_or(false);
+        let mut seen: HashSet<String> = HashSet::new();
+        let included: Vec<GcCode> = self
+            .selection
+            .include
+            .iter()
+            .map(|code| GcCode {
+                code: code.clone(),
+                approx_coord: None,
+            })
+            .collect();
+        let codes = stream::iter(tiles)
+            .map(|tile| async move {
+                let result = cache.discover(&tile, &self.usage).await;
+                (tile, result)
+            })
+            .buffer_unordered(Self::DISCOVER_CONCURRENCY)
+            .enumerate()
+            .flat_map(|(index, (tile, result))| {
+                self.set_message(&format!(
+                    "Discovered tile {}/{}: {}",
+                    index + 1,
+                    tile_len,
+                    tile
+                ));
+                self.set_progress(Phase::Discover, index + 1, tile_len);
+                let codes = match result {
+                    Ok(codes) => {
+                        debug!("Tile {} as of {}", tile, codes.ts);
+                        codes.data
+                    }
+                    Err(e) => {
+                        self.fail(error_chain(format!("Unable to discover tile {}", tile), &e));
+                        vec![]
+                    }
+                };
+                stream::iter(codes)
+            })
+            .take_while(|_| ready(!self.has_failed()))
+            .filter(|code| ready(pre_filter(code)))
+            .chain(stream::iter(included))
+            .filter(|code| {
+                ready(
+                    !self.selection.exclude.contains(&code.code) && seen.insert(code.code.clone()),
+                )
+            });
+
+        if discovery_only {
+            // without a token we only know code and approximate position, so the post filter can't apply
+            let approximate: Vec<Geocache> = codes
+                .filter_map(|code| {
+                    ready(
+                        code.approx_coord
+                            .map(|coord| Geocache::approximate(code.code, coord)),
+                    )
+                })
+                .collect()
+                .await;
+            if self.has_failed() {
+                self.save(cache).await;
+                return;
+            }
+            let message = format!(
+                "Finished (discovery only, {} approximate positions, no Groundspeak login configured)",
+                approximate.len()
+            );
+            self.state.lock().unwrap().geocaches = approximate;
+            self.finish(message);
+            self.save(cache).await;
+            return;
+        }
+
+        let filter = self.state.lock().unwrap().filter.clone();
+        let found_codes = match Self::found_codes(&filter, cache).await {
+            Ok(found_codes) => found_codes,
+            Err(e) => {
+                self.fail(error_chain(
+                    "Unable to load found geocaches".to_string(),
+                    &e,
+                ));
+                self.save(cache).await;
+                return;
+            }
+        };
+        let mut fetched = codes
+            .map(|code| {
+                found.fetch_add(1, Ordering::Relaxed);
+                code.code
+            })
+            .chunks(BATCH_SIZE)
+            .map(|chunk| async {
+                let len = chunk.len();
+                (len, cache.get(chunk, &self.usage).await)
+            })
+            .buffer_unordered(cache.fetch_concurrency());
+        let mut done = 0;
+        while let Some((len, result)) = fetched.next().await {
+            if self.has_failed() {
+                break;
+            }
+            let result = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    self.fail(error_chain("Unable to fetch geocaches".to_string(), &e));
+                    break;
+                }
+            };
+            done += len;
+            if !self.is_discovering() {
+                self.set_progress(Phase::Fetch, done, found.load(Ordering::Relaxed));
+            }
+            let now = cache.now();
+            // outside the lock, post filters record elevations and distances on the job
+            let in_region: Vec<(bool, Geocache)> = result
+                .into_iter()
+                .map(|gc| (self.selection.is_included(&gc.code), gc))
+                .filter(|(included, gc)| *included || post_filter(gc))
+                .collect();
+            let selected = {
+                let mut state = self.state.lock().unwrap();
+                for (included, gc) in in_region {
+                    state.candidates.push(gc.code.clone());
+                    for (leg, (_, region)) in state.legs.iter_mut().zip(&self.legs) {
+                        if region.contains(&gc.coord) {
+                            leg.codes.push(gc.code.clone());
+                        }
+                    }
+                    if included
+                        || state.filter.matches(
+                            &gc,
+                            found_codes.contains(&gc.code),
+                            state.offsets.get(&gc.code).copied(),
+                            now,
+                        )
+                    {
+                        state.geocaches.push(gc);
+                    }
+                }
+                state.geocaches.len()
+            };
             self.set_message(&format!(
-                "Discover tile {}/{}: {}",
-                index + 1,
-                tile_len,
-                tile
+                "Downloading geocaches, {} selected so far",
+                selected
             ));
-            let tmp = cache.discover(tile).await.unwrap();
-            tmp.data
-                .into_iter()
-                .filter(|code| pre_filter(code))
-                .for_each(|code| codes.push(code.code));
+            self.save(cache).await;
+        }
+        // stop the fetches still in flight
+        drop(fetched);
+        if self.has_failed() {
+            self.save(cache).await;
+            return;
         }
+        self.check_degraded(cache).await;
 
-        self.set_message(&format!("Downloading {} geocaches", codes.len()));
-        let all_geocaches: Vec<Geocache> = cache.get(codes.clone()).await.unwrap();
-        let selected = all_geocaches
+        let degraded = self.state.lock().unwrap().degraded;
+        let message = if degraded {
+            "Finished (partial, daily Groundspeak budget exhausted)".to_string()
+        } else {
+            "Finished".to_string()
+        };
+        self.finish(message);
+        self.save(cache).await;
+    }
+
+    /// Select the result again with another filter, from the candidates as stored in the
+    /// database. False if there is nothing to refine yet: the job is still running or only knows
+    /// approximate positions.
+    pub async fn refine(&self, filter: GeocacheFilter, cache: &Cache) -> Result<bool, Error> {
+        let candidates = {
+            let state = self.state.lock().unwrap();
+            if state.finished.is_none() || state.geocaches.iter().any(|gc| gc.approximate) {
+                return Ok(false);
+            }
+            state.candidates.clone()
+        };
+        let found_codes = Self::found_codes(&filter, cache).await?;
+        let geocaches = cache.load_stored(&candidates).await;
+        self.select_refined(filter, geocaches, &found_codes, cache.now());
+        self.save(cache).await;
+        Ok(true)
+    }
+
+    fn select_refined(
+        &self,
+        filter: GeocacheFilter,
+        geocaches: Vec<Geocache>,
+        found_codes: &HashSet<String>,
+        now: DateTime<Utc>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let geocaches: Vec<Geocache> = geocaches
             .into_iter()
-            .filter(|gc| post_filter(gc))
+            .filter(|gc| {
+                self.selection.is_included(&gc.code)
+                    || filter.matches(
+                        gc,
+                        found_codes.contains(&gc.code),
+                        state.offsets.get(&gc.code).copied(),
+                        now,
+                    )
+            })
             .collect();
+        info!(
+            "Job {}: refined from {} to {} geocaches",
+            self.id,
+            state.geocaches.len(),
+            geocaches.len()
+        );
+        state.geocaches = geocaches;
+        state.filter = filter;
+        state.refined = true;
+    }
+
+    /// Codes found through this service, only loaded if the filter needs them.
+    async fn found_codes(filter: &GeocacheFilter, cache: &Cache) -> Result<HashSet<String>, Error> {
+        if filter.exclude_found {
+            cache.found_codes().await
+        } else {
+            Ok(HashSet::new())
+        }
+    }
 
+    pub async fn purge(&self, bbox: &BBox, cache: &Cache) {
+        info!("Purging {} in job {}", bbox, self.id);
+        self.set_progress(Phase::Purge, 0, 0);
+        match cache
+            .purge(bbox, |message| self.set_message(&message))
+            .await
         {
-            let state = &mut self.state.lock().unwrap();
-            state.geocaches = selected;
-            state.message = "Finished".to_string();
-            info!("Job {}: {}", self.id, "Finished");
+            Ok((tiles, geocaches)) => {
+                self.set_progress(Phase::Finished, 0, 0);
+                self.set_message(&format!(
+                    "Finished, purged {} tiles and {} geocaches",
+                    tiles, geocaches
+                ));
+            }
+            Err(e) => self.fail(error_chain("Unable to purge".to_string(), &e)),
+        };
+        self.save(cache).await;
+    }
+
+    async fn check_degraded(&self, cache: &Cache) {
+        if cache.is_cache_only().await.unwrap_or(false) {
+            self.state.lock().unwrap().degraded = true;
+        }
+    }
+
+    fn finish(&self, message: String) {
+        let mut state = self.state.lock().unwrap();
+        state.finished = Some(Utc::now());
+        let total = state.progress.total;
+        state.progress.phase = Phase::Finished;
+        state.progress.current = total;
+        info!("Job {}: {}", self.id, message);
+        state.message = message;
+    }
+
+    /// Stop reporting progress, the job is done without a result.
+    fn fail(&self, error: Vec<String>) {
+        let mut state = self.state.lock().unwrap();
+        if state.progress.phase == Phase::Failed {
+            return;
         }
+        state.progress.phase = Phase::Failed;
+        state.message = format!("Failed: {}", error.join(": "));
+        error!("Job {}: {}", self.id, state.message);
+        state.error = error;
+    }
+
+    fn has_failed(&self) -> bool {
+        self.state.lock().unwrap().progress.phase == Phase::Failed
     }
 
     fn set_message(&self, message: &str) {
         let mut state = self.state.lock().unwrap();
+        if state.progress.phase == Phase::Failed {
+            return;
+        }
         state.message = message.to_string();
         info!("Job {}: {}", self.id, message);
     }
 
-    pub fn get_message(&self) -> String {
-        let state = &self.state.lock().unwrap();
-        state.message.clone()
+    fn set_progress(&self, phase: Phase, current: usize, total: usize) {
+        let mut state = self.state.lock().unwrap();
+        let progress = &mut state.progress;
+        if progress.phase == Phase::Failed {
+            return;
+        }
+        if progress.phase != phase {
+            progress.phase = phase;
+            progress.phase_started_at = Utc::now();
+        }
+        progress.current = current;
+        progress.total = total;
+    }
+
+    fn is_discovering(&self) -> bool {
+        let progress = &self.state.lock().unwrap().progress;
+        progress.phase == Phase::Discover && progress.current < progress.total
     }
 
-    pub fn get_geocaches(&self) -> Option<Vec<Geocache>> {
+    pub fn status(&self) -> JobStatus {
+        let state = self.state.lock().unwrap();
+        let progress = &state.progress;
+        JobStatus {
+            id: self.id.clone(),
+            message: state.message.clone(),
+            phase: progress.phase,
+            current: progress.current,
+            total: progress.total,
+            percent: progress.percent(),
+            started_at: progress.started_at,
+            eta: progress.eta(Utc::now()),
+            queue_position: None,
+            error: state.error.clone(),
+            api_calls: self.usage.calls(),
+            priority: self.priority,
+        }
+    }
+
+    /// The selected geocaches, once the job is finished.
+    pub fn get_snapshot(&self) -> Option<Snapshot> {
         let state = &self.state.lock().unwrap();
-        let geocaches = &state.geocaches;
-        if geocaches.is_empty() {
-            None
-        } else {
-            Some(geocaches.to_vec())
+        let selected: HashSet<&String> = state.geocaches.iter().map(|gc| &gc.code).collect();
+        state.finished.map(|ts| Snapshot {
+            ts,
+            geocaches: state.geocaches.to_vec(),
+            legs: state
+                .legs
+                .iter()
+                .map(|leg| Leg {
+                    name: leg.name.clone(),
+                    codes: leg
+                        .codes
+                        .iter()
+                        .filter(|code| selected.contains(code))
+                        .cloned()
+                        .collect(),
+                })
+                .collect(),
+            ascent: self.ascent,
+            elevations: state.elevations.clone(),
+            distances: state.distances.clone(),
+            offsets: state.offsets.clone(),
+            track: self.track.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcgeo::CacheType;
+
+    #[test]
+    fn sort_orders() {
+        let geocache = |code: &str, favorites, difficulty| Geocache {
+            favorite_points: favorites,
+            difficulty,
+            ..Geocache::premium(code.to_string())
+        };
+        let mut geocaches = vec![
+            geocache("GC1", 3, 2.0),
+            geocache("GC2", 10, 1.5),
+            geocache("GC3", 0, 4.0),
+        ];
+        let codes = |geocaches: &[Geocache]| -> Vec<String> {
+            geocaches.iter().map(|gc| gc.code.clone()).collect()
+        };
+        let distances = HashMap::from([("GC3".to_string(), 100.0), ("GC1".to_string(), 900.0)]);
+        let offsets = HashMap::from([("GC2".to_string(), 5.0), ("GC1".to_string(), 40.0)]);
+
+        SortOrder::RouteOrder.sort(&mut geocaches, &distances, &offsets);
+        assert_eq!(codes(&geocaches), ["GC3", "GC1", "GC2"]);
+        SortOrder::Distance.sort(&mut geocaches, &distances, &offsets);
+        assert_eq!(codes(&geocaches), ["GC2", "GC1", "GC3"]);
+        SortOrder::Favorites.sort(&mut geocaches, &distances, &offsets);
+        assert_eq!(codes(&geocaches), ["GC2", "GC1", "GC3"]);
+        SortOrder::Difficulty.sort(&mut geocaches, &distances, &offsets);
+        assert_eq!(codes(&geocaches), ["GC2", "GC1", "GC3"]);
+        assert_eq!("route_order".parse(), Ok(SortOrder::RouteOrder));
+        assert!("random".parse::<SortOrder>().is_err());
+    }
+
+    #[test]
+    fn parse_code_selection() {
+        let selection = CodeSelection::parse(Some("GC1234, gc5678,,GC1234"), Some(" gcabc "));
+        assert_eq!(selection.include, vec!["GC1234", "GC5678"]);
+        assert!(selection.exclude.contains("GCABC"));
+        assert!(selection.is_included("GC5678"));
+
+        let empty = CodeSelection::parse(None, None);
+        assert!(empty.include.is_empty() && empty.exclude.is_empty());
+    }
+
+    #[test]
+    fn restore_from_record() {
+        let record = JobRecord {
+            id: "job".to_string(),
+            kind: Some("route".to_string()),
+            message: "Finished".to_string(),
+            finished: DateTime::from_timestamp(1_717_243_200, 0),
+            codes: vec!["GC1".to_string(), "GC2".to_string()],
+            candidates: vec!["GC1".to_string(), "GC2".to_string(), "GC3".to_string()],
+            include_codes: vec!["GC1".to_string()],
+            exclude_codes: vec!["GC4".to_string(), "GC5".to_string()],
+            legs: vec![("Leg 1".to_string(), vec!["GC2".to_string()])],
+            distances: HashMap::from([("GC2".to_string(), 1200.0)]),
+            track: vec![vec![[8.5, 47.9], [8.6, 47.9]]],
+            api_calls: ApiCalls {
+                tiles: 3,
+                fetches: 2,
+            },
+            ..Default::default()
+        };
+        let geocaches = vec![
+            Geocache::premium("GC1".to_string()),
+            Geocache::premium("GC2".to_string()),
+        ];
+        let job = Job::restore(record.clone(), geocaches);
+        assert_eq!(job.record(), record);
+        assert!(job.selection.exclude.contains("GC5"));
+        assert_eq!(job.status().api_calls.tiles, 3);
+        let snapshot = job.get_snapshot().unwrap().leg(0).unwrap();
+        assert_eq!(snapshot.geocaches.len(), 1);
+        assert_eq!(snapshot.distances["GC2"], 1200.0);
+        assert_eq!(job.file_stem(&snapshot), "route-2024-06-01");
+
+        let running = Job::restore(
+            JobRecord {
+                message: "Discovered tile 3/10".to_string(),
+                ..Default::default()
+            },
+            vec![],
+        );
+        assert!(running.get_snapshot().is_none());
+        assert_eq!(running.kind, "geocaches");
+        assert!(running.status().message.contains("Discovered tile 3/10"));
+        assert_eq!(running.status().phase, Phase::Interrupted);
+
+        let failed = Job::restore(
+            JobRecord {
+                message: "Failed: Unable to fetch geocaches: db error".to_string(),
+                error: vec![
+                    "Unable to fetch geocaches".to_string(),
+                    "db error".to_string(),
+                ],
+                ..Default::default()
+            },
+            vec![],
+        );
+        let status = failed.status();
+        assert_eq!(status.phase, Phase::Failed);
+        assert_eq!(status.error.len(), 2);
+        assert_eq!(failed.record().error, status.error);
+    }
+
+    #[tokio::test]
+    async fn jobs_wait_for_a_slot() {
+        let jobs = JobQueue::new(1, Notifier::new(&Default::default()).unwrap());
+        let first = Arc::new(Job::new());
+        let second = Arc::new(Job::new());
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let running = jobs.spawn(first.clone(), async move {
+            let _ = released.await;
+        });
+        let waiting = jobs.spawn(second.clone(), async {});
+        tokio::task::yield_now().await;
+
+        assert_eq!(jobs.status(&first).queue_position, None);
+        let status = jobs.status(&second);
+        assert_eq!(status.queue_position, Some(1));
+        assert!(status.message.contains("position 1"));
+
+        release.send(()).unwrap();
+        running.await.unwrap();
+        waiting.await.unwrap();
+        assert_eq!(jobs.status(&second).queue_position, None);
+    }
+
+    #[tokio::test]
+    async fn removed_jobs_free_their_slot() {
+        let jobs = JobQueue::new(1, Notifier::new(&Default::default()).unwrap());
+        let stuck = Arc::new(Job::new());
+        let queued = Arc::new(Job::new());
+        let last = Arc::new(Job::new());
+        jobs.add(stuck.clone());
+        jobs.add(queued.clone());
+        let running = jobs.spawn(stuck.clone(), futures::future::pending());
+        jobs.spawn(queued.clone(), async {});
+        let waiting = jobs.spawn(last.clone(), async {});
+        tokio::task::yield_now().await;
+        assert_eq!(jobs.status(&last).queue_position, Some(2));
+
+        assert!(jobs.remove(&queued.id).is_some());
+        tokio::task::yield_now().await;
+        assert_eq!(jobs.status(&last).queue_position, Some(1));
+
+        assert!(jobs.remove(&stuck.id).is_some());
+        assert!(running.await.unwrap_err().is_cancelled());
+        waiting.await.unwrap();
+        assert!(jobs.get(&stuck.id).is_none());
+    }
+
+    #[tokio::test]
+    async fn small_jobs_go_first() {
+        let jobs = JobQueue::new(1, Notifier::new(&Default::default()).unwrap());
+        let running = Arc::new(Job::new());
+        let large = Arc::new(Job::new().with_priority(Priority::for_tiles(1000)));
+        let small = Arc::new(Job::new().with_priority(Priority::for_tiles(4)));
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let order = Arc::new(Mutex::new(vec![]));
+        let first = jobs.spawn(running.clone(), async move {
+            let _ = released.await;
+        });
+        tokio::task::yield_now().await;
+        let mut handles = vec![];
+        for job in [&large, &small] {
+            let order = order.clone();
+            let id = job.id.clone();
+            handles.push(jobs.spawn(job.clone(), async move {
+                order.lock().unwrap().push(id);
+            }));
+        }
+        tokio::task::yield_now().await;
+        assert_eq!(jobs.status(&small).queue_position, Some(1));
+        assert_eq!(jobs.status(&large).queue_position, Some(2));
+
+        release.send(()).unwrap();
+        first.await.unwrap();
+        for handle in handles {
+            handle.await.unwrap();
         }
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec![small.id.clone(), large.id.clone()]
+        );
+    }
+
+    #[tokio::test]
+    async fn panics_fail_the_job() {
+        let jobs = JobQueue::new(1, Notifier::new(&Default::default()).unwrap());
+        let job = Arc::new(Job::new());
+        jobs.spawn(job.clone(), async { panic!("no tiles") })
+            .await
+            .unwrap();
+        let status = job.status();
+        assert_eq!(status.phase, Phase::Failed);
+        assert_eq!(status.message, "Failed: no tiles");
+        assert!(job.get_snapshot().is_none());
+    }
+
+    #[test]
+    fn identical_jobs_are_reused() {
+        let positions = vec![vec![[8.5, 47.9], [8.6, 47.9]]];
+        let key = InputKey::new(
+            "track",
+            &positions,
+            "100",
+            &CodeSelection::parse(Some("GC1,GC2"), None),
+            &GeocacheFilter::default(),
+        );
+        let same = InputKey::new(
+            "track",
+            &[vec![[8.500_000_01, 47.9], [8.6, 47.9]]],
+            "100",
+            &CodeSelection::parse(Some("gc2, gc1"), None),
+            &GeocacheFilter::default(),
+        );
+        assert_eq!(key, same);
+        assert_ne!(
+            key,
+            InputKey::new(
+                "track",
+                &positions,
+                "100",
+                &CodeSelection::parse(Some("GC1,GC2"), None),
+                &GeocacheFilter::quick_stop()
+            )
+        );
+        // the order of the filter's lists doesn't matter
+        let filter = GeocacheFilter {
+            types: vec![CacheType::Traditional, CacheType::Multi],
+            exclude_owners: vec!["Alice".into(), "bob".into()],
+            ..GeocacheFilter::default()
+        };
+        let reordered = GeocacheFilter {
+            types: vec![CacheType::Multi, CacheType::Traditional],
+            exclude_owners: vec!["bob".into(), "alice".into()],
+            ..GeocacheFilter::default()
+        };
+        let selection = CodeSelection::default();
+        assert_eq!(
+            InputKey::new("area", &positions, "", &selection, &filter),
+            InputKey::new("area", &positions, "", &selection, &reordered)
+        );
+
+        let jobs = JobQueue::new(1, Notifier::new(&Default::default()).unwrap());
+        assert!(jobs.find_identical(key, None).is_none());
+        let job = Arc::new(Job::new().with_input_key(key));
+        jobs.add(job.clone());
+        jobs.add(Arc::new(Job::new()));
+        assert_eq!(jobs.find_identical(same, None).unwrap().id, job.id);
+        // someone else's job isn't reused
+        assert!(jobs.find_identical(same, Some("alice")).is_none());
+        let owned = Arc::new(
+            Job::new()
+                .with_input_key(key)
+                .with_owner(Some("alice".into())),
+        );
+        jobs.add(owned.clone());
+        assert_eq!(
+            jobs.find_identical(same, Some("alice")).unwrap().id,
+            owned.id
+        );
+        assert_eq!(jobs.list_for(Some("alice")).len(), 1);
+        assert_eq!(jobs.list_for(None).len(), 2);
+
+        // a refined job holds another selection than its input asked for
+        owned.select_refined(
+            GeocacheFilter::quick_stop(),
+            vec![],
+            &HashSet::new(),
+            Utc::now(),
+        );
+        assert!(jobs.find_identical(same, Some("alice")).is_none());
+    }
+
+    #[test]
+    fn progress_eta() {
+        let start = DateTime::from_timestamp(1_717_243_200, 0).unwrap();
+        let mut progress = Progress::new(Phase::Discover);
+        progress.phase_started_at = start;
+        assert_eq!(progress.percent(), None);
+        assert_eq!(progress.eta(start), None);
+
+        progress.current = 25;
+        progress.total = 100;
+        assert_eq!(progress.percent(), Some(25.0));
+        // a quarter took 30 seconds, three quarters to go
+        let now = start + chrono::Duration::seconds(30);
+        assert_eq!(progress.eta(now), Some(now + chrono::Duration::seconds(90)));
+
+        let job = Job::new();
+        job.set_progress(Phase::Discover, 3, 3);
+        assert!(!job.is_discovering());
+        job.finish("Finished".to_string());
+        let status = job.status();
+        assert_eq!(status.phase, Phase::Finished);
+        assert_eq!(status.percent, Some(100.0));
     }
 }
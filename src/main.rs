@@ -1,31 +1,57 @@
 #[macro_use]
 extern crate rocket;
 
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::str::FromStr;
-use std::time::SystemTime;
+use std::sync::Arc;
 
-use chrono::Local;
+use chrono::{DateTime, Local, NaiveDate, Utc};
 
+use futures::StreamExt;
 use geojson::GeoJson;
 use rocket::form::Form;
 use rocket::fs::{relative, FileServer};
-use rocket::http::Accept;
-use rocket::response::Responder;
+use rocket::response::stream::ReaderStream;
+use rocket::response::{Redirect, Responder};
+use rocket::serde::json::Json;
 use rocket::{data::ToByteUnit, Data, State};
-use rocket_dyn_templates::{context, Template};
+use rocket_dyn_templates::{context, Metadata, Template};
 use thiserror::Error;
 
-use crate::area::compute_area;
+use crate::area::{compute_area, MAX_RADIUS_M};
+use crate::auth::{Admin, ApiKeyAuth, Caller, ADMIN_USER};
+use crate::bbox::compute_bbox;
+use crate::config::Config;
+use crate::digest::schedule_digests;
+use crate::error::ApiError;
+use crate::export::{ExportOptions, Exporters};
 use crate::gcgeo::Coordinate;
-use crate::job::JobQueue;
-use crate::track::compute_track;
-use gc::Cache;
-use gcgeo::{CacheType, Geocache};
+use crate::job::{CodeSelection, Job, JobQueue, JobStatus, Phase, Priority, Snapshot, SortOrder};
+use crate::notify::{Callback, Notifier};
+use crate::polygon::compute_polygon;
+use crate::purge::compute_purge;
+use crate::ratelimit::RateLimit;
+use crate::track::{compute_track, Split, DEFAULT_CORRIDOR_M, MAX_CORRIDOR_M};
+use gc::garmin::{ExportMode, GpxFlavor, PoiTemplates};
+use gc::sheet::TripSheet;
+use gc::{ApiUsage, Cache, User};
+use gcgeo::{Geocache, GeocacheFilter, Health, TrackFormat};
 
+mod api;
 mod area;
+mod auth;
+mod bbox;
+mod config;
+mod digest;
+mod error;
+mod export;
 mod gc;
 mod gcgeo;
 mod job;
+mod notify;
+mod polygon;
+mod purge;
+mod ratelimit;
 mod track;
 
 #[derive(Error, Debug)]
@@ -37,7 +63,11 @@ pub enum Error {
     #[error("io")]
     Io(#[from] std::io::Error),
     #[error("rocket")]
-    Rocket(#[from] rocket::Error),
+    Rocket(#[from] Box<rocket::Error>),
+    #[error("config")]
+    Config(#[from] Box<rocket::figment::Error>),
+    #[error("gpi template: {0}")]
+    PoiTemplate(String),
     #[error("unknown data store error")]
     Unknown,
 }
@@ -46,110 +76,359 @@ pub enum Error {
 async fn main() -> Result<(), Error> {
     env_logger::init();
 
-    let jobs = JobQueue::new();
-    let cache = Cache::new_lite().await?;
+    let rocket = rocket::build();
+    let config: Config = rocket.figment().extract().map_err(Box::new)?;
+    let poi_templates = PoiTemplates::new(
+        config.gpi_title.as_deref(),
+        config.gpi_description.as_deref(),
+    )
+    .map_err(Error::PoiTemplate)?;
+    let exporters = Exporters::new(&config, poi_templates);
+    let jobs = JobQueue::new(
+        config.max_parallel_jobs,
+        Notifier::new(&config).map_err(gc::Error::from)?,
+    );
+    // one pool and token cache for all requests and jobs
+    let cache = Arc::new(Cache::new_lite(&config).await?);
+    std::fs::create_dir_all(&config.image_dir)?;
+    let images = FileServer::from(&config.image_dir);
+    let rate_limit = RateLimit::new(&config);
+
+    let retry_cache = cache.clone();
+    tokio::task::spawn(async move {
+        let cache = retry_cache;
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(300));
+        loop {
+            interval.tick().await;
+            match cache.flush_logs().await {
+                Ok(status) if status.posted > 0 || status.pending > 0 => info!(
+                    "Log queue: posted {}, pending {}",
+                    status.posted, status.pending
+                ),
+                Ok(_) => {}
+                Err(e) => error!("Unable to flush log queue: {}", e),
+            }
+        }
+    });
+
+    schedule_digests(&config, cache.clone());
 
     info!("Service starting up...");
 
-    let _rocket = rocket::build()
+    let _rocket = rocket
         .manage(jobs)
         .manage(cache)
+        .manage(config)
+        .manage(exporters)
         .mount(
             "/",
             routes![
                 index,
                 list_jobs,
                 upload,
+                upload_form,
+                delete_job,
                 fetch,
+                fetch_batch,
                 enqueue_task,
                 query_task,
                 query_task_gpi,
+                query_task_gpi_zip,
+                query_task_kml,
+                query_task_sheet,
+                download,
+                refine_task,
                 enqueue_area,
+                enqueue_area_coord,
+                enqueue_polygon,
+                enqueue_bbox,
+                enqueue_bbox_body,
+                description,
+                admin_auth,
+                admin_auth_refresh,
+                auth_login,
+                health_auth,
+                auth_callback,
+                admin_purge,
+                admin_create_user,
+                auth_required,
+                rate_limited,
+                login,
+                logout,
+                digest_html,
+                digest_markdown,
+                submit_logs,
                 test_route
             ],
         )
+        .mount("/api/v1", api::routes())
         .mount("/static/", FileServer::from(relative!("/static")))
+        .mount("/images/", images)
         .attach(Template::fairing())
+        .attach(ApiKeyAuth)
+        .attach(rate_limit)
         .launch()
-        .await?;
+        .await
+        .map_err(Box::new)?;
 
     Ok(())
 }
 
 #[get("/")]
-async fn index(jobs: &State<JobQueue>) -> Template {
-    list_jobs(jobs).await
+async fn index(caller: Caller, jobs: &State<JobQueue>, cache: &State<Arc<Cache>>) -> Template {
+    list_jobs(caller, jobs, cache).await
     // Template::render("index", context! { field: "value" })
 }
 
 enum JobResult {
-    Complete(Vec<Geocache>, Option<Accept>),
-    Incomplete(String),
+    /// the result in the named format, or as the client accepts
+    Complete(Snapshot, Option<&'static str>, ExportOptions),
+    /// a GeoJSON FeatureCollection of clusters and the snapshot time
+    Clustered(GeoJson, DateTime<Utc>),
+    /// the printable trip sheet as HTML
+    Sheet(TripSheet),
+    /// the trip sheet printed to PDF, and its name without the extension
+    Pdf(Vec<u8>, String),
+    /// 202 pointing to the job, with the progress as JSON if the client prefers it, the message
+    /// otherwise
+    Incomplete(JobStatus),
+    /// state, counts and download links for clients asking for application/json
+    Summary(JobSummary),
 }
 
 impl<'a> Responder<'a, 'static> for JobResult {
     fn respond_to(self, req: &'a rocket::Request<'_>) -> rocket::response::Result<'static> {
         match self {
-            JobResult::Complete(data, forced_accept) => {
-                let json = rocket::http::Accept::JSON;
-                let accept = forced_accept.as_ref().or(req.accept()).unwrap_or(&json);
-                match accept.preferred().sub().as_str() {
-                    "gpx" => {
-                        let mut output: Vec<u8> = Vec::new();
-                        gc::garmin::Garmin::gpx(data, &CacheType::Traditional, &mut output)
-                            .expect("gpx writing failed");
-                        rocket::response::Response::build()
-                            .header(rocket::http::ContentType::XML)
-                            .sized_body(output.len(), std::io::Cursor::new(output))
-                            .ok()
-                    }
-                    "gpi" => {
-                        let mut output: Vec<u8> = Vec::new();
-                        gc::garmin::Garmin::gpi(data, &CacheType::Traditional, &mut output)
-                            .expect("gpi writing failed");
-                        rocket::response::Response::build()
-                            .header(
-                                rocket::http::ContentType::parse_flexible("application/gpi")
-                                    .unwrap(),
-                            )
-                            .sized_body(output.len(), std::io::Cursor::new(output))
-                            .ok()
-                    }
-                    _ => {
-                        let json = bundle_geojson(data).to_string();
-                        rocket::response::Response::build()
-                            .header(rocket::http::ContentType::Plain)
-                            .sized_body(json.len(), std::io::Cursor::new(json))
-                            .ok()
-                    }
+            JobResult::Complete(snapshot, format, options) => {
+                let exporters = req
+                    .rocket()
+                    .state::<Exporters>()
+                    .ok_or(rocket::http::Status::InternalServerError)?;
+                let exporter = match format {
+                    Some(name) => exporters
+                        .by_name(name)
+                        .ok_or(rocket::http::Status::NotFound)?,
+                    None => exporters.for_media_type(
+                        req.accept()
+                            .map(|accept| accept.preferred().media_type())
+                            .unwrap_or(&rocket::http::MediaType::JSON),
+                    ),
+                };
+                let snapshot_header =
+                    rocket::http::Header::new("X-Snapshot", snapshot.ts.to_rfc3339());
+                let content_type = exporter.content_type();
+                let disposition = options.file_name(exporter.as_ref()).map(attachment);
+                let body = export::stream(exporter, snapshot, options).map(std::io::Cursor::new);
+                let mut response = rocket::response::Response::build();
+                response
+                    .header(content_type)
+                    .header(snapshot_header)
+                    .streamed_body(ReaderStream::from(body));
+                if let Some(disposition) = disposition {
+                    response.header(disposition);
                 }
+                response.ok()
             }
-            JobResult::Incomplete(message) => rocket::response::Response::build()
-                .header(rocket::http::ContentType::Plain)
-                .sized_body(message.len(), std::io::Cursor::new(message))
+            JobResult::Sheet(sheet) => {
+                Template::render("sheet", context! { sheet: &sheet }).respond_to(req)
+            }
+            JobResult::Pdf(pdf, file_stem) => rocket::response::Response::build()
+                .header(rocket::http::ContentType::PDF)
+                .header(attachment(format!("{}.pdf", file_stem)))
+                .sized_body(pdf.len(), std::io::Cursor::new(pdf))
                 .ok(),
+            JobResult::Clustered(geojson, ts) => {
+                let json = geojson.to_string();
+                rocket::response::Response::build()
+                    .header(rocket::http::ContentType::Plain)
+                    .header(rocket::http::Header::new("X-Snapshot", ts.to_rfc3339()))
+                    .sized_body(json.len(), std::io::Cursor::new(json))
+                    .ok()
+            }
+            JobResult::Summary(summary) => {
+                let status = if summary.status == "finished" || summary.status == "failed" {
+                    rocket::http::Status::Ok
+                } else {
+                    rocket::http::Status::Accepted
+                };
+                (status, Json(summary)).respond_to(req)
+            }
+            JobResult::Incomplete(status) => {
+                let location =
+                    rocket::http::Header::new("Location", format!("/jobs/{}", status.id));
+                let mut response = if wants_json(req.accept()) {
+                    Json(status).respond_to(req)?
+                } else {
+                    let message = status.message;
+                    rocket::response::Response::build()
+                        .header(rocket::http::ContentType::Plain)
+                        // browsers ask again until the download is ready
+                        .header(rocket::http::Header::new("Refresh", "5"))
+                        .sized_body(message.len(), std::io::Cursor::new(message))
+                        .finalize()
+                };
+                response.set_status(rocket::http::Status::Accepted);
+                response.set_header(location);
+                Ok(response)
+            }
         }
     }
 }
 
-fn bundle_geojson(data: Vec<Geocache>) -> GeoJson {
-    let features: Vec<geojson::Feature> = data
-        .iter()
-        .map(|gc| {
+/// Makes browsers save the response under this name instead of the job id.
+fn attachment(file_name: String) -> rocket::http::Header<'static> {
+    rocket::http::Header::new(
+        "Content-Disposition",
+        format!("attachment; filename=\"{}\"", file_name),
+    )
+}
+
+/// A response with extra headers, e.g. where the geocaches in it came from.
+struct WithHeaders<R>(R, Vec<rocket::http::Header<'static>>);
+
+impl<'a, R: Responder<'a, 'static>> Responder<'a, 'static> for WithHeaders<R> {
+    fn respond_to(self, req: &'a rocket::Request<'_>) -> rocket::response::Result<'static> {
+        let mut response = self.0.respond_to(req)?;
+        for header in self.1 {
+            response.set_header(header);
+        }
+        Ok(response)
+    }
+}
+
+/// X-Provenance of a single geocache: cache, live or premium.
+fn provenance(lookup: &gc::Lookup) -> rocket::http::Header<'static> {
+    rocket::http::Header::new("X-Provenance", lookup.provenance.name())
+}
+
+/// X-Missing-Codes and X-Premium-Codes: the codes of a list without a geocache, or with only
+/// what a basic member may see.
+fn unresolved(lookups: &[gc::Lookup]) -> Vec<rocket::http::Header<'static>> {
+    [
+        ("X-Missing-Codes", gc::Provenance::Missing),
+        ("X-Premium-Codes", gc::Provenance::Premium),
+    ]
+    .into_iter()
+    .filter_map(|(name, provenance)| {
+        let codes: Vec<&str> = lookups
+            .iter()
+            .filter(|lookup| lookup.provenance == provenance)
+            .map(|lookup| lookup.code.as_str())
+            .collect();
+        (!codes.is_empty()).then(|| rocket::http::Header::new(name, codes.join(",")))
+    })
+    .collect()
+}
+
+/// The result of a job that was already done when it was requested, as GeoJSON by default.
+fn complete(job: &Job, snapshot: Snapshot) -> JobResult {
+    let file_stem = job.file_stem(&snapshot);
+    JobResult::Complete(
+        snapshot,
+        None,
+        ExportOptions {
+            file_stem: Some(file_stem),
+            ..Default::default()
+        },
+    )
+}
+
+/// An explicit application/json, */* and browsers get the export or the message.
+fn wants_json(accept: Option<&rocket::http::Accept>) -> bool {
+    accept.is_some_and(|accept| accept.preferred().is_json())
+}
+
+#[derive(rocket::serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+struct JobSummary {
+    /// queued, running, finished, interrupted or failed
+    status: &'static str,
+    progress: JobStatus,
+    snapshot: Option<DateTime<Utc>>,
+    total: usize,
+    /// number of geocaches by type
+    counts: BTreeMap<&'static str, usize>,
+    legs: Vec<LegSummary>,
+    /// format name to URL
+    downloads: BTreeMap<&'static str, String>,
+}
+
+#[derive(rocket::serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+struct LegSummary {
+    name: String,
+    count: usize,
+}
+
+impl JobSummary {
+    fn new(progress: JobStatus, snapshot: Option<&Snapshot>) -> Self {
+        let status = match progress.phase {
+            _ if progress.queue_position.is_some() => "queued",
+            Phase::Queued => "queued",
+            Phase::Finished => "finished",
+            Phase::Interrupted => "interrupted",
+            Phase::Failed => "failed",
+            _ => "running",
+        };
+        let mut counts = BTreeMap::new();
+        for gc in snapshot.iter().flat_map(|snapshot| &snapshot.geocaches) {
+            *counts
+                .entry(gc::garmin::Garmin::type_name(&gc.cache_type))
+                .or_insert(0) += 1;
+        }
+        let id = &progress.id;
+        let downloads = if snapshot.is_some() {
+            BTreeMap::from([
+                ("geojson", format!("/jobs/{}/download/geojson", id)),
+                ("gpx", format!("/jobs/{}/download/gpx", id)),
+                ("gpi", format!("/jobs/{}/download/gpi", id)),
+                ("gpi.zip", format!("/jobs/{}/download/zip", id)),
+                ("kml", format!("/jobs/{}/download/kml", id)),
+                ("kmz", format!("/jobs/{}/download/kmz", id)),
+                ("csv", format!("/jobs/{}/download/csv", id)),
+                ("sheet", format!("/jobs/{}/sheet", id)),
+                ("pdf", format!("/jobs/{}/sheet?pdf=true", id)),
+            ])
+        } else {
+            BTreeMap::new()
+        };
+        Self {
+            status,
+            snapshot: snapshot.map(|snapshot| snapshot.ts),
+            total: snapshot.map_or(0, |snapshot| snapshot.geocaches.len()),
+            counts,
+            legs: snapshot
+                .iter()
+                .flat_map(|snapshot| &snapshot.legs)
+                .map(|leg| LegSummary {
+                    name: leg.name.clone(),
+                    count: leg.codes.len(),
+                })
+                .collect(),
+            downloads,
+            progress,
+        }
+    }
+}
+
+/// One point per cluster with the number of geocaches in it, single geocaches keep their code.
+fn bundle_clusters(snapshot: &Snapshot, z: u8) -> GeoJson {
+    let features = gcgeo::cluster(&snapshot.geocaches, z)
+        .into_iter()
+        .map(|cluster| {
             let mut properties = geojson::JsonObject::new();
             properties.insert(
-                "name".to_string(),
-                geojson::JsonValue::from(gc.code.clone()),
-            );
-            properties.insert(
-                "marker-color".to_string(),
-                geojson::JsonValue::from("#000000"),
+                "count".to_string(),
+                geojson::JsonValue::from(cluster.count()),
             );
+            if let [code] = cluster.codes.as_slice() {
+                properties.insert("name".to_string(), geojson::JsonValue::from(code.clone()));
+            }
             geojson::Feature {
                 properties: Some(properties),
                 geometry: Some(geojson::Geometry::new(geojson::Value::Point(vec![
-                    gc.coord.lon,
-                    gc.coord.lat,
+                    cluster.coord.lon,
+                    cluster.coord.lat,
                 ]))),
                 bbox: None,
                 id: None,
@@ -157,29 +436,322 @@ fn bundle_geojson(data: Vec<Geocache>) -> GeoJson {
             }
         })
         .collect();
+    let mut foreign_members = geojson::JsonObject::new();
+    foreign_members.insert(
+        "snapshot".to_string(),
+        geojson::JsonValue::from(snapshot.ts.to_rfc3339()),
+    );
     GeoJson::FeatureCollection(geojson::FeatureCollection {
         features,
         bbox: None,
-        foreign_members: None,
+        foreign_members: Some(foreign_members),
     })
 }
 
-#[post("/track", data = "<data>")]
+/// Comma separated GC codes to always fetch (include_codes) or to drop (exclude_codes), and the
+/// filter for everything else, e.g. types=traditional,multi&max_d=2.5&exclude_found=true
+#[derive(FromForm)]
+struct SelectionParams {
+    include_codes: Option<String>,
+    exclude_codes: Option<String>,
+    types: Option<String>,
+    min_d: Option<f32>,
+    max_d: Option<f32>,
+    min_t: Option<f32>,
+    max_t: Option<f32>,
+    /// comma separated container sizes, e.g. "small,regular,large"
+    sizes: Option<String>,
+    exclude_sizes: Option<String>,
+    /// comma separated attribute names or ids, e.g. "available 24/7"
+    attributes: Option<String>,
+    /// e.g. "boat,scuba"
+    exclude_attributes: Option<String>,
+    min_favorites: Option<u32>,
+    exclude_premium: Option<bool>,
+    exclude_inactive: Option<bool>,
+    exclude_found: Option<bool>,
+    /// comma separated owner usernames
+    exclude_owner: Option<String>,
+    /// skip the hides of the owner_names in the config
+    exclude_own: Option<bool>,
+    max_consecutive_dnf: Option<usize>,
+    /// keep geocaches with a streak of DNFs even if the defaults skip them
+    include_dnf: Option<bool>,
+    found_within_days: Option<i64>,
+    max_distance_m: Option<f64>,
+    /// only events during these days, e.g. events_from=2024-06-10&events_until=2024-06-14
+    events_from: Option<String>,
+    events_until: Option<String>,
+    /// name or description contains this, or matches it as /regex/
+    q: Option<String>,
+    /// named filter the other parameters start from, e.g. kid-friendly
+    profile: Option<String>,
+}
+
+impl SelectionParams {
+    fn selection(&self) -> CodeSelection {
+        CodeSelection::parse(self.include_codes.as_deref(), self.exclude_codes.as_deref())
+    }
+
+    /// The given filter with whatever the request sets.
+    fn filter(&self, defaults: GeocacheFilter, config: &Config) -> Result<GeocacheFilter, String> {
+        let defaults = profile(self.profile.as_deref(), defaults, config)?;
+        let mut exclude_owners =
+            parse_list(self.exclude_owner.as_deref())?.unwrap_or(defaults.exclude_owners);
+        if self.exclude_own.unwrap_or(false) {
+            exclude_owners.extend(config.owner_names.iter().cloned());
+        }
+        Ok(GeocacheFilter {
+            types: parse_list(self.types.as_deref())?.unwrap_or(defaults.types),
+            sizes: parse_list(self.sizes.as_deref())?.unwrap_or(defaults.sizes),
+            exclude_sizes: parse_list(self.exclude_sizes.as_deref())?
+                .unwrap_or(defaults.exclude_sizes),
+            min_d: self.min_d.or(defaults.min_d),
+            max_d: self.max_d.or(defaults.max_d),
+            min_t: self.min_t.or(defaults.min_t),
+            max_t: self.max_t.or(defaults.max_t),
+            attributes: parse_list(self.attributes.as_deref())?.unwrap_or(defaults.attributes),
+            exclude_attributes: parse_list(self.exclude_attributes.as_deref())?
+                .unwrap_or(defaults.exclude_attributes),
+            min_favorites: self.min_favorites.or(defaults.min_favorites),
+            exclude_premium: self.exclude_premium.unwrap_or(defaults.exclude_premium),
+            exclude_inactive: self.exclude_inactive.unwrap_or(defaults.exclude_inactive),
+            exclude_found: self.exclude_found.unwrap_or(defaults.exclude_found),
+            exclude_owners,
+            max_consecutive_dnf: if self.include_dnf.unwrap_or(false) {
+                None
+            } else {
+                self.max_consecutive_dnf.or(defaults.max_consecutive_dnf)
+            },
+            found_within_days: self.found_within_days.or(defaults.found_within_days),
+            max_distance_m: self.max_distance_m.or(defaults.max_distance_m),
+            events_from: parse_date(self.events_from.as_deref())?.or(defaults.events_from),
+            events_until: parse_date(self.events_until.as_deref())?.or(defaults.events_until),
+            query: self
+                .q
+                .as_deref()
+                .filter(|q| !q.is_empty())
+                .map(str::parse)
+                .transpose()?
+                .or(defaults.query),
+        })
+    }
+}
+
+/// The filter profile by name, the given defaults if there is none.
+fn profile(
+    name: Option<&str>,
+    defaults: GeocacheFilter,
+    config: &Config,
+) -> Result<GeocacheFilter, String> {
+    match name.filter(|name| !name.is_empty()) {
+        Some(name) => config.filter_profile(name).ok_or_else(|| {
+            format!(
+                "unknown profile {}, built-in ones are {}",
+                name,
+                GeocacheFilter::PRESETS.join(", ")
+            )
+        }),
+        None => Ok(defaults),
+    }
+}
+
+/// A YYYY-MM-DD date, None if the parameter wasn't given.
+fn parse_date(date: Option<&str>) -> Result<Option<NaiveDate>, String> {
+    date.map(|date| {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map_err(|e| format!("invalid date {}: {}", date, e))
+    })
+    .transpose()
+}
+
+/// Comma separated values, None if the parameter wasn't given.
+fn parse_list<T>(list: Option<&str>) -> Result<Option<Vec<T>>, String>
+where
+    T: FromStr,
+    T::Err: ToString,
+{
+    list.map(|list| {
+        list.split(',')
+            .filter(|name| !name.trim().is_empty())
+            .map(|name| T::from_str(name.trim()).map_err(|e| e.to_string()))
+            .collect()
+    })
+    .transpose()
+}
+
+/// Corridor width from the request, within the range tracks can cover.
+fn corridor_m(corridor_m: Option<u16>) -> Result<u16, ApiError> {
+    match corridor_m {
+        None => Ok(DEFAULT_CORRIDOR_M),
+        Some(m) if m > 0 && m <= MAX_CORRIDOR_M => Ok(m),
+        Some(m) => {
+            info!("Rejecting corridor of {} m", m);
+            Err(ApiError::bad_request(format!(
+                "corridor_m must be between 1 and {}",
+                MAX_CORRIDOR_M
+            )))
+        }
+    }
+}
+
+/// Center and radius of an area from the request, on the map and within the range area jobs
+/// can cover.
+fn check_area(center: &Coordinate, radius: f64) -> Result<(), ApiError> {
+    if !(-90.0..=90.0).contains(&center.lat) || !(-180.0..=180.0).contains(&center.lon) {
+        info!("Rejecting area around {}", center);
+        return Err(ApiError::bad_request(
+            "lat must be between -90 and 90, lon between -180 and 180",
+        ));
+    }
+    // also false for NaN
+    if !(radius > 0.0 && radius <= MAX_RADIUS_M) {
+        info!("Rejecting radius of {} m", radius);
+        return Err(ApiError::bad_request(format!(
+            "radius must be greater than 0 and at most {}",
+            MAX_RADIUS_M
+        )));
+    }
+    Ok(())
+}
+
+/// Legs from the request, either split_km kilometers each or one per day.
+fn split(split_km: Option<f64>, split_days: Option<bool>) -> Result<Split, ApiError> {
+    match (split_km, split_days.unwrap_or(false)) {
+        (Some(_), true) => Err(ApiError::bad_request(
+            "split_km and split_days can't be combined".to_string(),
+        )),
+        (Some(km), false) if km > 0.0 => Ok(Split::Distance(km)),
+        (Some(_), false) => Err(ApiError::bad_request(
+            "split_km must be positive".to_string(),
+        )),
+        (None, true) => Ok(Split::Day),
+        (None, false) => Ok(Split::None),
+    }
+}
+
+/// Uploads that aren't a usable track are the client's fault, tell them what's wrong.
+fn parse_track(
+    data: &[u8],
+    format: TrackFormat,
+    waypoints: bool,
+    reverse: bool,
+) -> Result<gcgeo::Track, ApiError> {
+    let track = gcgeo::Track::parse(data, format, waypoints).map_err(|e| {
+        info!("Rejecting track: {}", e);
+        ApiError::bad_request(e.to_string())
+    })?;
+    Ok(if reverse { track.reversed() } else { track })
+}
+
+#[post(
+    "/track?<corridor_m>&<waypoints>&<reverse>&<split_km>&<split_days>&<callback>&<force>&<priority>&<selection..>",
+    data = "<data>"
+)]
+#[allow(clippy::too_many_arguments)]
 async fn enqueue_task(
     data: Data<'_>,
+    corridor_m: Option<u16>,
+    waypoints: Option<bool>,
+    reverse: Option<bool>,
+    split_km: Option<f64>,
+    split_days: Option<bool>,
+    callback: Option<&str>,
+    force: Option<bool>,
+    priority: Option<&str>,
+    selection: SelectionParams,
+    content_type: Option<&rocket::http::ContentType>,
+    caller: Caller,
     jobs: &State<JobQueue>,
-) -> Result<JobResult, rocket::http::Status> {
+    cache: &State<Arc<Cache>>,
+    config: &State<Config>,
+) -> Result<JobResult, ApiError> {
+    caller.check_quota(jobs)?;
     let data_stream = data.open(10.megabytes());
-    let reader = data_stream.into_bytes().await.unwrap();
-    let track = gcgeo::Track::from_gpx(reader.as_slice()).unwrap();
-    let job = compute_track(track, jobs.inner()).await;
+    let reader = data_stream
+        .into_bytes()
+        .await
+        .map_err(|e| ApiError::bad_request(format!("unable to read track: {}", e)))?;
+    let format = content_type
+        .and_then(|ct| TrackFormat::from_media_type(ct.top().as_str(), ct.sub().as_str()))
+        .unwrap_or_else(|| TrackFormat::sniff(&reader));
+    let track = parse_track(
+        &reader,
+        format,
+        waypoints.unwrap_or(false),
+        reverse.unwrap_or(false),
+    )?;
+    let job = compute_track(
+        track,
+        self::corridor_m(corridor_m)?,
+        self::split(split_km, split_days)?,
+        selection.selection(),
+        self::filter(&selection, GeocacheFilter::quick_stop(), config)?,
+        self::callback(callback)?,
+        force.unwrap_or(false),
+        self::priority(priority)?,
+        caller.name(),
+        jobs.inner(),
+        cache.inner(),
+        config.inner(),
+    )
+    .await;
 
-    if let Some(geocaches) = job.get_geocaches() {
+    if let Some(snapshot) = job.get_snapshot() {
         info!("Job {} is already done", job.id);
-        Ok(JobResult::Complete(geocaches, None))
+        Ok(complete(&job, snapshot))
     } else {
         info!("Job {} is still running", job.id);
-        Ok(JobResult::Incomplete(job.get_message()))
+        Ok(JobResult::Incomplete(jobs.status(&job)))
+    }
+}
+
+/// Everything inside a GeoJSON Polygon or MultiPolygon, e.g. drawn on geojson.io
+#[post(
+    "/polygon?<callback>&<force>&<priority>&<selection..>",
+    data = "<data>"
+)]
+#[allow(clippy::too_many_arguments)]
+async fn enqueue_polygon(
+    data: Data<'_>,
+    callback: Option<&str>,
+    force: Option<bool>,
+    priority: Option<&str>,
+    selection: SelectionParams,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+    config: &State<Config>,
+) -> Result<JobResult, ApiError> {
+    caller.check_quota(jobs)?;
+    let body = data
+        .open(10.megabytes())
+        .into_string()
+        .await
+        .map_err(|e| ApiError::bad_request(format!("unable to read polygon: {}", e)))?;
+    let polygon: gcgeo::Polygon = body.parse().map_err(|e| {
+        info!("Rejecting polygon: {}", e);
+        ApiError::bad_request(format!("invalid polygon: {}", e))
+    })?;
+    let job = compute_polygon(
+        polygon,
+        selection.selection(),
+        self::filter(&selection, GeocacheFilter::default(), config)?,
+        self::callback(callback)?,
+        force.unwrap_or(false),
+        self::priority(priority)?,
+        caller.name(),
+        jobs.inner(),
+        cache.inner(),
+    )
+    .await;
+
+    if let Some(snapshot) = job.get_snapshot() {
+        info!("Job {} is already done", job.id);
+        Ok(complete(&job, snapshot))
+    } else {
+        info!("Job {} is still running", job.id);
+        Ok(JobResult::Incomplete(jobs.status(&job)))
     }
 }
 
@@ -188,86 +760,1282 @@ struct AreaRequest {
     lat: f64,
     lon: f64,
     radius: f64,
+    callback: Option<String>,
+    force: bool,
+    priority: Option<String>,
 }
 
-#[post("/area", data = "<area>")]
+/// An area job from a form, with the same filter fields as GET /area.
+#[post("/area", format = "form", data = "<body>")]
 async fn enqueue_area(
-    area: Form<AreaRequest>,
+    body: String,
+    caller: Caller,
     jobs: &State<JobQueue>,
-) -> Result<JobResult, rocket::http::Status> {
+    cache: &State<Arc<Cache>>,
+    config: &State<Config>,
+) -> Result<JobResult, ApiError> {
+    caller.check_quota(jobs)?;
+    // both parse leniently, each takes its fields from the form
+    let area: AreaRequest = Form::parse(&body).map_err(|e| {
+        info!("Rejecting area: {}", e);
+        ApiError::bad_request(format!("invalid area: {}", e))
+    })?;
+    let selection: SelectionParams = Form::parse(&body).map_err(|e| {
+        info!("Rejecting filter: {}", e);
+        ApiError::bad_request(format!("invalid filter: {}", e))
+    })?;
+    let center = Coordinate {
+        lat: area.lat,
+        lon: area.lon,
+    };
+    check_area(&center, area.radius)?;
     let job = compute_area(
-        &Coordinate {
-            lat: area.lat,
-            lon: area.lon,
-        },
+        &center,
         area.radius,
+        selection.selection(),
+        filter(&selection, GeocacheFilter::default(), config)?,
+        callback(area.callback.as_deref())?,
+        area.force,
+        priority(area.priority.as_deref())?,
+        caller.name(),
+        jobs.inner(),
+        cache.inner(),
+    )
+    .await;
+    if let Some(snapshot) = job.get_snapshot() {
+        info!("Job {} is already done", job.id);
+        Ok(complete(&job, snapshot))
+    } else {
+        info!("Job {} is still running", job.id);
+        Ok(JobResult::Incomplete(jobs.status(&job)))
+    }
+}
+
+/// Area job for a typed in coordinate, e.g. /area?coord=N 47° 56.769 E 008° 30.123&radius=2000
+#[get("/area?<coord>&<radius>&<callback>&<force>&<priority>&<selection..>")]
+#[allow(clippy::too_many_arguments)]
+async fn enqueue_area_coord(
+    coord: &str,
+    radius: f64,
+    callback: Option<&str>,
+    force: Option<bool>,
+    priority: Option<&str>,
+    selection: SelectionParams,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+    config: &State<Config>,
+) -> Result<JobResult, ApiError> {
+    caller.check_quota(jobs)?;
+    let coord: Coordinate = coord.parse().map_err(|e| {
+        info!("Rejecting area: {}", e);
+        ApiError::bad_request(format!("invalid area: {}", e))
+    })?;
+    check_area(&coord, radius)?;
+    info!("Area around {} ({})", coord.to_dm(), coord);
+    let job = compute_area(
+        &coord,
+        radius,
+        selection.selection(),
+        self::filter(&selection, GeocacheFilter::default(), config)?,
+        self::callback(callback)?,
+        force.unwrap_or(false),
+        self::priority(priority)?,
+        caller.name(),
+        jobs.inner(),
+        cache.inner(),
+    )
+    .await;
+    if let Some(snapshot) = job.get_snapshot() {
+        info!("Job {} is already done", job.id);
+        Ok(complete(&job, snapshot))
+    } else {
+        info!("Job {} is still running", job.id);
+        Ok(JobResult::Incomplete(jobs.status(&job)))
+    }
+}
+
+/// Everything inside minLat,minLon,maxLat,maxLon, e.g. /bbox?bbox=47.9,8.4,48.1,8.6
+#[get("/bbox?<bbox>&<callback>&<force>&<priority>&<selection..>")]
+#[allow(clippy::too_many_arguments)]
+async fn enqueue_bbox(
+    bbox: &str,
+    callback: Option<&str>,
+    force: Option<bool>,
+    priority: Option<&str>,
+    selection: SelectionParams,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+    config: &State<Config>,
+) -> Result<JobResult, ApiError> {
+    caller.check_quota(jobs)?;
+    let bbox: gcgeo::BBox = bbox.parse().map_err(|e: String| {
+        info!("Rejecting bbox: {}", e);
+        ApiError::bad_request(e)
+    })?;
+    let job = compute_bbox(
+        bbox,
+        selection.selection(),
+        self::filter(&selection, GeocacheFilter::default(), config)?,
+        self::callback(callback)?,
+        force.unwrap_or(false),
+        self::priority(priority)?,
+        caller.name(),
         jobs.inner(),
+        cache.inner(),
     )
     .await;
-    if let Some(geocaches) = job.get_geocaches() {
+    if let Some(snapshot) = job.get_snapshot() {
         info!("Job {} is already done", job.id);
-        Ok(JobResult::Complete(geocaches, None))
+        Ok(complete(&job, snapshot))
     } else {
         info!("Job {} is still running", job.id);
-        Ok(JobResult::Incomplete(job.get_message()))
+        Ok(JobResult::Incomplete(jobs.status(&job)))
     }
 }
 
+/// Same as GET /bbox, with minLat,minLon,maxLat,maxLon as the body
+#[post("/bbox?<callback>&<force>&<priority>&<selection..>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn enqueue_bbox_body(
+    data: Data<'_>,
+    callback: Option<&str>,
+    force: Option<bool>,
+    priority: Option<&str>,
+    selection: SelectionParams,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+    config: &State<Config>,
+) -> Result<JobResult, ApiError> {
+    let body = data
+        .open(1.kibibytes())
+        .into_string()
+        .await
+        .map_err(|e| ApiError::bad_request(format!("unable to read bbox: {}", e)))?;
+    enqueue_bbox(
+        body.trim(),
+        callback,
+        force,
+        priority,
+        selection,
+        caller,
+        jobs,
+        cache,
+        config,
+    )
+    .await
+}
+
 #[derive(FromForm)]
 struct UploadForm<'r> {
     file: &'r [u8],
+    corridor_m: Option<u16>,
+    waypoints: bool,
+    reverse: bool,
+    split_km: Option<f64>,
+    split_days: bool,
+    include_codes: Option<String>,
+    exclude_codes: Option<String>,
+    force: bool,
+    profile: Option<String>,
+    /// cache types replacing those of the profile, one field per type
+    types: Vec<String>,
+    min_d: Option<f32>,
+    max_d: Option<f32>,
+    min_t: Option<f32>,
+    max_t: Option<f32>,
+    /// download the result in this format instead of going back to the job list
+    format: Option<String>,
+}
+
+/// The track upload with filter controls and the result format.
+#[get("/upload")]
+fn upload_form(config: &State<Config>) -> Template {
+    let mut profiles: Vec<String> = GeocacheFilter::PRESETS
+        .iter()
+        .map(|name| name.to_string())
+        .chain(config.filter_profiles.keys().cloned())
+        .collect();
+    profiles.sort();
+    profiles.dedup();
+    Template::render("upload", context! { profiles })
+}
+
+/// One line of the job list.
+#[derive(rocket::serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+struct JobRow {
+    id: String,
+    kind: String,
+    /// since the job started, e.g. "5 min"
+    age: String,
+    #[serde(flatten)]
+    summary: JobSummary,
 }
 
 #[get("/jobs")]
-async fn list_jobs(jobs: &State<JobQueue>) -> Template {
-    let mut jobs_for_context = Vec::new();
-    for job in jobs.list().iter() {
-        jobs_for_context.push((job.id.clone(), job.get_message()));
-    }
-    Template::render("jobs", context! { jobs: jobs_for_context })
+async fn list_jobs(caller: Caller, jobs: &State<JobQueue>, cache: &State<Arc<Cache>>) -> Template {
+    let now = Utc::now();
+    let mut rows: Vec<JobRow> = jobs
+        .list()
+        .iter()
+        .filter(|job| caller.sees(job.owner.as_deref()))
+        .map(|job| {
+            let status = jobs.status(job);
+            let snapshot = job.get_snapshot();
+            JobRow {
+                id: job.id.clone(),
+                kind: job.kind.clone(),
+                age: format_age(now - status.started_at),
+                summary: JobSummary::new(status, snapshot.as_ref()),
+            }
+        })
+        .collect();
+    rows.sort_by_key(|row| std::cmp::Reverse(row.summary.progress.started_at));
+    let needs_login = match cache.auth_status().await {
+        Ok(accounts) => accounts.iter().any(|(_, status, _)| status.needs_login),
+        Err(e) => {
+            error!("Unable to load auth status: {}", e);
+            false
+        }
+    };
+    Template::render("jobs", context! { jobs: rows, needs_login })
 }
 
 #[post("/jobs", data = "<data>")]
-async fn upload(data: Form<UploadForm<'_>>, jobs: &State<JobQueue>) -> Template {
-    let track = gcgeo::Track::from_gpx(data.file).unwrap();
-    compute_track(track, jobs.inner()).await;
-    list_jobs(jobs).await
+async fn upload(
+    data: Form<UploadForm<'_>>,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+    config: &State<Config>,
+    exporters: &State<Exporters>,
+) -> Result<rocket::Either<Template, Redirect>, ApiError> {
+    caller.check_quota(jobs)?;
+    let download = data.format.as_deref().filter(|format| !format.is_empty());
+    if let Some(format) = download {
+        if format != "zip" && exporters.by_name(format).is_none() {
+            return Err(ApiError::bad_request(format!("unknown format {}", format)));
+        }
+    }
+    let corridor_m = corridor_m(data.corridor_m)?;
+    let format = TrackFormat::sniff(data.file);
+    let track = parse_track(data.file, format, data.waypoints, data.reverse)?;
+    let selection =
+        CodeSelection::parse(data.include_codes.as_deref(), data.exclude_codes.as_deref());
+    let split = split(data.split_km, Some(data.split_days))?;
+    let mut filter = profile(
+        data.profile.as_deref(),
+        GeocacheFilter::quick_stop(),
+        config,
+    )
+    .map_err(ApiError::bad_request)?;
+    if !data.types.is_empty() {
+        filter.types = data
+            .types
+            .iter()
+            .map(|name| name.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|e| ApiError::bad_request(format!("invalid types: {}", e)))?;
+    }
+    filter.min_d = data.min_d.or(filter.min_d);
+    filter.max_d = data.max_d.or(filter.max_d);
+    filter.min_t = data.min_t.or(filter.min_t);
+    filter.max_t = data.max_t.or(filter.max_t);
+    let job = compute_track(
+        track,
+        corridor_m,
+        split,
+        selection,
+        filter,
+        None,
+        data.force,
+        None,
+        caller.name(),
+        jobs.inner(),
+        cache.inner(),
+        config.inner(),
+    )
+    .await;
+    Ok(match download {
+        Some(format) => rocket::Either::Right(Redirect::to(format!(
+            "/jobs/{}/download/{}",
+            job.id, format
+        ))),
+        None => rocket::Either::Left(list_jobs(caller, jobs, cache).await),
+    })
+}
+
+/// Stops the job if it is still queued or running and forgets it, also across restarts.
+#[delete("/jobs/<job_id>")]
+async fn delete_job(
+    job_id: &str,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+) -> Result<(), ApiError> {
+    let job = find_job(job_id, &caller, jobs, cache).await?;
+    jobs.remove(&job.id);
+    cache.delete_job(&job.id).await.map_err(|e| {
+        error!("Unable to delete job {}: {}", job.id, e);
+        ApiError::from(e)
+    })?;
+    info!("Deleted job {}", job.id);
+    Ok(())
 }
 
-#[get("/jobs/<job_id>")]
-async fn query_task(job_id: &str, jobs: &State<JobQueue>) -> JobResult {
-    let job = jobs.get(job_id).unwrap();
-    if let Some(geocaches) = job.get_geocaches() {
-        JobResult::Complete(geocaches, None)
+/// The result as GeoJSON, or in the format asked for by name or Accept header. Clients asking for
+/// application/json get the job summary, 202 while it is still running.
+#[get("/jobs/<job_id>?<cluster>&<flavor>&<format>&<result..>")]
+#[allow(clippy::too_many_arguments)]
+async fn query_task(
+    job_id: &str,
+    flavor: Option<&str>,
+    format: Option<&str>,
+    cluster: Option<u8>,
+    result: ResultParams<'_>,
+    accept: Option<&rocket::http::Accept>,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+    exporters: &State<Exporters>,
+) -> Result<JobResult, ApiError> {
+    let job = find_job(job_id, &caller, jobs, cache).await?;
+    let format = match format {
+        None => None,
+        Some(name) => Some(
+            exporters
+                .by_name(name)
+                .ok_or_else(|| {
+                    info!("Rejecting unknown format {}", name);
+                    ApiError::bad_request(format!("unknown format {}", name))
+                })?
+                .name(),
+        ),
+    };
+    if format.is_none() && cluster.is_none() && wants_json(accept) {
+        let snapshot = job.get_snapshot();
+        return Ok(JobResult::Summary(JobSummary::new(
+            jobs.status(&job),
+            snapshot.as_ref(),
+        )));
+    }
+    if let Some(snapshot) = job.get_snapshot() {
+        let file_stem = job.file_stem(&snapshot);
+        if let Some(z) = cluster {
+            if z > MAX_CLUSTER_ZOOM {
+                info!("Rejecting cluster zoom {}", z);
+                return Err(ApiError::bad_request(format!(
+                    "cluster zoom must be at most {}",
+                    MAX_CLUSTER_ZOOM
+                )));
+            }
+            // the map only shows where the geocaches are, no need to sort or translate them
+            let snapshot = with_leg(snapshot, result.leg)?;
+            let geocaches = with_health(snapshot.geocaches, result.health, &snapshot.ts)?;
+            let snapshot = Snapshot {
+                geocaches,
+                ..snapshot
+            };
+            return Ok(JobResult::Clustered(
+                bundle_clusters(&snapshot, z),
+                snapshot.ts,
+            ));
+        }
+        let (snapshot, mode) = result.prepare(snapshot, cache).await?;
+        Ok(JobResult::Complete(
+            snapshot,
+            format,
+            ExportOptions {
+                mode,
+                flavor: gpx_flavor(flavor)?,
+                file_stem: Some(file_stem),
+                ..Default::default()
+            },
+        ))
     } else {
-        JobResult::Incomplete(job.get_message())
+        Ok(JobResult::Incomplete(jobs.status(&job)))
     }
 }
 
-#[get("/jobs/<job_id>/gpi")]
-async fn query_task_gpi(job_id: &str, jobs: &State<JobQueue>) -> JobResult {
-    let job = jobs.get(job_id).unwrap();
-    if let Some(geocaches) = job.get_geocaches() {
-        JobResult::Complete(
+/// The result as a file named after the job, e.g. route-2024-06-01.gpx. Formats by name, zip is
+/// the GPI together with its icons.
+#[get("/jobs/<job_id>/download/<format>?<flavor>&<result..>")]
+#[allow(clippy::too_many_arguments)]
+async fn download(
+    job_id: &str,
+    format: &str,
+    flavor: Option<&str>,
+    result: ResultParams<'_>,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+    exporters: &State<Exporters>,
+) -> Result<JobResult, ApiError> {
+    let name = if format == "zip" { "gpi.zip" } else { format };
+    let exporter = exporters.by_name(name).ok_or_else(|| {
+        info!("Rejecting unknown format {}", format);
+        ApiError::not_found(format!("unknown format {}", format))
+    })?;
+    let job = find_job(job_id, &caller, jobs, cache).await?;
+    let Some(snapshot) = job.get_snapshot() else {
+        return Ok(JobResult::Incomplete(jobs.status(&job)));
+    };
+    let file_stem = job.file_stem(&snapshot);
+    let (snapshot, mode) = result.prepare(snapshot, cache).await?;
+    let found = if exporter.name() == "gpi.zip" {
+        cache.found_codes().await.map_err(|e| {
+            error!("Unable to load found geocaches: {}", e);
+            ApiError::from(e)
+        })?
+    } else {
+        HashSet::new()
+    };
+    Ok(JobResult::Complete(
+        snapshot,
+        Some(exporter.name()),
+        ExportOptions {
+            mode,
+            flavor: gpx_flavor(flavor)?,
+            found,
+            file_stem: Some(file_stem),
+        },
+    ))
+}
+
+#[get("/jobs/<job_id>/gpi?<result..>")]
+async fn query_task_gpi(
+    job_id: &str,
+    result: ResultParams<'_>,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+) -> Result<JobResult, ApiError> {
+    let job = find_job(job_id, &caller, jobs, cache).await?;
+    if let Some(snapshot) = job.get_snapshot() {
+        let file_stem = job.file_stem(&snapshot);
+        let (snapshot, mode) = result.prepare(snapshot, cache).await?;
+        Ok(JobResult::Complete(
+            snapshot,
+            Some("gpi"),
+            ExportOptions {
+                mode,
+                file_stem: Some(file_stem),
+                ..Default::default()
+            },
+        ))
+    } else {
+        Ok(JobResult::Incomplete(jobs.status(&job)))
+    }
+}
+
+/// KML for Google Earth, kmz=true zips it together with the GPI icons
+#[get("/jobs/<job_id>/kml?<kmz>&<result..>")]
+async fn query_task_kml(
+    job_id: &str,
+    kmz: Option<bool>,
+    result: ResultParams<'_>,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+) -> Result<JobResult, ApiError> {
+    let job = find_job(job_id, &caller, jobs, cache).await?;
+    if let Some(snapshot) = job.get_snapshot() {
+        let file_stem = job.file_stem(&snapshot);
+        let (snapshot, mode) = result.prepare(snapshot, cache).await?;
+        let format = if kmz.unwrap_or(false) { "kmz" } else { "kml" };
+        Ok(JobResult::Complete(
+            snapshot,
+            Some(format),
+            ExportOptions {
+                mode,
+                file_stem: Some(file_stem),
+                ..Default::default()
+            },
+        ))
+    } else {
+        Ok(JobResult::Incomplete(jobs.status(&job)))
+    }
+}
+
+/// Printable list for the car, grouped by distance along the route, pdf=true prints it to PDF.
+/// In route order unless sort= asks for another one.
+#[get("/jobs/<job_id>/sheet?<pdf>&<result..>")]
+async fn query_task_sheet(
+    job_id: &str,
+    pdf: Option<bool>,
+    result: ResultParams<'_>,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+    templates: Metadata<'_>,
+) -> Result<JobResult, ApiError> {
+    let job = find_job(job_id, &caller, jobs, cache).await?;
+    if let Some(snapshot) = job.get_snapshot() {
+        let file_stem = job.file_stem(&snapshot);
+        // the limit counts the geocaches of the chosen types only
+        let params = ResultParams {
+            sort: result.sort.or(Some("route_order")),
+            limit: None,
+            ..result
+        };
+        let (snapshot, mode) = params.prepare(snapshot, cache).await?;
+        let geocaches: Vec<Geocache> = snapshot
+            .geocaches
+            .into_iter()
+            .filter(|gc| mode.includes(gc))
+            .take(result.limit.unwrap_or(usize::MAX))
+            .collect();
+        let sheet = TripSheet::new(&geocaches, &snapshot.distances, &snapshot.ts);
+        if !pdf.unwrap_or(false) {
+            return Ok(JobResult::Sheet(sheet));
+        }
+        let (_, html) = templates
+            .render("sheet", context! { sheet: &sheet })
+            .ok_or(rocket::http::Status::InternalServerError)?;
+        let pdf = TripSheet::pdf(&html).await.map_err(|e| {
+            error!("Unable to print trip sheet: {}", e);
+            ApiError::from(e)
+        })?;
+        Ok(JobResult::Pdf(pdf, file_stem))
+    } else {
+        Ok(JobResult::Incomplete(jobs.status(&job)))
+    }
+}
+
+#[get("/jobs/<job_id>/gpi.zip?<result..>")]
+async fn query_task_gpi_zip(
+    job_id: &str,
+    result: ResultParams<'_>,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+) -> Result<JobResult, ApiError> {
+    let job = find_job(job_id, &caller, jobs, cache).await?;
+    if let Some(snapshot) = job.get_snapshot() {
+        let file_stem = job.file_stem(&snapshot);
+        let (snapshot, mode) = result.prepare(snapshot, cache).await?;
+        let found = cache.found_codes().await.map_err(|e| {
+            error!("Unable to load found geocaches: {}", e);
+            ApiError::from(e)
+        })?;
+        Ok(JobResult::Complete(
+            snapshot,
+            Some("gpi.zip"),
+            ExportOptions {
+                mode,
+                found,
+                file_stem: Some(file_stem),
+                ..Default::default()
+            },
+        ))
+    } else {
+        Ok(JobResult::Incomplete(jobs.status(&job)))
+    }
+}
+
+/// Select the result of a finished job again with another filter, from the geocaches already in
+/// the database. Unset filter fields match everything, the codes of the job can't be changed
+/// here. 409 while the job is still running.
+#[post("/jobs/<job_id>/refine", data = "<refine>")]
+async fn refine_task(
+    job_id: &str,
+    refine: Form<SelectionParams>,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+    config: &State<Config>,
+) -> Result<JobResult, ApiError> {
+    let filter = filter(&refine, GeocacheFilter::default(), config)?;
+    let job = find_job(job_id, &caller, jobs, cache).await?;
+    match job.refine(filter, cache).await {
+        Ok(true) => {}
+        Ok(false) => {
+            info!("Job {} can't be refined", job.id);
+            return Err(ApiError::conflict(format!(
+                "job {} can't be refined before it's finished",
+                job.id
+            )));
+        }
+        Err(e) => {
+            error!("Unable to refine job {}: {}", job.id, e);
+            return Err(e.into());
+        }
+    }
+    let snapshot = job
+        .get_snapshot()
+        .ok_or(rocket::http::Status::InternalServerError)?;
+    Ok(complete(&job, snapshot))
+}
+
+/// The job from memory or the database, 404 if there never was such a job or it belongs to
+/// someone else.
+async fn find_job(
+    job_id: &str,
+    caller: &Caller,
+    jobs: &JobQueue,
+    cache: &Cache,
+) -> Result<Arc<Job>, ApiError> {
+    match jobs.restore(job_id, cache).await {
+        Ok(Some(job)) if caller.sees(job.owner.as_deref()) => Ok(job),
+        Ok(Some(job)) => {
+            info!("Job {} belongs to someone else", job.id);
+            Err(ApiError::not_found(format!("no job {}", job_id)))
+        }
+        Ok(None) => {
+            info!("Unknown job {}", job_id);
+            Err(ApiError::not_found(format!("no job {}", job_id)))
+        }
+        Err(e) => {
+            error!("Unable to restore job {}: {}", job_id, e);
+            Err(e.into())
+        }
+    }
+}
+
+/// The filter of a job request, 400 for unknown types.
+fn filter(
+    selection: &SelectionParams,
+    defaults: GeocacheFilter,
+    config: &Config,
+) -> Result<GeocacheFilter, ApiError> {
+    selection.filter(defaults, config).map_err(|e| {
+        info!("Rejecting filter: {}", e);
+        ApiError::bad_request(format!("invalid filter: {}", e))
+    })
+}
+
+/// high, normal or low, by the job's size if not given
+fn priority(priority: Option<&str>) -> Result<Option<Priority>, ApiError> {
+    priority
+        .map(|priority| {
+            priority.parse().map_err(|e| {
+                info!("Rejecting priority: {}", e);
+                ApiError::bad_request(format!("invalid priority: {}", e))
+            })
+        })
+        .transpose()
+}
+
+/// Notify a webhook, ntfy topic or Telegram chat when the job is done
+fn callback(callback: Option<&str>) -> Result<Option<Callback>, ApiError> {
+    callback
+        .map(|callback| {
+            callback.parse().map_err(|e| {
+                info!("Rejecting callback: {}", e);
+                ApiError::bad_request(format!("invalid callback: {}", e))
+            })
+        })
+        .transpose()
+}
+
+/// Cache types to export, "all" or one type. Traditionals only by default.
+fn export_mode(types: Option<&str>) -> Result<ExportMode, ApiError> {
+    match types {
+        None => Ok(ExportMode::default()),
+        Some(types) => types.parse().map_err(|e| {
+            info!("Rejecting export mode: {}", e);
+            ApiError::bad_request(format!("invalid export mode: {}", e))
+        }),
+    }
+}
+
+/// GPX for Garmin/c:geo, Locus Map or OsmAnd
+fn gpx_flavor(flavor: Option<&str>) -> Result<GpxFlavor, ApiError> {
+    match flavor {
+        None => Ok(GpxFlavor::default()),
+        Some(flavor) => flavor.parse().map_err(|e| {
+            info!("Rejecting GPX flavor: {}", e);
+            ApiError::bad_request(format!("invalid GPX flavor: {}", e))
+        }),
+    }
+}
+
+/// What part of a job's result to export and how, the same for all result formats, e.g.
+/// leg=1&health=good,unknown&types=all&sort=favorites&limit=200&lang=de
+#[derive(FromForm)]
+struct ResultParams<'r> {
+    leg: Option<usize>,
+    lang: Option<&'r str>,
+    health: Option<&'r str>,
+    types: Option<&'r str>,
+    sort: Option<&'r str>,
+    limit: Option<usize>,
+}
+
+impl ResultParams<'_> {
+    /// The snapshot narrowed down to the leg, health, found and limit asked for, sorted and
+    /// translated, with the export mode from types=.
+    async fn prepare(
+        &self,
+        snapshot: Snapshot,
+        cache: &Cache,
+    ) -> Result<(Snapshot, ExportMode), ApiError> {
+        let snapshot = with_leg(snapshot, self.leg)?;
+        let geocaches = with_health(snapshot.geocaches, self.health, &snapshot.ts)?;
+        let mode = export_mode(self.types)?;
+        let geocaches = with_found(geocaches, &mode, cache).await?;
+        let geocaches = with_order(
             geocaches,
-            Some(Accept::from_str("application/gpi").unwrap()),
-        )
+            self.sort,
+            self.limit,
+            &snapshot.distances,
+            &snapshot.offsets,
+        )?;
+        let geocaches = translated(geocaches, self.lang, cache).await;
+        Ok((
+            Snapshot {
+                geocaches,
+                ..snapshot
+            },
+            mode,
+        ))
+    }
+}
+
+/// Beyond this clusters are single geocaches anyway
+const MAX_CLUSTER_ZOOM: u8 = 20;
+
+/// Narrow the result down to one leg of a split track, counted from 0.
+fn with_leg(snapshot: Snapshot, leg: Option<usize>) -> Result<Snapshot, ApiError> {
+    match leg {
+        None => Ok(snapshot),
+        Some(index) => snapshot.leg(index).ok_or_else(|| {
+            info!("No leg {} in this job", index);
+            ApiError::not_found(format!("no leg {} in this job", index))
+        }),
+    }
+}
+
+/// Keep only geocaches with one of the comma separated health values, e.g. health=good,unknown
+fn with_health(
+    geocaches: Vec<Geocache>,
+    health: Option<&str>,
+    now: &DateTime<Utc>,
+) -> Result<Vec<Geocache>, ApiError> {
+    let Some(health) = health else {
+        return Ok(geocaches);
+    };
+    let accepted = health
+        .split(',')
+        .map(Health::from_str)
+        .collect::<Result<Vec<Health>, _>>()
+        .map_err(|e| {
+            info!("Rejecting health filter: {}", e);
+            ApiError::bad_request(format!("invalid health filter: {}", e))
+        })?;
+    Ok(geocaches
+        .into_iter()
+        .filter(|gc| accepted.contains(&gc.health(*now)))
+        .collect())
+}
+
+/// sort=distance|favorites|difficulty|route_order, then at most limit geocaches, e.g. the 200 with
+/// the most favorite points along a route
+fn with_order(
+    mut geocaches: Vec<Geocache>,
+    sort: Option<&str>,
+    limit: Option<usize>,
+    distances: &HashMap<String, f64>,
+    offsets: &HashMap<String, f64>,
+) -> Result<Vec<Geocache>, ApiError> {
+    if let Some(sort) = sort {
+        let order: SortOrder = sort.parse().map_err(|e| {
+            info!("Rejecting sort: {}", e);
+            ApiError::bad_request(format!("invalid sort: {}", e))
+        })?;
+        order.sort(&mut geocaches, distances, offsets);
+    }
+    if let Some(limit) = limit {
+        geocaches.truncate(limit);
+    }
+    Ok(geocaches)
+}
+
+/// types=found keeps the geocaches with a find among the logs submitted here
+async fn with_found(
+    geocaches: Vec<Geocache>,
+    mode: &ExportMode,
+    cache: &Cache,
+) -> Result<Vec<Geocache>, ApiError> {
+    if *mode != ExportMode::Found {
+        return Ok(geocaches);
+    }
+    let found = cache.found_codes().await.map_err(|e| {
+        error!("Unable to load found geocaches: {}", e);
+        ApiError::from(e)
+    })?;
+    Ok(geocaches
+        .into_iter()
+        .filter(|gc| found.contains(&gc.code))
+        .collect())
+}
+
+async fn translated(geocaches: Vec<Geocache>, lang: Option<&str>, cache: &Cache) -> Vec<Geocache> {
+    match lang {
+        Some(lang) => cache.translate(geocaches, lang).await,
+        None => geocaches,
+    }
+}
+
+#[get("/admin/auth")]
+async fn admin_auth(_admin: Admin, cache: &State<Arc<Cache>>) -> Result<Template, ApiError> {
+    let accounts = cache.auth_status().await.map_err(|e| {
+        error!("Unable to load auth status: {}", e);
+        ApiError::from(e)
+    })?;
+    let now = Utc::now();
+    let age = |ts: Option<DateTime<Utc>>| ts.map(|ts| format_age(now - ts));
+    let accounts: Vec<_> = accounts
+        .into_iter()
+        .map(|(account, status, calls)| {
+            context! {
+                account,
+                calls_today: calls,
+                needs_login: status.needs_login,
+                access_token_expiry: status.access_token_expiry.map(|ts| ts.to_rfc3339()),
+                access_token_remaining: status.access_token_expiry.map(|ts| format_age(ts - now)),
+                access_token_expired: status.access_token_expiry.map(|ts| ts <= now).unwrap_or(true),
+                refresh_token_age: age(status.refresh_token_updated),
+                last_refresh: status.last_refresh.map(|ts| ts.to_rfc3339()),
+                last_refresh_age: age(status.last_refresh),
+                last_error: status.last_error,
+                last_error_age: age(status.last_error_ts),
+            }
+        })
+        .collect();
+    Ok(Template::render("admin_auth", context! { accounts }))
+}
+
+#[derive(rocket::serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+struct AccountHealth {
+    account: String,
+    calls_today: u32,
+    #[serde(flatten)]
+    status: gc::AuthStatus,
+}
+
+/// Token state of all accounts, 503 if any of them needs a new login.
+#[get("/health/auth")]
+async fn health_auth(
+    cache: &State<Arc<Cache>>,
+) -> Result<(rocket::http::Status, Json<Vec<AccountHealth>>), ApiError> {
+    let accounts: Vec<AccountHealth> = cache
+        .auth_status()
+        .await
+        .map_err(|e| {
+            error!("Unable to load auth status: {}", e);
+            ApiError::from(e)
+        })?
+        .into_iter()
+        .map(|(account, status, calls_today)| AccountHealth {
+            account,
+            calls_today,
+            status,
+        })
+        .collect();
+    let status = if accounts.iter().any(|account| account.status.needs_login) {
+        rocket::http::Status::ServiceUnavailable
+    } else {
+        rocket::http::Status::Ok
+    };
+    Ok((status, Json(accounts)))
+}
+
+#[derive(FromForm)]
+struct RefreshRequest {
+    account: String,
+}
+
+async fn latest_digest(region: &str, cache: &Cache) -> Result<gc::Digest, ApiError> {
+    match cache.latest_digest(region).await {
+        Ok(Some(digest)) => Ok(digest),
+        Ok(None) => Err(ApiError::not_found(format!("no digest for {}", region))),
+        Err(e) => {
+            error!("Unable to load digest {}: {}", region, e);
+            Err(e.into())
+        }
+    }
+}
+
+#[get("/digest/<region>")]
+async fn digest_html(region: &str, cache: &State<Arc<Cache>>) -> Result<Template, ApiError> {
+    let digest = latest_digest(region, cache).await?;
+    Ok(Template::render(
+        "digest",
+        context! {
+            since: digest.since.map(|ts| ts.format("%Y-%m-%d").to_string()),
+            ts: digest.ts.format("%Y-%m-%d").to_string(),
+            sections: [
+                ("New geocaches", &digest.new),
+                ("Archived", &digest.archived),
+                ("Newly disabled", &digest.disabled),
+                ("DNF streaks", &digest.dnf_streaks),
+                ("Upcoming events", &digest.events),
+            ],
+            digest: &digest,
+        },
+    ))
+}
+
+#[get("/digest/<region>/markdown")]
+async fn digest_markdown(region: &str, cache: &State<Arc<Cache>>) -> Result<String, ApiError> {
+    Ok(latest_digest(region, cache).await?.to_markdown())
+}
+
+#[post("/admin/auth/refresh", data = "<refresh>")]
+async fn admin_auth_refresh(
+    _admin: Admin,
+    refresh: Form<RefreshRequest>,
+    cache: &State<Arc<Cache>>,
+) -> Redirect {
+    if let Err(e) = cache.refresh_token(&refresh.account).await {
+        error!("Forced token refresh of {} failed: {}", refresh.account, e);
+    }
+    Redirect::to(uri!(admin_auth))
+}
+
+/// Log an account in to Groundspeak, admins only as it replaces the tokens of the service.
+#[get("/auth/login?<account>")]
+async fn auth_login(
+    _admin: Admin,
+    account: Option<&str>,
+    cache: &State<Arc<Cache>>,
+) -> Result<Redirect, ApiError> {
+    let url = cache.login_url(account).await.map_err(|e| {
+        error!("Unable to start Groundspeak login for {:?}: {}", account, e);
+        ApiError::from(e)
+    })?;
+    Ok(Redirect::to(url))
+}
+
+/// Groundspeak redirects here after login, auth_redirect_url has to point to this route. Public,
+/// but it only accepts the one-time state of a login an admin started with /auth/login.
+#[get("/auth/callback?<code>&<state>")]
+async fn auth_callback(code: &str, state: &str, cache: &State<Arc<Cache>>) -> Redirect {
+    if let Err(e) = cache.complete_login(code, state).await {
+        error!("Groundspeak login failed: {}", e);
+    }
+    Redirect::to(uri!(admin_auth))
+}
+
+/// Where ApiKeyAuth sends requests without a valid key.
+#[get("/auth/required")]
+fn auth_required() -> (rocket::http::Status, String) {
+    (
+        rocket::http::Status::Unauthorized,
+        "A valid API key is required: send it as X-Api-Key header or bearer token, or log in with /login?api_key=...\n".to_string(),
+    )
+}
+
+/// Where RateLimit sends requests over the limit, it adds the Retry-After header.
+#[get("/rate-limited")]
+fn rate_limited() -> (rocket::http::Status, &'static str) {
+    (
+        rocket::http::Status::TooManyRequests,
+        "Too many new jobs, try again later\n",
+    )
+}
+
+/// Keeps the key in a cookie so the browser pages work without it in every URL.
+#[get("/login?<api_key>")]
+async fn login(
+    api_key: &str,
+    cookies: &rocket::http::CookieJar<'_>,
+    cache: &State<Arc<Cache>>,
+) -> Result<Redirect, ApiError> {
+    let user = cache.user_for_key(api_key).await.map_err(|e| {
+        error!("Unable to look up API key: {}", e);
+        ApiError::from(e)
+    })?;
+    if user.is_none() {
+        info!("Login with an unknown API key");
+        return Err(ApiError::new(
+            rocket::http::Status::Unauthorized,
+            "unknown API key",
+        ));
+    }
+    cookies.add(
+        rocket::http::Cookie::build(("api_key", api_key.to_string()))
+            .http_only(true)
+            .same_site(rocket::http::SameSite::Lax),
+    );
+    Ok(Redirect::to(uri!(list_jobs)))
+}
+
+#[get("/logout")]
+fn logout(cookies: &rocket::http::CookieJar<'_>) -> Redirect {
+    cookies.remove("api_key");
+    Redirect::to(uri!(auth_required))
+}
+
+#[derive(FromForm)]
+struct UserRequest {
+    name: String,
+    jobs_per_hour: Option<u32>,
+    #[field(default = false)]
+    admin: bool,
+}
+
+/// Creates the user, or replaces the key of an existing one, and returns the new key.
+#[post("/admin/users", data = "<request>")]
+async fn admin_create_user(
+    _admin: Admin,
+    request: Form<UserRequest>,
+    cache: &State<Arc<Cache>>,
+) -> Result<String, ApiError> {
+    let name = request.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::bad_request("name is missing"));
+    }
+    if name.eq_ignore_ascii_case(ADMIN_USER) {
+        info!("Rejecting reserved user name {}", name);
+        return Err(ApiError::bad_request(format!(
+            "{} is reserved for the admin key",
+            ADMIN_USER
+        )));
+    }
+    let user = User {
+        name: name.to_string(),
+        admin: request.admin,
+        jobs_per_hour: request.jobs_per_hour,
+    };
+    let key = cache.create_user(&user).await.map_err(|e| {
+        error!("Unable to create user {}: {}", name, e);
+        ApiError::from(e)
+    })?;
+    info!("Created API key for {}", name);
+    Ok(key)
+}
+
+#[derive(FromForm)]
+struct PurgeRequest {
+    /// minLat,minLon,maxLat,maxLon
+    bbox: String,
+}
+
+#[post("/admin/purge", data = "<purge>")]
+async fn admin_purge(
+    _admin: Admin,
+    purge: Form<PurgeRequest>,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+) -> Result<String, ApiError> {
+    let bbox: gcgeo::BBox = purge.bbox.parse().map_err(ApiError::bad_request)?;
+    let job = compute_purge(bbox, jobs.inner(), cache.inner()).await;
+    Ok(format!("Purge job {} started", job.id))
+}
+
+fn format_age(duration: chrono::Duration) -> String {
+    let minutes = duration.num_minutes().abs();
+    if minutes < 60 {
+        format!("{} min", minutes)
+    } else if minutes < 48 * 60 {
+        format!("{} h {} min", minutes / 60, minutes % 60)
     } else {
-        JobResult::Incomplete(job.get_message())
+        format!("{} days", minutes / (24 * 60))
     }
 }
 
 #[get("/test")]
 fn test_route() -> String {
-    return Local::now().to_rfc3339();
+    Local::now().to_rfc3339()
 }
 
-// for debugging, needed?
-#[get("/geocache/<code>")]
-async fn fetch(code: String) -> String {
-    let cache = Cache::new_lite().await.unwrap();
-    let geocaches = cache.get(vec![code]).await.ok().unwrap();
-    let geocache = geocaches.get(0).unwrap();
-    info!("Geocache: {:?}", geocache);
-    serde_json::to_string(geocache).unwrap()
+/// One geocache from the cache, fetched if it isn't there or too old. As JSON, or in the format
+/// asked for, e.g. /geocache/GC1BXN4?format=gpx. X-Provenance tells where it came from.
+#[get("/geocache/<code>?<format>&<flavor>")]
+async fn fetch(
+    code: &str,
+    format: Option<&str>,
+    flavor: Option<&str>,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+    exporters: &State<Exporters>,
+) -> Result<WithHeaders<rocket::Either<Json<Geocache>, JobResult>>, ApiError> {
+    caller.check_quota(jobs)?;
+    let code = Geocache::parse_code(code).map_err(|e| {
+        info!("Rejecting geocache: {}", e);
+        ApiError::bad_request(e)
+    })?;
+    let exporter = format
+        .map(|name| {
+            exporters.by_name(name).ok_or_else(|| {
+                info!("Rejecting unknown format {}", name);
+                ApiError::bad_request(format!("unknown format {}", name))
+            })
+        })
+        .transpose()?;
+    let lookup = cache
+        .lookup(vec![code.clone()], &ApiUsage::default())
+        .await
+        .map_err(|e| {
+            error!("Unable to get geocache {}: {}", code, e);
+            ApiError::from(e)
+        })?
+        .into_iter()
+        .next()
+        .ok_or_else(|| ApiError::not_found(format!("no geocache {}", code)))?;
+    let header = provenance(&lookup);
+    let geocache = lookup
+        .geocache
+        .ok_or_else(|| ApiError::not_found(format!("no geocache {}", code)))?;
+    let Some(exporter) = exporter else {
+        return Ok(WithHeaders(
+            rocket::Either::Left(Json(geocache)),
+            vec![header],
+        ));
+    };
+    Ok(WithHeaders(
+        rocket::Either::Right(JobResult::Complete(
+            Snapshot::of(vec![geocache], Utc::now()),
+            Some(exporter.name()),
+            ExportOptions {
+                mode: ExportMode::All,
+                flavor: gpx_flavor(flavor)?,
+                file_stem: Some(code),
+                ..Default::default()
+            },
+        )),
+        vec![header],
+    ))
+}
+
+/// Most codes looked up at once, a bookmark list holds up to 1000
+const MAX_BATCH_CODES: usize = 1000;
+
+/// Geocaches by code, a JSON array or one code per line in the body, fetched if they aren't
+/// cached or too old. Exported like a job result, all types unless types= says otherwise. Codes
+/// without a result are listed in X-Missing-Codes, premium only ones in X-Premium-Codes.
+#[post("/geocaches?<format>&<flavor>&<types>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn fetch_batch(
+    data: Data<'_>,
+    format: Option<&str>,
+    flavor: Option<&str>,
+    types: Option<&str>,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+    exporters: &State<Exporters>,
+) -> Result<WithHeaders<JobResult>, ApiError> {
+    caller.check_quota(jobs)?;
+    let format = format
+        .map(|name| {
+            exporters
+                .by_name(name)
+                .map(|exporter| exporter.name())
+                .ok_or_else(|| {
+                    info!("Rejecting unknown format {}", name);
+                    ApiError::bad_request(format!("unknown format {}", name))
+                })
+        })
+        .transpose()?;
+    let mode = match types {
+        None => ExportMode::All,
+        types => export_mode(types)?,
+    };
+    let body = data
+        .open(1.mebibytes())
+        .into_string()
+        .await
+        .map_err(|e| ApiError::bad_request(format!("unable to read codes: {}", e)))?;
+    let codes = Geocache::parse_codes(&body).map_err(|e| {
+        info!("Rejecting codes: {}", e);
+        ApiError::bad_request(e)
+    })?;
+    if codes.is_empty() || codes.len() > MAX_BATCH_CODES {
+        return Err(ApiError::bad_request(format!(
+            "between 1 and {} codes please, got {}",
+            MAX_BATCH_CODES,
+            codes.len()
+        )));
+    }
+    info!("Looking up {} geocaches", codes.len());
+    let count = codes.len();
+    let lookups = cache
+        .lookup(codes, &ApiUsage::default())
+        .await
+        .map_err(|e| {
+            error!("Unable to get {} geocaches: {}", count, e);
+            ApiError::from(e)
+        })?;
+    let headers = unresolved(&lookups);
+    // lookup keeps the order of the list
+    let geocaches = lookups
+        .into_iter()
+        .filter_map(|lookup| lookup.geocache)
+        .collect();
+    let now = Utc::now();
+    Ok(WithHeaders(
+        JobResult::Complete(
+            Snapshot::of(geocaches, now),
+            format,
+            ExportOptions {
+                mode,
+                flavor: gpx_flavor(flavor)?,
+                file_stem: Some(format!("geocaches-{}", now.format("%Y-%m-%d"))),
+                ..Default::default()
+            },
+        ),
+        headers,
+    ))
+}
+
+#[get("/geocache/<code>/description?<lang>")]
+async fn description(
+    code: &str,
+    lang: Option<&str>,
+    caller: Caller,
+    jobs: &State<JobQueue>,
+    cache: &State<Arc<Cache>>,
+) -> Result<Template, ApiError> {
+    caller.check_quota(jobs)?;
+    let code = Geocache::parse_code(code).map_err(ApiError::bad_request)?;
+    let geocaches = cache
+        .get(vec![code.clone()], &ApiUsage::default())
+        .await
+        .map_err(|e| {
+            error!("Unable to get geocache {}: {}", code, e);
+            ApiError::from(e)
+        })?;
+    let geocache = geocaches
+        .first()
+        .ok_or_else(|| ApiError::not_found(format!("no geocache {}", code)))?;
+    let files = cache.download_images(geocache).await.map_err(|e| {
+        error!("Unable to download images for {}: {}", code, e);
+        ApiError::from(e)
+    })?;
+    let geocaches = translated(geocaches, lang, cache).await;
+    let geocache = &geocaches[0];
+    Ok(Template::render(
+        "description",
+        context! {
+            name: &geocache.name,
+            short_description: gc::images::rewrite_links(&geocache.short_description, &files, "/images/"),
+            long_description: gc::images::rewrite_links(&geocache.long_description, &files, "/images/"),
+            gallery: files.values().collect::<Vec<_>>(),
+        },
+    ))
+}
+
+/// Posts the logs as the main account, admins only.
+#[post("/logs", data = "<drafts>")]
+async fn submit_logs(
+    _admin: Admin,
+    drafts: Json<Vec<gc::logqueue::LogDraft>>,
+    cache: &State<Arc<Cache>>,
+) -> Result<Json<gc::logqueue::LogQueueStatus>, ApiError> {
+    cache
+        .submit_logs(drafts.into_inner())
+        .await
+        .map(Json)
+        .map_err(|e| {
+            error!("Unable to submit logs: {}", e);
+            ApiError::from(e)
+        })
 }
@@ -0,0 +1,174 @@
+use std::str::FromStr;
+
+use log::{info, warn};
+use rocket::serde::Serialize;
+
+use crate::config::Config;
+use crate::gc::groundspeak::{http_client, Error};
+use crate::job::{Job, JobStatus};
+
+/// Where to report a finished job, from the callback parameter of a job request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Callback {
+    /// POST the job status as JSON to this URL
+    Webhook(String),
+    /// ntfy topic URL, gets a plain text message
+    Ntfy(String),
+    /// Telegram chat id, messages come from the bot configured with telegram_bot_token
+    Telegram(String),
+}
+
+impl FromStr for Callback {
+    type Err = String;
+
+    /// https://example.com/hook, ntfy:topic, ntfy:https://ntfy.example.com/topic or telegram:chat_id
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let is_url = |s: &str| s.starts_with("https://") || s.starts_with("http://");
+        if let Some(topic) = s.strip_prefix("ntfy:") {
+            if is_url(topic) {
+                return Ok(Self::Ntfy(topic.to_string()));
+            }
+            if !topic.is_empty()
+                && topic
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                return Ok(Self::Ntfy(format!("https://ntfy.sh/{}", topic)));
+            }
+            return Err(format!("invalid ntfy topic {}", topic));
+        }
+        if let Some(chat) = s.strip_prefix("telegram:") {
+            if chat.parse::<i64>().is_ok() || chat.starts_with('@') {
+                return Ok(Self::Telegram(chat.to_string()));
+            }
+            return Err(format!("invalid telegram chat {}", chat));
+        }
+        if is_url(s) {
+            return Ok(Self::Webhook(s.to_string()));
+        }
+        Err(format!("unknown callback {}", s))
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Notification<'a> {
+    job: &'a JobStatus,
+    geocaches: usize,
+    url: Option<String>,
+}
+
+/// Sends the callbacks of finished jobs.
+pub struct Notifier {
+    client: reqwest::Client,
+    telegram_bot_token: Option<String>,
+    public_url: Option<String>,
+}
+
+impl Notifier {
+    pub fn new(config: &Config) -> Result<Self, Error> {
+        Ok(Self {
+            client: http_client(config)?,
+            telegram_bot_token: config.telegram_bot_token.clone(),
+            public_url: config
+                .public_url
+                .as_ref()
+                .map(|url| url.trim_end_matches('/').to_string()),
+        })
+    }
+
+    /// Report the job to its callback, failures are only logged.
+    pub async fn notify(&self, job: &Job, status: &JobStatus) {
+        let Some(callback) = job.callback() else {
+            return;
+        };
+        let notification = Notification {
+            job: status,
+            geocaches: job
+                .get_snapshot()
+                .map_or(0, |snapshot| snapshot.geocaches.len()),
+            url: self
+                .public_url
+                .as_ref()
+                .map(|base| format!("{}/jobs/{}", base, job.id)),
+        };
+        info!("Notifying {:?} about job {}", callback, job.id);
+        if let Err(e) = self.send(callback, &notification).await {
+            warn!(
+                "Unable to notify {:?} about job {}: {}",
+                callback, job.id, e
+            );
+        }
+    }
+
+    async fn send(
+        &self,
+        callback: &Callback,
+        notification: &Notification<'_>,
+    ) -> Result<(), reqwest::Error> {
+        let request = match callback {
+            Callback::Webhook(url) => self.client.post(url).json(notification),
+            Callback::Ntfy(url) => self
+                .client
+                .post(url)
+                .header("Title", "cachecache job finished")
+                .body(Self::text(notification)),
+            Callback::Telegram(chat) => {
+                let Some(token) = &self.telegram_bot_token else {
+                    warn!("Telegram callback requested, but no telegram_bot_token configured");
+                    return Ok(());
+                };
+                self.client
+                    .post(format!("https://api.telegram.org/bot{}/sendMessage", token))
+                    .json(&rocket::serde::json::json!({
+                        "chat_id": chat,
+                        "text": Self::text(notification),
+                    }))
+            }
+        };
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    fn text(notification: &Notification) -> String {
+        let mut text = format!(
+            "Job {}: {}, {} geocaches",
+            notification.job.id, notification.job.message, notification.geocaches
+        );
+        if let Some(url) = &notification.url {
+            text.push('\n');
+            text.push_str(url);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_callbacks() {
+        assert_eq!(
+            "https://example.com/hook".parse(),
+            Ok(Callback::Webhook("https://example.com/hook".to_string()))
+        );
+        assert_eq!(
+            "ntfy:my-caches".parse(),
+            Ok(Callback::Ntfy("https://ntfy.sh/my-caches".to_string()))
+        );
+        assert_eq!(
+            "ntfy:https://ntfy.example.com/caches".parse(),
+            Ok(Callback::Ntfy(
+                "https://ntfy.example.com/caches".to_string()
+            ))
+        );
+        assert_eq!(
+            "telegram:-100123".parse(),
+            Ok(Callback::Telegram("-100123".to_string()))
+        );
+        assert!("ntfy:a/b".parse::<Callback>().is_err());
+        assert!("telegram:me".parse::<Callback>().is_err());
+        assert!("ftp://example.com".parse::<Callback>().is_err());
+    }
+}
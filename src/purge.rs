@@ -0,0 +1,18 @@
+use std::sync::Arc;
+
+use crate::gc::Cache;
+use crate::gcgeo::BBox;
+use crate::job::{Job, JobQueue};
+
+pub async fn compute_purge(bbox: BBox, jobs: &JobQueue, cache: &Arc<Cache>) -> Arc<Job> {
+    let job = Arc::new(Job::new().with_kind("purge"));
+    let job_for_result = job.clone();
+    jobs.add(job.clone());
+
+    let cache = cache.clone();
+    jobs.spawn(job.clone(), async move {
+        job.purge(&bbox, &cache).await;
+    });
+
+    job_for_result
+}
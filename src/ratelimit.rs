@@ -0,0 +1,175 @@
+//! Protects the service itself, independent of the Groundspeak quotas: each API key, or each
+//! client address without a key, gets a token bucket for requests that start jobs or look up
+//! geocaches.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Header, Method};
+use rocket::{Data, Request, Response};
+
+use crate::config::Config;
+
+/// Where limited requests end up, answered with 429.
+pub const LIMITED_PATH: &str = "/rate-limited";
+
+/// Buckets are dropped once they are full again, checked when there are more than this.
+const MAX_BUCKETS: usize = 1000;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Seconds until the next token, set by on_request for on_response.
+struct RetryAfter(Option<u64>);
+
+/// Token bucket limiter for the routes that create jobs.
+pub struct RateLimit {
+    /// requests in a burst
+    capacity: f64,
+    /// tokens added per second, 0 disables the limit
+    rate: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimit {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            capacity: config.rate_limit_burst.max(1) as f64,
+            rate: config.rate_limit_per_minute.max(0.0) / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.capacity)
+    }
+
+    /// Takes a token for client, or tells how long to wait for the next one.
+    fn take(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        if self.rate <= 0.0 {
+            return Ok(());
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|_, bucket| self.refilled(bucket, now) < self.capacity);
+        }
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+/// The routes that start jobs or look up geocaches, including the JSON API.
+fn creates_job(method: Method, path: &str) -> bool {
+    let path = path.strip_prefix("/api/v1").unwrap_or(path);
+    match method {
+        Method::Post => {
+            matches!(
+                path,
+                "/track" | "/polygon" | "/area" | "/bbox" | "/jobs" | "/geocaches"
+            ) || path.starts_with("/jobs/")
+        }
+        Method::Get => {
+            path == "/area"
+                || path == "/bbox"
+                || path.starts_with("/geocache/")
+                || path.starts_with("/geocaches/")
+        }
+        _ => false,
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RateLimit {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limit for job creation",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        if !creates_job(req.method(), req.uri().path().as_str()) {
+            return;
+        }
+        let client = match crate::auth::api_key(req) {
+            Some(key) => format!("key:{}", crate::gc::key_hash(&key)),
+            None => match req.client_ip() {
+                Some(ip) => format!("ip:{}", ip),
+                None => return,
+            },
+        };
+        if let Err(wait) = self.take(&client, Instant::now()) {
+            info!(
+                "Rate limit for {} {} reached, retry in {:?}",
+                req.method(),
+                req.uri().path(),
+                wait
+            );
+            let secs = wait.as_secs_f64().ceil() as u64;
+            req.local_cache(|| RetryAfter(Some(secs.max(1))));
+            req.set_method(Method::Get);
+            req.set_uri(Origin::parse(LIMITED_PATH).unwrap());
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if let Some(secs) = req.local_cache(|| RetryAfter(None)).0 {
+            res.set_header(Header::new("Retry-After", secs.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        let limit = RateLimit {
+            capacity: 2.0,
+            rate: 1.0 / 30.0,
+            buckets: Mutex::new(HashMap::new()),
+        };
+        let now = Instant::now();
+        assert!(limit.take("a", now).is_ok());
+        assert!(limit.take("a", now).is_ok());
+        let wait = |result: Result<(), Duration>| result.unwrap_err().as_secs_f64().round() as u64;
+        assert_eq!(wait(limit.take("a", now)), 30);
+        assert!(limit.take("b", now).is_ok());
+        assert_eq!(wait(limit.take("a", now + Duration::from_secs(20))), 10);
+        assert!(limit.take("a", now + Duration::from_secs(30)).is_ok());
+    }
+
+    #[test]
+    fn job_routes() {
+        assert!(creates_job(Method::Post, "/track"));
+        assert!(creates_job(Method::Get, "/area"));
+        assert!(creates_job(Method::Get, "/bbox"));
+        assert!(creates_job(Method::Post, "/jobs/abc/refine"));
+        assert!(creates_job(Method::Post, "/api/v1/jobs/area"));
+        assert!(!creates_job(Method::Get, "/jobs"));
+        assert!(!creates_job(Method::Get, "/api/v1/jobs/abc"));
+        assert!(creates_job(Method::Post, "/geocaches"));
+        assert!(creates_job(Method::Get, "/geocache/GC1BXN4"));
+        assert!(creates_job(Method::Get, "/geocache/GC1BXN4/description"));
+        assert!(creates_job(Method::Get, "/api/v1/geocaches/GC1BXN4"));
+        assert!(!creates_job(Method::Post, "/logs"));
+    }
+}
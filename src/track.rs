@@ -1,52 +1,142 @@
 use std::sync::Arc;
 
-use crate::gc::groundspeak::GcCode;
+use chrono::Duration;
+
+use crate::config::Config;
 use crate::gc::Cache;
-use crate::gcgeo::{CacheType, Geocache, Track};
-use crate::job::{Job, JobQueue};
+use crate::gcgeo::{Corridor, Geocache, GeocacheFilter, Region, Track};
+use crate::job::{approx_within, CodeSelection, InputKey, Job, JobQueue, Priority};
+use crate::notify::Callback;
 
-pub async fn compute_track(track: Track, jobs: &JobQueue) -> Arc<Job> {
-    // ugh, there must be a nicer way, right?
-    let track_pre_filter = track.clone();
-    let track_post_filter = track.clone();
-    let tiles = track.tiles;
+/// Distance in meters a geocache may be away from the track
+pub const DEFAULT_CORRIDOR_M: u16 = 100;
+/// Tracks discover the tiles around each point at zoom 14, which reach at least this far
+pub const MAX_CORRIDOR_M: u16 = 1000;
+/// A pause in the recording this long starts a new day
+const DAY_GAP_HOURS: i64 = 6;
+/// Tracks ending this close to their start are round trips or out-and-back routes
+const LOOP_TOLERANCE_M: f64 = 200.0;
+
+/// How to cut a track into legs that can be downloaded separately.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Split {
+    None,
+    /// about this many kilometers per leg
+    Distance(f64),
+    /// one leg per day, by the timestamps in the track
+    Day,
+}
 
-    let pre_filter = {
-        move |gc: &GcCode| match &gc.approx_coord {
-            Some(coord) => track_pre_filter.near(&coord) <= 100,
-            None => true,
+#[allow(clippy::too_many_arguments)]
+pub async fn compute_track(
+    track: Track,
+    corridor_m: u16,
+    split: Split,
+    selection: CodeSelection,
+    filter: GeocacheFilter,
+    callback: Option<Callback>,
+    force: bool,
+    priority: Option<Priority>,
+    owner: Option<String>,
+    jobs: &JobQueue,
+    cache: &Arc<Cache>,
+    config: &Config,
+) -> Arc<Job> {
+    info!(
+        "Track from {}, {:.1} km{}",
+        track.report,
+        track.length() / 1000.0,
+        if track.is_loop(LOOP_TOLERANCE_M) {
+            ", loop"
+        } else {
+            ""
         }
+    );
+    let corridor_m = corridor_m.min(MAX_CORRIDOR_M);
+    let key = InputKey::new(
+        "track",
+        &track.positions(),
+        &format!("{} {:?}", corridor_m, split),
+        &selection,
+        &filter,
+    );
+    if let Some(job) = jobs
+        .find_identical(key, owner.as_deref())
+        .filter(|_| !force)
+    {
+        info!("Reusing job {} for the same track", job.id);
+        return job;
+    }
+    let legs = match split {
+        Split::None => vec![],
+        Split::Distance(km) => track.split_by_distance(km),
+        Split::Day => track.split_by_time_gap(Duration::hours(DAY_GAP_HOURS)),
     };
+    let legs: Vec<(String, Box<dyn Region>)> = if legs.len() > 1 {
+        legs.into_iter()
+            .enumerate()
+            .map(|(index, leg)| {
+                let name = match leg.start_time() {
+                    Some(start) if split == Split::Day => {
+                        format!("Day {} ({})", index + 1, start.format("%Y-%m-%d"))
+                    }
+                    _ => format!("Leg {}", index + 1),
+                };
+                let corridor = Corridor::new(leg.simplified(config.track_simplify_m), corridor_m);
+                (name, Box::new(corridor) as Box<dyn Region>)
+            })
+            .collect()
+    } else {
+        vec![]
+    };
+    info!("Track split into {} legs", legs.len());
+
+    let points = track.point_count();
+    let track = track.simplified(config.track_simplify_m);
+    info!(
+        "Track simplified from {} to {} points",
+        points,
+        track.point_count()
+    );
+    let corridor = Corridor::new(track.clone(), corridor_m);
+    let ascent = track.ascent();
+    let positions = track.positions();
+    let tiles = track.tiles;
+
+    let pre_filter = approx_within(corridor.clone());
+    let job = Arc::new(
+        Job::with_selection(selection)
+            .with_kind("route")
+            .with_legs(legs)
+            .with_ascent(ascent)
+            .with_track(positions)
+            .with_callback(callback)
+            .with_filter(filter)
+            .with_priority(priority.unwrap_or_else(|| Priority::for_tiles(tiles.len())))
+            .with_owner(owner)
+            .with_input_key(key),
+    );
+    let job_for_filter = job.clone();
     let post_filter = move |gc: &Geocache| {
-        is_active(gc) && is_quick_stop(gc) && track_post_filter.near(&gc.coord) <= 100
+        let keep = corridor.contains(&gc.coord);
+        if keep {
+            job_for_filter.set_offset(&gc.code, corridor.track().near(&gc.coord) as f64);
+            if let Some(elevation) = corridor.track().elevation_at(&gc.coord) {
+                job_for_filter.set_elevation(&gc.code, elevation);
+            }
+            if let Some(distance) = corridor.track().distance_along(&gc.coord) {
+                job_for_filter.set_distance(&gc.code, distance);
+            }
+        }
+        keep
     };
-    let job = Arc::new(Job::new());
     let job_for_result = job.clone();
     jobs.add(job.clone());
-    let handle = tokio::task::spawn(async move {
-        let cache = Cache::new_lite().await.unwrap();
+    let cache = cache.clone();
+    jobs.spawn(job.clone(), async move {
         job.process_filtered(tiles, &cache, pre_filter, post_filter)
             .await;
     });
 
-    // If everything is already cached, the job will finish very quickly, and we can immediately return the result
-    let timeout = tokio::time::Duration::from_secs(2);
-    let _ = tokio::time::timeout(timeout, handle).await;
-
     job_for_result
 }
-
-fn is_active(gc: &Geocache) -> bool {
-    !gc.is_premium && gc.available && !gc.archived
-}
-
-fn is_quick_stop(gc: &Geocache) -> bool {
-    let quick_type = match gc.cache_type {
-        // CacheType::Traditional | CacheType::Earth | CacheType::Webcam => true,
-        CacheType::Traditional => true,
-        _ => false,
-    };
-    let quick_diff_terrain = gc.difficulty <= 3.0 && gc.terrain <= 3.0;
-
-    quick_type && quick_diff_terrain
-}